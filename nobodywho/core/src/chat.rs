@@ -1,8 +1,13 @@
 use crate::chat_state;
+use crate::code_fence;
+use crate::json_stream;
 use crate::llm;
-use tokio::sync::mpsc;
+use crate::postprocess;
+use crate::replay;
+use crate::sampler_config;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::StreamExt;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ChatLoopError {
@@ -13,7 +18,7 @@ pub enum ChatLoopError {
         This is likely because you're using an older GGUF file, \
         which might not include a chat template. \
         For example, this is the case for most LLaMA2-based GGUF files. \
-        Try using a more recent GGUF model file. \
+        Try using a more recent GGUF model file, or set a fallback chat template. \
         If you want to check if a given model includes a chat template, \
         you can use the gguf-dump script from llama.cpp. \
         Here is a more technical detailed error: {0}"
@@ -21,7 +26,7 @@ pub enum ChatLoopError {
     InitChatTemplateError(#[from] chat_state::FromModelError),
 
     #[error("Failed rendering chat template: {0}")]
-    RenderChatTemplateError(#[from] minijinja::Error),
+    RenderChatTemplateError(#[from] chat_state::ApplyTemplateError),
 
     #[error("Failed initializing the LLM worker: {0}")]
     InitWorkerError(#[from] llm::InitWorkerError),
@@ -38,70 +43,759 @@ pub enum ChatLoopError {
 
 pub trait ChatOutput {
     fn emit_token(&self, token: String);
+    /// Called with each generated token and how likely the LLM found it, if the worker was built with
+    /// `token_probabilities`. This is the token as generated, even if `ChatParams::stream_field` leaves it out of `emit_token`.
+    fn emit_token_probability(&self, _token: &str, _probability: f32) {}
+    /// Called with the ids of every token sampled for a response, right before the response itself is emitted.
+    /// Useful for debugging, since the text hides special tokens and how it was split up.
+    fn emit_response_tokens(&self, _tokens: Vec<i32>) {}
+    /// Called when a markdown code block starts in the response, with the language after the fence, which may be empty.
+    /// Only called when `ChatParams::detect_code_blocks` is set.
+    fn emit_code_block_started(&self, _language: String) {}
+    /// Called with the code inside code blocks as it is generated, instead of `emit_token`. By default, it goes to `emit_token`.
+    fn emit_code(&self, code: String) {
+        self.emit_token(code);
+    }
+    /// Called when a code block ends, or the response ends inside of one.
+    fn emit_code_block_ended(&self) {}
     fn emit_response(&self, resp: String);
     fn emit_error(&self, err: String);
+    /// Called with the prompt as the LLM sees it, before generating a response.
+    /// Only called when `ChatParams::echo_prompt` is set.
+    fn emit_prompt(&self, _prompt: String) {}
+    /// Called with the text generated so far, when generating a response fails partway through.
+    /// The error itself is sent to `emit_error`, before this is called.
+    fn emit_partial_response(&self, _partial: String) {}
+    /// Called when the context fills up while generating, if the worker was built with `ask_on_context_full`.
+    /// Generation pauses until a strategy is sent back. By default, the context is shifted right away.
+    fn emit_context_full(&self, resolve_to: oneshot::Sender<llm::OverflowStrategy>) {
+        let _ = resolve_to.send(llm::OverflowStrategy::Shift);
+    }
+    /// Called with the most likely next tokens before each token is sampled, if the worker was built
+    /// with `logit_processor_top_k`. Generation pauses until adjustments to their logits are sent back.
+    /// By default, nothing is adjusted.
+    fn emit_adjust_logits(
+        &self,
+        _candidates: Vec<llm::TokenCandidate>,
+        resolve_to: oneshot::Sender<Vec<(i32, f32)>>,
+    ) {
+        let _ = resolve_to.send(vec![]);
+    }
+    /// Called with the reason the response ended, right before `emit_response`.
+    fn emit_finish_reason(&self, _finish_reason: llm::FinishReason) {}
+    /// Called instead of `emit_response` when every attempt at a `ChatMsg::SayJson` gave invalid JSON, with the last attempt.
+    fn emit_invalid_response(&self, _response: String) {}
+    /// Called with every response to a `ChatMsg::SayN`, once they are all generated.
+    fn emit_responses(&self, _responses: Vec<String>) {}
+    /// Called for each prompt of a `CompletionMsg::CompleteBatch` as soon as its response is done, with the index of the prompt.
+    fn emit_batch_response(&self, _index: usize, _response: String) {}
+    /// Called with every draft from a `ChatMsg::Draft`, once they are all generated.
+    fn emit_drafts(&self, _drafts: Vec<String>) {}
+    /// Called with the answer to a `ChatMsg::AskYesNo`, and how confident the LLM is in it, from 0.5 to 1.
+    /// The answer is `None` if there is none: the question was ignored or couldn't be read, or the LLM found
+    /// "yes" and "no" equally likely.
+    fn emit_yes_no(&self, _answer: Option<bool>, _confidence: f32) {}
+    /// Called once the worker has started, with how long that took.
+    fn emit_worker_ready(&self, _init_duration: std::time::Duration) {}
+    /// Called with how long a `ChatMsg::Say` took, right before `emit_finish_reason`.
+    /// These are the statistics of the turn, like the number of tokens generated.
+    fn emit_turn_timings(&self, _timings: TurnTimings) {}
+    /// Called when the first token of a response is generated, before it is sent to `emit_token`,
+    /// with how long it took since the message was sent. Useful for e.g. ending a "thinking" animation.
+    fn emit_first_token(&self, _time_to_first_token: std::time::Duration) {}
+    /// Called when the context was shifted to make room for the prompt or the response, with how many of the oldest tokens
+    /// were forgotten. The response keeps going, but the LLM no longer sees the beginning of the conversation.
+    fn emit_context_shifted(&self, _n_discarded: u32) {}
+    /// Called when the worker has started and after each message is handled, with how many tokens are in the context
+    /// and how many fit in it. For a `ChatMsg::Say`, it is also called right before `emit_finish_reason`.
+    /// The context can be smaller than `LLMActorParams::n_ctx`, as it is capped at the model's training context length.
+    fn emit_context_usage(&self, _n_past: u32, _n_ctx: u32) {}
+}
+
+/// How long the parts of a single response took.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TurnTimings {
+    /// From sending the message until the first token, which is mostly reading the message.
+    /// For the first message, this includes reading the system prompt.
+    pub prompt: std::time::Duration,
+    /// From the first token until the response was done.
+    pub generation: std::time::Duration,
+    /// The number of tokens generated.
+    pub n_tokens: usize,
 }
 
 pub enum ChatMsg {
     Say(String),
+    /// Generates several independent responses to the same message.
+    /// Only the first one is kept in the chat history.
+    SayN(String, usize),
+    /// Generates a response constrained to JSON, and checks that it parses. If it doesn't, e.g. because the context
+    /// filled up in the middle of an object, it is generated again with another seed, up to the given number of attempts.
+    SayJson(String, usize),
+    /// Reads token ids straight into the context, without the chat template, and generates a response to them.
+    /// The LLM first reads whatever of the chat it hasn't yet, like the end of the last response's turn.
+    /// Neither the tokens nor the response are added to the chat history, so they are forgotten if the conversation
+    /// is read again, e.g. after `SetContextLength`.
+    SayTokens(Vec<i32>),
+    /// Generates several independent responses to a message, like `SayN`, but keeps neither the message nor the
+    /// responses. The context goes back to where it was before the message, so the conversation so far is only
+    /// read once for all of the drafts. Use `CommitDraft` to continue the conversation from one of them.
+    Draft(String, usize),
+    /// Asks the LLM a yes/no question about the conversation so far, and answers it with whichever of "yes" and "no"
+    /// the LLM finds more likely to start its response. Nothing is generated, and the question is not kept in the history.
+    /// Reasoning is turned off for the question, so reasoning models answer right away instead of starting to think.
+    AskYesNo(String),
+    /// Adds the message of the last `Draft`, and the draft with the given index, to the chat history.
+    /// The LLM reads them along with the next message. Drafts are forgotten after any other message.
+    CommitDraft(usize),
+    /// Constrains the responses after this to a GBNF grammar,
+    /// or goes back to the sampler's own grammar setting with `None`.
+    SetGrammar(Option<String>),
+    /// Changes the seed of the sampler, for the responses after this.
+    SetSeed(u32),
     ResetContext(String),
+    /// Defragments the KV cache of the worker, see `LLMActorHandle::defragment`.
+    Defragment,
+    /// Restarts the worker with a new context length. The conversation is kept, and read again with the next message.
+    /// If it doesn't fit in a smaller context, reading it fails like any message that is too long.
+    SetContextLength(u32),
+    /// Forgets every message except the system prompt. Unlike `ResetContext`, the system prompt stays in the context,
+    /// so it doesn't have to be read again, unless the context was shifted or reset since it was read.
+    NewConversation,
+}
+
+/// Parameters for configuring the chat on top of the LLM worker.
+///
+/// # Fields
+/// * `system_prompt` - The first message of the chat, instructing the LLM how to behave
+/// * `max_history_messages` - Maximum number of user and assistant messages to keep, or `None` for no limit
+/// * `echo_prompt` - Whether to send each prompt, round-tripped through the tokenizer, to `ChatOutput::emit_prompt`
+/// * `role_names` - The role names the chat template expects, if they differ from "system", "user" and "assistant"
+/// * `fallback_chat_template` - Chat template to use when the model file doesn't include one
+/// * `chat_template` - Chat template to use instead of the one included in the model file
+/// * `recording` - Whether to record the responses to a file, or verify them against an earlier recording
+/// * `empty_message_placeholder` - Sent instead of user messages that are empty or only whitespace, or `None` to ignore those messages
+/// * `prepend_bos` - Whether to put the model's BOS token at the start of the conversation, when the chat template leaves it out
+/// * `post_process` - Steps applied to each response before it is sent to `ChatOutput::emit_response`. The chat history keeps the unprocessed response
+/// * `stream_field` - For responses that are JSON objects, only stream this string field to `ChatOutput::emit_token`. The full object still goes to `ChatOutput::emit_response`
+/// * `detect_code_blocks` - Whether to pick markdown code blocks out of the streamed response. Their code goes to `ChatOutput::emit_code` instead of `ChatOutput::emit_token`, between `ChatOutput::emit_code_block_started` and `ChatOutput::emit_code_block_ended`
+/// * `fallback_generation_prompt` - Appended after user messages to start the assistant's turn, if the chat template renders nothing for the generation prompt. See `chat_state::ChatState::set_fallback_generation_prompt`
+/// * `enable_thinking` - Turns reasoning on or off for models whose chat template supports it, like Qwen3, or `None` to leave it to the template
+/// * `strip_strings` - Strings removed from the streamed and the final responses, e.g. special tokens like `<|im_end|>` that leak into the text. See `llm::special_token_strings`. The chat history keeps them
+#[derive(Clone, Debug, Default)]
+pub struct ChatParams {
+    pub system_prompt: String,
+    pub max_history_messages: Option<usize>,
+    pub echo_prompt: bool,
+    pub role_names: chat_state::RoleNames,
+    pub fallback_chat_template: Option<String>,
+    pub chat_template: Option<String>,
+    pub recording: Option<replay::RecordingMode>,
+    pub empty_message_placeholder: Option<String>,
+    pub prepend_bos: bool,
+    pub post_process: Vec<postprocess::PostProcessStep>,
+    pub stream_field: Option<String>,
+    pub detect_code_blocks: bool,
+    pub enable_thinking: Option<bool>,
+    pub fallback_generation_prompt: Option<String>,
+    pub strip_strings: Vec<String>,
+}
+
+/// Sets up the chat template and the system prompt, as the chat loop does.
+fn init_chat_state(
+    model: &llm::Model,
+    chat_params: &ChatParams,
+) -> Result<chat_state::ChatState, ChatLoopError> {
+    let mut chat_state = if let Some(chat_template) = &chat_params.chat_template {
+        chat_state::ChatState::from_model_with_template(model, chat_template.clone())?
+    } else {
+        match (
+            chat_state::ChatState::from_model(model),
+            &chat_params.fallback_chat_template,
+        ) {
+            (Err(chat_state::FromModelError::ChatTemplateError(e)), Some(fallback)) => {
+                warn!("Model has no usable chat template, using the fallback template: {e}");
+                chat_state::ChatState::from_model_with_template(model, fallback.clone())?
+            }
+            (result, _) => result?,
+        }
+    };
+    chat_state.set_role_names(chat_params.role_names.clone());
+    chat_state.set_prepend_bos(chat_params.prepend_bos);
+    chat_state.set_enable_thinking(chat_params.enable_thinking);
+    chat_state.set_fallback_generation_prompt(chat_params.fallback_generation_prompt.clone());
+    chat_state.add_message("system".to_string(), chat_params.system_prompt.clone());
+    Ok(chat_state)
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "The system prompt is {n_tokens} tokens long, which leaves no room for messages in a context of {n_ctx} tokens. \
+    Increase the context length, or shorten the system prompt"
+)]
+pub struct SystemPromptTooLongError {
+    pub n_tokens: usize,
+    pub n_ctx: u32,
+}
+
+/// Checks that the system prompt, as the chat template renders it, fits in a context of `n_ctx` tokens with room to spare.
+/// Like the worker, `n_ctx` is capped at the context length the model was trained with.
+/// A too small context otherwise only fails once the first message is read, with a much less obvious error.
+/// If the chat template can't be set up, this passes, and starting the chat reports the template error instead.
+pub fn check_system_prompt_fits(
+    model: &llm::Model,
+    chat_params: &ChatParams,
+    n_ctx: u32,
+) -> Result<(), SystemPromptTooLongError> {
+    let n_ctx = std::cmp::min(n_ctx, model.n_ctx_train());
+    let Ok(mut chat_state) = init_chat_state(model, chat_params) else {
+        return Ok(());
+    };
+    // templates that can't render the system prompt on its own merge it into the first message
+    let system_prompt = match chat_state.render_system_prompt() {
+        Ok(Some(rendered)) => rendered,
+        _ => chat_params.system_prompt.clone(),
+    };
+    let n_tokens = llm::tokenize(model, &system_prompt).map_or(0, |tokens| tokens.len());
+    if n_tokens >= n_ctx as usize {
+        return Err(SystemPromptTooLongError { n_tokens, n_ctx });
+    }
+    Ok(())
+}
+
+/// Checks whether the chat template renders nothing when asked for a generation prompt, which leaves the LLM
+/// without a cue that it is its turn. Broken or unusual templates otherwise only show up as poor responses.
+/// Returns `false` if the chat template can't be set up, since starting the chat reports that anyway.
+pub fn lacks_generation_prompt(model: &llm::Model, chat_params: &ChatParams) -> bool {
+    let Ok(mut chat_state) = init_chat_state(model, chat_params) else {
+        return false;
+    };
+    chat_state.add_message("user".to_string(), "Hi".to_string());
+    let _ = chat_state.render_diff();
+    chat_state.lacks_generation_prompt() == Some(true)
 }
 
 #[tracing::instrument(level = "trace", skip(output, params))]
 pub async fn simple_chat_loop(
     params: llm::LLMActorParams,
-    system_prompt: String,
-    mut msg_rx: mpsc::Receiver<ChatMsg>,
+    chat_params: ChatParams,
+    msg_rx: mpsc::Receiver<ChatMsg>,
     output: Box<dyn ChatOutput>,
 ) -> Result<(), ChatLoopError> {
     // init chat state
-    let mut chat_state = chat_state::ChatState::from_model(&params.model)?;
-    chat_state.add_message("system".to_string(), system_prompt.clone());
+    let chat_state = init_chat_state(&params.model, &chat_params)?;
     info!("Initialized chat state.");
 
+    let sampler_config = params.sampler_config.clone();
+    chat_loop(
+        &params,
+        sampler_config,
+        chat_state,
+        chat_params,
+        msg_rx,
+        output,
+    )
+    .await
+}
+
+/// The chat loop behind `simple_chat_loop`. Its workers are started by `workers`, so it can run on
+/// `llm::mock::MockWorker` in tests, with a chat state set up by hand.
+async fn chat_loop(
+    workers: &impl llm::StartWorker,
+    mut sampler_config: sampler_config::SamplerConfig,
+    mut chat_state: chat_state::ChatState,
+    mut chat_params: ChatParams,
+    mut msg_rx: mpsc::Receiver<ChatMsg>,
+    output: Box<dyn ChatOutput>,
+) -> Result<(), ChatLoopError> {
+    // the streamed tokens are stripped as they arrive, and every full response before the other steps
+    if !chat_params.strip_strings.is_empty() {
+        chat_params.post_process.insert(
+            0,
+            postprocess::PostProcessStep::RemoveStrings(chat_params.strip_strings.clone()),
+        );
+    }
+
     // init actor
-    let actor = llm::LLMActorHandle::new(params).await?;
+    let tokenizer = workers.tokenizer();
+    // the sampler config the worker currently uses, including a grammar set with SetGrammar
+    let mut active_sampler_config = sampler_config.clone();
+    let init_started = std::time::Instant::now();
+    let mut actor = workers.start(None, sampler_config.clone()).await?;
     info!("Initialized actor.");
+    output.emit_worker_ready(init_started.elapsed());
+    output.emit_context_usage(0, actor.n_ctx());
+
+    // right after the system prompt, where a new conversation starts
+    let mut system_prompt_checkpoint = None;
+
+    // every response so far, for recording or verifying them
+    let mut recording = replay::ChatRecording::default();
+
+    // the message and responses of the last Draft, until they are committed or another message arrives
+    let mut drafts: Option<(String, Vec<String>)> = None;
 
     // wait for message from user
     while let Some(msg) = msg_rx.recv().await {
+        let last_drafts = drafts.take();
         match msg {
             ChatMsg::Say(message) => {
-                chat_state.add_message("user".to_string(), message);
-                let diff = chat_state.render_diff()?;
+                let started = std::time::Instant::now();
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
+                let Some((message, diff)) = render_user_message(
+                    message,
+                    &mut chat_state,
+                    &mut system_prompt_checkpoint,
+                    &chat_params,
+                    &actor,
+                    tokenizer,
+                    output.as_ref(),
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                let streamed = stream_response(
+                    &actor,
+                    diff,
+                    started,
+                    output.as_ref(),
+                    StreamOptions::from_chat_params(&chat_params),
+                )
+                .await?;
+                let timings = streamed.timings(started);
+                let (full_response, finish_reason) = match streamed.result {
+                    Ok(done) => done,
+                    // the worker discarded the failed turn, so forget the message too
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding message after recoverable error: {err}");
+                        chat_state.undo_last_message();
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let tokens = streamed.tokens;
 
-                // stream out the response
-                let full_response = actor
-                    .generate_response(diff)
-                    .await
-                    .fold(None, |_, out| match out {
-                        Ok(llm::WriteOutput::Token(token)) => {
-                            output.emit_token(token);
-                            None
+                // we have a full response. send it out.
+                emit_finished_response(
+                    &actor,
+                    output.as_ref(),
+                    &chat_params,
+                    timings,
+                    &full_response,
+                    finish_reason,
+                )
+                .await?;
+
+                recording.responses.push(replay::RecordedResponse {
+                    message,
+                    tokens,
+                    response: full_response.clone(),
+                });
+                match &chat_params.recording {
+                    Some(replay::RecordingMode::Record(path)) => {
+                        if let Err(err) = recording.save(path) {
+                            warn!("Could not save recording: {err}");
                         }
-                        Err(err) => {
-                            error!("Got error from worker: {err:?}");
+                    }
+                    Some(replay::RecordingMode::Verify(expected)) => {
+                        let index = recording.responses.len() - 1;
+                        if let Err(diff) = expected.verify(index, &recording.responses[index]) {
+                            output.emit_error(format!("Response does not match recording: {diff}"));
+                        }
+                    }
+                    None => (),
+                }
+                chat_state.add_message("assistant".to_string(), full_response);
+
+                // the LLM read the response while writing it, but the end of the turn is read with the next message
+                chat_state.mark_response_read()?;
+
+                if streamed.summarize {
+                    replace_history_with_summary(&actor, &mut chat_state).await?;
+                    system_prompt_checkpoint = None;
+                }
+            }
+            ChatMsg::SayN(message, n) => {
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
+                let Some((_, diff)) = render_user_message(
+                    message,
+                    &mut chat_state,
+                    &mut system_prompt_checkpoint,
+                    &chat_params,
+                    &actor,
+                    tokenizer,
+                    output.as_ref(),
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                let responses = match actor.generate_responses(diff, n).await? {
+                    Ok(responses) => responses,
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding message after recoverable error: {err}");
+                        output.emit_error(format!("{err:?}"));
+                        chat_state.undo_last_message();
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                if responses.context_cleared {
+                    warn!("Context was cleared while generating responses, re-reading the chat.");
+                    chat_state.mark_unread();
+                }
+
+                // keep the first response in the history. the worker discarded it from the context,
+                // so skip render_diff here, and let the LLM read it along with the next message.
+                if let Some(first) = responses.responses.first() {
+                    chat_state.add_message("assistant".to_string(), first.clone());
+                }
+                output.emit_responses(
+                    responses
+                        .responses
+                        .iter()
+                        .map(|response| postprocess::apply_all(&chat_params.post_process, response))
+                        .collect(),
+                );
+            }
+            ChatMsg::SayJson(message, max_attempts) => {
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
+                let Some((_, diff)) = render_user_message(
+                    message,
+                    &mut chat_state,
+                    &mut system_prompt_checkpoint,
+                    &chat_params,
+                    &actor,
+                    tokenizer,
+                    output.as_ref(),
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                // the worker goes back to the end of the message after each attempt, so it only reads it once
+                let mut diff = Some(diff);
+                let mut response = None;
+                for attempt in 0..max_attempts.max(1) {
+                    let json_config = sampler_config::SamplerConfig {
+                        use_grammar: true,
+                        gbnf_grammar: sampler_config::JSON_GRAMMAR.to_string(),
+                        ..sampler_config.with_seed_offset(attempt as u32)
+                    };
+                    actor.set_sampler_config(json_config).await?;
+                    let text = diff.take().unwrap_or_default();
+                    let reads_message = !text.is_empty();
+                    let responses = match actor.generate_responses(text, 1).await? {
+                        Ok(responses) => responses,
+                        Err(err) if err.is_recoverable() => {
                             output.emit_error(format!("{err:?}"));
-                            Some(Err(err))
+                            // the worker only discards the message if this attempt read it
+                            if reads_message {
+                                warn!("Discarding message after recoverable error: {err}");
+                                chat_state.undo_last_message();
+                            }
+                            response = None;
+                            break;
                         }
-                        Ok(llm::WriteOutput::Done(resp)) => Some(Ok(resp)),
-                    })
-                    .await
-                    .ok_or(ChatLoopError::NoResponseError)??;
+                        Err(err) => return Err(err.into()),
+                    };
+                    if responses.context_cleared {
+                        warn!(
+                            "Context was cleared while generating a response, re-reading the chat."
+                        );
+                        chat_state.mark_unread();
+                        if attempt + 1 < max_attempts {
+                            diff = Some(chat_state.render_diff()?);
+                        }
+                    }
+                    let Some(attempted) = responses.responses.into_iter().next() else {
+                        continue;
+                    };
+                    let valid = serde_json::from_str::<serde_json::Value>(&attempted).is_ok();
+                    response = Some((attempted, valid));
+                    if valid {
+                        break;
+                    }
+                    warn!(
+                        "Attempt {} of {max_attempts} did not give valid JSON",
+                        attempt + 1
+                    );
+                }
+                actor
+                    .set_sampler_config(active_sampler_config.clone())
+                    .await?;
 
-                // we have a full response. send it out.
-                output.emit_response(full_response.clone());
-                chat_state.add_message("assistant".to_string(), full_response);
+                // like with SayN, the LLM reads the response along with the next message
+                match response {
+                    Some((response, true)) => {
+                        chat_state.add_message("assistant".to_string(), response.clone());
+                        output.emit_response(postprocess::apply_all(
+                            &chat_params.post_process,
+                            &response,
+                        ));
+                    }
+                    Some((response, false)) => {
+                        chat_state.add_message("assistant".to_string(), response.clone());
+                        output.emit_invalid_response(response);
+                    }
+                    None => output.emit_invalid_response(String::new()),
+                }
+            }
+            ChatMsg::SayTokens(tokens) => {
+                let started = std::time::Instant::now();
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
 
-                // render diff just to update the internal length state
-                let _ = chat_state.render_diff();
+                // the tokens follow the unread part of the chat, like the end of the last response's turn
+                let diff = chat_state.render_diff()?;
+                match actor.read(diff).await? {
+                    Ok(()) => (),
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding tokens after recoverable error: {err}");
+                        output.emit_error(format!("{err:?}"));
+                        chat_state.undo_render_diff();
+                        continue;
+                    }
+                    Err(err) => return Err(llm::GenerateResponseError::from(err).into()),
+                }
+                match actor.read_tokens(tokens).await? {
+                    Ok(()) => (),
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding tokens after recoverable error: {err}");
+                        output.emit_error(format!("{err:?}"));
+                        continue;
+                    }
+                    Err(err) => return Err(llm::GenerateResponseError::from(err).into()),
+                }
+
+                // the tokens are read already, so this only writes the response
+                let streamed = stream_response(
+                    &actor,
+                    String::new(),
+                    started,
+                    output.as_ref(),
+                    StreamOptions::from_chat_params(&chat_params),
+                )
+                .await?;
+                let timings = streamed.timings(started);
+                match streamed.result {
+                    Ok((response, finish_reason)) => {
+                        emit_finished_response(
+                            &actor,
+                            output.as_ref(),
+                            &chat_params,
+                            timings,
+                            &response,
+                            finish_reason,
+                        )
+                        .await?;
+                    }
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding response after recoverable error: {err}");
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+
+                if streamed.summarize {
+                    replace_history_with_summary(&actor, &mut chat_state).await?;
+                    system_prompt_checkpoint = None;
+                }
+            }
+            ChatMsg::Draft(message, n) => {
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
+                let before_message = actor.checkpoint().await?;
+                let Some((message, diff)) = render_user_message(
+                    message,
+                    &mut chat_state,
+                    &mut system_prompt_checkpoint,
+                    &chat_params,
+                    &actor,
+                    tokenizer,
+                    output.as_ref(),
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                let result = actor.generate_responses(diff, n).await?;
+                chat_state.undo_last_message();
+                let responses = match result {
+                    Ok(responses) => responses,
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding draft after recoverable error: {err}");
+                        output.emit_error(format!("{err:?}"));
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                // the rollback fails if the context was cleared or shifted while drafting
+                if !actor.rollback(before_message).await? {
+                    warn!("Context changed while drafting, re-reading the chat.");
+                    actor.reset_context().await?;
+                    chat_state.mark_unread();
+                    system_prompt_checkpoint = None;
+                }
+
+                output.emit_drafts(
+                    responses
+                        .responses
+                        .iter()
+                        .map(|response| postprocess::apply_all(&chat_params.post_process, response))
+                        .collect(),
+                );
+                drafts = Some((message, responses.responses));
+            }
+            ChatMsg::AskYesNo(question) => {
+                if system_prompt_checkpoint.is_none() {
+                    system_prompt_checkpoint = read_system_prompt(&actor, &mut chat_state).await?;
+                }
+                let before_question = actor.checkpoint().await?;
+                chat_state.set_enable_thinking(Some(false));
+                let rendered = render_user_message(
+                    question,
+                    &mut chat_state,
+                    &mut system_prompt_checkpoint,
+                    &chat_params,
+                    &actor,
+                    tokenizer,
+                    output.as_ref(),
+                )
+                .await;
+                chat_state.set_enable_thinking(chat_params.enable_thinking);
+                let Some((_, diff)) = rendered? else {
+                    output.emit_yes_no(None, 0.5);
+                    continue;
+                };
+
+                let read = actor.read(close_think_block(diff)).await?;
+                chat_state.undo_last_message();
+                match read {
+                    Ok(()) => (),
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding question after recoverable error: {err}");
+                        output.emit_error(format!("{err:?}"));
+                        output.emit_yes_no(None, 0.5);
+                        continue;
+                    }
+                    Err(err) => return Err(llm::GenerateResponseError::from(err).into()),
+                }
+
+                let (yes_tokens, no_tokens) = yes_no_tokens(tokenizer);
+                let probabilities = actor
+                    .next_token_probabilities([yes_tokens.as_slice(), &no_tokens].concat())
+                    .await?;
+                let (yes, no) = probabilities.split_at(yes_tokens.len());
+                let (yes, no) = (yes.iter().sum::<f32>(), no.iter().sum::<f32>());
+
+                if !actor.rollback(before_question).await? {
+                    warn!("Context changed while reading the question, re-reading the chat.");
+                    actor.reset_context().await?;
+                    chat_state.mark_unread();
+                    system_prompt_checkpoint = None;
+                }
+
+                let confidence = if yes + no > 0.0 {
+                    yes.max(no) / (yes + no)
+                } else {
+                    0.5
+                };
+                debug!("Answering yes/no question with p(yes) = {yes}, p(no) = {no}");
+                let answer = (yes != no).then_some(yes > no);
+                if answer.is_none() {
+                    warn!(
+                        "The LLM found yes and no equally likely, leaving the question unanswered."
+                    );
+                }
+                output.emit_yes_no(answer, confidence);
+            }
+            ChatMsg::CommitDraft(index) => {
+                let Some((message, mut responses)) = last_drafts else {
+                    warn!("No drafts to commit, ignoring.");
+                    continue;
+                };
+                if index >= responses.len() {
+                    warn!(
+                        "There are only {} drafts, can't commit draft {index}",
+                        responses.len()
+                    );
+                    drafts = Some((message, responses));
+                    continue;
+                }
+                // like with SayN, the LLM reads these along with the next message
+                chat_state.add_message("user".to_string(), message);
+                chat_state.add_message("assistant".to_string(), responses.swap_remove(index));
+            }
+            ChatMsg::SetGrammar(grammar) => {
+                active_sampler_config = match grammar {
+                    Some(gbnf_grammar) => sampler_config::SamplerConfig {
+                        use_grammar: true,
+                        gbnf_grammar,
+                        ..sampler_config.clone()
+                    },
+                    None => sampler_config.clone(),
+                };
+                actor
+                    .set_sampler_config(active_sampler_config.clone())
+                    .await?;
+            }
+            ChatMsg::SetSeed(seed) => {
+                sampler_config = sampler_config.with_seed(seed);
+                active_sampler_config = active_sampler_config.with_seed(seed);
+                actor
+                    .set_sampler_config(active_sampler_config.clone())
+                    .await?;
             }
             ChatMsg::ResetContext(system_prompt) => {
                 chat_state.reset();
                 chat_state.add_message("system".to_string(), system_prompt.clone());
                 actor.reset_context().await?;
+                system_prompt_checkpoint = None;
+            }
+            ChatMsg::NewConversation => {
+                let kept = match system_prompt_checkpoint {
+                    Some(checkpoint) => actor.rollback(checkpoint).await?,
+                    None => false,
+                };
+                chat_state.clear_history(kept);
+                if !kept {
+                    info!("The system prompt is no longer in the context, reading it again.");
+                    actor.reset_context().await?;
+                    system_prompt_checkpoint = None;
+                }
+            }
+            ChatMsg::Defragment => {
+                let fragmentation = actor.defragment().await?;
+                info!("Defragmented KV cache, fragmentation was {fragmentation:.2}");
+            }
+            ChatMsg::SetContextLength(n_ctx) => {
+                // a context can't be resized, so start over with a new worker,
+                // which reads the whole conversation along with the next message.
+                // the old worker is dropped first, to free its context before allocating the new one.
+                info!("Restarting worker with a context length of {n_ctx}");
+                drop(actor);
+                actor = workers
+                    .start(Some(n_ctx), active_sampler_config.clone())
+                    .await?;
+                chat_state.mark_unread();
+                system_prompt_checkpoint = None;
             }
         }
+        output.emit_context_usage(actor.checkpoint().await?.n_past(), actor.n_ctx());
     }
 
     // XXX: we only arrive here when the sender-part of the say channel is dropped
@@ -110,6 +804,531 @@ pub async fn simple_chat_loop(
     Ok(()) // accept our fate
 }
 
+/// How `stream_response` sends the tokens to the output. The default sends them as they are.
+#[derive(Clone, Copy, Default)]
+struct StreamOptions<'a> {
+    /// Only stream this field of a JSON response, see `ChatParams::stream_field`.
+    stream_field: Option<&'a str>,
+    /// Send code blocks to `ChatOutput::emit_code`, see `ChatParams::detect_code_blocks`.
+    detect_code_blocks: bool,
+    /// Remove these from the tokens, see `ChatParams::strip_strings`.
+    strip_strings: &'a [String],
+}
+
+impl<'a> StreamOptions<'a> {
+    fn from_chat_params(chat_params: &'a ChatParams) -> Self {
+        Self {
+            stream_field: chat_params.stream_field.as_deref(),
+            detect_code_blocks: chat_params.detect_code_blocks,
+            strip_strings: &chat_params.strip_strings,
+        }
+    }
+}
+
+/// A response generated by `stream_response`.
+struct StreamedResponse {
+    /// The full response and why it ended, or the error from the worker, which was already sent to `ChatOutput::emit_error`.
+    result: Result<(String, llm::FinishReason), llm::GenerateResponseError>,
+    /// Every token, as it was generated.
+    tokens: Vec<String>,
+    /// How long it took from `started` until the first token, if there was one.
+    prompt_duration: Option<std::time::Duration>,
+    /// Whether the context filled up, and the output chose to summarize the conversation afterwards.
+    summarize: bool,
+}
+
+impl StreamedResponse {
+    /// The timings of the turn, if it is done now. `started` is the same as for `stream_response`.
+    fn timings(&self, started: std::time::Instant) -> TurnTimings {
+        let prompt = self.prompt_duration.unwrap_or_else(|| started.elapsed());
+        TurnTimings {
+            prompt,
+            generation: started.elapsed().saturating_sub(prompt),
+            n_tokens: self.tokens.len(),
+        }
+    }
+}
+
+/// Sends out a finished response from `stream_response`, along with its timings and the context usage,
+/// so every kind of message that streams a response finishes it the same way.
+async fn emit_finished_response(
+    actor: &llm::LLMActorHandle,
+    output: &dyn ChatOutput,
+    chat_params: &ChatParams,
+    timings: TurnTimings,
+    response: &str,
+    finish_reason: llm::FinishReason,
+) -> Result<(), ChatLoopError> {
+    output.emit_turn_timings(timings);
+    output.emit_context_usage(actor.checkpoint().await?.n_past(), actor.n_ctx());
+    output.emit_finish_reason(finish_reason);
+    output.emit_response(postprocess::apply_all(&chat_params.post_process, response));
+    Ok(())
+}
+
+/// Summarizes the chat history, after the output chose to when the context was full, and clears the context,
+/// so the LLM reads the summary with the next message. The system prompt has to be read again too.
+async fn replace_history_with_summary(
+    actor: &llm::LLMActorHandle,
+    chat_state: &mut chat_state::ChatState,
+) -> Result<(), ChatLoopError> {
+    match summarize_history(actor, chat_state).await {
+        Ok(summary) => {
+            info!("Replaced chat history with a summary.");
+            chat_state.replace_history_with_summary(&summary);
+        }
+        Err(ChatLoopError::GenerateResponseError(err)) if err.is_recoverable() => {
+            warn!("Could not summarize chat history, dropping it instead: {err}");
+            chat_state.prune_history(0);
+        }
+        Err(err) => return Err(err),
+    }
+    actor.reset_context().await?;
+    Ok(())
+}
+
+/// Generates a response to the text, and streams it to the output. This is the only place that streams responses,
+/// so every kind of message gets the same events. `started` is when handling the message began, for the timings.
+/// If the worker fails partway through, what was generated so far is sent to `ChatOutput::emit_partial_response`.
+async fn stream_response(
+    actor: &llm::LLMActorHandle,
+    text: String,
+    started: std::time::Instant,
+    output: &dyn ChatOutput,
+    options: StreamOptions<'_>,
+) -> Result<StreamedResponse, ChatLoopError> {
+    let mut field_streamer = options.stream_field.map(json_stream::FieldStreamer::new);
+    let mut code_fences = options
+        .detect_code_blocks
+        .then(code_fence::CodeFenceStreamer::new);
+    let mut stripper = postprocess::StringStripper::new(options.strip_strings.to_vec());
+    let mut tokens = Vec::new();
+    let mut prompt_duration = None;
+    let mut summarize = false;
+    let mut stream = actor.generate_response(text).await;
+    let mut full_response = None;
+    while let Some(out) = stream.next().await {
+        match out {
+            Ok(llm::WriteOutput::Token(token, probability)) => {
+                if prompt_duration.is_none() {
+                    let elapsed = started.elapsed();
+                    prompt_duration = Some(elapsed);
+                    output.emit_first_token(elapsed);
+                }
+                if let Some(probability) = probability {
+                    output.emit_token_probability(&token, probability);
+                }
+                tokens.push(token.clone());
+                let text = stripper.push(&token);
+                if !text.is_empty() {
+                    emit_streamed_token(
+                        output,
+                        field_streamer.as_mut(),
+                        code_fences.as_mut(),
+                        text,
+                    );
+                }
+            }
+            Ok(llm::WriteOutput::ContextFull(resolve_to)) => {
+                // ask the frontend, but remember if we have to summarize afterwards
+                let (strategy_tx, strategy_rx) = oneshot::channel();
+                output.emit_context_full(strategy_tx);
+                let strategy = strategy_rx.await.unwrap_or_default();
+                info!("Context is full, resolving with {strategy:?}");
+                summarize |= strategy == llm::OverflowStrategy::Summarize;
+                let _ = resolve_to.send(strategy);
+            }
+            Ok(llm::WriteOutput::ContextShifted(n_discarded)) => {
+                output.emit_context_shifted(n_discarded);
+            }
+            Ok(llm::WriteOutput::AdjustLogits(candidates, resolve_to)) => {
+                output.emit_adjust_logits(candidates, resolve_to);
+            }
+            Err(err) => {
+                error!("Got error from worker: {err:?}");
+                output.emit_error(format!("{err:?}"));
+                full_response = Some(Err(err));
+            }
+            Ok(llm::WriteOutput::Done(resp, finish_reason, response_tokens)) => {
+                output.emit_response_tokens(response_tokens);
+                full_response = Some(Ok((resp, finish_reason)))
+            }
+        }
+    }
+    let held_back = stripper.finish();
+    if !held_back.is_empty() {
+        emit_streamed_token(
+            output,
+            field_streamer.as_mut(),
+            code_fences.as_mut(),
+            held_back,
+        );
+    }
+    if let Some(code_fences) = code_fences.as_mut() {
+        emit_fence_events(output, code_fences.finish());
+    }
+    let result = full_response.ok_or(ChatLoopError::NoResponseError)?;
+    // don't lose what was generated before the error
+    if result.is_err() && !tokens.is_empty() {
+        output.emit_partial_response(match &field_streamer {
+            Some(streamer) => streamer.text().to_string(),
+            None => tokens.concat(),
+        });
+    }
+    Ok(StreamedResponse {
+        result,
+        tokens,
+        prompt_duration,
+        summarize,
+    })
+}
+
+/// The first tokens of the ways a response can start with "yes", and with "no".
+fn yes_no_tokens(tokenizer: &dyn llm::Tokenizer) -> (Vec<i32>, Vec<i32>) {
+    let first_tokens = |words: [&str; 6]| {
+        let mut tokens: Vec<i32> = words
+            .iter()
+            .filter_map(|word| llm::tokenize(tokenizer, word).ok()?.first().copied())
+            .collect();
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    };
+    (
+        first_tokens(["yes", "Yes", "YES", " yes", " Yes", " YES"]),
+        first_tokens(["no", "No", "NO", " no", " No", " NO"]),
+    )
+}
+
+/// Closes a `<think>` block left open at the end of a rendered prompt, as some reasoning models' templates do
+/// even with reasoning turned off, so the next token is the start of the answer rather than of the reasoning.
+fn close_think_block(mut prompt: String) -> String {
+    if let Some(open) = prompt.rfind("<think>") {
+        if !prompt[open..].contains("</think>") {
+            prompt.push_str("\n</think>\n\n");
+        }
+    }
+    prompt
+}
+
+/// Sends a token to the output, or only the part of it that belongs to the streamed field, if there is one.
+fn emit_streamed_token(
+    output: &dyn ChatOutput,
+    field_streamer: Option<&mut json_stream::FieldStreamer>,
+    code_fences: Option<&mut code_fence::CodeFenceStreamer>,
+    token: String,
+) {
+    let text = match field_streamer {
+        Some(streamer) => {
+            let text = streamer.push(&token);
+            if text.is_empty() {
+                return;
+            }
+            text
+        }
+        None => token,
+    };
+    match code_fences {
+        Some(code_fences) => emit_fence_events(output, code_fences.push(&text)),
+        None => output.emit_token(text),
+    }
+}
+
+fn emit_fence_events(output: &dyn ChatOutput, events: Vec<code_fence::FenceEvent>) {
+    for event in events {
+        match event {
+            code_fence::FenceEvent::Text(text) => output.emit_token(text),
+            code_fence::FenceEvent::CodeBlockStarted(language) => {
+                output.emit_code_block_started(language)
+            }
+            code_fence::FenceEvent::Code(code) => output.emit_code(code),
+            code_fence::FenceEvent::CodeBlockEnded => output.emit_code_block_ended(),
+        }
+    }
+}
+
+/// Reads the system prompt on its own before the first message, so `ChatMsg::NewConversation` can go back to it.
+/// Returns a checkpoint right after it, or `None` if the template can't render it on its own,
+/// in which case it is read along with the first message.
+async fn read_system_prompt(
+    actor: &llm::LLMActorHandle,
+    chat_state: &mut chat_state::ChatState,
+) -> Result<Option<llm::WorkerCheckpoint>, ChatLoopError> {
+    let Some(system_prompt) = chat_state.render_system_prompt()? else {
+        return Ok(None);
+    };
+    match actor.read(system_prompt).await? {
+        Ok(()) => Ok(Some(actor.checkpoint().await?)),
+        Err(err) if err.is_recoverable() => {
+            warn!(
+                "Could not read the system prompt on its own, reading it with the message: {err}"
+            );
+            chat_state.mark_unread();
+            Ok(None)
+        }
+        Err(err) => Err(llm::GenerateResponseError::from(err).into()),
+    }
+}
+
+/// Adds a user message to the chat, and renders the part of the chat the LLM hasn't read yet.
+/// Returns the message as it was added along with the rendered text,
+/// or `None` if the message was ignored. If the history is pruned, the context is reset,
+/// so the system prompt checkpoint is cleared too.
+async fn render_user_message(
+    message: String,
+    chat_state: &mut chat_state::ChatState,
+    system_prompt_checkpoint: &mut Option<llm::WorkerCheckpoint>,
+    chat_params: &ChatParams,
+    actor: &llm::LLMActorHandle,
+    tokenizer: &dyn llm::Tokenizer,
+    output: &dyn ChatOutput,
+) -> Result<Option<(String, String)>, ChatLoopError> {
+    // empty messages are usually accidental, and only confuse the LLM
+    let message = if message.trim().is_empty() {
+        match &chat_params.empty_message_placeholder {
+            Some(placeholder) => placeholder.clone(),
+            None => {
+                warn!("Ignoring empty message.");
+                return Ok(None);
+            }
+        }
+    } else {
+        message
+    };
+    chat_state.add_message("user".to_string(), message.clone());
+
+    // drop old messages, and re-read the remaining conversation from scratch
+    if let Some(max_messages) = chat_params.max_history_messages {
+        if chat_state.prune_history(max_messages) {
+            info!("Pruned chat history to {max_messages} messages.");
+            actor.reset_context().await?;
+            *system_prompt_checkpoint = None;
+        }
+    }
+
+    let diff = chat_state.render_diff()?;
+
+    if chat_params.echo_prompt {
+        match llm::tokenize_roundtrip(tokenizer, &diff) {
+            Ok(prompt) => output.emit_prompt(prompt),
+            Err(err) => warn!("Could not echo prompt: {err}"),
+        }
+    }
+
+    Ok(Some((message, diff)))
+}
+
+/// Asks the LLM for a summary of the chat history, in a fresh context.
+/// The context is left with the summary request in it, so it should be reset afterwards.
+async fn summarize_history(
+    actor: &llm::LLMActorHandle,
+    chat_state: &chat_state::ChatState,
+) -> Result<String, ChatLoopError> {
+    let request = chat_state.render_summary_request()?;
+    actor.reset_context().await?;
+    let mut stream = actor.generate_response(request).await;
+    while let Some(out) = stream.next().await {
+        match out? {
+            llm::WriteOutput::Token(..) | llm::WriteOutput::ContextShifted(_) => (),
+            // the request might be long, but the summary is short, so just make room for it
+            llm::WriteOutput::ContextFull(resolve_to) => {
+                let _ = resolve_to.send(llm::OverflowStrategy::Shift);
+            }
+            // the summary is for us, not the frontend, so leave it as the model wrote it
+            llm::WriteOutput::AdjustLogits(_, resolve_to) => {
+                let _ = resolve_to.send(vec![]);
+            }
+            llm::WriteOutput::Done(summary, _, _) => return Ok(summary),
+        }
+    }
+    Err(ChatLoopError::NoResponseError)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayLoopError {
+    #[error("The recording has no more responses, but got the message: {0:?}")]
+    RecordingExhausted(String),
+}
+
+/// Answers messages with the responses from a recording, in order, without running the LLM.
+/// Tokens are sent to `output` one by one, like a live chat would, which makes this useful
+/// for testing dialogue without a model.
+pub async fn replay_chat_loop(
+    recording: replay::ChatRecording,
+    post_process: Vec<postprocess::PostProcessStep>,
+    stream_field: Option<String>,
+    detect_code_blocks: bool,
+    mut msg_rx: mpsc::Receiver<ChatMsg>,
+    output: Box<dyn ChatOutput>,
+) -> Result<(), ReplayLoopError> {
+    let mut responses = recording.responses.into_iter();
+    while let Some(msg) = msg_rx.recv().await {
+        match msg {
+            ChatMsg::Say(message) => {
+                let Some(recorded) = responses.next() else {
+                    let err = ReplayLoopError::RecordingExhausted(message);
+                    output.emit_error(err.to_string());
+                    return Err(err);
+                };
+                if recorded.message != message {
+                    warn!(
+                        "Replaying a response to {:?}, but got the message {message:?}",
+                        recorded.message
+                    );
+                }
+                let mut field_streamer =
+                    stream_field.as_deref().map(json_stream::FieldStreamer::new);
+                let mut code_fences = detect_code_blocks.then(code_fence::CodeFenceStreamer::new);
+                for token in recorded.tokens {
+                    emit_streamed_token(
+                        output.as_ref(),
+                        field_streamer.as_mut(),
+                        code_fences.as_mut(),
+                        token,
+                    );
+                }
+                if let Some(code_fences) = code_fences.as_mut() {
+                    emit_fence_events(output.as_ref(), code_fences.finish());
+                }
+                output.emit_response(postprocess::apply_all(&post_process, &recorded.response));
+            }
+            // only single responses are recorded
+            ChatMsg::SayN(message, _) => {
+                warn!("Can't replay several responses, ignoring the message {message:?}");
+                output.emit_responses(Vec::new());
+            }
+            ChatMsg::SayJson(message, _) => {
+                warn!("Can't replay JSON responses, ignoring the message {message:?}");
+                output.emit_invalid_response(String::new());
+            }
+            ChatMsg::AskYesNo(question) => {
+                warn!("Can't replay answers to yes/no questions, ignoring {question:?}");
+                output.emit_yes_no(None, 0.5);
+            }
+            ChatMsg::SayTokens(tokens) => {
+                warn!(
+                    "Can't replay responses to tokens, ignoring {} tokens",
+                    tokens.len()
+                );
+                output.emit_response(String::new());
+            }
+            ChatMsg::Draft(message, _) => {
+                warn!("Can't replay drafts, ignoring the message {message:?}");
+                output.emit_drafts(Vec::new());
+            }
+            // the recorded responses already reflect any resets, grammars and seeds used while recording
+            ChatMsg::ResetContext(_)
+            | ChatMsg::SetGrammar(_)
+            | ChatMsg::SetSeed(_)
+            | ChatMsg::Defragment
+            | ChatMsg::SetContextLength(_)
+            | ChatMsg::NewConversation
+            | ChatMsg::CommitDraft(_) => (),
+        }
+    }
+    Ok(())
+}
+
+pub enum CompletionMsg {
+    /// Reads the text as it is, and generates a continuation of it.
+    Complete(String),
+    /// Continues each of the texts independently of each other and of the story so far, one after another in the same context.
+    /// This is cheaper than a worker per text, since the context is only created once.
+    /// The story is forgotten afterwards.
+    CompleteBatch(Vec<String>),
+    /// Forgets everything that was read and generated so far.
+    ResetContext,
+}
+
+/// Continues texts as they are, without a chat template or roles, e.g. for interactive fiction.
+/// Everything that was read and generated stays in the context, so each text continues the story so far.
+/// Only the continuation is sent to `ChatOutput::emit_response`, not the text itself.
+pub async fn simple_completion_loop(
+    params: llm::LLMActorParams,
+    mut msg_rx: mpsc::Receiver<CompletionMsg>,
+    output: Box<dyn ChatOutput>,
+) -> Result<(), ChatLoopError> {
+    let bos = llm::model_adds_bos(&params.model).then(|| params.model.token_bos().0);
+    let init_started = std::time::Instant::now();
+    let actor = llm::LLMActorHandle::new(params).await?;
+    info!("Initialized actor.");
+    output.emit_worker_ready(init_started.elapsed());
+
+    // the worker never adds a BOS token by itself, so add it at the start of the story for models that expect one
+    let mut at_start = true;
+    while let Some(msg) = msg_rx.recv().await {
+        match msg {
+            CompletionMsg::Complete(text) => {
+                if let Some(bos) = bos.filter(|_| at_start) {
+                    if let Err(err) = actor.read_tokens(vec![bos]).await? {
+                        return Err(llm::GenerateResponseError::from(err).into());
+                    }
+                }
+                at_start = false;
+                match stream_response(
+                    &actor,
+                    text,
+                    std::time::Instant::now(),
+                    output.as_ref(),
+                    StreamOptions::default(),
+                )
+                .await?
+                .result
+                {
+                    Ok((response, finish_reason)) => {
+                        output.emit_finish_reason(finish_reason);
+                        output.emit_response(response);
+                    }
+                    Err(err) if err.is_recoverable() => {
+                        warn!("Discarding text after recoverable error: {err}");
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            CompletionMsg::CompleteBatch(texts) => {
+                let mut responses = Vec::with_capacity(texts.len());
+                for (index, text) in texts.into_iter().enumerate() {
+                    actor.reset_context().await?;
+                    if let Some(bos) = bos {
+                        if let Err(err) = actor.read_tokens(vec![bos]).await? {
+                            return Err(llm::GenerateResponseError::from(err).into());
+                        }
+                    }
+                    let response = match stream_response(
+                        &actor,
+                        text,
+                        std::time::Instant::now(),
+                        output.as_ref(),
+                        StreamOptions::default(),
+                    )
+                    .await?
+                    .result
+                    {
+                        Ok((response, _)) => response,
+                        Err(err) if err.is_recoverable() => {
+                            warn!("Skipping text {index} of batch after recoverable error: {err}");
+                            String::new()
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    output.emit_batch_response(index, response.clone());
+                    responses.push(response);
+                }
+                actor.reset_context().await?;
+                at_start = true;
+                output.emit_responses(responses);
+            }
+            CompletionMsg::ResetContext => {
+                actor.reset_context().await?;
+                at_start = true;
+            }
+        }
+    }
+
+    info!("simple_completion_loop exiting");
+    Ok(())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum EmbeddingLoopError {
     #[error("Failed initializing the LLM worker: {0}")]
@@ -121,17 +1340,82 @@ pub enum EmbeddingLoopError {
 
 pub trait EmbeddingOutput {
     fn emit_embedding(&self, embd: Vec<f32>);
+    /// Called instead of `emit_embedding` when embedding the text takes longer than `EmbeddingParams::timeout`.
+    fn emit_timed_out(&self, _text: String) {}
+}
+
+/// Parameters for configuring the embedding loop on top of the LLM worker.
+///
+/// # Fields
+/// * `normalize` - Whether to scale embeddings to unit length, like sentence-transformers does
+/// * `timeout` - How long to wait for a single embedding before giving up on it and moving on to the next text, or `None` to wait forever
+/// * `preprocess` - Steps applied to each text before it is embedded, e.g. lowercasing it so casing doesn't affect similarity
+#[derive(Clone, Debug)]
+pub struct EmbeddingParams {
+    pub normalize: bool,
+    pub timeout: Option<std::time::Duration>,
+    pub preprocess: Vec<postprocess::PostProcessStep>,
+}
+
+impl Default for EmbeddingParams {
+    fn default() -> Self {
+        Self {
+            normalize: true,
+            timeout: None,
+            preprocess: Vec::new(),
+        }
+    }
+}
+
+/// Drives the timers of loops that run outside of a tokio runtime, e.g. on Godot's main thread.
+static TIMER_RUNTIME: std::sync::LazyLock<tokio::runtime::Runtime> =
+    std::sync::LazyLock::new(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("nobodywho-timers")
+            .enable_time()
+            .build()
+            .expect("Failed to start the timer runtime")
+    });
+
+/// Like `tokio::time::timeout`, but it also works outside of a tokio runtime, by using `TIMER_RUNTIME` there.
+fn timeout<F: std::future::Future>(
+    duration: std::time::Duration,
+    future: F,
+) -> tokio::time::Timeout<F> {
+    // the timer registers with the runtime it is created in, and is cancelled when it is dropped
+    let _runtime = tokio::runtime::Handle::try_current()
+        .is_err()
+        .then(|| TIMER_RUNTIME.enter());
+    tokio::time::timeout(duration, future)
 }
 
 pub async fn simple_embedding_loop(
     params: llm::LLMActorParams,
+    embedding_params: EmbeddingParams,
     mut text_rx: mpsc::Receiver<String>,
     output: Box<dyn EmbeddingOutput>,
 ) -> Result<(), EmbeddingLoopError> {
     let actor = llm::LLMActorHandle::new(params).await?;
     while let Some(text) = text_rx.recv().await {
-        let embd = actor.generate_embedding(text).await?;
-        output.emit_embedding(embd);
+        let input = postprocess::apply_all(&embedding_params.preprocess, &text);
+        let embd = match embedding_params.timeout {
+            // the worker can't be interrupted, so it still finishes the embedding, but nobody waits for it
+            Some(duration) => match timeout(duration, actor.generate_embedding(input)).await {
+                Ok(embd) => embd?,
+                Err(_) => {
+                    warn!("Embedding took longer than {duration:?}, skipping it: {text:?}");
+                    output.emit_timed_out(text);
+                    continue;
+                }
+            },
+            None => actor.generate_embedding(input).await?,
+        };
+        if embedding_params.normalize {
+            output.emit_embedding(llm::normalize_embedding(&embd));
+        } else {
+            output.emit_embedding(embd);
+        }
     }
     Ok(()) // we dead
 }
@@ -139,7 +1423,6 @@ pub async fn simple_embedding_loop(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::sampler_config::SamplerConfig;
     use crate::test_utils;
 
     struct MockOutput {
@@ -164,8 +1447,193 @@ mod tests {
             error!("MockEngine: {err}");
             panic!()
         }
+        fn emit_invalid_response(&self, response: String) {
+            panic!("Got invalid response: {response}")
+        }
+        fn emit_drafts(&self, drafts: Vec<String>) {
+            for draft in drafts {
+                self.response_tx.try_send(draft).expect("send failed!");
+            }
+        }
+        fn emit_batch_response(&self, index: usize, response: String) {
+            self.response_tx
+                .try_send(format!("{index}: {response}"))
+                .expect("send failed!");
+        }
+        fn emit_yes_no(&self, answer: Option<bool>, _confidence: f32) {
+            let answer = match answer {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "undecided",
+            };
+            self.response_tx
+                .try_send(answer.to_string())
+                .expect("send failed!");
+        }
+    }
+
+    // the tests below need no model file, so they can run anywhere
+
+    /// Collects the streamed text, and summarizes when the context is full.
+    struct StreamOutput {
+        streamed: std::sync::Mutex<String>,
+    }
+
+    impl ChatOutput for StreamOutput {
+        fn emit_token(&self, token: String) {
+            self.streamed.lock().unwrap().push_str(&token);
+        }
+        fn emit_response(&self, _resp: String) {}
+        fn emit_error(&self, err: String) {
+            panic!("Got error: {err}")
+        }
+        fn emit_context_full(&self, resolve_to: oneshot::Sender<llm::OverflowStrategy>) {
+            let _ = resolve_to.send(llm::OverflowStrategy::Summarize);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_response() {
+        let actor = llm::mock::MockWorker::new(vec!["One <b>two</b> three".to_string()])
+            .n_ctx(8)
+            .ask_on_context_full(true)
+            .spawn();
+        let output = StreamOutput {
+            streamed: std::sync::Mutex::new(String::new()),
+        };
+        let strip_strings = ["<b>".to_string(), "</b>".to_string()];
+        let options = StreamOptions {
+            strip_strings: &strip_strings,
+            ..StreamOptions::default()
+        };
+
+        // the context fills up after two tokens of the response
+        let streamed = stream_response(
+            &actor,
+            "1 2 3 4 5".to_string(),
+            std::time::Instant::now(),
+            &output,
+            options,
+        )
+        .await
+        .unwrap();
+        let (response, finish_reason) = streamed.result.unwrap();
+        assert_eq!(response, "One <b>two</b>");
+        assert_eq!(finish_reason, llm::FinishReason::ContextFull);
+        assert_eq!(streamed.tokens, ["One", " <b>two</b>"]);
+        assert!(streamed.prompt_duration.is_some());
+        assert!(streamed.summarize);
+        assert_eq!(*output.streamed.lock().unwrap(), "One two");
+    }
+
+    #[test]
+    fn test_close_think_block() {
+        let open = "<|im_start|>assistant\n<think>\n".to_string();
+        assert_eq!(
+            close_think_block(open),
+            "<|im_start|>assistant\n<think>\n\n</think>\n\n"
+        );
+        let closed = "<|im_start|>assistant\n<think>\n\n</think>\n\n".to_string();
+        assert_eq!(close_think_block(closed.clone()), closed);
+        let plain = "<|im_start|>assistant\n".to_string();
+        assert_eq!(close_think_block(plain.clone()), plain);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_stop_token_after_context_shift() {
+        test_utils::init_test_tracing();
+        // small enough that the context has to shift before the mock worker counts to 10
+        let worker =
+            llm::mock::MockWorker::new(vec!["1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,".to_string()])
+                .n_ctx(16)
+                .stop_tokens(vec!["10".to_string()]);
+        let mut chat_state = chat_state::ChatState::new(
+            chat_state::CHATML_TEMPLATE.to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
+        chat_state.add_message("system".to_string(), "You count numbers.".to_string());
+
+        /// Sends the events that matter here, in the order they arrive.
+        struct EventOutput {
+            event_tx: mpsc::Sender<String>,
+        }
+
+        impl ChatOutput for EventOutput {
+            fn emit_token(&self, _token: String) {}
+            fn emit_response(&self, resp: String) {
+                self.event_tx.try_send(resp).expect("send failed!");
+            }
+            fn emit_error(&self, err: String) {
+                panic!("Got error: {err}")
+            }
+            fn emit_context_shifted(&self, n_discarded: u32) {
+                self.event_tx
+                    .try_send(format!("shifted {n_discarded}"))
+                    .expect("send failed!");
+            }
+            fn emit_finish_reason(&self, finish_reason: llm::FinishReason) {
+                self.event_tx
+                    .try_send(format!("finished {finish_reason:?}"))
+                    .expect("send failed!");
+            }
+        }
+
+        let (event_tx, mut event_rx) = mpsc::channel(1024);
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(async move {
+            chat_loop(
+                &worker,
+                sampler_config::SamplerConfig::default(),
+                chat_state,
+                ChatParams::default(),
+                say_rx,
+                Box::new(EventOutput { event_tx }),
+            )
+            .await
+        });
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::Say(
+                    "Count from 1 to 12, separated by commas.".to_string(),
+                ))
+                .await;
+            let mut n_shifts = 0;
+            let finish_reason = loop {
+                let event = event_rx.recv().await.unwrap();
+                match event.strip_prefix("shifted ") {
+                    Some(n_discarded) => {
+                        assert!(n_discarded.parse::<u32>().unwrap() > 0);
+                        n_shifts += 1;
+                    }
+                    None => break event,
+                }
+            };
+            let response = event_rx.recv().await.unwrap();
+
+            assert!(
+                n_shifts > 0,
+                "Expected the context to shift, got: {response}"
+            );
+            assert_eq!(finish_reason, "finished StopToken(\"10\")");
+            assert!(
+                response.contains("8, 9, 10"),
+                "Expected the count to reach the stop token, got: {response}"
+            );
+            assert!(
+                !response.contains("11"),
+                "Expected the count to stop at the stop token, got: {response}"
+            );
+        };
+
+        local.run_until(check_results).await;
     }
 
+    // the tests below load the model given by TEST_MODEL
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_chat_loop() {
         test_utils::init_test_tracing();
@@ -175,13 +1643,7 @@ mod tests {
         let system_prompt =
             "You are a helpful assistant. The user asks you a question, and you provide an answer."
                 .to_string();
-        let params = llm::LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 4096,
-            stop_tokens: vec![],
-            use_embeddings: false,
-        };
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
 
         let (mock_output, mut response_rx) = MockOutput::new();
         let (say_tx, say_rx) = mpsc::channel(2);
@@ -189,7 +1651,10 @@ mod tests {
         let local = tokio::task::LocalSet::new();
         local.spawn_local(simple_chat_loop(
             params,
-            system_prompt,
+            ChatParams {
+                system_prompt,
+                ..ChatParams::default()
+            },
             say_rx,
             Box::new(mock_output),
         ));
@@ -221,6 +1686,363 @@ mod tests {
         local.run_until(check_results).await;
     }
 
+    struct MockEmbeddingOutput {
+        event_tx: mpsc::Sender<String>,
+    }
+
+    impl EmbeddingOutput for MockEmbeddingOutput {
+        fn emit_embedding(&self, _embd: Vec<f32>) {
+            self.event_tx
+                .try_send("embedded".to_string())
+                .expect("send failed!");
+        }
+        fn emit_timed_out(&self, text: String) {
+            self.event_tx
+                .try_send(format!("timed out: {text}"))
+                .expect("send failed!");
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_embedding_timeout() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_embeddings_model();
+        // holding the model's inference lock keeps the worker from decoding, so the first embedding can't finish in time
+        let inference_lock = llm::model_inference_lock(&model);
+        let decoding = inference_lock.lock().unwrap();
+        let params = llm::LLMActorParams::builder()
+            .model(model)
+            .use_embeddings(true)
+            .build()
+            .unwrap();
+
+        let (event_tx, mut event_rx) = mpsc::channel(2);
+        let (text_tx, text_rx) = mpsc::channel(2);
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_embedding_loop(
+            params,
+            EmbeddingParams {
+                timeout: Some(std::time::Duration::from_secs(2)),
+                ..EmbeddingParams::default()
+            },
+            text_rx,
+            Box::new(MockEmbeddingOutput { event_tx }),
+        ));
+
+        let check_results = async move {
+            text_tx
+                .send("The dragon is on the hill.".to_string())
+                .await
+                .unwrap();
+            assert_eq!(
+                event_rx.recv().await.unwrap(),
+                "timed out: The dragon is on the hill."
+            );
+
+            // a timeout skips the text, and moves on to the next one
+            drop(decoding);
+            text_tx
+                .send("The dragon is hungry.".to_string())
+                .await
+                .unwrap();
+            assert_eq!(event_rx.recv().await.unwrap(), "embedded");
+        };
+        local.run_until(check_results).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_say_json() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_chat_loop(
+            params,
+            ChatParams::default(),
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::SayJson(
+                    "Describe Denmark as a JSON object with the keys \"capital\" and \"language\"."
+                        .to_string(),
+                    3,
+                ))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            let json: serde_json::Value = serde_json::from_str(&response).unwrap();
+            assert!(json.is_object(), "Expected a JSON object, got: {response}");
+
+            // the grammar only applies to that one message
+            let _ = say_tx
+                .send(ChatMsg::Say("Now say hello, without JSON.".to_string()))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert!(
+                !response.trim_start().starts_with('{'),
+                "Expected plain text, got: {response}"
+            );
+        };
+
+        local.run_until(check_results).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_set_context_length() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder()
+            .model(model)
+            .n_ctx(1024)
+            .build()
+            .unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_chat_loop(
+            params,
+            ChatParams::default(),
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::Say(
+                    "My name is Gertrud. Please remember it.".to_string(),
+                ))
+                .await;
+            let _ = response_rx.recv().await.unwrap();
+
+            let _ = say_tx.send(ChatMsg::SetContextLength(4096)).await;
+
+            // the new worker reads the conversation again
+            let _ = say_tx
+                .send(ChatMsg::Say("What is my name?".to_string()))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert!(
+                response.contains("Gertrud"),
+                "Expected the conversation to survive the restart, got: {response}"
+            );
+        };
+
+        local.run_until(check_results).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_draft() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_chat_loop(
+            params,
+            ChatParams {
+                system_prompt: "You are a helpful assistant.".to_string(),
+                ..ChatParams::default()
+            },
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::Draft(
+                    "What is the capital of Denmark?".to_string(),
+                    2,
+                ))
+                .await;
+            for _ in 0..2 {
+                let draft = response_rx.recv().await.unwrap();
+                assert!(
+                    draft.contains("Copenhagen"),
+                    "Expected draft to contain 'Copenhagen', got: {draft}"
+                );
+            }
+
+            let _ = say_tx.send(ChatMsg::CommitDraft(1)).await;
+            let _ = say_tx
+                .send(ChatMsg::Say(
+                    "Which country did I just ask about?".to_string(),
+                ))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert!(
+                response.contains("Denmark"),
+                "Expected the committed draft to be in the conversation, got: {response}"
+            );
+        };
+
+        local.run_until(check_results).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_ask_yes_no() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_chat_loop(
+            params,
+            ChatParams {
+                system_prompt: "You are a helpful assistant.".to_string(),
+                ..ChatParams::default()
+            },
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::AskYesNo(
+                    "Is Copenhagen the capital of Denmark? Answer yes or no.".to_string(),
+                ))
+                .await;
+            assert_eq!(response_rx.recv().await.unwrap(), "yes");
+
+            let _ = say_tx
+                .send(ChatMsg::AskYesNo(
+                    "Is Berlin the capital of Denmark? Answer yes or no.".to_string(),
+                ))
+                .await;
+            assert_eq!(response_rx.recv().await.unwrap(), "no");
+        };
+
+        local.run_until(check_results).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_loop() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (msg_tx, msg_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_completion_loop(
+            params,
+            msg_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = msg_tx
+                .send(CompletionMsg::Complete(
+                    "I'm gonna count to 10: 1, 2, 3, ".to_string(),
+                ))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert!(
+                response.contains("4, 5, 6"),
+                "Expected the text to be continued, got: {response}"
+            );
+            assert!(
+                !response.contains("I'm gonna count"),
+                "Expected only the continuation, got: {response}"
+            );
+
+            let _ = msg_tx
+                .send(CompletionMsg::CompleteBatch(vec![
+                    "I'm gonna count to 10: 1, 2, 3, ".to_string(),
+                    "I'm gonna count to 10: 5, 6, 7, ".to_string(),
+                ]))
+                .await;
+            let first = response_rx.recv().await.unwrap();
+            let second = response_rx.recv().await.unwrap();
+            assert!(
+                first.starts_with("0: ") && first.contains("4, 5, 6"),
+                "Expected the first text to be continued first, got: {first}"
+            );
+            assert!(
+                second.starts_with("1: ") && second.contains("8, 9"),
+                "Expected the second text to be continued second, got: {second}"
+            );
+        };
+
+        local.run_until(check_results).await;
+    }
+
+    #[test]
+    fn test_check_system_prompt_fits() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let chat_params = ChatParams {
+            system_prompt: "You are a helpful assistant. ".repeat(20),
+            ..ChatParams::default()
+        };
+
+        assert!(check_system_prompt_fits(&model, &chat_params, 4096).is_ok());
+        let err = check_system_prompt_fits(&model, &chat_params, 64).unwrap_err();
+        assert!(
+            err.n_tokens > 64,
+            "Expected the rendered prompt to be counted"
+        );
+        assert_eq!(err.n_ctx, 64);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_new_conversation() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(simple_chat_loop(
+            params,
+            ChatParams {
+                system_prompt: "You are a pirate. Always talk like a pirate.".to_string(),
+                ..ChatParams::default()
+            },
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::Say("My name is Gertrud.".to_string()))
+                .await;
+            let _ = response_rx.recv().await.unwrap();
+
+            let _ = say_tx.send(ChatMsg::NewConversation).await;
+            let _ = say_tx
+                .send(ChatMsg::Say("What is my name?".to_string()))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert!(
+                !response.contains("Gertrud"),
+                "Expected the old conversation to be forgotten, got: {response}"
+            );
+        };
+
+        local.run_until(check_results).await;
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_reset_context() {
         test_utils::init_test_tracing();
@@ -230,13 +2052,7 @@ mod tests {
         let system_prompt =
             "You are a helpful assistant. The user asks you a question, and you provide an answer."
                 .to_string();
-        let params = llm::LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 4096,
-            stop_tokens: vec![],
-            use_embeddings: false,
-        };
+        let params = llm::LLMActorParams::builder().model(model).build().unwrap();
 
         let (mock_output, mut response_rx) = MockOutput::new();
         let (say_tx, say_rx) = mpsc::channel(2);
@@ -244,7 +2060,10 @@ mod tests {
         let local = tokio::task::LocalSet::new();
         local.spawn_local(simple_chat_loop(
             params,
-            system_prompt,
+            ChatParams {
+                system_prompt,
+                ..ChatParams::default()
+            },
             say_rx,
             Box::new(mock_output),
         ));
@@ -268,4 +2087,38 @@ mod tests {
         // run stuff
         local.run_until(check_results).await;
     }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_replay_chat_loop() {
+        let recording = replay::ChatRecording {
+            responses: vec![replay::RecordedResponse {
+                message: "What is the capital of Denmark?".into(),
+                tokens: vec!["Cop".into(), "enh".into(), "agen".into()],
+                response: "Copenhagen".into(),
+            }],
+        };
+
+        let (mock_output, mut response_rx) = MockOutput::new();
+        let (say_tx, say_rx) = mpsc::channel(2);
+
+        let local = tokio::task::LocalSet::new();
+        local.spawn_local(replay_chat_loop(
+            recording,
+            vec![],
+            None,
+            false,
+            say_rx,
+            Box::new(mock_output),
+        ));
+
+        let check_results = async move {
+            let _ = say_tx
+                .send(ChatMsg::Say("What is the capital of Denmark?".to_string()))
+                .await;
+            let response = response_rx.recv().await.unwrap();
+            assert_eq!(response, "Copenhagen");
+        };
+
+        local.run_until(check_results).await;
+    }
 }
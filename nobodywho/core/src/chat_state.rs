@@ -1,9 +1,9 @@
-use std::sync::LazyLock;
+use std::sync::{LazyLock, RwLock};
 
 use minijinja::{context, Environment};
 use serde::{self, Serialize};
 
-static MINIJINJA_ENV: LazyLock<Environment> = LazyLock::new(|| {
+static MINIJINJA_ENV: LazyLock<RwLock<Environment<'static>>> = LazyLock::new(|| {
     let mut env = Environment::new();
     env.add_function(
         "raise_exception",
@@ -19,9 +19,31 @@ static MINIJINJA_ENV: LazyLock<Environment> = LazyLock::new(|| {
     // add a bunch of python-isms, like str.split() or dict.get()
     // was introduced in #106 to fix the deepseek chat template
     env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
-    env
+
+    // add the filters and functions from minijinja-contrib (e.g. `pluralize`),
+    // on top of the builtins like `tojson` that minijinja already ships with
+    minijinja_contrib::add_to_environment(&mut env);
+    RwLock::new(env)
 });
 
+/// Gives mutable access to the shared template environment used for rendering all chat templates.
+/// This is the place to register extra filters and functions that a chat template needs, but minijinja lacks.
+///
+/// ```
+/// nobodywho::chat_state::configure_template_environment(|env| {
+///     env.add_filter("shout", |s: String| s.to_uppercase());
+/// });
+/// ```
+pub fn configure_template_environment<F>(configure: F)
+where
+    F: FnOnce(&mut Environment<'static>),
+{
+    let mut env = MINIJINJA_ENV
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    configure(&mut env);
+}
+
 fn strftime_now(format_str: &str) -> String {
     chrono::Local::now().format(format_str).to_string()
 }
@@ -32,12 +54,54 @@ pub struct Message {
     pub content: String,
 }
 
+/// The role names that a chat template expects for each kind of message.
+/// Messages are always added with the standard "system", "user" and "assistant" roles,
+/// and are renamed to these when rendering. E.g. gemma templates call the assistant "model".
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoleNames {
+    pub system: String,
+    pub user: String,
+    pub assistant: String,
+}
+
+impl Default for RoleNames {
+    fn default() -> Self {
+        Self {
+            system: "system".to_string(),
+            user: "user".to_string(),
+            assistant: "assistant".to_string(),
+        }
+    }
+}
+
+impl RoleNames {
+    fn rename(&self, role: &str) -> String {
+        match role {
+            "system" => self.system.clone(),
+            "user" => self.user.clone(),
+            "assistant" => self.assistant.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
 pub struct ChatState {
     messages: Vec<Message>,
     chat_template: String,
     length: usize,
+    previous_length: usize,
     eos_token: String,
     bos_token: String,
+    merge_system_prompt: bool,
+    role_names: RoleNames,
+    continue_final_message: bool,
+    prepend_bos: bool,
+    enable_thinking: Option<bool>,
+    fallback_generation_prompt: Option<String>,
+    /// Whether the template renders nothing for `add_generation_prompt`, once that is known.
+    lacks_generation_prompt: Option<bool>,
+    /// How much of the render is the system prompt, if it was read on its own with `render_system_prompt`.
+    system_prompt_length: Option<usize>,
 }
 
 /// given a chat history where the first two messages are from system and user
@@ -66,6 +130,107 @@ fn concat_system_and_first_user_messages(
     Ok(new_messages)
 }
 
+/// cuts off everything the template rendered after the content of the final message,
+/// e.g. the end-of-turn token, so the LLM can continue writing the message.
+fn cut_after_final_message(
+    mut rendered: String,
+    final_content: &str,
+) -> Result<String, minijinja::Error> {
+    match rendered.rfind(final_content) {
+        Some(start) => {
+            rendered.truncate(start + final_content.len());
+            Ok(rendered)
+        }
+        None => Err(minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            "Cannot continue the final message, since the chat template changed its content.",
+        )),
+    }
+}
+
+/// A chat template failed to render. The message includes the part of the template where it failed, if known.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct ApplyTemplateError {
+    message: String,
+    #[source]
+    source: minijinja::Error,
+}
+
+impl ApplyTemplateError {
+    fn new(source: minijinja::Error, chat_template: &str) -> Self {
+        let message = match template_snippet(chat_template, &source) {
+            Some(snippet) => format!("{source}\nIn this part of the chat template:\n{snippet}"),
+            None => source.to_string(),
+        };
+        Self { message, source }
+    }
+}
+
+/// returns the part of the template that an error points to, with a bit of surrounding context.
+/// chat templates are often huge, and sometimes all on one line, so the snippet is kept short.
+fn template_snippet(template: &str, err: &minijinja::Error) -> Option<String> {
+    const CONTEXT_BYTES: usize = 60;
+    let (start, end) = match err.range() {
+        Some(range) if range.end <= template.len() => (
+            range.start.saturating_sub(CONTEXT_BYTES),
+            (range.end + CONTEXT_BYTES).min(template.len()),
+        ),
+        // no exact location, so fall back to the start of the line
+        _ => {
+            let line_start: usize = template
+                .split_inclusive('\n')
+                .take(err.line()?.checked_sub(1)?)
+                .map(str::len)
+                .sum();
+            (
+                line_start,
+                (line_start + 2 * CONTEXT_BYTES).min(template.len()),
+            )
+        }
+    };
+
+    // don't cut multi-byte characters in half
+    let start = (0..=start).rev().find(|i| template.is_char_boundary(*i))?;
+    let end = (end..=template.len()).find(|i| template.is_char_boundary(*i))?;
+
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < template.len() { "..." } else { "" };
+    Some(format!("{prefix}{}{suffix}", &template[start..end]))
+}
+
+/// The instruction for `ChatState::render_summary_request`, followed by the conversation.
+const SUMMARY_INSTRUCTION: &str = "Summarize the following conversation in a few sentences. \
+Keep names, facts and decisions that may matter later in the conversation.";
+
+/// The ChatML format, used by Qwen, Hermes and many other finetunes.
+pub const CHATML_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+/// The LLaMA2 chat format, as used by most LLaMA2-based models, which often don't include a chat template in the GGUF file.
+pub const LLAMA2_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+/// Looks up one of the chat templates bundled with nobodywho by name, e.g. "chatml" or "llama2".
+pub fn builtin_template(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "chatml" => Some(CHATML_TEMPLATE),
+        "llama2" => Some(LLAMA2_TEMPLATE),
+        _ => None,
+    }
+}
+
+/// Checks that a chat template renders a short sample conversation, so a broken template is caught
+/// before it's used with a model.
+pub fn validate_template(chat_template: &str) -> Result<(), ApplyTemplateError> {
+    let mut chatstate = ChatState::new(chat_template.into(), String::new(), String::new());
+    chatstate.add_message("system".into(), "You are a helpful assistant.".into());
+    chatstate.add_message("user".into(), "Hi there!".into());
+    chatstate.render_diff()?;
+    chatstate.add_message("assistant".into(), "Hello! How can I help?".into());
+    chatstate.add_message("user".into(), "What's the capital of Denmark?".into());
+    chatstate.render_diff()?;
+    Ok(())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum FromModelError {
     #[error("Lama.cpp failed fetching chat template from the model file. This is likely because you're using an older GGUF file, which might not include a chat template. For example, this is the case for most LLaMA2-based GGUF files. Try using a more recent GGUF model file. If you want to check if a given model includes a chat template, you can use the gguf-dump script from llama.cpp. Here is a more technical detailed error: {0}")]
@@ -79,51 +244,309 @@ pub enum FromModelError {
 }
 
 impl ChatState {
+    /// Creates a chat state for the given chat template.
+    ///
+    /// The BOS and EOS tokens are only ever used as the `bos_token` and `eos_token` template variables.
+    /// Nothing is prepended or appended to what the template renders, and the worker reads the rendered text
+    /// without adding a BOS token of its own, so the template alone decides where they go.
+    /// See `set_prepend_bos` for templates that leave the BOS token out.
     pub fn new(chat_template: String, bos_token: String, eos_token: String) -> Self {
         Self {
             messages: Vec::new(),
             chat_template,
             length: 0,
+            previous_length: 0,
             eos_token,
             bos_token,
+            merge_system_prompt: false,
+            role_names: RoleNames::default(),
+            continue_final_message: false,
+            prepend_bos: false,
+            enable_thinking: None,
+            fallback_generation_prompt: None,
+            lacks_generation_prompt: None,
+            system_prompt_length: None,
         }
     }
 
+    pub fn set_role_names(&mut self, role_names: RoleNames) {
+        self.role_names = role_names;
+    }
+
+    /// Chooses between rendering for a new turn (the default), and rendering for continuing the final assistant message.
+    /// When continuing, no generation prompt is added, and the end-of-turn markup after the final message is cut off,
+    /// so the LLM picks up right where the message ended.
+    pub fn set_continue_final_message(&mut self, continue_final_message: bool) {
+        self.continue_final_message = continue_final_message;
+    }
+
+    /// Prepends the BOS token to the conversation when the template doesn't render it at the start itself.
+    /// Models expect their BOS token first, but hand-written templates often leave it out.
+    pub fn set_prepend_bos(&mut self, prepend_bos: bool) {
+        self.prepend_bos = prepend_bos;
+    }
+
+    /// Sets the `enable_thinking` template variable, which templates of reasoning models like Qwen3 use
+    /// to turn reasoning on or off. With `None`, the variable is left undefined, so the template decides.
+    pub fn set_enable_thinking(&mut self, enable_thinking: Option<bool>) {
+        self.enable_thinking = enable_thinking;
+    }
+
+    /// Sets the text that starts an assistant turn, for templates that render nothing when asked for a generation prompt.
+    /// Without it, the LLM has no cue that it is its turn, and tends to continue the user's message or write garbage.
+    /// It should match how the template starts assistant messages, e.g. `<|assistant|>\n`.
+    /// Templates that do render a generation prompt are left alone.
+    pub fn set_fallback_generation_prompt(&mut self, generation_prompt: Option<String>) {
+        self.fallback_generation_prompt = generation_prompt;
+    }
+
+    /// Whether the template turned out to render nothing for the generation prompt, or `None` until a generation prompt was rendered.
+    pub fn lacks_generation_prompt(&self) -> Option<bool> {
+        self.lacks_generation_prompt
+    }
+
     pub fn from_model(model: &llama_cpp_2::model::LlamaModel) -> Result<Self, FromModelError> {
         let template = model.get_chat_template()?.to_string()?;
-        let tokenize = llama_cpp_2::model::Special::Tokenize;
-        let bos = model.token_to_str(model.token_bos(), tokenize)?;
-        let eos = model.token_to_str(model.token_eos(), tokenize)?;
-        Ok(Self::new(template, bos, eos))
+        Self::from_model_with_template(model, template)
+    }
+
+    /// Like `from_model`, but uses the given chat template instead of the one embedded in the model file.
+    pub fn from_model_with_template(
+        model: &llama_cpp_2::model::LlamaModel,
+        chat_template: String,
+    ) -> Result<Self, FromModelError> {
+        // some models have no bos or eos token at all (llama.cpp reports them as -1)
+        // templates may still reference `bos_token` and `eos_token`, so we render those as empty strings
+        let token_to_str = |token: llama_cpp_2::token::LlamaToken| {
+            if token.0 < 0 {
+                return Ok(String::new());
+            }
+            model.token_to_str(token, llama_cpp_2::model::Special::Tokenize)
+        };
+        let bos = token_to_str(model.token_bos())?;
+        let eos = token_to_str(model.token_eos())?;
+        Ok(Self::new(chat_template, bos, eos))
     }
 
     pub fn reset(&mut self) {
         self.length = 0;
+        self.previous_length = 0;
         self.messages = Vec::new();
+        self.system_prompt_length = None;
+    }
+
+    /// Drops every message except the system prompt, to start a new conversation with the same system prompt.
+    /// If `keep_system_prompt_read` is set, the system prompt stays read as `render_system_prompt` rendered it,
+    /// so the LLM context should be rolled back to right after it. Otherwise the next `render_diff` renders it again.
+    pub fn clear_history(&mut self, keep_system_prompt_read: bool) {
+        let n_system = self
+            .messages
+            .iter()
+            .take_while(|msg| msg.role == "system")
+            .count();
+        self.messages.truncate(n_system);
+        self.length = match self.system_prompt_length {
+            Some(length) if keep_system_prompt_read => length,
+            _ => 0,
+        };
+        self.previous_length = self.length;
+    }
+
+    /// Renders the system prompt on its own, so the LLM can read it before the first message,
+    /// and a new conversation can start right after it, see `clear_history`. It is marked as read.
+    /// Returns `None` if anything was read already, if there are other messages than the system prompt,
+    /// or if the template renders the system prompt differently once a user message follows it.
+    pub fn render_system_prompt(&mut self) -> Result<Option<String>, ApplyTemplateError> {
+        if self.length != 0
+            || self.continue_final_message
+            || self.messages.is_empty()
+            || self.messages.iter().any(|msg| msg.role != "system")
+        {
+            return Ok(None);
+        }
+
+        // the first message is rendered with the system prompt, so check that it starts the same way
+        self.add_message("user".to_string(), String::new());
+        let with_message = self.render();
+        self.messages.pop();
+        let Ok(with_message) = with_message else {
+            return Ok(None);
+        };
+        // templates without a system role merge it into the first message
+        if self.merge_system_prompt {
+            return Ok(None);
+        }
+        let system_prompt = self
+            .render()
+            .map_err(|err| ApplyTemplateError::new(err, &self.chat_template))?;
+        if system_prompt.is_empty() || !with_message.starts_with(&system_prompt) {
+            return Ok(None);
+        }
+
+        self.previous_length = 0;
+        self.length = system_prompt.len();
+        self.system_prompt_length = Some(system_prompt.len());
+        Ok(Some(system_prompt))
     }
 
     pub fn add_message(&mut self, role: String, content: String) {
         self.messages.push(Message { role, content });
     }
 
+    /// Drops the oldest messages until at most `max_messages` non-system messages remain.
+    /// The system prompt is always kept, and the remaining history always starts with a user message,
+    /// since many templates require the roles to alternate.
+    /// Returns true if anything was removed. In that case nothing counts as read anymore, not even the system prompt,
+    /// and the next `render_diff` renders the entire conversation again, so the LLM context should be reset before reading it.
+    pub fn prune_history(&mut self, max_messages: usize) -> bool {
+        let n_system = self
+            .messages
+            .iter()
+            .take_while(|msg| msg.role == "system")
+            .count();
+        if self.messages.len() - n_system <= max_messages {
+            return false;
+        }
+
+        let mut first_kept = self.messages.len() - max_messages;
+        while first_kept < self.messages.len() && self.messages[first_kept].role != "user" {
+            first_kept += 1;
+        }
+        self.messages.drain(n_system..first_kept);
+        self.length = 0;
+        self.previous_length = 0;
+        self.system_prompt_length = None;
+        true
+    }
+
+    /// Renders a separate conversation that asks the LLM to summarize this one, excluding the system prompt.
+    /// The result should be read into an empty context.
+    pub fn render_summary_request(&self) -> Result<String, ApplyTemplateError> {
+        let transcript = self
+            .messages
+            .iter()
+            .filter(|msg| msg.role != "system")
+            .map(|msg| format!("{}: {}", msg.role, msg.content))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let mut summary_chat = Self::new(
+            self.chat_template.clone(),
+            self.bos_token.clone(),
+            self.eos_token.clone(),
+        );
+        summary_chat.set_role_names(self.role_names.clone());
+        summary_chat.set_prepend_bos(self.prepend_bos);
+        summary_chat.set_enable_thinking(self.enable_thinking);
+        summary_chat.set_fallback_generation_prompt(self.fallback_generation_prompt.clone());
+        summary_chat.add_message(
+            "user".to_string(),
+            format!("{SUMMARY_INSTRUCTION}\n\n{transcript}"),
+        );
+        summary_chat.render_diff()
+    }
+
+    /// Drops every message except the system prompt, and adds the summary of them to the system prompt.
+    /// Like `prune_history`, the next `render_diff` renders the entire conversation again.
+    pub fn replace_history_with_summary(&mut self, summary: &str) {
+        let n_system = self
+            .messages
+            .iter()
+            .take_while(|msg| msg.role == "system")
+            .count();
+        self.messages.truncate(n_system);
+        self.system_prompt_length = None;
+        let summary = format!("Summary of the conversation so far:\n{}", summary.trim());
+        match self.messages.last_mut() {
+            Some(system_prompt) => {
+                system_prompt.content = format!("{}\n\n{summary}", system_prompt.content)
+            }
+            None => self.add_message("system".to_string(), summary),
+        }
+        self.length = 0;
+        self.previous_length = 0;
+    }
+
     fn render(&mut self) -> Result<String, minijinja::Error> {
-        let tmpl = MINIJINJA_ENV.template_from_str(&self.chat_template)?;
+        // the system prompt is merged into the first user message on every render,
+        // rather than once, so that pruning the history never loses the system prompt
+        let messages = if self.merge_system_prompt {
+            concat_system_and_first_user_messages(&self.messages)?
+        } else {
+            self.messages.clone()
+        };
+        let add_generation_prompt =
+            !self.continue_final_message && messages.last().map_or(false, |msg| msg.role == "user");
+        let continued_content = match messages.last() {
+            Some(msg) if self.continue_final_message && msg.role == "assistant" => {
+                Some(msg.content.trim().to_string())
+            }
+            _ => None,
+        };
+        let messages: Vec<Message> = messages
+            .into_iter()
+            .map(|msg| Message {
+                role: self.role_names.rename(&msg.role),
+                content: msg.content,
+            })
+            .collect();
 
         let ctx = context! {
-            messages => &self.messages,
-            add_generation_prompt => self.messages.last().map_or(false, |msg| msg.role == "user"),
+            messages => &messages,
+            add_generation_prompt => add_generation_prompt,
             eos_token => self.eos_token,
             bos_token => self.bos_token,
+            // llama 3.x templates read today's date from this variable, and fall back to a hardcoded date without it
+            date_string => strftime_now("%d %b %Y"),
+        };
+        // templates check whether it is defined at all, so it is only set when asked for
+        let ctx = match self.enable_thinking {
+            Some(enable_thinking) => context! { enable_thinking => enable_thinking, ..ctx },
+            None => ctx,
         };
 
-        match tmpl.render(ctx) {
-            Ok(rendered) => Ok(rendered),
+        // the environment lock is released before retrying below
+        let result = {
+            let env = MINIJINJA_ENV
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            env.template_from_str(&self.chat_template).and_then(|tmpl| {
+                let rendered = tmpl.render(ctx.clone())?;
+                // find out once whether the template renders a generation prompt at all
+                if add_generation_prompt && self.lacks_generation_prompt.is_none() {
+                    let without = tmpl.render(context! { add_generation_prompt => false, ..ctx })?;
+                    self.lacks_generation_prompt = Some(without == rendered);
+                    if without == rendered {
+                        tracing::warn!(
+                            "The chat template renders nothing for the generation prompt, so the LLM may not know it is its turn to respond. \
+                            Set a fallback generation prompt, or use another chat template."
+                        );
+                    }
+                }
+                Ok(rendered)
+            })
+        };
+
+        match result {
+            Ok(mut rendered) => {
+                if self.prepend_bos && !rendered.starts_with(&self.bos_token) {
+                    rendered.insert_str(0, &self.bos_token);
+                }
+                if add_generation_prompt && self.lacks_generation_prompt == Some(true) {
+                    if let Some(generation_prompt) = &self.fallback_generation_prompt {
+                        rendered.push_str(generation_prompt);
+                    }
+                }
+                match continued_content {
+                    Some(content) => cut_after_final_message(rendered, &content),
+                    None => Ok(rendered),
+                }
+            }
             Err(err) => match err.kind() {
-                minijinja::ErrorKind::InvalidOperation => {
+                minijinja::ErrorKind::InvalidOperation if !self.merge_system_prompt => {
                     if err.to_string().contains("System role not supported") {
                         // this is the error message we get when rendering the gemma2 template
                         // concat the first two messages and try again
-                        self.messages = concat_system_and_first_user_messages(&self.messages)?;
+                        self.merge_system_prompt = true;
                         self.render()
                     } else if err.to_string().contains(
                         "Conversation roles must alternate user/assistant/user/assistant/...",
@@ -131,7 +554,7 @@ impl ChatState {
                         // this is the error we get when rendering the mistral 7b v0.3 template,
                         // which, like gemma2, does not support the system role
                         // concat the first two messages and try again
-                        self.messages = concat_system_and_first_user_messages(&self.messages)?;
+                        self.merge_system_prompt = true;
                         self.render()
                     } else {
                         Err(err)
@@ -142,24 +565,108 @@ impl ChatState {
         }
     }
 
-    pub fn render_diff(&mut self) -> Result<String, minijinja::Error> {
+    pub fn render_diff(&mut self) -> Result<String, ApplyTemplateError> {
         // render the full template
-        let text = self.render()?;
+        let text = self
+            .render()
+            .map_err(|err| ApplyTemplateError::new(err, &self.chat_template))?;
 
         // get the chars that are new since the last template render
         let diff = text[self.length..].to_string();
 
         // note the length of this template render
+        self.previous_length = self.length;
         self.length = text.len();
 
         Ok(diff)
     }
+
+    /// Like `render_diff`, but for right after adding a response the LLM generated itself.
+    /// The LLM already read the response while writing it, but not the end-of-turn markup that the template
+    /// puts after it, so only the response is marked as read. The next diff then starts with the end of the turn.
+    /// If the template changes the response so it can't be found, nothing is marked as read, and the next diff
+    /// starts with the response. Reading it again is better than skipping the end-of-turn markup.
+    pub fn mark_response_read(&mut self) -> Result<(), ApplyTemplateError> {
+        let text = self
+            .render()
+            .map_err(|err| ApplyTemplateError::new(err, &self.chat_template))?;
+
+        // templates often trim the content, so look for the trimmed response
+        let response = self.messages.last().map_or("", |msg| msg.content.trim());
+        let Some(start) = text.get(self.length..).and_then(|diff| diff.find(response)) else {
+            tracing::warn!(
+                "Could not find the response in the rendered chat template, so the LLM reads it again with the next message."
+            );
+            return Ok(());
+        };
+        let end = self.length + start + response.len();
+
+        self.previous_length = self.length;
+        self.length = end;
+        Ok(())
+    }
+
+    /// Removes the last message, and undoes the `render_diff` that followed it,
+    /// e.g. when the LLM failed to respond and discarded what it read.
+    pub fn undo_last_message(&mut self) {
+        self.messages.pop();
+        self.undo_render_diff();
+    }
+
+    /// Undoes the last `render_diff`, e.g. when the LLM failed to read the diff and discarded it.
+    pub fn undo_render_diff(&mut self) {
+        self.length = self.previous_length;
+    }
+
+    /// Makes the next `render_diff` render the entire conversation again,
+    /// e.g. after the LLM context was cleared.
+    pub fn mark_unread(&mut self) {
+        self.length = 0;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_mark_response_read() {
+        let template = "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'] | trim + '<|im_end|>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("user".into(), "Hi!".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n"
+        );
+
+        // the generated response is skipped, but its end-of-turn markup is not
+        chatstate.add_message("assistant".into(), "Hello there. ".into());
+        chatstate.mark_response_read().unwrap();
+        chatstate.add_message("user".into(), "Bye!".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|im_end|>\n<|im_start|>user\nBye!<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_mark_response_read_not_found() {
+        // a template that changes the content, so the response can't be found in the render
+        let template = "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'] | upper + '<|im_end|>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("user".into(), "Hi!".into());
+        chatstate.render_diff().unwrap();
+
+        // nothing is marked as read, so neither the response nor its end-of-turn markup is lost
+        chatstate.add_message("assistant".into(), "Hello there.".into());
+        chatstate.mark_response_read().unwrap();
+        chatstate.add_message("user".into(), "Bye!".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "HELLO THERE.<|im_end|>\n<|im_start|>user\nBYE!<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
     #[test]
     fn test_llama31_template() {
         // test that llama 3.1 template renders
@@ -203,4 +710,476 @@ Hello, world!<|eot_id|><|start_header_id|>assistant<|end_header_id|>
         println!("{:?}", rendered);
         assert!(rendered.is_ok());
     }
+
+    #[test]
+    fn test_prune_history() {
+        let template = "{% for message in messages %}{{ message['role'] }}: {{ message['content'] }}\n{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "<|bos|>".into(), "<|eos|>".into());
+        chatstate.add_message("system".into(), "Be nice.".into());
+        chatstate.add_message("user".into(), "one".into());
+        chatstate.add_message("assistant".into(), "two".into());
+        chatstate.add_message("user".into(), "three".into());
+        let _ = chatstate.render_diff().unwrap();
+
+        // nothing to prune yet
+        assert!(!chatstate.prune_history(3));
+
+        // pruning to two messages would start the history on an assistant message,
+        // so it should be trimmed further, down to the last user message
+        assert!(chatstate.prune_history(2));
+        let rendered = chatstate.render_diff().unwrap();
+        assert_eq!(rendered, "system: Be nice.\nuser: three\n");
+    }
+
+    #[test]
+    fn test_prune_history_forgets_what_was_read() {
+        let template = "{% for message in messages %}{{ message['role'] }}: {{ message['content'] }}\n{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("system".into(), "Be nice.".into());
+        assert!(chatstate.render_system_prompt().unwrap().is_some());
+        chatstate.add_message("user".into(), "one".into());
+        chatstate.render_diff().unwrap();
+        chatstate.add_message("assistant".into(), "two".into());
+        chatstate.add_message("user".into(), "three".into());
+        chatstate.render_diff().unwrap();
+
+        // undoing the message after a prune starts over, instead of going back to a render from before the prune
+        assert!(chatstate.prune_history(1));
+        chatstate.undo_last_message();
+        chatstate.add_message("user".into(), "four".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "system: Be nice.\nuser: four\n"
+        );
+
+        // the system prompt was read into the context from before the prune, so it can't be kept
+        chatstate.clear_history(true);
+        assert_eq!(chatstate.render_diff().unwrap(), "system: Be nice.\n");
+    }
+
+    #[test]
+    fn test_clear_history_keeps_system_prompt() {
+        let template = "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("system".into(), "Be nice.".into());
+        assert_eq!(
+            chatstate.render_system_prompt().unwrap().unwrap(),
+            "<system>Be nice."
+        );
+        // only once, before anything else
+        assert_eq!(chatstate.render_system_prompt().unwrap(), None);
+
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<user>Hi");
+        chatstate.add_message("assistant".into(), "Hello".into());
+        chatstate.mark_response_read().unwrap();
+
+        chatstate.clear_history(true);
+        chatstate.add_message("user".into(), "Who are you?".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<user>Who are you?");
+
+        chatstate.clear_history(false);
+        chatstate.add_message("user".into(), "Hi again".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<system>Be nice.<user>Hi again"
+        );
+    }
+
+    #[test]
+    fn test_render_system_prompt_without_system_role() {
+        let template = "{% for message in messages %}{% if message['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("system".into(), "Be nice.".into());
+        assert_eq!(chatstate.render_system_prompt().unwrap(), None);
+
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<user>Be nice.\n\nHi");
+    }
+
+    #[test]
+    fn test_custom_template_filter() {
+        configure_template_environment(|env| {
+            env.add_filter("shout", |s: String| s.to_uppercase());
+        });
+        let template = "{% for message in messages %}{{ message['content'] | shout }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "<|bos|>".into(), "<|eos|>".into());
+        chatstate.add_message("user".into(), "Hello, world!".into());
+        let rendered = chatstate.render_diff().unwrap();
+        assert_eq!(rendered, "HELLO, WORLD!");
+    }
+
+    #[test]
+    fn test_date_aware_template() {
+        // trimmed down from the llama 3.2 template, which uses both `strftime_now` and `date_string`
+        let template = "{%- if strftime_now is defined %}{%- set today = strftime_now(\"%d %b %Y\") %}{%- endif %}{%- if not date_string is defined %}{%- set date_string = \"26 Jul 2024\" %}{%- endif %}{{ today }}|{{ date_string }}";
+        let mut chatstate = ChatState::new(template.into(), "<|bos|>".into(), "<|eos|>".into());
+        chatstate.add_message("user".into(), "What day is it?".into());
+        let rendered = chatstate.render_diff().unwrap();
+        let today = strftime_now("%d %b %Y");
+        assert_eq!(rendered, format!("{today}|{today}"));
+    }
+
+    #[test]
+    fn test_eos_token_template() {
+        // mistral-style templates close every assistant turn with the `eos_token` variable
+        let template = "{{ bos_token }}{% for message in messages %}{% if message['role'] == 'user' %}[INST] {{ message['content'] }} [/INST]{% else %}{{ message['content'] }}{{ eos_token }}{% endif %}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "<s>".into(), "</s>".into());
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<s>[INST] Hi [/INST]");
+        chatstate.add_message("assistant".into(), "Hello!".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "Hello!</s>");
+    }
+
+    #[test]
+    fn test_prepend_bos() {
+        let template = "{% for message in messages %}<|{{ message['role'] }}|>{{ message['content'] }}{{ eos_token }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "<s>".into(), "</s>".into());
+        chatstate.add_message("user".into(), "Hi".into());
+
+        // by default, the template is rendered as is
+        assert_eq!(chatstate.render_diff().unwrap(), "<|user|>Hi</s>");
+
+        chatstate.reset();
+        chatstate.set_prepend_bos(true);
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<s><|user|>Hi</s>");
+        chatstate.add_message("assistant".into(), "Hello!".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<|assistant|>Hello!</s>");
+
+        // templates that render it themselves don't get it twice
+        let template = format!("{{{{ bos_token }}}}{template}");
+        let mut chatstate = ChatState::new(template, "<s>".into(), "</s>".into());
+        chatstate.set_prepend_bos(true);
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<s><|user|>Hi</s>");
+    }
+
+    #[test]
+    fn test_enable_thinking() {
+        // shortened from the qwen3 template
+        let template = "{% for message in messages %}<|im_start|>{{ message['role'] }}\n{{ message['content'] }}<|im_end|>\n{% endfor %}{% if add_generation_prompt %}<|im_start|>assistant\n{% if enable_thinking is defined and enable_thinking is false %}<think>\n\n</think>\n\n{% endif %}{% endif %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "<|im_end|>".into());
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+
+        chatstate.reset();
+        chatstate.set_enable_thinking(Some(false));
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n<think>\n\n</think>\n\n"
+        );
+
+        chatstate.reset();
+        chatstate.set_enable_thinking(Some(true));
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_fallback_generation_prompt() {
+        let template = "{% for message in messages %}<|{{ message['role'] }}|>\n{{ message['content'] }}\n{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<|user|>\nHi\n");
+        assert_eq!(chatstate.lacks_generation_prompt(), Some(true));
+
+        chatstate.reset();
+        chatstate.set_fallback_generation_prompt(Some("<|assistant|>\n".into()));
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|user|>\nHi\n<|assistant|>\n"
+        );
+        chatstate.add_message("assistant".into(), "Hello!".into());
+        chatstate.add_message("user".into(), "Bye".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "Hello!\n<|user|>\nBye\n<|assistant|>\n"
+        );
+
+        // templates with a generation prompt don't get a second one
+        let template =
+            format!("{template}{{% if add_generation_prompt %}}<|assistant|>\n{{% endif %}}");
+        let mut chatstate = ChatState::new(template, "".into(), "".into());
+        chatstate.set_fallback_generation_prompt(Some("<|assistant|>\n".into()));
+        chatstate.add_message("user".into(), "Hi".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|user|>\nHi\n<|assistant|>\n"
+        );
+        assert_eq!(chatstate.lacks_generation_prompt(), Some(false));
+    }
+
+    #[test]
+    fn test_role_names() {
+        // gemma-style template, which calls the assistant "model"
+        let template = "{% for message in messages %}{% if message['role'] not in ['user', 'model'] %}{{ raise_exception('Unknown role') }}{% endif %}<start_of_turn>{{ message['role'] }}\n{{ message['content'] }}<end_of_turn>\n{% endfor %}{% if add_generation_prompt %}<start_of_turn>model\n{% endif %}";
+        let mut chatstate = ChatState::new(template.into(), "<bos>".into(), "<eos>".into());
+        chatstate.set_role_names(RoleNames {
+            assistant: "model".into(),
+            ..RoleNames::default()
+        });
+        chatstate.add_message("user".into(), "Hi".into());
+        chatstate.add_message("assistant".into(), "Hello!".into());
+        chatstate.add_message("user".into(), "How are you?".into());
+        let rendered = chatstate.render_diff().unwrap();
+        assert_eq!(
+            rendered,
+            "<start_of_turn>user\nHi<end_of_turn>\n<start_of_turn>model\nHello!<end_of_turn>\n<start_of_turn>user\nHow are you?<end_of_turn>\n<start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn test_continue_final_message() {
+        let template = "{% for message in messages %}<|start_header_id|>{{ message['role'] }}<|end_header_id|>\n\n{{ message['content'] | trim }}<|eot_id|>{% endfor %}{% if add_generation_prompt %}<|start_header_id|>assistant<|end_header_id|>\n\n{% endif %}";
+
+        // a new turn gets a generation prompt after the user message
+        let mut chatstate = ChatState::new(template.into(), "<|bos|>".into(), "<|eos|>".into());
+        chatstate.add_message("user".into(), "Tell me a story.".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|start_header_id|>user<|end_header_id|>\n\nTell me a story.<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+
+        // a finished assistant message is closed with the end-of-turn token
+        chatstate.add_message("assistant".into(), "Once upon a time ".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "Once upon a time<|eot_id|>"
+        );
+
+        // continuing leaves the final message open, and does not add a second generation prompt
+        let mut chatstate = ChatState::new(template.into(), "<|bos|>".into(), "<|eos|>".into());
+        chatstate.set_continue_final_message(true);
+        chatstate.add_message("user".into(), "Tell me a story.".into());
+        chatstate.add_message("assistant".into(), "Once upon a time ".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<|start_header_id|>user<|end_header_id|>\n\nTell me a story.<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\nOnce upon a time"
+        );
+    }
+
+    #[test]
+    fn test_template_error_snippet() {
+        let template = format!(
+            "{}{{{{ messages[0]['content'] | no_such_filter }}}}{}",
+            "a".repeat(200),
+            "b".repeat(200)
+        );
+        let mut chatstate = ChatState::new(template, "<|bos|>".into(), "<|eos|>".into());
+        chatstate.add_message("user".into(), "Hello, world!".into());
+        let message = chatstate.render_diff().unwrap_err().to_string();
+        assert!(
+            message.contains("no_such_filter"),
+            "Expected the error to show the failing part of the template, got: {message}"
+        );
+        assert!(
+            !message.contains(&"a".repeat(200)),
+            "Expected the snippet to be truncated, got: {message}"
+        );
+    }
+
+    #[test]
+    fn test_builtin_templates() {
+        assert!(builtin_template("ChatML").is_some());
+        assert!(builtin_template("no-such-template").is_none());
+
+        let mut chatstate = ChatState::new(
+            builtin_template("llama2").unwrap().into(),
+            "<s>".into(),
+            "</s>".into(),
+        );
+        chatstate.add_message("system".into(), "You are a pirate.".into());
+        chatstate.add_message("user".into(), "Hello!".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<s>[INST] <<SYS>>\nYou are a pirate.\n<</SYS>>\n\nHello! [/INST]"
+        );
+        chatstate.add_message("assistant".into(), "Arr!".into());
+        assert_eq!(chatstate.render_diff().unwrap(), " Arr! </s>");
+    }
+
+    #[test]
+    fn test_validate_template() {
+        assert!(validate_template(CHATML_TEMPLATE).is_ok());
+        assert!(validate_template(LLAMA2_TEMPLATE).is_ok());
+        assert!(validate_template("{{ messages[0]['content'] | no_such_filter }}").is_err());
+        assert!(validate_template("{% for message in messages %}").is_err());
+    }
+
+    #[test]
+    fn test_undo_last_message() {
+        let template = "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("user".into(), "Hi".into());
+        chatstate.render_diff().unwrap();
+        chatstate.add_message("assistant".into(), "Hello".into());
+        chatstate.render_diff().unwrap();
+
+        chatstate.add_message("user".into(), "This fails".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<user>This fails");
+        chatstate.undo_last_message();
+
+        chatstate.add_message("user".into(), "Try again".into());
+        assert_eq!(chatstate.render_diff().unwrap(), "<user>Try again");
+    }
+
+    #[test]
+    fn test_undo_render_diff() {
+        let template = "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}</{{ message['role'] }}>{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("user".into(), "Hi".into());
+        chatstate.render_diff().unwrap();
+        chatstate.add_message("assistant".into(), "Hello".into());
+        chatstate.mark_response_read().unwrap();
+
+        // the end of the turn is unread until a diff is read
+        assert_eq!(chatstate.render_diff().unwrap(), "</assistant>");
+        chatstate.undo_render_diff();
+        assert_eq!(chatstate.render_diff().unwrap(), "</assistant>");
+        assert_eq!(chatstate.render_diff().unwrap(), "");
+    }
+
+    #[test]
+    fn test_summarize_history() {
+        let template = "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}{% endfor %}";
+        let mut chatstate = ChatState::new(template.into(), "".into(), "".into());
+        chatstate.add_message("system".into(), "Be nice.".into());
+        chatstate.add_message("user".into(), "My name is Bob".into());
+        chatstate.add_message("assistant".into(), "Hi Bob".into());
+        chatstate.render_diff().unwrap();
+
+        let request = chatstate.render_summary_request().unwrap();
+        assert!(request.starts_with("<user>Summarize"));
+        assert!(request.contains("user: My name is Bob\n\nassistant: Hi Bob"));
+        assert!(!request.contains("Be nice."));
+
+        chatstate.replace_history_with_summary("Bob said hi.\n");
+        chatstate.add_message("user".into(), "What's my name?".into());
+        assert_eq!(
+            chatstate.render_diff().unwrap(),
+            "<system>Be nice.\n\nSummary of the conversation so far:\nBob said hi.<user>What's my name?"
+        );
+    }
+
+    /// Tiny xorshift generator, so the conversations below are varied but reproducible.
+    struct Xorshift(u64);
+
+    impl Xorshift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_message_content(rng: &mut Xorshift, with_reasoning: bool) -> String {
+        const WORDS: &[&str] = &[
+            "hello",
+            "world",
+            "  ",
+            "\n",
+            "\n\n",
+            "æøå",
+            "日本語",
+            "{{",
+            "}}",
+            "'",
+            "\"",
+            "<b>",
+            "[INST]",
+            "🦜",
+            "\t",
+            "the",
+            "quick",
+            "fox",
+        ];
+        let mut content = String::new();
+        if with_reasoning && rng.below(2) == 0 {
+            content.push_str("<think>\nhmm, ");
+            content.push_str(WORDS[rng.below(WORDS.len() as u64) as usize]);
+            content.push_str("\n</think>\n\n");
+        }
+        for _ in 0..(1 + rng.below(12)) {
+            content.push_str(WORDS[rng.below(WORDS.len() as u64) as usize]);
+            content.push(' ');
+        }
+        content
+    }
+
+    /// Grows a random conversation one message at a time, and checks that every render
+    /// starts with the one before it. `render_diff` relies on this to only send new text.
+    fn check_prefix_invariant(template: &str, seed: u64) -> Result<(), String> {
+        let mut rng = Xorshift(seed);
+        let mut chatstate = ChatState::new(template.into(), "<s>".into(), "</s>".into());
+        chatstate.add_message("system".into(), random_message_content(&mut rng, false));
+        let mut previous = String::new();
+        for turn in 0..(2 + rng.below(8)) {
+            let role = if turn % 2 == 0 { "user" } else { "assistant" };
+            let content = random_message_content(&mut rng, role == "assistant");
+            chatstate.add_message(role.into(), content);
+            let rendered = chatstate.render().map_err(|e| e.to_string())?;
+            if !rendered.starts_with(&previous) {
+                return Err(format!(
+                    "render after message {turn} does not extend the previous one.\nprevious: {previous:?}\nrendered: {rendered:?}"
+                ));
+            }
+            previous = rendered;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_diff_prefix_invariant() {
+        let templates = [
+            (
+                "chatml",
+                include_str!("../fixtures/chat_templates/chatml.jinja"),
+            ),
+            (
+                "llama31",
+                include_str!("../fixtures/chat_templates/llama31.jinja"),
+            ),
+            (
+                "mistral",
+                include_str!("../fixtures/chat_templates/mistral.jinja"),
+            ),
+            (
+                "gemma2",
+                include_str!("../fixtures/chat_templates/gemma2.jinja"),
+            ),
+            (
+                "deepseek_r1",
+                include_str!("../fixtures/chat_templates/deepseek_r1.jinja"),
+            ),
+        ];
+        for (name, template) in templates {
+            for seed in 1..=200 {
+                if let Err(e) = check_prefix_invariant(template, seed) {
+                    panic!("{name} template breaks the prefix invariant with seed {seed}: {e}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefix_invariant_catches_reasoning_stripping() {
+        // templates that strip reasoning from all but the latest assistant message rewrite
+        // history, so render_diff can't work with them. make sure the check notices.
+        let template = include_str!("../fixtures/chat_templates/qwen3.jinja");
+        assert!((1..=200).any(|seed| check_prefix_invariant(template, seed).is_err()));
+    }
 }
@@ -0,0 +1,201 @@
+//! Picking markdown code blocks out of a response while it is being generated.
+//! Fences like ```` ```python ```` usually span several tokens, so this buffers the start of each line
+//! until it is clear whether it is a fence, and passes everything else through right away.
+
+/// A piece of a response, split up by `CodeFenceStreamer`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FenceEvent {
+    /// Text outside of code blocks.
+    Text(String),
+    /// An opening fence, with the language written after it, or an empty string if there is none.
+    CodeBlockStarted(String),
+    /// Text inside a code block. The fences themselves are left out.
+    Code(String),
+    /// A closing fence, or the end of the response in the middle of a code block.
+    CodeBlockEnded,
+}
+
+/// Reads a response a piece at a time, and splits it into text, code, and the starts and ends of code blocks.
+/// Only fences on a line of their own count, optionally indented, like in markdown.
+#[derive(Clone, Debug)]
+pub struct CodeFenceStreamer {
+    in_code: bool,
+    at_line_start: bool,
+    /// The start of the current line, while it may still turn out to be a fence.
+    line: String,
+}
+
+const FENCE: &str = "```";
+
+impl Default for CodeFenceStreamer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeFenceStreamer {
+    pub fn new() -> Self {
+        Self {
+            in_code: false,
+            at_line_start: true,
+            line: String::new(),
+        }
+    }
+
+    /// Whether the text read so far ended inside a code block.
+    pub fn in_code_block(&self) -> bool {
+        self.in_code
+    }
+
+    /// Reads the next piece of the response, and returns what can be told about it so far.
+    pub fn push(&mut self, text: &str) -> Vec<FenceEvent> {
+        let mut events = Vec::new();
+        for c in text.chars() {
+            if !self.at_line_start {
+                self.emit(&mut events, c.to_string());
+                self.at_line_start = c == '\n';
+                continue;
+            }
+            if c == '\n' {
+                self.end_line(&mut events);
+                continue;
+            }
+            self.line.push(c);
+            let trimmed = self.line.trim_start_matches([' ', '\t']);
+            if !trimmed.starts_with(FENCE) && !FENCE.starts_with(trimmed) {
+                // not a fence after all
+                let line = std::mem::take(&mut self.line);
+                self.emit(&mut events, line);
+                self.at_line_start = false;
+            }
+        }
+        events
+    }
+
+    /// Ends the response, and returns whatever was still held back.
+    /// A code block that was never closed is ended here, so every start has an end.
+    pub fn finish(&mut self) -> Vec<FenceEvent> {
+        let mut events = Vec::new();
+        if !self.line.is_empty() && !self.read_fence(&mut events) {
+            let line = std::mem::take(&mut self.line);
+            self.emit(&mut events, line);
+        }
+        if self.in_code {
+            self.in_code = false;
+            events.push(FenceEvent::CodeBlockEnded);
+        }
+        *self = Self::new();
+        events
+    }
+
+    fn end_line(&mut self, events: &mut Vec<FenceEvent>) {
+        if !self.read_fence(events) {
+            let mut line = std::mem::take(&mut self.line);
+            line.push('\n');
+            self.emit(events, line);
+        }
+        self.line.clear();
+        self.at_line_start = true;
+    }
+
+    /// Opens or closes a code block if the buffered line is a fence. Closing fences can't have a language.
+    fn read_fence(&mut self, events: &mut Vec<FenceEvent>) -> bool {
+        let Some(info) = self
+            .line
+            .trim_start_matches([' ', '\t'])
+            .strip_prefix(FENCE)
+        else {
+            return false;
+        };
+        let info = info.trim();
+        if self.in_code && info.is_empty() {
+            self.in_code = false;
+            events.push(FenceEvent::CodeBlockEnded);
+        } else if !self.in_code {
+            self.in_code = true;
+            events.push(FenceEvent::CodeBlockStarted(info.to_string()));
+        } else {
+            return false;
+        }
+        self.line.clear();
+        true
+    }
+
+    /// Adds text to the events, merged with the text before it if it is of the same kind.
+    fn emit(&self, events: &mut Vec<FenceEvent>, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        match (events.last_mut(), self.in_code) {
+            (Some(FenceEvent::Code(code)), true) => code.push_str(&text),
+            (Some(FenceEvent::Text(prose)), false) => prose.push_str(&text),
+            (_, true) => events.push(FenceEvent::Code(text)),
+            (_, false) => events.push(FenceEvent::Text(text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Streams the response in pieces of `size` characters, like tokens, and merges the events back together.
+    fn stream(response: &str, size: usize) -> Vec<FenceEvent> {
+        let mut streamer = CodeFenceStreamer::new();
+        let chars: Vec<char> = response.chars().collect();
+        let mut all: Vec<FenceEvent> = chars
+            .chunks(size)
+            .flat_map(|chunk| streamer.push(&chunk.iter().collect::<String>()))
+            .collect();
+        all.extend(streamer.finish());
+        let mut events: Vec<FenceEvent> = Vec::new();
+        for event in all {
+            match (events.last_mut(), event) {
+                (Some(FenceEvent::Text(a)), FenceEvent::Text(b)) => a.push_str(&b),
+                (Some(FenceEvent::Code(a)), FenceEvent::Code(b)) => a.push_str(&b),
+                (_, event) => events.push(event),
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_code_fences() {
+        let response =
+            "Here you go:\n```gdscript\nfunc _ready():\n    print(\"`hi`\")\n```\nEnjoy!";
+        let expected = vec![
+            FenceEvent::Text("Here you go:\n".to_string()),
+            FenceEvent::CodeBlockStarted("gdscript".to_string()),
+            FenceEvent::Code("func _ready():\n    print(\"`hi`\")\n".to_string()),
+            FenceEvent::CodeBlockEnded,
+            FenceEvent::Text("Enjoy!".to_string()),
+        ];
+        for size in [1, 2, 3, 5, 100] {
+            assert_eq!(stream(response, size), expected, "pieces of {size}");
+        }
+    }
+
+    #[test]
+    fn test_unclosed_code_fence() {
+        let events = stream("```\nlet x = 1;\n``", 2);
+        assert_eq!(
+            events,
+            vec![
+                FenceEvent::CodeBlockStarted(String::new()),
+                FenceEvent::Code("let x = 1;\n``".to_string()),
+                FenceEvent::CodeBlockEnded,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_backticks() {
+        let events = stream("Use `print` or ``` in a sentence.\n", 3);
+        assert_eq!(
+            events,
+            vec![FenceEvent::Text(
+                "Use `print` or ``` in a sentence.\n".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Reading the metadata of a GGUF model file without loading the model, e.g. to check whether it will fit in memory.
+//! Only the header, the metadata and the tensor descriptions are read, not the tensors themselves.
+
+use crate::llm;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+/// What a model file contains, as far as can be told from its headers.
+///
+/// # Fields
+/// * `file_size` - The size of the file in bytes, which is roughly the memory the weights take up
+/// * `architecture` - The model architecture, like "llama" or "qwen2"
+/// * `n_params` - The number of parameters, counted from the tensor shapes
+/// * `file_type` - The quantization of most of the weights, like "Q4_K_M"
+/// * `n_layers` - The number of layers
+/// * `n_ctx_train` - The context length the model was trained with
+/// * `n_embd` - The size of the embeddings
+/// * `n_head` - The number of attention heads
+/// * `n_head_kv` - The number of key/value heads, which is lower than `n_head` for models with grouped-query attention
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelInfo {
+    pub file_size: u64,
+    pub architecture: String,
+    pub n_params: u64,
+    pub file_type: String,
+    pub n_layers: u32,
+    pub n_ctx_train: u32,
+    pub n_embd: u32,
+    pub n_head: u32,
+    pub n_head_kv: u32,
+}
+
+impl ModelInfo {
+    /// A rough estimate of the memory needed to run the model with a context of `n_ctx` tokens, in bytes:
+    /// the weights, an f16 KV cache, and some room for the compute buffers.
+    /// The actual use depends on the backend and the batch size, so treat this as a lower bound.
+    pub fn estimate_memory(&self, n_ctx: u32) -> u64 {
+        let n_embd_kv = match self.n_head {
+            0 => self.n_embd as u64,
+            n_head => self.n_embd as u64 * self.n_head_kv as u64 / n_head as u64,
+        };
+        // keys and values, 2 bytes each
+        let kv_cache = 2 * 2 * self.n_layers as u64 * n_ctx as u64 * n_embd_kv;
+        let compute_buffers = self.file_size / 10;
+        self.file_size + kv_cache + compute_buffers
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InspectModelError {
+    #[error("{0}")]
+    Header(#[from] llm::LoadModelError),
+
+    #[error("Could not read model file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed GGUF metadata: {0}")]
+    Malformed(String),
+}
+
+// the types of metadata values, as numbered in the GGUF format
+const TYPE_U8: u32 = 0;
+const TYPE_I8: u32 = 1;
+const TYPE_U16: u32 = 2;
+const TYPE_I16: u32 = 3;
+const TYPE_U32: u32 = 4;
+const TYPE_I32: u32 = 5;
+const TYPE_F32: u32 = 6;
+const TYPE_BOOL: u32 = 7;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_U64: u32 = 10;
+const TYPE_I64: u32 = 11;
+const TYPE_F64: u32 = 12;
+
+/// The metadata values this module needs. Everything else is skipped.
+enum Value {
+    Int(u64),
+    String(String),
+    Other,
+}
+
+struct GgufReader<R> {
+    reader: R,
+}
+
+impl<R: Read + Seek> GgufReader<R> {
+    fn u32(&mut self) -> Result<u32, InspectModelError> {
+        let mut bytes = [0u8; 4];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, InspectModelError> {
+        let mut bytes = [0u8; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn skip(&mut self, n_bytes: u64) -> Result<(), InspectModelError> {
+        let n_bytes = i64::try_from(n_bytes)
+            .map_err(|_| InspectModelError::Malformed(format!("can't skip {n_bytes} bytes")))?;
+        self.reader.seek(SeekFrom::Current(n_bytes))?;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, InspectModelError> {
+        let len = self.u64()?;
+        // nothing in the metadata we read is this long, so it must be garbage
+        if len > 1 << 20 {
+            return Err(InspectModelError::Malformed(format!(
+                "string of {len} bytes"
+            )));
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<Value, InspectModelError> {
+        let value = match value_type {
+            TYPE_U8 | TYPE_I8 | TYPE_BOOL => {
+                self.skip(1)?;
+                Value::Other
+            }
+            TYPE_U16 | TYPE_I16 => {
+                self.skip(2)?;
+                Value::Other
+            }
+            TYPE_U32 => Value::Int(self.u32()? as u64),
+            TYPE_I32 | TYPE_F32 => {
+                self.skip(4)?;
+                Value::Other
+            }
+            TYPE_U64 => Value::Int(self.u64()?),
+            TYPE_I64 | TYPE_F64 => {
+                self.skip(8)?;
+                Value::Other
+            }
+            TYPE_STRING => Value::String(self.string()?),
+            TYPE_ARRAY => {
+                let item_type = self.u32()?;
+                let len = self.u64()?;
+                match item_type {
+                    TYPE_U8 | TYPE_I8 | TYPE_BOOL => self.skip(len)?,
+                    TYPE_U16 | TYPE_I16 => self.skip(len.saturating_mul(2))?,
+                    TYPE_U32 | TYPE_I32 | TYPE_F32 => self.skip(len.saturating_mul(4))?,
+                    TYPE_U64 | TYPE_I64 | TYPE_F64 => self.skip(len.saturating_mul(8))?,
+                    // e.g. the vocabulary, which has to be walked one string at a time
+                    _ => {
+                        for _ in 0..len {
+                            self.value(item_type)?;
+                        }
+                    }
+                }
+                Value::Other
+            }
+            other => {
+                return Err(InspectModelError::Malformed(format!(
+                    "unknown value type {other}"
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// The name llama.cpp uses for a `general.file_type`.
+fn file_type_name(file_type: u64) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        other => return format!("unknown ({other})"),
+    };
+    name.to_string()
+}
+
+/// Reads the metadata of a GGUF model file. This is fast even for large models, since the tensors are skipped.
+pub fn inspect_model(model_path: &str) -> Result<ModelInfo, InspectModelError> {
+    llm::check_gguf_header(model_path)?;
+    let file = std::fs::File::open(model_path)?;
+    let mut info = ModelInfo {
+        file_size: file.metadata()?.len(),
+        ..ModelInfo::default()
+    };
+    let mut gguf = GgufReader {
+        reader: BufReader::new(file),
+    };
+
+    // magic and version, which check_gguf_header already looked at
+    gguf.skip(8)?;
+    let n_tensors = gguf.u64()?;
+    let n_kv = gguf.u64()?;
+
+    // keys like `llama.block_count` depend on the architecture, so collect the numbers and look them up afterwards
+    let mut ints = std::collections::HashMap::new();
+    for _ in 0..n_kv {
+        let key = gguf.string()?;
+        let value_type = gguf.u32()?;
+        match gguf.value(value_type)? {
+            Value::String(value) if key == "general.architecture" => info.architecture = value,
+            Value::Int(value) => {
+                ints.insert(key, value);
+            }
+            _ => (),
+        }
+    }
+    let arch_int = |name: &str| {
+        ints.get(&format!("{}.{name}", info.architecture))
+            .map_or(0, |value| *value as u32)
+    };
+    info.n_layers = arch_int("block_count");
+    info.n_ctx_train = arch_int("context_length");
+    info.n_embd = arch_int("embedding_length");
+    info.n_head = arch_int("attention.head_count");
+    info.n_head_kv = match arch_int("attention.head_count_kv") {
+        0 => info.n_head,
+        n_head_kv => n_head_kv,
+    };
+    info.file_type = ints.get("general.file_type").map_or_else(
+        || "unknown".to_string(),
+        |file_type| file_type_name(*file_type),
+    );
+
+    for _ in 0..n_tensors {
+        let _name = gguf.string()?;
+        let n_dims = gguf.u32()?;
+        let mut n_elements: u64 = 1;
+        for _ in 0..n_dims {
+            n_elements = n_elements.saturating_mul(gguf.u64()?);
+        }
+        // tensor type and offset
+        gguf.skip(4 + 8)?;
+        info.n_params += n_elements;
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a small GGUF file with the given metadata, and one tensor of the given shape.
+    fn write_gguf(name: &str, kvs: &[(&str, u32, Vec<u8>)], tensor_shape: &[u64]) -> String {
+        let string = |s: &str| {
+            [
+                (s.len() as u64).to_le_bytes().to_vec(),
+                s.as_bytes().to_vec(),
+            ]
+            .concat()
+        };
+        let mut bytes = b"GGUF".to_vec();
+        bytes.extend(3u32.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend((kvs.len() as u64).to_le_bytes());
+        for (key, value_type, value) in kvs {
+            bytes.extend(string(key));
+            bytes.extend(value_type.to_le_bytes());
+            bytes.extend(value);
+        }
+        bytes.extend(string("token_embd.weight"));
+        bytes.extend((tensor_shape.len() as u32).to_le_bytes());
+        for dim in tensor_shape {
+            bytes.extend(dim.to_le_bytes());
+        }
+        bytes.extend(2u32.to_le_bytes());
+        bytes.extend(0u64.to_le_bytes());
+
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_inspect_model() {
+        let string = |s: &str| {
+            [
+                (s.len() as u64).to_le_bytes().to_vec(),
+                s.as_bytes().to_vec(),
+            ]
+            .concat()
+        };
+        let u32_value = |n: u32| n.to_le_bytes().to_vec();
+        let vocab = [
+            TYPE_STRING.to_le_bytes().to_vec(),
+            2u64.to_le_bytes().to_vec(),
+            string("hello"),
+            string("world"),
+        ]
+        .concat();
+        let path = write_gguf(
+            "nobodywho-inspect.gguf",
+            &[
+                ("general.architecture", TYPE_STRING, string("llama")),
+                ("general.file_type", TYPE_U32, u32_value(15)),
+                ("tokenizer.ggml.tokens", TYPE_ARRAY, vocab),
+                ("llama.block_count", TYPE_U32, u32_value(16)),
+                ("llama.context_length", TYPE_U32, u32_value(8192)),
+                ("llama.embedding_length", TYPE_U32, u32_value(2048)),
+                ("llama.attention.head_count", TYPE_U32, u32_value(32)),
+                ("llama.attention.head_count_kv", TYPE_U32, u32_value(8)),
+                (
+                    "llama.rope.freq_base",
+                    TYPE_F32,
+                    10000f32.to_le_bytes().to_vec(),
+                ),
+            ],
+            &[2048, 1000],
+        );
+
+        let info = inspect_model(&path).unwrap();
+        assert_eq!(info.architecture, "llama");
+        assert_eq!(info.file_type, "Q4_K_M");
+        assert_eq!(info.n_layers, 16);
+        assert_eq!(info.n_ctx_train, 8192);
+        assert_eq!(info.n_head_kv, 8);
+        assert_eq!(info.n_params, 2048 * 1000);
+
+        // the KV cache of 16 layers, 4096 tokens and 512 key/value dimensions, in f16
+        let kv_cache = 2 * 2 * 16 * 4096 * 512;
+        assert!(info.estimate_memory(4096) >= info.file_size + kv_cache);
+    }
+
+    #[test]
+    fn test_inspect_malformed_model() {
+        let path = write_gguf(
+            "nobodywho-malformed.gguf",
+            &[("general.architecture", 99, vec![])],
+            &[1],
+        );
+        assert!(matches!(
+            inspect_model(&path),
+            Err(InspectModelError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,280 @@
+//! Helpers for building GBNF grammars, for when writing one by hand is overkill.
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum RegexGrammarError {
+    #[error("Pattern ended unexpectedly")]
+    UnexpectedEnd,
+
+    #[error("Unexpected {0:?} at position {1}")]
+    UnexpectedChar(char, usize),
+
+    #[error("Unsupported regex syntax at position {1}: {0}")]
+    Unsupported(String, usize),
+}
+
+/// Converts a regular expression to a GBNF grammar that only accepts text matching the entire pattern.
+///
+/// Supports literals, `.`, character classes like `[a-z]` and `[^0-9]`, the escapes `\d`, `\w` and `\s`
+/// (and their negations), groups, alternation with `|`, and the quantifiers `*`, `+`, `?` and `{m,n}`.
+/// Anchors (`^` and `$`) are accepted but ignored, since the whole response always has to match.
+/// Lookarounds and backreferences can't be expressed in GBNF, and return an error.
+pub fn regex_to_gbnf(pattern: &str) -> Result<String, RegexGrammarError> {
+    let mut parser = RegexParser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+    };
+    let expr = parser.alternation()?;
+    match parser.peek() {
+        None => Ok(format!("root ::= {expr}\n")),
+        Some(c) => Err(RegexGrammarError::UnexpectedChar(c, parser.pos)),
+    }
+}
+
+struct RegexParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl RegexParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char, RegexGrammarError> {
+        let c = self.peek().ok_or(RegexGrammarError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<String, RegexGrammarError> {
+        let mut branches = vec![self.sequence()?];
+        while self.eat('|') {
+            branches.push(self.sequence()?);
+        }
+        Ok(branches.join(" | "))
+    }
+
+    fn sequence(&mut self) -> Result<String, RegexGrammarError> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let Some(atom) = self.atom()? else {
+                continue;
+            };
+            let quantifier = self.quantifier()?;
+            items.push(format!("{atom}{quantifier}"));
+        }
+        if items.is_empty() {
+            // matches the empty string
+            Ok("\"\"".to_string())
+        } else {
+            Ok(items.join(" "))
+        }
+    }
+
+    /// Parses a single item, or returns `None` for anchors, which don't match any text.
+    fn atom(&mut self) -> Result<Option<String>, RegexGrammarError> {
+        let start = self.pos;
+        let atom = match self.next()? {
+            '^' | '$' => return Ok(None),
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(RegexGrammarError::Unsupported(
+                        "lookarounds and named groups".into(),
+                        start,
+                    ));
+                }
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(RegexGrammarError::UnexpectedEnd);
+                }
+                format!("({inner})")
+            }
+            '[' => self.class()?,
+            '.' => "[^\\n]".to_string(),
+            '\\' => match self.next()? {
+                'd' => "[0-9]".to_string(),
+                'D' => "[^0-9]".to_string(),
+                'w' => "[a-zA-Z0-9_]".to_string(),
+                'W' => "[^a-zA-Z0-9_]".to_string(),
+                's' => "[ \\t\\n\\r]".to_string(),
+                'S' => "[^ \\t\\n\\r]".to_string(),
+                c if c.is_ascii_digit() => {
+                    return Err(RegexGrammarError::Unsupported(
+                        "backreferences".into(),
+                        start,
+                    ))
+                }
+                c => literal(unescape(c)),
+            },
+            c @ ('*' | '+' | '?' | '{' | ')') => {
+                return Err(RegexGrammarError::UnexpectedChar(c, start))
+            }
+            c => literal(c),
+        };
+        Ok(Some(atom))
+    }
+
+    /// Parses the body of a character class, after the opening bracket.
+    fn class(&mut self) -> Result<String, RegexGrammarError> {
+        let mut class = String::from("[");
+        if self.eat('^') {
+            class.push('^');
+        }
+        let mut first = true;
+        // whether the last item was a single character, which a dash can start a range from
+        let mut after_char = false;
+        loop {
+            let c = self.next()?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            match c {
+                '\\' => {
+                    let escaped = self.next()?;
+                    after_char = !matches!(escaped, 'd' | 'w' | 's');
+                    match escaped {
+                        'd' => class.push_str("0-9"),
+                        'w' => class.push_str("a-zA-Z0-9_"),
+                        's' => class.push_str(" \\t\\n\\r"),
+                        c => class.push_str(&class_char(unescape(c))),
+                    }
+                }
+                // a dash between two characters is a range, anywhere else it's a literal dash
+                '-' if after_char && self.peek() != Some(']') => {
+                    class.push('-');
+                    after_char = false;
+                }
+                c => {
+                    class.push_str(&class_char(c));
+                    after_char = true;
+                }
+            }
+        }
+        class.push(']');
+        Ok(class)
+    }
+
+    fn quantifier(&mut self) -> Result<String, RegexGrammarError> {
+        let quantifier = match self.peek() {
+            Some(c @ ('*' | '+' | '?')) => {
+                self.pos += 1;
+                c.to_string()
+            }
+            Some('{') => {
+                let start = self.pos;
+                self.pos += 1;
+                let mut body = String::new();
+                loop {
+                    match self.next()? {
+                        '}' => break,
+                        c @ ('0'..='9' | ',') => body.push(c),
+                        c => return Err(RegexGrammarError::UnexpectedChar(c, self.pos - 1)),
+                    }
+                }
+                if body.is_empty() || body.starts_with(',') || body.matches(',').count() > 1 {
+                    return Err(RegexGrammarError::Unsupported(
+                        format!("the quantifier {{{body}}}"),
+                        start,
+                    ));
+                }
+                format!("{{{body}}}")
+            }
+            _ => return Ok(String::new()),
+        };
+        // lazy and greedy quantifiers accept the same text
+        self.eat('?');
+        Ok(quantifier)
+    }
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        c => c,
+    }
+}
+
+/// Escapes a character for use in a GBNF string literal.
+fn literal(c: char) -> String {
+    match c {
+        '"' => "\"\\\"\"".to_string(),
+        '\\' => "\"\\\\\"".to_string(),
+        c => format!("\"{}\"", escape_control(c)),
+    }
+}
+
+/// Escapes a character for use in a GBNF character class.
+fn class_char(c: char) -> String {
+    match c {
+        '\\' | ']' | '[' => format!("\\{c}"),
+        '-' | '^' => format!("\\x{:02X}", c as u32),
+        c => escape_control(c),
+    }
+}
+
+fn escape_control(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        c if c.is_ascii_control() => format!("\\x{:02X}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex_to_gbnf() {
+        assert_eq!(
+            regex_to_gbnf(r"\d{3}-\d{4}").unwrap(),
+            "root ::= [0-9]{3} \"-\" [0-9]{4}\n"
+        );
+        assert_eq!(
+            regex_to_gbnf(r"^(yes|no)$").unwrap(),
+            "root ::= (\"y\" \"e\" \"s\" | \"n\" \"o\")\n"
+        );
+        assert_eq!(
+            regex_to_gbnf(r#"[A-Z][a-z-]+\."?"#).unwrap(),
+            "root ::= [A-Z] [a-z\\x2D]+ \".\" \"\\\"\"?\n"
+        );
+        assert_eq!(
+            regex_to_gbnf(r"(?:ab)*?c").unwrap(),
+            "root ::= (\"a\" \"b\")* \"c\"\n"
+        );
+    }
+
+    #[test]
+    fn test_regex_to_gbnf_errors() {
+        assert_eq!(regex_to_gbnf("(abc"), Err(RegexGrammarError::UnexpectedEnd));
+        assert_eq!(
+            regex_to_gbnf("*a"),
+            Err(RegexGrammarError::UnexpectedChar('*', 0))
+        );
+        assert!(matches!(
+            regex_to_gbnf("(?=a)"),
+            Err(RegexGrammarError::Unsupported(_, 0))
+        ));
+        assert!(matches!(
+            regex_to_gbnf(r"(a)\1"),
+            Err(RegexGrammarError::Unsupported(_, 3))
+        ));
+    }
+}
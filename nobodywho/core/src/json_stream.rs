@@ -0,0 +1,172 @@
+//! Streaming one field out of a JSON object while it is being generated.
+//! For responses like `{"emotion": "angry", "text": "Get out of my shop!"}`, this lets the `text` field
+//! be shown as it is written, while the rest of the object is only used once it is complete.
+
+/// Reads a JSON object a piece at a time, and picks out the characters of one string field at the top level.
+/// Escape sequences are decoded, so the streamed text is the value the field ends up with.
+/// Fields that aren't strings, or that are nested in other objects, are not streamed.
+#[derive(Clone, Debug)]
+pub struct FieldStreamer {
+    field: String,
+    /// The open objects and arrays, as their opening characters.
+    containers: Vec<char>,
+    in_string: bool,
+    /// The characters of an escape sequence after the backslash, while in one.
+    escape: Option<String>,
+    /// The first half of a UTF-16 surrogate pair, waiting for the second half.
+    high_surrogate: Option<u16>,
+    expecting_key: bool,
+    reading_key: bool,
+    key: String,
+    streaming: bool,
+    text: String,
+}
+
+impl FieldStreamer {
+    pub fn new(field: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            containers: Vec::new(),
+            in_string: false,
+            escape: None,
+            high_surrogate: None,
+            expecting_key: false,
+            reading_key: false,
+            key: String::new(),
+            streaming: false,
+            text: String::new(),
+        }
+    }
+
+    /// Reads the next piece of the JSON, and returns the part of the field's value that was in it.
+    pub fn push(&mut self, json: &str) -> String {
+        let start = self.text.len();
+        for c in json.chars() {
+            if self.in_string {
+                self.read_string_char(c);
+            } else {
+                self.read_structure_char(c);
+            }
+        }
+        self.text[start..].to_string()
+    }
+
+    /// All of the field's value streamed so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn read_structure_char(&mut self, c: char) {
+        let top_level = self.containers.len() == 1 && self.containers[0] == '{';
+        match c {
+            '"' => {
+                self.in_string = true;
+                if top_level && self.expecting_key {
+                    self.reading_key = true;
+                    self.key.clear();
+                } else if top_level {
+                    self.streaming = self.key == self.field;
+                }
+            }
+            '{' | '[' => {
+                self.containers.push(c);
+                self.expecting_key = c == '{';
+            }
+            '}' | ']' => {
+                self.containers.pop();
+            }
+            ':' if top_level => self.expecting_key = false,
+            ',' if top_level => self.expecting_key = true,
+            _ => (),
+        }
+    }
+
+    fn read_string_char(&mut self, c: char) {
+        let Some(mut escape) = self.escape.take() else {
+            match c {
+                '\\' => self.escape = Some(String::new()),
+                '"' => {
+                    self.in_string = false;
+                    self.reading_key = false;
+                    self.streaming = false;
+                }
+                c => self.emit(c),
+            }
+            return;
+        };
+        escape.push(c);
+        let decoded = match escape.as_str() {
+            "n" => '\n',
+            "t" => '\t',
+            "r" => '\r',
+            "b" => '\u{8}',
+            "f" => '\u{c}',
+            unicode if unicode.starts_with('u') && unicode.len() < 5 => {
+                self.escape = Some(escape);
+                return;
+            }
+            unicode if unicode.starts_with('u') => {
+                let Ok(unit) = u16::from_str_radix(&unicode[1..], 16) else {
+                    return;
+                };
+                match self.high_surrogate.take() {
+                    None if (0xD800..0xDC00).contains(&unit) => {
+                        self.high_surrogate = Some(unit);
+                        return;
+                    }
+                    Some(high) => {
+                        let code =
+                            0x10000 + ((high as u32 - 0xD800) << 10) + (unit as u32 - 0xDC00);
+                        char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                    }
+                    None => char::from_u32(unit as u32).unwrap_or(char::REPLACEMENT_CHARACTER),
+                }
+            }
+            // `\"`, `\\` and `\/` are the character itself
+            _ => c,
+        };
+        self.emit(decoded);
+    }
+
+    fn emit(&mut self, c: char) {
+        if self.reading_key {
+            self.key.push(c);
+        } else if self.streaming {
+            self.text.push(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_field() {
+        let json = r#"{"emotion": "angry", "text": "Get \"out\"\nof my shop!", "volume": 11}"#;
+        let mut streamer = FieldStreamer::new("text");
+        // split it up like tokens would be, including in the middle of escape sequences
+        let streamed: Vec<String> = json
+            .as_bytes()
+            .chunks(3)
+            .map(|chunk| streamer.push(std::str::from_utf8(chunk).unwrap()))
+            .collect();
+        assert_eq!(streamed.concat(), "Get \"out\"\nof my shop!");
+        assert_eq!(streamer.text(), "Get \"out\"\nof my shop!");
+        assert!(streamed.iter().filter(|s| !s.is_empty()).count() > 1);
+    }
+
+    #[test]
+    fn test_stream_field_ignores_nested_fields() {
+        let json =
+            r#"{"meta": {"text": "nested"}, "tags": ["text"], "text": "top æ\u00e6\ud83d\ude00"}"#;
+        let mut streamer = FieldStreamer::new("text");
+        assert_eq!(streamer.push(json), "top ææ😀");
+    }
+
+    #[test]
+    fn test_stream_missing_field() {
+        let mut streamer = FieldStreamer::new("text");
+        assert_eq!(streamer.push(r#"{"emotion": "happy", "count": 2}"#), "");
+    }
+}
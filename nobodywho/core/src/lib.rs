@@ -1,6 +1,12 @@
 pub mod chat;
 pub mod chat_state;
+pub mod code_fence;
+pub mod gguf;
+pub mod grammar;
+pub mod json_stream;
 pub mod llm;
+pub mod postprocess;
+pub mod replay;
 pub mod sampler_config;
 
 #[cfg(test)]
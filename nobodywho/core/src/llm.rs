@@ -1,4 +1,4 @@
-use crate::sampler_config::{make_sampler, SamplerConfig};
+use crate::sampler_config::{make_sampler, Sampler, SamplerConfig};
 use lazy_static::lazy_static;
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::context::LlamaContext;
@@ -7,25 +7,76 @@ use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::model::{AddBos, Special};
-use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::token::LlamaToken;
+use llama_cpp_2::token::{LlamaToken, LlamaTokenAttr};
+use std::collections::HashMap;
 use std::pin::pin;
 use std::sync::{Arc, LazyLock, Mutex};
 use tokio;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, debug_span, error, info, info_span, trace, trace_span, warn};
 
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
 const MAX_TOKEN_STR_LEN: usize = 128;
 
-const CHANNEL_SIZE: usize = 4096; // this number is very arbitrary
+/// How many generated tokens may pile up before the worker waits for them to be consumed.
+const DEFAULT_MAX_BUFFERED_TOKENS: usize = 4096; // this number is very arbitrary
 
 lazy_static! {
-    static ref GLOBAL_INFERENCE_LOCK: Mutex<()> = Mutex::new(());
+    /// The inference lock of every model, by the address of the model. See `model_inference_lock`.
+    static ref INFERENCE_LOCKS: Mutex<HashMap<usize, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the inference lock of the model, which every worker that uses the model shares.
+/// A model that is loaded where a dropped model used to be gets the same lock, which is only slower, not unsafe.
+pub(crate) fn model_inference_lock(model: &LlamaModel) -> Arc<Mutex<()>> {
+    let mut locks = INFERENCE_LOCKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    locks
+        .entry(model as *const LlamaModel as usize)
+        .or_default()
+        .clone()
+}
+
+/// Decodes a batch while holding the model's inference lock, unless there is no lock.
+/// Contexts referencing the same model are not thread safe: if two of them decode at the same time,
+/// llama.cpp segfaults. Contexts of different models don't share a lock, so they decode at the same time.
+/// The lock is held for one decode at a time rather than a whole message,
+/// so a long response on one worker only delays other workers (e.g. embeddings) by a single token.
+fn locked_decode(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    lock: Option<&Mutex<()>>,
+) -> Result<(), llama_cpp_2::DecodeError> {
+    let Some(lock) = lock else {
+        return ctx.decode(batch);
+    };
+    let _inference_lock = inference_lock(lock);
+    ctx.decode(batch)
+}
+
+/// Takes an inference lock. If a worker panicked while holding it, the lock is poisoned,
+/// but it only guards llama.cpp against concurrent decodes and holds no data that could be left half-updated.
+/// So rather than failing every decode from then on, the poison is cleared and the other workers keep going.
+fn inference_lock(lock: &Mutex<()>) -> std::sync::MutexGuard<'_, ()> {
+    lock.lock().unwrap_or_else(|poisoned| {
+        error!("A worker panicked while decoding. Recovering the inference lock.");
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
 }
 
 static LLAMA_BACKEND: LazyLock<LlamaBackend> =
     LazyLock::new(|| LlamaBackend::init().expect("Failed to initialize llama backend"));
 
+/// Initializes the llama.cpp backend, unless it already is. This otherwise happens on whatever thread first loads a model,
+/// which can take a while with GPU backends.
+pub fn init_backend() {
+    LazyLock::force(&LLAMA_BACKEND);
+}
+
 #[derive(Debug)]
 pub enum LLMOutput {
     Token(String),
@@ -53,12 +104,102 @@ pub fn has_discrete_gpu() -> bool {
     false
 }
 
+/// Scheduling priority of the worker thread, relative to the other threads of the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WorkerPriority {
+    #[default]
+    Normal,
+    /// Lets other threads, like a game's render thread, run first when the CPU is busy.
+    /// This makes generation slower, but can reduce stutter.
+    Low,
+}
+
+/// Lowers the OS scheduling priority of the calling thread. This is best effort, failures are only logged.
+/// Threads spawned from the calling thread afterwards (like llama.cpp's compute threads) inherit the priority on most platforms.
+fn set_current_thread_priority(priority: WorkerPriority) {
+    if priority == WorkerPriority::Normal {
+        return;
+    }
+
+    #[cfg(target_os = "linux")]
+    let failed = {
+        extern "C" {
+            fn nice(inc: std::ffi::c_int) -> std::ffi::c_int;
+            fn __errno_location() -> *mut std::ffi::c_int;
+        }
+        // on linux, nice values apply to single threads, not the entire process.
+        // -1 is also a valid new nice value, so errno tells if it failed, as described in nice(2).
+        unsafe {
+            *__errno_location() = 0;
+            nice(10) == -1 && *__errno_location() != 0
+        }
+    };
+
+    #[cfg(target_os = "macos")]
+    let failed = {
+        extern "C" {
+            fn pthread_set_qos_class_self_np(
+                qos_class: std::ffi::c_uint,
+                relative_priority: std::ffi::c_int,
+            ) -> std::ffi::c_int;
+        }
+        const QOS_CLASS_UTILITY: std::ffi::c_uint = 0x11;
+        unsafe { pthread_set_qos_class_self_np(QOS_CLASS_UTILITY, 0) != 0 }
+    };
+
+    #[cfg(target_os = "windows")]
+    let failed = {
+        extern "system" {
+            fn GetCurrentThread() -> *mut std::ffi::c_void;
+            fn SetThreadPriority(thread: *mut std::ffi::c_void, priority: std::ffi::c_int) -> i32;
+        }
+        const THREAD_PRIORITY_BELOW_NORMAL: std::ffi::c_int = -1;
+        unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) == 0 }
+    };
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    let failed = true;
+
+    if failed {
+        warn!(?priority, "Could not change worker thread priority");
+    } else {
+        debug!(?priority, "Changed worker thread priority");
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum LoadModelError {
     #[error("Model not found: {0}")]
     ModelNotFound(String),
     #[error("Invalid or unsupported GGUF model: {0}")]
     InvalidModel(String),
+    #[error("Not a GGUF model file: {0}. Check that the path points at a .gguf file, and that it finished downloading")]
+    NotAGGUF(String),
+    #[error("Model file {path} uses GGUF version {version}, but only versions 2 and 3 are supported. Try a newer conversion of the model")]
+    UnsupportedGGUFVersion { path: String, version: u32 },
+}
+
+/// The GGUF versions llama.cpp can load.
+const SUPPORTED_GGUF_VERSIONS: std::ops::RangeInclusive<u32> = 2..=3;
+
+/// Checks the magic bytes and version at the start of a model file, which is much faster than loading it,
+/// and gives a clearer error for files that aren't GGUF at all, like a `.bin` file or a partial download.
+pub fn check_gguf_header(model_path: &str) -> Result<(), LoadModelError> {
+    use std::io::Read;
+
+    let mut header = [0u8; 8];
+    let read = std::fs::File::open(model_path).and_then(|mut file| file.read_exact(&mut header));
+    if read.is_err() || &header[..4] != b"GGUF" {
+        return Err(LoadModelError::NotAGGUF(model_path.into()));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if !SUPPORTED_GGUF_VERSIONS.contains(&version) {
+        return Err(LoadModelError::UnsupportedGGUFVersion {
+            path: model_path.into(),
+            version,
+        });
+    }
+    Ok(())
 }
 
 #[tracing::instrument(level = "info")]
@@ -71,6 +212,10 @@ pub fn get_model(
         error!(error = %e, "Model file not found");
         return Err(e);
     }
+    if let Err(e) = check_gguf_header(model_path) {
+        error!(error = %e, "Model file is not a valid GGUF file");
+        return Err(e);
+    }
 
     // TODO: `LlamaModelParams` uses all devices by default. Set it to an empty list once an upstream device API is available.
     let use_gpu = use_gpu_if_available && has_discrete_gpu();
@@ -95,6 +240,97 @@ pub fn get_model(
     Ok(Arc::new(model))
 }
 
+/// Whether the model asks for a BOS token at the start of the text, according to its `tokenizer.ggml.add_bos_token` metadata.
+/// Models without that metadata are assumed not to.
+pub fn model_adds_bos(model: &LlamaModel) -> bool {
+    model
+        .meta_val_str("tokenizer.ggml.add_bos_token")
+        .is_ok_and(|add_bos| add_bos == "true")
+}
+
+/// Whether the model has an end-of-generation token. Base models sometimes don't, and then a response only ends
+/// at a stop token, a time limit, or when the context fills up.
+pub fn has_eog_token(model: &LlamaModel) -> bool {
+    model.is_eog_token(model.token_eos())
+}
+
+/// The text of the model's control tokens, like `<|im_end|>`. These only have meaning to the chat template,
+/// so if their text shows up in a response, it is an artifact, see `chat::ChatParams::strip_strings`.
+pub fn special_token_strings(model: &LlamaModel) -> Vec<String> {
+    (0..model.n_vocab())
+        .map(LlamaToken::new)
+        .filter(|&token| model.token_attr(token).contains(LlamaTokenAttr::Control))
+        .filter_map(|token| {
+            model
+                .token_to_str_with_size(token, MAX_TOKEN_STR_LEN, Special::Tokenize)
+                .ok()
+        })
+        .filter(|text| !text.is_empty())
+        .collect()
+}
+
+/// The parts of a model that the worker and the chat loop use to turn text into tokens and back.
+/// Besides `LlamaModel`, `mock::MockModel` implements it, so they can be tested without a model file.
+pub trait Tokenizer {
+    fn tokenize(
+        &self,
+        text: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, llama_cpp_2::StringToTokenError>;
+    /// The text of the token, with special tokens written out. Bytes that aren't valid UTF-8 become "�".
+    fn token_text(&self, token: LlamaToken) -> String;
+    fn is_eog(&self, token: LlamaToken) -> bool;
+    fn n_vocab(&self) -> i32;
+}
+
+impl Tokenizer for LlamaModel {
+    fn tokenize(
+        &self,
+        text: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, llama_cpp_2::StringToTokenError> {
+        self.str_to_token(text, add_bos)
+    }
+
+    fn token_text(&self, token: LlamaToken) -> String {
+        // fall back to "U+FFFD REPLACEMENT CHARACTER"
+        // when encountering bytes that aren't valid UTF-8
+        // wikipedia: "used to replace an unknown, unrecognised, or unrepresentable character"
+        self.token_to_str_with_size(token, MAX_TOKEN_STR_LEN, Special::Tokenize)
+            .unwrap_or("�".to_string())
+    }
+
+    fn is_eog(&self, token: LlamaToken) -> bool {
+        self.is_eog_token(token)
+    }
+
+    fn n_vocab(&self) -> i32 {
+        LlamaModel::n_vocab(self)
+    }
+}
+
+impl<T: Tokenizer + ?Sized> Tokenizer for Arc<T> {
+    fn tokenize(
+        &self,
+        text: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, llama_cpp_2::StringToTokenError> {
+        (**self).tokenize(text, add_bos)
+    }
+
+    fn token_text(&self, token: LlamaToken) -> String {
+        (**self).token_text(token)
+    }
+
+    fn is_eog(&self, token: LlamaToken) -> bool {
+        (**self).is_eog(token)
+    }
+
+    fn n_vocab(&self) -> i32 {
+        (**self).n_vocab()
+    }
+}
+
 #[allow(dead_code)]
 fn print_kv_cache(ctx: &mut LlamaContext) {
     let mut kv_cache_view = ctx.new_kv_cache_view(1);
@@ -104,29 +340,47 @@ fn print_kv_cache(ctx: &mut LlamaContext) {
     }
 }
 
+/// How fragmented the free space in the KV cache is, from 0.0 when it's all in one block, to near 1.0 when it's scattered
+/// in single cells. Context shifting leaves holes in the cache, which make decoding slower until it is defragmented.
+fn kv_cache_fragmentation(ctx: &LlamaContext) -> f32 {
+    let mut kv_cache_view = ctx.new_kv_cache_view(1);
+    kv_cache_view.update();
+    let n_free = kv_cache_view.n_cells() - kv_cache_view.used_cells();
+    if n_free <= 0 {
+        return 0.0;
+    }
+    1.0 - kv_cache_view.max_contiguous() as f32 / n_free as f32
+}
+
+/// Computes how many tokens context shifting discards: half of the tokens after the first `n_keep`.
+fn context_shift_n_discard(n_past: i32, n_keep: i32) -> i32 {
+    debug_assert!(0 <= n_keep && n_keep <= n_past);
+    (n_past - n_keep) / 2
+}
+
 /// Performs context window shifting by discarding old tokens and shifting remaining ones left.
 /// This prevents context overflow by removing older tokens when nearing context length limits.
 /// As implemented in <https://github.com/ggerganov/llama.cpp/blob/3b4f2e33e2cbfca621e623c4b92b88da57a8c2f4/examples/main/main.cpp#L528>
 ///
 /// # Arguments
 /// * `ctx` - LLaMA context to perform shifting on
-/// * `pos` - Current position in context window
+/// * `n_past` - Number of tokens currently in the context window
+/// * `n_keep` - Number of tokens at the start of the context window to never discard
 ///
 /// # Returns
-/// * `Ok(n_discard)` - Number of tokens discarded from start of context
-/// * `Err(WorkerError)` - If cache operations fail
+/// * `Ok(n_discard)` - Number of tokens discarded after the first `n_keep` tokens
+/// * `Err(KvCacheConversionError)` - If cache operations fail
 fn apply_context_shifting(
     ctx: &mut LlamaContext,
     n_past: i32,
+    n_keep: i32,
 ) -> Result<i32, llama_cpp_2::context::kv_cache::KvCacheConversionError> {
     warn!("Applying context shifting.");
-    let n_keep = 0;
-    let n_left = n_past - n_keep;
-    let n_discard = n_left / 2;
+    let n_discard = context_shift_n_discard(n_past, n_keep);
 
     debug_assert!(n_past == ctx.get_kv_cache_token_count());
 
-    // Delete the first `n_discard` tokens
+    // Delete the `n_discard` tokens following the first `n_keep`
     ctx.clear_kv_cache_seq(
         Some(0),
         Some(n_keep as u32),
@@ -150,6 +404,64 @@ fn apply_context_shifting(
     Ok(n_discard)
 }
 
+/// What the worker does when the LLM produces an end-of-generation (EOG) token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum EogBehavior {
+    /// End the response. This is what you want for regular chat.
+    #[default]
+    Stop,
+    /// Treat EOG as a soft boundary between parts of one response, e.g. for a monologue with several parts.
+    /// The `separator` is added to the response instead of the EOG token, and generation continues.
+    /// After `max_parts` parts, EOG ends the response like `Stop` does.
+    Continue { separator: String, max_parts: u32 },
+}
+
+/// Pauses and resumes generation from any thread, e.g. while the game is paused.
+/// Clones share the same state, so a frontend can keep one and give another to the worker with
+/// `LLMActorParamsBuilder::pause_gate`. The worker only stops between tokens, so nothing is lost.
+#[derive(Clone, Debug, Default)]
+pub struct PauseGate(Arc<(Mutex<bool>, std::sync::Condvar)>);
+
+impl PauseGate {
+    /// Makes the worker stop before generating its next token, until `resume` is called.
+    pub fn pause(&self) {
+        *self.paused() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused() = false;
+        self.0 .1.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused()
+    }
+
+    /// Blocks the calling thread for as long as the gate is paused, and returns how long that was.
+    fn wait_while_paused(&self) -> std::time::Duration {
+        let paused = self.paused();
+        if !*paused {
+            return std::time::Duration::ZERO;
+        }
+        debug!("Worker paused");
+        let waited = std::time::Instant::now();
+        let _paused = self
+            .0
+             .1
+            .wait_while(paused, |paused| *paused)
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        debug!("Worker resumed");
+        waited.elapsed()
+    }
+
+    fn paused(&self) -> std::sync::MutexGuard<'_, bool> {
+        self.0
+             .0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
 /// Parameters for configuring an LLM actor instance.
 ///
 /// This struct contains the configuration needed to create a new LLM actor,
@@ -160,6 +472,20 @@ fn apply_context_shifting(
 /// * `sampler_config` - Configuration for the token sampling strategy
 /// * `n_ctx` - Maximum context length in tokens
 /// * `stop_tokens` - List of strings that will cause token generation to stop when encountered
+/// * `use_embeddings` - Whether the context should compute embeddings rather than generate text
+/// * `priority` - Scheduling priority of the worker thread
+/// * `eog_behavior` - What to do when the LLM produces an end-of-generation token
+/// * `max_buffered_tokens` - Number of generated tokens that may wait for the consumer before the worker pauses generation
+/// * `ask_on_context_full` - Whether to send `WriteOutput::ContextFull` and wait for an `OverflowStrategy` when the context fills up, instead of shifting it right away
+/// * `negative_prompt` - Text to steer generation away from, with classifier-free guidance. `None` disables guidance
+/// * `cfg_scale` - How strongly to steer away from `negative_prompt`. 1.0 means no guidance, higher values steer harder
+/// * `max_response_duration` - Longest time to spend generating a single response, after which it ends with `FinishReason::TimeLimit`. `None` means no limit
+/// * `logit_processor_top_k` - Number of most likely tokens to send out with `WriteOutput::AdjustLogits` before sampling each token, so the consumer can adjust them. `None` disables it, which is much faster, since generation has to wait for the consumer at every token
+/// * `token_probabilities` - Whether to send the probability of each generated token along with it in `WriteOutput::Token`. This costs a softmax over the whole vocabulary per token
+/// * `auto_defrag_threshold` - Fragmentation of the KV cache, between 0.0 and 1.0, above which it is defragmented after a context shift. `None` never defragments automatically
+/// * `embedding_add_bos` - Whether to start the text of each embedding with the BOS token, which changes the embeddings. `None` follows the model's `tokenizer.ggml.add_bos_token` metadata. Chat text never gets a BOS token from the worker
+/// * `unsafe_skip_inference_lock` - Decodes without taking the model's inference lock. Only safe if no other worker uses the same model at the same time, otherwise llama.cpp can segfault
+/// * `pause_gate` - Lets the frontend pause generation between tokens, and resume it later, see `PauseGate`
 #[derive(Clone)]
 pub struct LLMActorParams {
     pub model: Arc<LlamaModel>,
@@ -167,11 +493,209 @@ pub struct LLMActorParams {
     pub n_ctx: u32,
     pub stop_tokens: Vec<String>,
     pub use_embeddings: bool,
+    pub priority: WorkerPriority,
+    pub eog_behavior: EogBehavior,
+    pub max_buffered_tokens: usize,
+    pub ask_on_context_full: bool,
+    pub negative_prompt: Option<String>,
+    pub cfg_scale: f32,
+    pub max_response_duration: Option<std::time::Duration>,
+    pub logit_processor_top_k: Option<usize>,
+    pub token_probabilities: bool,
+    pub auto_defrag_threshold: Option<f32>,
+    pub embedding_add_bos: Option<bool>,
+    pub unsafe_skip_inference_lock: bool,
+    pub pause_gate: PauseGate,
+}
+
+impl LLMActorParams {
+    /// Creates a builder for `LLMActorParams`, where everything except the model has a default.
+    ///
+    /// ```ignore
+    /// let params = LLMActorParams::builder().model(model).n_ctx(4096).build()?;
+    /// ```
+    pub fn builder() -> LLMActorParamsBuilder {
+        LLMActorParamsBuilder::default()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuildParamsError {
+    #[error("A model is required to build LLMActorParams")]
+    MissingModel,
+}
+
+/// Builder for `LLMActorParams`. See `LLMActorParams::builder`.
+#[derive(Clone)]
+pub struct LLMActorParamsBuilder {
+    model: Option<Arc<LlamaModel>>,
+    sampler_config: SamplerConfig,
+    n_ctx: u32,
+    stop_tokens: Vec<String>,
+    use_embeddings: bool,
+    priority: WorkerPriority,
+    eog_behavior: EogBehavior,
+    max_buffered_tokens: usize,
+    ask_on_context_full: bool,
+    negative_prompt: Option<String>,
+    cfg_scale: f32,
+    max_response_duration: Option<std::time::Duration>,
+    logit_processor_top_k: Option<usize>,
+    token_probabilities: bool,
+    auto_defrag_threshold: Option<f32>,
+    embedding_add_bos: Option<bool>,
+    unsafe_skip_inference_lock: bool,
+    pause_gate: PauseGate,
+}
+
+impl Default for LLMActorParamsBuilder {
+    fn default() -> Self {
+        Self {
+            model: None,
+            sampler_config: SamplerConfig::default(),
+            n_ctx: 4096,
+            stop_tokens: vec![],
+            use_embeddings: false,
+            priority: WorkerPriority::default(),
+            eog_behavior: EogBehavior::default(),
+            max_buffered_tokens: DEFAULT_MAX_BUFFERED_TOKENS,
+            ask_on_context_full: false,
+            negative_prompt: None,
+            cfg_scale: 1.5,
+            max_response_duration: None,
+            logit_processor_top_k: None,
+            token_probabilities: false,
+            auto_defrag_threshold: None,
+            embedding_add_bos: None,
+            unsafe_skip_inference_lock: false,
+            pause_gate: PauseGate::default(),
+        }
+    }
+}
+
+impl LLMActorParamsBuilder {
+    pub fn model(mut self, model: Arc<LlamaModel>) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn sampler_config(mut self, sampler_config: SamplerConfig) -> Self {
+        self.sampler_config = sampler_config;
+        self
+    }
+
+    pub fn n_ctx(mut self, n_ctx: u32) -> Self {
+        self.n_ctx = n_ctx;
+        self
+    }
+
+    pub fn stop_tokens(mut self, stop_tokens: Vec<String>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    pub fn use_embeddings(mut self, use_embeddings: bool) -> Self {
+        self.use_embeddings = use_embeddings;
+        self
+    }
+
+    pub fn priority(mut self, priority: WorkerPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn eog_behavior(mut self, eog_behavior: EogBehavior) -> Self {
+        self.eog_behavior = eog_behavior;
+        self
+    }
+
+    pub fn max_buffered_tokens(mut self, max_buffered_tokens: usize) -> Self {
+        self.max_buffered_tokens = max_buffered_tokens;
+        self
+    }
+
+    pub fn ask_on_context_full(mut self, ask_on_context_full: bool) -> Self {
+        self.ask_on_context_full = ask_on_context_full;
+        self
+    }
+
+    pub fn negative_prompt(mut self, negative_prompt: Option<String>) -> Self {
+        self.negative_prompt = negative_prompt;
+        self
+    }
+
+    pub fn cfg_scale(mut self, cfg_scale: f32) -> Self {
+        self.cfg_scale = cfg_scale;
+        self
+    }
+
+    pub fn max_response_duration(
+        mut self,
+        max_response_duration: Option<std::time::Duration>,
+    ) -> Self {
+        self.max_response_duration = max_response_duration;
+        self
+    }
+
+    pub fn logit_processor_top_k(mut self, logit_processor_top_k: Option<usize>) -> Self {
+        self.logit_processor_top_k = logit_processor_top_k;
+        self
+    }
+
+    pub fn token_probabilities(mut self, token_probabilities: bool) -> Self {
+        self.token_probabilities = token_probabilities;
+        self
+    }
+
+    pub fn auto_defrag_threshold(mut self, auto_defrag_threshold: Option<f32>) -> Self {
+        self.auto_defrag_threshold = auto_defrag_threshold;
+        self
+    }
+
+    pub fn embedding_add_bos(mut self, embedding_add_bos: Option<bool>) -> Self {
+        self.embedding_add_bos = embedding_add_bos;
+        self
+    }
+
+    pub fn unsafe_skip_inference_lock(mut self, unsafe_skip_inference_lock: bool) -> Self {
+        self.unsafe_skip_inference_lock = unsafe_skip_inference_lock;
+        self
+    }
+
+    pub fn pause_gate(mut self, pause_gate: PauseGate) -> Self {
+        self.pause_gate = pause_gate;
+        self
+    }
+
+    pub fn build(self) -> Result<LLMActorParams, BuildParamsError> {
+        Ok(LLMActorParams {
+            model: self.model.ok_or(BuildParamsError::MissingModel)?,
+            sampler_config: self.sampler_config,
+            n_ctx: self.n_ctx,
+            stop_tokens: self.stop_tokens,
+            use_embeddings: self.use_embeddings,
+            priority: self.priority,
+            eog_behavior: self.eog_behavior,
+            max_buffered_tokens: self.max_buffered_tokens,
+            ask_on_context_full: self.ask_on_context_full,
+            negative_prompt: self.negative_prompt,
+            cfg_scale: self.cfg_scale,
+            max_response_duration: self.max_response_duration,
+            logit_processor_top_k: self.logit_processor_top_k,
+            token_probabilities: self.token_probabilities,
+            auto_defrag_threshold: self.auto_defrag_threshold,
+            embedding_add_bos: self.embedding_add_bos,
+            unsafe_skip_inference_lock: self.unsafe_skip_inference_lock,
+            pause_gate: self.pause_gate,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct LLMActorHandle {
     message_tx: std::sync::mpsc::Sender<WorkerMsg>,
+    max_buffered_tokens: usize,
+    n_ctx: u32,
 }
 
 impl LLMActorHandle {
@@ -181,14 +705,19 @@ impl LLMActorHandle {
 
         let (message_tx, message_rx) = std::sync::mpsc::channel();
         let (init_tx, init_rx) = oneshot::channel();
+        let max_buffered_tokens = params.max_buffered_tokens.max(1);
 
         std::thread::spawn(move || completion_worker_actor(message_rx, init_tx, params));
 
         debug!("Waiting for worker initialization");
         let result = match init_rx.await {
-            Ok(Ok(())) => {
+            Ok(Ok(n_ctx)) => {
                 info!("LLM actor initialized successfully");
-                Ok(Self { message_tx })
+                Ok(Self {
+                    message_tx,
+                    max_buffered_tokens,
+                    n_ctx,
+                })
             }
             Ok(Err(e)) => {
                 error!(error = ?e, "LLM actor initialization failed");
@@ -203,6 +732,12 @@ impl LLMActorHandle {
         result
     }
 
+    /// How many tokens fit in the context of the worker. This can be less than `LLMActorParams::n_ctx`,
+    /// as it is capped at the context length the model was trained with.
+    pub fn n_ctx(&self) -> u32 {
+        self.n_ctx
+    }
+
     #[tracing::instrument(level = "debug", skip(self))]
     pub async fn reset_context(&self) -> Result<(), oneshot::error::RecvError> {
         debug!("Resetting context");
@@ -217,6 +752,76 @@ impl LLMActorHandle {
         result
     }
 
+    /// Keeps only the first `n_tokens` tokens of the context, and forgets the rest.
+    /// This is the building block for undoing or regenerating parts of a conversation.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn truncate_to(
+        &self,
+        n_tokens: u32,
+    ) -> Result<Result<(), TruncateError>, oneshot::error::RecvError> {
+        debug!("Truncating context");
+        let (respond_to, response) = oneshot::channel();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::TruncateTo(n_tokens, respond_to));
+        let result = response.await;
+        match &result {
+            Ok(Ok(())) => debug!("Context truncated to {n_tokens} tokens"),
+            Ok(Err(e)) => error!(error = ?e, "Failed to truncate context"),
+            Err(_) => error!("Worker died while truncating context"),
+        }
+        result
+    }
+
+    /// Remembers the current point in the conversation, to go back to it later with `rollback`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn checkpoint(&self) -> Result<WorkerCheckpoint, oneshot::error::RecvError> {
+        let (respond_to, response) = oneshot::channel();
+        let _ = self.message_tx.send(WorkerMsg::Checkpoint(respond_to));
+        response.await
+    }
+
+    /// Forgets everything that was read or written after the checkpoint, keeping what came before it.
+    /// Returns false, and changes nothing, if the context was shifted or reset since the checkpoint.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn rollback(
+        &self,
+        checkpoint: WorkerCheckpoint,
+    ) -> Result<bool, oneshot::error::RecvError> {
+        let (respond_to, response) = oneshot::channel();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::Rollback(checkpoint, respond_to));
+        let result = response.await;
+        if let Ok(false) = result {
+            debug!("Could not roll back, the context changed since the checkpoint");
+        }
+        result
+    }
+
+    /// Replaces the sampler configuration, for the responses generated after this.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn set_sampler_config(
+        &self,
+        sampler_config: SamplerConfig,
+    ) -> Result<(), oneshot::error::RecvError> {
+        debug!("Setting sampler config");
+        let (respond_to, response) = oneshot::channel();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::SetSamplerConfig(sampler_config, respond_to));
+        response.await
+    }
+
+    /// Defragments the KV cache, which can make decoding faster after many context shifts.
+    /// Returns how fragmented the cache was before, see `auto_defrag_threshold`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn defragment(&self) -> Result<f32, oneshot::error::RecvError> {
+        let (respond_to, response) = oneshot::channel();
+        let _ = self.message_tx.send(WorkerMsg::Defragment(respond_to));
+        response.await
+    }
+
     #[tracing::instrument(level = "debug", skip(self), fields(text_length = text.len()))]
     pub async fn read(
         &self,
@@ -237,10 +842,38 @@ impl LLMActorHandle {
         result
     }
 
+    /// Returns how likely each of the tokens is to come next, given what has been read so far, without generating anything.
+    /// Useful for classifying with the LLM, by comparing the probabilities of the possible answers.
+    pub async fn next_token_probabilities(
+        &self,
+        tokens: Vec<i32>,
+    ) -> Result<Vec<f32>, oneshot::error::RecvError> {
+        let (respond_to, response) = oneshot::channel();
+        let tokens = tokens.into_iter().map(LlamaToken::new).collect();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::NextTokenProbabilities(tokens, respond_to));
+        response.await
+    }
+
+    /// Reads tokens into the context as they are, without tokenizing or adding a BOS token.
+    /// Fails with `ReadError::InvalidToken` if any of them are outside the model's vocabulary.
+    pub async fn read_tokens(
+        &self,
+        tokens: Vec<i32>,
+    ) -> Result<Result<(), ReadError>, oneshot::error::RecvError> {
+        let (respond_to, response_channel) = oneshot::channel();
+        let tokens = tokens.into_iter().map(LlamaToken::new).collect();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::ReadTokens(tokens, respond_to));
+        response_channel.await
+    }
+
     pub async fn write_until_done(
         &self,
     ) -> tokio_stream::wrappers::ReceiverStream<Result<WriteOutput, WriteError>> {
-        let (respond_to, response_channel) = mpsc::channel(CHANNEL_SIZE);
+        let (respond_to, response_channel) = mpsc::channel(self.max_buffered_tokens);
         let _ = self.message_tx.send(WorkerMsg::WriteUntilDone(respond_to));
         response_channel.into()
     }
@@ -257,13 +890,27 @@ impl LLMActorHandle {
         &self,
         text: String,
     ) -> tokio_stream::wrappers::ReceiverStream<Result<WriteOutput, GenerateResponseError>> {
-        let (respond_to, response_channel) = mpsc::channel(CHANNEL_SIZE);
+        let (respond_to, response_channel) = mpsc::channel(self.max_buffered_tokens);
         let _ = self
             .message_tx
             .send(WorkerMsg::GenerateResponse(text, respond_to));
         response_channel.into()
     }
 
+    /// Reads the text once, and then generates `n` independent responses to it, each with a different seed.
+    /// Between responses, the context is truncated back to the end of the text rather than read again.
+    pub async fn generate_responses(
+        &self,
+        text: String,
+        n: usize,
+    ) -> Result<Result<Responses, GenerateResponseError>, oneshot::error::RecvError> {
+        let (respond_to, response_channel) = oneshot::channel();
+        let _ = self
+            .message_tx
+            .send(WorkerMsg::GenerateResponses(text, n, respond_to));
+        response_channel.await
+    }
+
     pub async fn generate_embedding(
         &self,
         text: String,
@@ -276,27 +923,48 @@ impl LLMActorHandle {
     }
 }
 
+/// Starts workers: `LLMActorParams` start ones that run the model, and `mock::MockWorker` ones that need no model file.
+/// The chat loop starts its workers through this, so it can be tested without a model too.
+pub(crate) trait StartWorker {
+    /// The tokenizer of the model the workers use.
+    fn tokenizer(&self) -> &dyn Tokenizer;
+
+    /// Starts a worker with the sampler settings, and a context of `n_ctx` tokens, or the usual length if `None`.
+    async fn start(
+        &self,
+        n_ctx: Option<u32>,
+        sampler_config: SamplerConfig,
+    ) -> Result<LLMActorHandle, InitWorkerError>;
+}
+
+impl StartWorker for LLMActorParams {
+    fn tokenizer(&self) -> &dyn Tokenizer {
+        &*self.model
+    }
+
+    async fn start(
+        &self,
+        n_ctx: Option<u32>,
+        sampler_config: SamplerConfig,
+    ) -> Result<LLMActorHandle, InitWorkerError> {
+        let mut params = self.clone();
+        params.n_ctx = n_ctx.unwrap_or(params.n_ctx);
+        params.sampler_config = sampler_config;
+        LLMActorHandle::new(params).await
+    }
+}
+
 fn completion_worker_actor(
     message_rx: std::sync::mpsc::Receiver<WorkerMsg>,
-    init_tx: oneshot::Sender<Result<(), InitWorkerError>>,
+    init_tx: oneshot::Sender<Result<u32, InitWorkerError>>,
     params: LLMActorParams,
 ) {
+    set_current_thread_priority(params.priority);
+
     match WorkerState::new(&params) {
-        Ok(mut state) => {
-            let _ = init_tx.send(Ok(())); // no way to recover from this send error
-
-            // listen for messages forever
-            while let Ok(msg) = message_rx.recv() {
-                match handle_msg(state, msg) {
-                    Ok(newstate) => {
-                        state = newstate;
-                    }
-                    Err(()) => {
-                        error!("Failed handling message");
-                        return; // we died.
-                    }
-                }
-            } // message queue dropped. we died.
+        Ok(state) => {
+            let _ = init_tx.send(Ok(state.inference.n_ctx())); // no way to recover from this send error
+            run_worker(state, message_rx);
         }
         Err(initerr) => {
             error!("Init WorkerState failure.");
@@ -306,6 +974,25 @@ fn completion_worker_actor(
     }
 }
 
+/// Handles messages until the handle is dropped, or a message kills the worker.
+fn run_worker<I: Inference>(
+    mut state: WorkerState<I>,
+    message_rx: std::sync::mpsc::Receiver<WorkerMsg>,
+) {
+    // listen for messages forever
+    while let Ok(msg) = message_rx.recv() {
+        match handle_msg(state, msg) {
+            Ok(newstate) => {
+                state = newstate;
+            }
+            Err(()) => {
+                error!("Failed handling message");
+                return; // we died.
+            }
+        }
+    } // message queue dropped. we died.
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InitWorkerError {
     #[error("Could not determine number of threads available: {0}")]
@@ -316,25 +1003,278 @@ pub enum InitWorkerError {
 
     #[error("Got no response after initializing worker.")]
     NoResponse,
-}
 
-#[derive(Debug)]
-struct WorkerState<'a> {
-    n_past: i32,
-    ctx: LlamaContext<'a>,
-    sampler: LlamaSampler,
-    big_batch: LlamaBatch,
-    small_batch: LlamaBatch,
-    stop_tokens: Vec<String>,
+    #[error("Could not read the negative prompt: {0}")]
+    NegativePromptError(#[from] ReadError),
+
+    #[error("penalty_last_n must be -1 for the whole context, or a number of tokens, got {0}")]
+    InvalidPenaltyLastN(i32),
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum WorkerError {
-    #[error("Could not determine number of threads available: {0}")]
-    ThreadCountError(#[from] std::io::Error),
+/// The parts of an inference context that the worker uses: decoding tokens, their logits, and the KV cache.
+/// Besides `LlamaInference`, `mock::MockInference` implements it, so the worker can be tested without a model file.
+trait Inference {
+    fn tokenizer(&self) -> &dyn Tokenizer;
 
-    #[error("Could not create context: {0}")]
-    CreateContextError(#[from] llama_cpp_2::LlamaContextLoadError),
+    /// How many tokens fit in the context.
+    fn n_ctx(&self) -> u32;
+
+    /// Decodes the tokens at the positions from `n_past` on, and keeps the logits of the last one.
+    fn decode<E>(&mut self, tokens: &[LlamaToken], n_past: i32) -> Result<(), E>
+    where
+        E: From<llama_cpp_2::llama_batch::BatchAddError> + From<llama_cpp_2::DecodeError>;
+
+    /// The logits of the last decoded token, one for every token in the vocabulary.
+    fn logits(&self) -> &[f32];
+
+    fn clear_kv_cache(&mut self);
+
+    /// Forgets every token from position `n_tokens` on.
+    fn truncate_kv_cache(
+        &mut self,
+        n_tokens: u32,
+    ) -> Result<(), llama_cpp_2::context::kv_cache::KvCacheConversionError>;
+
+    /// Forgets half of the tokens after the first `n_keep`, see `apply_context_shifting`, and returns how many.
+    fn shift_kv_cache(
+        &mut self,
+        n_past: i32,
+        n_keep: i32,
+    ) -> Result<i32, llama_cpp_2::context::kv_cache::KvCacheConversionError>;
+
+    /// See `kv_cache_fragmentation`.
+    fn kv_cache_fragmentation(&self) -> f32;
+
+    fn defragment_kv_cache(&mut self);
+
+    /// The embedding of what was decoded, for contexts made for embeddings.
+    fn embedding(&self) -> Result<Vec<f32>, llama_cpp_2::EmbeddingsError>;
+
+    fn make_sampler(&self, sampler_config: SamplerConfig) -> Sampler;
+}
+
+/// A llama.cpp context, with the batches it decodes and the inference lock of its model.
+#[derive(Debug)]
+struct LlamaInference<'a> {
+    ctx: LlamaContext<'a>,
+    big_batch: LlamaBatch,
+    small_batch: LlamaBatch,
+    logits_index: i32,
+    /// Shared with every other worker that uses the model, or `None` with `unsafe_skip_inference_lock`.
+    inference_lock: Option<Arc<Mutex<()>>>,
+}
+
+impl Inference for LlamaInference<'_> {
+    fn tokenizer(&self) -> &dyn Tokenizer {
+        self.ctx.model
+    }
+
+    fn n_ctx(&self) -> u32 {
+        self.ctx.n_ctx()
+    }
+
+    fn decode<E>(&mut self, tokens: &[LlamaToken], n_past: i32) -> Result<(), E>
+    where
+        E: From<llama_cpp_2::llama_batch::BatchAddError> + From<llama_cpp_2::DecodeError>,
+    {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        // a batch of one while writing, and a big one for reading
+        let batch = if tokens.len() == 1 {
+            &mut self.small_batch
+        } else {
+            &mut self.big_batch
+        };
+        batch.clear();
+        let seq_ids = &[0];
+        for (i, token) in (0..).zip(tokens.iter()) {
+            // Only compute logits for the last token to save computation
+            let output_logits = i == tokens.len() - 1;
+            batch.add(*token, n_past + i as i32, seq_ids, output_logits)?;
+        }
+        locked_decode(&mut self.ctx, batch, self.inference_lock.as_deref())?;
+        self.logits_index = tokens.len() as i32 - 1;
+        Ok(())
+    }
+
+    fn logits(&self) -> &[f32] {
+        self.ctx.get_logits_ith(self.logits_index)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.ctx.clear_kv_cache();
+    }
+
+    fn truncate_kv_cache(
+        &mut self,
+        n_tokens: u32,
+    ) -> Result<(), llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        self.ctx.clear_kv_cache_seq(Some(0), Some(n_tokens), None)?;
+        Ok(())
+    }
+
+    fn shift_kv_cache(
+        &mut self,
+        n_past: i32,
+        n_keep: i32,
+    ) -> Result<i32, llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        apply_context_shifting(&mut self.ctx, n_past, n_keep)
+    }
+
+    fn kv_cache_fragmentation(&self) -> f32 {
+        kv_cache_fragmentation(&self.ctx)
+    }
+
+    fn defragment_kv_cache(&mut self) {
+        self.ctx.kv_cache_defrag();
+        self.ctx.kv_cache_update();
+    }
+
+    fn embedding(&self) -> Result<Vec<f32>, llama_cpp_2::EmbeddingsError> {
+        self.ctx.embeddings_seq_ith(0).map(|embd| embd.to_vec())
+    }
+
+    fn make_sampler(&self, sampler_config: SamplerConfig) -> Sampler {
+        make_sampler(self.ctx.model, sampler_config, self.ctx.n_ctx())
+    }
+}
+
+#[derive(Debug)]
+struct WorkerState<'a, I = LlamaInference<'a>> {
+    n_past: i32,
+    n_context_shifts: u32,
+    /// Tokens forgotten by context shifts while reading, which haven't been reported in a response yet.
+    n_unreported_discarded: u32,
+    n_resets: u32,
+    inference: I,
+    sampler_config: SamplerConfig,
+    sampler: Sampler,
+    stop_tokens: Vec<String>,
+    eog_behavior: EogBehavior,
+    ask_on_context_full: bool,
+    max_response_duration: Option<std::time::Duration>,
+    logit_processor_top_k: Option<usize>,
+    token_probabilities: bool,
+    auto_defrag_threshold: Option<f32>,
+    add_bos: AddBos,
+    guidance: Option<GuidanceContext<'a>>,
+    pause_gate: PauseGate,
+}
+
+/// Converts logits to log-probabilities, so logits from different contexts can be compared.
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum_exp = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum_exp).collect()
+}
+
+/// The second context for classifier-free guidance. It reads the negative prompt first,
+/// and then mirrors everything the main context reads and writes, so the two only differ by the negative prompt.
+/// Sampling then pushes the main context's predictions away from this one's.
+#[derive(Debug)]
+struct GuidanceContext<'a> {
+    ctx: LlamaContext<'a>,
+    batch: LlamaBatch,
+    n_negative: i32,
+    n_past: i32,
+    logits_index: i32,
+    scale: f32,
+    inference_lock: Option<Arc<Mutex<()>>>,
+}
+
+impl<'a> GuidanceContext<'a> {
+    fn new(
+        model: &'a LlamaModel,
+        ctx_params: LlamaContextParams,
+        n_ctx: u32,
+        negative_prompt: &str,
+        scale: f32,
+        inference_lock: Option<Arc<Mutex<()>>>,
+    ) -> Result<Self, InitWorkerError> {
+        let negative_tokens = model
+            .str_to_token(negative_prompt, AddBos::Never)
+            .map_err(ReadError::from)?;
+        // room for the negative prompt on top of everything the main context holds
+        let n_ctx = n_ctx + negative_tokens.len() as u32;
+        let ctx = model.new_context(
+            &LLAMA_BACKEND,
+            ctx_params.with_n_ctx(std::num::NonZero::new(n_ctx)),
+        )?;
+        let mut guidance = Self {
+            batch: LlamaBatch::new(n_ctx as usize, 1),
+            ctx,
+            n_negative: 0,
+            n_past: 0,
+            logits_index: 0,
+            scale,
+            inference_lock,
+        };
+        guidance.read_tokens::<ReadError>(&negative_tokens)?;
+        guidance.n_negative = guidance.n_past;
+        Ok(guidance)
+    }
+
+    fn read_tokens<E>(&mut self, tokens: &[LlamaToken]) -> Result<(), E>
+    where
+        E: From<llama_cpp_2::llama_batch::BatchAddError> + From<llama_cpp_2::DecodeError>,
+    {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        self.batch.clear();
+        for (i, token) in (0..).zip(tokens.iter()) {
+            let output_logits = i == tokens.len() - 1;
+            self.batch
+                .add(*token, self.n_past + i as i32, &[0], output_logits)?;
+        }
+        locked_decode(
+            &mut self.ctx,
+            &mut self.batch,
+            self.inference_lock.as_deref(),
+        )?;
+        self.n_past += tokens.len() as i32;
+        self.logits_index = tokens.len() as i32 - 1;
+        Ok(())
+    }
+
+    /// Mirrors a context shift of the main context, keeping the negative prompt.
+    fn shift(&mut self) -> Result<(), llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        self.n_past -= apply_context_shifting(&mut self.ctx, self.n_past, self.n_negative)?;
+        Ok(())
+    }
+
+    /// Mirrors truncating the main context to `n_tokens`, keeping the negative prompt.
+    fn truncate_to(
+        &mut self,
+        n_tokens: u32,
+    ) -> Result<(), llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        let n_keep = self.n_negative + n_tokens as i32;
+        self.ctx
+            .clear_kv_cache_seq(Some(0), Some(n_keep as u32), None)?;
+        self.n_past = n_keep.min(self.n_past);
+        Ok(())
+    }
+
+    /// The guided log-probabilities: `negative + scale * (positive - negative)`, given the main context's logits.
+    fn logits(&self, positive: &[f32]) -> Vec<f32> {
+        let positive = log_softmax(positive);
+        let negative = log_softmax(self.ctx.get_logits_ith(self.logits_index));
+        positive
+            .iter()
+            .zip(&negative)
+            .map(|(pos, neg)| neg + self.scale * (pos - neg))
+            .collect()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WorkerError {
+    #[error("Could not determine number of threads available: {0}")]
+    ThreadCountError(#[from] std::io::Error),
+
+    #[error("Could not create context: {0}")]
+    CreateContextError(#[from] llama_cpp_2::LlamaContextLoadError),
 
     #[error("Could not initialize worker: {0}")]
     InitWorkerError(#[from] InitWorkerError),
@@ -350,11 +1290,6 @@ pub enum WorkerError {
 
     #[error("Could not send newly generated token out to the game engine.")]
     SendError, // this is actually a SendError<LLMOutput>, but that becomes recursive and weird
-
-    // #[error("Could not receive from channel: {0}")]
-    // RecvError(#[from] mpsc::error::RecvError),
-    #[error("Global Inference Lock was poisoned.")]
-    GILPoisonError, // this is actually a std::sync::PoisonError<std::sync::MutexGuard<'static, ()>>, but that doesn't implement Send, so we do this
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -381,9 +1316,16 @@ pub enum GenerateEmbeddingError {
 #[derive(Debug)]
 pub enum WorkerMsg {
     ReadString(String, oneshot::Sender<Result<(), ReadError>>),
+    ReadTokens(Vec<LlamaToken>, oneshot::Sender<Result<(), ReadError>>),
     WriteUntilDone(mpsc::Sender<Result<WriteOutput, WriteError>>),
     GetEmbedding(oneshot::Sender<Result<Vec<f32>, llama_cpp_2::EmbeddingsError>>),
     ResetContext(oneshot::Sender<()>),
+    TruncateTo(u32, oneshot::Sender<Result<(), TruncateError>>),
+    Checkpoint(oneshot::Sender<WorkerCheckpoint>),
+    Rollback(WorkerCheckpoint, oneshot::Sender<bool>),
+    SetSamplerConfig(SamplerConfig, oneshot::Sender<()>),
+    Defragment(oneshot::Sender<f32>),
+    NextTokenProbabilities(Vec<LlamaToken>, oneshot::Sender<Vec<f32>>),
     GenerateResponse(
         String,
         mpsc::Sender<Result<WriteOutput, GenerateResponseError>>,
@@ -392,38 +1334,112 @@ pub enum WorkerMsg {
         String,
         oneshot::Sender<Result<Vec<f32>, GenerateEmbeddingError>>,
     ),
+    GenerateResponses(
+        String,
+        usize,
+        oneshot::Sender<Result<Responses, GenerateResponseError>>,
+    ),
+}
+
+/// Several independent responses to the same text, from `LLMActorHandle::generate_responses`.
+///
+/// # Fields
+/// * `responses` - The responses. There may be fewer than requested, if the context filled up while generating them
+/// * `context_cleared` - Whether the context was cleared, because it filled up. If so, the conversation must be read again.
+///   Otherwise the context ends with the text, and none of the responses.
+#[derive(Debug, Default)]
+pub struct Responses {
+    pub responses: Vec<String>,
+    pub context_cleared: bool,
+}
+
+/// Sends generated output to the consumer. When the consumer has fallen `max_buffered_tokens` behind,
+/// e.g. because the game is paused, this blocks the worker until there is room again,
+/// instead of buffering an unbounded amount of tokens.
+fn send_blocking<T>(respond_to: &mpsc::Sender<T>, value: T) {
+    if respond_to.capacity() == 0 {
+        debug!("Token buffer is full, waiting for the consumer to catch up");
+    }
+    let _ = respond_to.blocking_send(value);
+}
+
+/// Where the worker was before handling a message, so it can return there if the message fails.
+/// Also see `LLMActorHandle::checkpoint`, for returning to a point in the conversation later.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerCheckpoint {
+    n_past: i32,
+    n_context_shifts: u32,
+    n_resets: u32,
+}
+
+impl WorkerCheckpoint {
+    /// How many tokens were in the context at the checkpoint.
+    pub fn n_past(&self) -> u32 {
+        self.n_past as u32
+    }
+}
+
+/// After a failed message, rolls the context back to the checkpoint so the worker can keep going.
+/// Fatal errors, and errors that can't be rolled back, kill the worker.
+fn recover<I: Inference>(
+    mut state: WorkerState<I>,
+    checkpoint: WorkerCheckpoint,
+    recoverable: bool,
+) -> Result<WorkerState<I>, ()> {
+    if recoverable && state.rollback(checkpoint) {
+        warn!("Recovered from error by discarding the failed turn.");
+        Ok(state)
+    } else {
+        Err(())
+    }
 }
 
-fn handle_msg(state: WorkerState, msg: WorkerMsg) -> Result<WorkerState, ()> {
-    // HACK
-    // this is needed because contexts referencing the same model are not thread safe
-    // if two contexts referencing the same model try to decode at the same time,
-    // then llama.cpp segfaults and everybody dies and i become sad
+fn handle_msg<I: Inference>(
+    mut state: WorkerState<I>,
+    msg: WorkerMsg,
+) -> Result<WorkerState<I>, ()> {
+    // decoding is serialized across workers that use the same model by `locked_decode`, unless the params opt out
     debug!("Worker handling message: {msg:?}");
-    let _inference_lock = GLOBAL_INFERENCE_LOCK.lock().expect("GIL mutex poisoned.");
+    let checkpoint = state.checkpoint();
 
     match msg {
         WorkerMsg::ReadString(text, respond_to) => match state.read_string(text) {
-            Ok(newstate) => {
+            Ok(()) => {
                 let _ = respond_to.send(Ok(()));
-                Ok(newstate)
+                Ok(state)
             }
             Err(e) => {
+                let recoverable = e.is_recoverable();
                 let _ = respond_to.send(Err(e));
-                Err(())
+                recover(state, checkpoint, recoverable)
             }
         },
-        WorkerMsg::WriteUntilDone(respond_to) => state
-            .write_until_done(|out| {
-                let _ = respond_to.blocking_send(Ok(out));
-            })
-            .map_err(|e| {
-                let _ = respond_to.blocking_send(Err(e.into()));
-                ()
-            }),
-        WorkerMsg::GetEmbedding(respond_to) => match state.ctx.embeddings_seq_ith(0) {
+        WorkerMsg::ReadTokens(tokens, respond_to) => match state.read_tokens(tokens) {
+            Ok(()) => {
+                let _ = respond_to.send(Ok(()));
+                Ok(state)
+            }
+            Err(e) => {
+                let recoverable = e.is_recoverable();
+                let _ = respond_to.send(Err(e));
+                recover(state, checkpoint, recoverable)
+            }
+        },
+        WorkerMsg::WriteUntilDone(respond_to) => {
+            match state.write_until_done(|out| {
+                send_blocking(&respond_to, Ok(out));
+            }) {
+                Ok(()) => Ok(state),
+                Err(e) => {
+                    let recoverable = e.is_recoverable();
+                    send_blocking(&respond_to, Err(e));
+                    recover(state, checkpoint, recoverable)
+                }
+            }
+        }
+        WorkerMsg::GetEmbedding(respond_to) => match state.inference.embedding() {
             Ok(embd) => {
-                let _ = respond_to.send(Ok(embd.to_vec()));
+                let _ = respond_to.send(Ok(embd));
                 Ok(state)
             }
             Err(e) => {
@@ -432,42 +1448,100 @@ fn handle_msg(state: WorkerState, msg: WorkerMsg) -> Result<WorkerState, ()> {
             }
         },
         WorkerMsg::ResetContext(respond_to) => {
-            let new_state = state.reset_context();
+            state.reset_context();
             let _ = respond_to.send(());
-            Ok(new_state)
+            Ok(state)
+        }
+        WorkerMsg::TruncateTo(n_tokens, respond_to) => {
+            let _ = respond_to.send(state.truncate_to(n_tokens));
+            Ok(state)
+        }
+        WorkerMsg::Checkpoint(respond_to) => {
+            let _ = respond_to.send(state.checkpoint());
+            Ok(state)
+        }
+        WorkerMsg::Rollback(checkpoint, respond_to) => {
+            let _ = respond_to.send(state.rollback(checkpoint));
+            Ok(state)
+        }
+        // takes effect from the next response, since the sampler is rebuilt for every response
+        WorkerMsg::SetSamplerConfig(sampler_config, respond_to) => {
+            state.sampler_config = sampler_config;
+            let _ = respond_to.send(());
+            Ok(state)
+        }
+        WorkerMsg::Defragment(respond_to) => {
+            let _ = respond_to.send(state.defragment());
+            Ok(state)
+        }
+        WorkerMsg::NextTokenProbabilities(tokens, respond_to) => {
+            let _ = respond_to.send(state.next_token_probabilities(&tokens));
+            Ok(state)
         }
         // read then write text until done
-        WorkerMsg::GenerateResponse(text, respond_to) => state
-            .read_string(text)
-            .map_err(|e| {
-                let _ = respond_to.blocking_send(Err(e.into()));
-                ()
-            })?
-            .write_until_done(|out| {
-                let _ = respond_to.blocking_send(Ok(out));
-            })
-            .map_err(|e| {
-                let _ = respond_to.blocking_send(Err(e.into()));
-                ()
-            }),
+        WorkerMsg::GenerateResponse(text, respond_to) => {
+            let result = state
+                .read_string(text)
+                .map_err(GenerateResponseError::from)
+                .and_then(|()| {
+                    state
+                        .write_until_done(|out| {
+                            send_blocking(&respond_to, Ok(out));
+                        })
+                        .map_err(GenerateResponseError::from)
+                });
+            match result {
+                Ok(()) => Ok(state),
+                Err(e) => {
+                    let recoverable = e.is_recoverable();
+                    send_blocking(&respond_to, Err(e));
+                    recover(state, checkpoint, recoverable)
+                }
+            }
+        }
+        // read string once, then write several responses to it
+        WorkerMsg::GenerateResponses(text, n, respond_to) => {
+            let result = state
+                .read_string(text)
+                .map_err(GenerateResponseError::from)
+                .and_then(|()| {
+                    state
+                        .write_responses(n)
+                        .map_err(GenerateResponseError::from)
+                });
+            match result {
+                Ok(responses) => {
+                    let _ = respond_to.send(Ok(responses));
+                    Ok(state)
+                }
+                Err(e) => {
+                    let recoverable = e.is_recoverable();
+                    let _ = respond_to.send(Err(e));
+                    recover(state, checkpoint, recoverable)
+                }
+            }
+        }
         // read string then retrieve embedding
         WorkerMsg::GenerateEmbedding(text, respond_to) => {
             // try reading the string
-            let state = match state.read_string(text) {
-                Ok(new_state) => new_state,
-                Err(e) => {
-                    // error and return early, moving respond_to only once
-                    let _ = respond_to.send(Err(e.into()));
-                    return Err(());
+            if let Err(e) = state.read_string(text) {
+                let recoverable = e.is_recoverable();
+                let _ = respond_to.send(Err(e.into()));
+                // embeddings always start from an empty context, so there is nothing to keep
+                if recoverable {
+                    state.reset_context();
+                    return Ok(state);
                 }
-            };
+                return Err(());
+            }
 
             // try getting embeddings
-            match state.ctx.embeddings_seq_ith(0) {
+            match state.inference.embedding() {
                 Ok(embd) => {
                     // success!
-                    let _ = respond_to.send(Ok(embd.to_vec()));
-                    Ok(state.reset_context())
+                    let _ = respond_to.send(Ok(embd));
+                    state.reset_context();
+                    Ok(state)
                 }
                 Err(e) => {
                     // :(
@@ -492,12 +1566,88 @@ pub enum ReadError {
 
     #[error("Could not apply context shifting: {0}")]
     ContextShiftError(#[from] llama_cpp_2::context::kv_cache::KvCacheConversionError),
+
+    #[error("Text of {n_tokens} tokens does not fit in the context of {n_ctx} tokens")]
+    ContextTooSmall { n_tokens: usize, n_ctx: u32 },
+
+    #[error("Token {token} is not in the vocabulary of {n_vocab} tokens")]
+    InvalidToken { token: i32, n_vocab: i32 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TruncateError {
+    #[error("Can't truncate to {n_tokens} tokens, the context only has {n_past} tokens")]
+    OutOfRange { n_tokens: u32, n_past: i32 },
+
+    #[error("Could not remove tokens from the context: {0}")]
+    KvCacheError(#[from] llama_cpp_2::context::kv_cache::KvCacheConversionError),
+}
+
+/// What to do when the context fills up while generating a response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowStrategy {
+    /// Forget the oldest part of the context, and keep generating.
+    #[default]
+    Shift,
+    /// End the response here. The chat then replaces its history with a summary, to make room.
+    Summarize,
+    /// End the response here.
+    Stop,
+}
+
+/// Why a response ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The LLM ended its turn.
+    Eog,
+    /// One of the stop tokens was generated. Holds the stop token that matched.
+    StopToken(String),
+    /// The context filled up, and the `OverflowStrategy` ended the response.
+    ContextFull,
+    /// Generating took longer than `max_response_duration`.
+    TimeLimit,
+    // no `MaxTokens` or `Cancelled` yet: responses can't be limited to a number of tokens, or cancelled
+}
+
+impl FinishReason {
+    /// The snake_case name, e.g. "stop_token", as shown to game code.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Eog => "eog",
+            FinishReason::StopToken(_) => "stop_token",
+            FinishReason::ContextFull => "context_full",
+            FinishReason::TimeLimit => "time_limit",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum WriteOutput {
-    Token(String),
-    Done(String),
+    /// A generated token, with how likely the LLM found it, between 0.0 and 1.0, if `token_probabilities` is set.
+    /// The probability is read before sampling, so it doesn't include temperature, top-k and the like.
+    Token(String, Option<f32>),
+    /// The full response, why it ended, and the ids of every token that was sampled for it. The ids include
+    /// special tokens that the text may hide, like the end-of-generation token that ended the response.
+    Done(String, FinishReason, Vec<i32>),
+    /// The context is full. Generation pauses until an `OverflowStrategy` is sent back.
+    /// Only sent when `ask_on_context_full` is set.
+    ContextFull(oneshot::Sender<OverflowStrategy>),
+    /// The context was shifted to make room, forgetting this many of the oldest tokens.
+    /// Shifts while reading the prompt are reported when the response starts.
+    ContextShifted(u32),
+    /// The most likely next tokens, before one of them is sampled. Generation pauses until a list
+    /// of `(token, bias)` adjustments is sent back, which are added to the logits of those tokens.
+    /// Only sent when `logit_processor_top_k` is set.
+    AdjustLogits(Vec<TokenCandidate>, oneshot::Sender<Vec<(i32, f32)>>),
+}
+
+/// A possible next token, as sent with `WriteOutput::AdjustLogits`.
+#[derive(Debug, Clone)]
+pub struct TokenCandidate {
+    /// The token id, for sending adjustments back.
+    pub token: i32,
+    pub text: String,
+    pub logit: f32,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -515,21 +1665,143 @@ pub enum WriteError {
     SendError,
 }
 
+/// Decoding fails with these when the batch doesn't fit in the context, which leaves the context intact.
+fn decode_error_is_recoverable(err: &llama_cpp_2::DecodeError) -> bool {
+    matches!(
+        err,
+        llama_cpp_2::DecodeError::NoKvCacheSlot | llama_cpp_2::DecodeError::NTokensZero
+    )
+}
+
+/// llama.cpp returns -2 from decoding when it can't allocate the compute buffers, i.e. when the GPU or RAM is full.
+fn decode_error_is_out_of_memory(err: &llama_cpp_2::DecodeError) -> bool {
+    matches!(err, llama_cpp_2::DecodeError::Unknown(-2))
+}
+
+impl ReadError {
+    /// Whether the worker can keep going after this error, by discarding the failed read.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ReadError::TokenizerError(_)
+            | ReadError::BatchAddError(_)
+            | ReadError::ContextTooSmall { .. }
+            | ReadError::InvalidToken { .. } => true,
+            ReadError::DecodeError(e) => decode_error_is_recoverable(e),
+            ReadError::ContextShiftError(_) => false,
+        }
+    }
+
+    /// Whether decoding failed because there wasn't enough memory.
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self, ReadError::DecodeError(e) if decode_error_is_out_of_memory(e))
+    }
+}
+
+impl WriteError {
+    /// Whether the worker can keep going after this error, by discarding the failed response.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            WriteError::BatchAddError(_) => true,
+            WriteError::DecodeError(e) => decode_error_is_recoverable(e),
+            WriteError::ContextShiftError(_) | WriteError::SendError => false,
+        }
+    }
+
+    /// Whether decoding failed because there wasn't enough memory.
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self, WriteError::DecodeError(e) if decode_error_is_out_of_memory(e))
+    }
+}
+
+impl GenerateResponseError {
+    /// Whether the worker survived this error. If so, the failed turn was discarded,
+    /// and the worker is ready for the next message.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            GenerateResponseError::ReadError(e) => e.is_recoverable(),
+            GenerateResponseError::WriteError(e) => e.is_recoverable(),
+        }
+    }
+
+    /// Whether the GPU or RAM ran out of memory. Lowering the context length or the number of GPU layers may help.
+    pub fn is_out_of_memory(&self) -> bool {
+        match self {
+            GenerateResponseError::ReadError(e) => e.is_out_of_memory(),
+            GenerateResponseError::WriteError(e) => e.is_out_of_memory(),
+        }
+    }
+}
+
+impl GenerateEmbeddingError {
+    /// Whether the GPU or RAM ran out of memory. Lowering the context length or the number of GPU layers may help.
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self, GenerateEmbeddingError::ReadError(e) if e.is_out_of_memory())
+    }
+}
+
 impl<'a> WorkerState<'a> {
-    fn new(params: &LLMActorParams) -> Result<WorkerState, InitWorkerError> {
+    fn new(params: &'a LLMActorParams) -> Result<Self, InitWorkerError> {
         info!("Initializing WorkerState");
+        if params.sampler_config.penalty_last_n < -1 {
+            return Err(InitWorkerError::InvalidPenaltyLastN(
+                params.sampler_config.penalty_last_n,
+            ));
+        }
         // Set up context parameters using available parallelism
-        let ctx = {
-            let n_threads = std::thread::available_parallelism()?.get() as i32;
-            let n_ctx = std::cmp::min(params.n_ctx, params.model.n_ctx_train());
-            let ctx_params = LlamaContextParams::default()
-                .with_n_ctx(std::num::NonZero::new(n_ctx))
-                .with_n_threads(n_threads)
-                .with_n_threads_batch(n_threads)
-                .with_embeddings(params.use_embeddings);
-
-            // Create inference context and sampler
-            params.model.new_context(&LLAMA_BACKEND, ctx_params)?
+        let n_threads = std::thread::available_parallelism()?.get() as i32;
+        let n_ctx = std::cmp::min(params.n_ctx, params.model.n_ctx_train());
+        if n_ctx < params.n_ctx {
+            warn!(
+                "Requested a context of {} tokens, but the model was trained with {n_ctx}. Using {n_ctx} tokens.",
+                params.n_ctx
+            );
+        }
+        if !params.use_embeddings
+            && !has_eog_token(&params.model)
+            && params.stop_tokens.is_empty()
+            && params.max_response_duration.is_none()
+        {
+            warn!("The model has no end-of-generation token, so responses only end when the context fills up. Set stop tokens or a max response duration.");
+        }
+        if params.unsafe_skip_inference_lock {
+            warn!("Decoding without the model's inference lock. This segfaults if another worker decodes with the same model at the same time.");
+        }
+        let inference_lock =
+            (!params.unsafe_skip_inference_lock).then(|| model_inference_lock(&params.model));
+        let ctx_params = LlamaContextParams::default()
+            .with_n_ctx(std::num::NonZero::new(n_ctx))
+            .with_n_threads(n_threads)
+            .with_n_threads_batch(n_threads)
+            .with_embeddings(params.use_embeddings);
+
+        // Create inference context and sampler
+        let ctx = params
+            .model
+            .new_context(&LLAMA_BACKEND, ctx_params.clone())?;
+
+        let guidance = match &params.negative_prompt {
+            Some(negative_prompt) if !params.use_embeddings => {
+                info!("Creating guidance context for the negative prompt");
+                Some(GuidanceContext::new(
+                    &params.model,
+                    ctx_params,
+                    n_ctx,
+                    negative_prompt,
+                    params.cfg_scale,
+                    inference_lock.clone(),
+                )?)
+            }
+            _ => None,
+        };
+
+        // the context is cleared before every embedding, so a BOS token always ends up at the start.
+        // chat templates add their own BOS token, if any.
+        let add_bos = match params.embedding_add_bos {
+            _ if !params.use_embeddings => AddBos::Never,
+            Some(true) => AddBos::Always,
+            Some(false) => AddBos::Never,
+            None if model_adds_bos(&params.model) => AddBos::Always,
+            None => AddBos::Never,
         };
 
         let big_batch = LlamaBatch::new(ctx.n_ctx() as usize, 1);
@@ -537,147 +1809,487 @@ impl<'a> WorkerState<'a> {
 
         let state = WorkerState {
             n_past: 0,
+            n_context_shifts: 0,
+            n_unreported_discarded: 0,
+            n_resets: 0,
+            sampler: make_sampler(&params.model, params.sampler_config.clone(), n_ctx),
+            inference: LlamaInference {
+                ctx,
+                big_batch,
+                small_batch,
+                logits_index: 0,
+                inference_lock,
+            },
             stop_tokens: params.stop_tokens.clone(),
-            sampler: make_sampler(&params.model, params.sampler_config.clone()),
-            ctx,
-            big_batch,
-            small_batch,
+            eog_behavior: params.eog_behavior.clone(),
+            ask_on_context_full: params.ask_on_context_full,
+            max_response_duration: params.max_response_duration,
+            logit_processor_top_k: params.logit_processor_top_k,
+            token_probabilities: params.token_probabilities,
+            auto_defrag_threshold: params.auto_defrag_threshold,
+            pause_gate: params.pause_gate.clone(),
+            add_bos,
+            guidance,
+            sampler_config: params.sampler_config.clone(),
         };
         Ok(state)
     }
+}
 
+impl<I: Inference> WorkerState<'_, I> {
     #[tracing::instrument(level = "trace", skip(self))]
-    fn reset_context(mut self) -> Self {
-        self.ctx.clear_kv_cache();
+    fn reset_context(&mut self) {
+        self.inference.clear_kv_cache();
         self.n_past = 0;
-        self
+        self.n_unreported_discarded = 0;
+        self.n_resets += 1;
+        if let Some(guidance) = &mut self.guidance {
+            if let Err(e) = guidance.truncate_to(0) {
+                warn!("Could not reset guidance context: {e}");
+            }
+        }
+    }
+
+    /// Forgets the oldest half of the context to make room, in the guidance context too, and returns how many tokens were forgotten.
+    fn shift_context(
+        &mut self,
+    ) -> Result<u32, llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        let n_discarded = self.inference.shift_kv_cache(self.n_past, 0)?;
+        self.n_past -= n_discarded;
+        self.n_context_shifts += 1;
+        if let Some(guidance) = &mut self.guidance {
+            guidance.shift()?;
+        }
+        if let Some(threshold) = self.auto_defrag_threshold {
+            let fragmentation = self.inference.kv_cache_fragmentation();
+            debug!("KV cache fragmentation after context shift: {fragmentation:.2}");
+            if fragmentation > threshold {
+                self.defragment();
+            }
+        }
+        Ok(n_discarded as u32)
+    }
+
+    /// Defragments the KV cache of the context, and of the guidance context if there is one.
+    /// Returns the fragmentation of the main context before defragmenting.
+    fn defragment(&mut self) -> f32 {
+        let fragmentation = self.inference.kv_cache_fragmentation();
+        info!("Defragmenting KV cache, fragmentation is {fragmentation:.2}");
+        self.inference.defragment_kv_cache();
+        if let Some(guidance) = &mut self.guidance {
+            guidance.ctx.kv_cache_defrag();
+            guidance.ctx.kv_cache_update();
+        }
+        fragmentation
+    }
+
+    /// How likely each of the tokens is to come next, before any sampling, with classifier-free guidance applied.
+    /// Returns zeros if nothing has been read yet.
+    fn next_token_probabilities(&self, tokens: &[LlamaToken]) -> Vec<f32> {
+        if self.n_past == 0 {
+            return vec![0.0; tokens.len()];
+        }
+        let logits = match &self.guidance {
+            Some(guidance) => guidance.logits(self.inference.logits()),
+            None => self.inference.logits().to_vec(),
+        };
+        let log_probabilities = log_softmax(&logits);
+        tokens
+            .iter()
+            .map(|token| {
+                log_probabilities
+                    .get(token.0 as usize)
+                    .map_or(0.0, |log_probability| log_probability.exp())
+            })
+            .collect()
+    }
+
+    fn sample<F>(&mut self, respond: &F) -> LlamaToken
+    where
+        F: Fn(WriteOutput),
+    {
+        if self.guidance.is_none() && self.logit_processor_top_k.is_none() {
+            return self.sampler.sample(self.inference.logits());
+        }
+        let mut logits = match &self.guidance {
+            Some(guidance) => guidance.logits(self.inference.logits()),
+            None => self.inference.logits().to_vec(),
+        };
+        if let Some(top_k) = self.logit_processor_top_k {
+            for (token, bias) in self.logit_adjustments(respond, &logits, top_k) {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit += bias;
+                }
+            }
+        }
+        self.sampler.sample(&logits)
+    }
+
+    /// Sends the `top_k` most likely next tokens to the consumer, and blocks until it answers
+    /// with adjustments to add to their logits. If it goes away without answering, nothing is adjusted.
+    fn logit_adjustments<F>(&self, respond: &F, logits: &[f32], top_k: usize) -> Vec<(i32, f32)>
+    where
+        F: Fn(WriteOutput),
+    {
+        let mut ids: Vec<usize> = (0..logits.len()).collect();
+        let top_k = top_k.min(ids.len());
+        if top_k == 0 {
+            return vec![];
+        }
+        ids.select_nth_unstable_by(top_k - 1, |a, b| logits[*b].total_cmp(&logits[*a]));
+        ids.truncate(top_k);
+        ids.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+        let candidates = ids
+            .into_iter()
+            .map(|id| TokenCandidate {
+                token: id as i32,
+                text: self
+                    .inference
+                    .tokenizer()
+                    .token_text(LlamaToken::new(id as i32)),
+                logit: logits[id],
+            })
+            .collect();
+        let (resolve_to, adjustments) = oneshot::channel();
+        respond(WriteOutput::AdjustLogits(candidates, resolve_to));
+        adjustments.blocking_recv().unwrap_or_default()
+    }
+
+    /// Replaces the sampler with a fresh one, so the random seed, penalties and mirostat state
+    /// all start over. This makes a fixed seed give the same response to the same prompt,
+    /// no matter what was generated before.
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn reset_sampler(&mut self) {
+        self.sampler = self.inference.make_sampler(self.sampler_config.clone());
+    }
+
+    fn checkpoint(&self) -> WorkerCheckpoint {
+        WorkerCheckpoint {
+            n_past: self.n_past,
+            n_context_shifts: self.n_context_shifts,
+            n_resets: self.n_resets,
+        }
+    }
+
+    /// Forgets everything that was read or written after the checkpoint.
+    /// Returns false if that isn't possible, because the context was shifted or reset in the meantime.
+    fn rollback(&mut self, checkpoint: WorkerCheckpoint) -> bool {
+        if self.n_context_shifts != checkpoint.n_context_shifts
+            || self.n_resets != checkpoint.n_resets
+        {
+            return false;
+        }
+        self.truncate_to(checkpoint.n_past as u32).is_ok()
+    }
+
+    fn truncate_to(&mut self, n_tokens: u32) -> Result<(), TruncateError> {
+        if n_tokens as i32 > self.n_past {
+            return Err(TruncateError::OutOfRange {
+                n_tokens,
+                n_past: self.n_past,
+            });
+        }
+        self.inference.truncate_kv_cache(n_tokens)?;
+        self.n_past = n_tokens as i32;
+        if let Some(guidance) = &mut self.guidance {
+            guidance.truncate_to(n_tokens)?;
+        }
+        Ok(())
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    fn read_string(mut self, text: String) -> Result<Self, ReadError> {
-        let tokens = self.ctx.model.str_to_token(&text, AddBos::Never)?;
+    fn read_string(&mut self, text: String) -> Result<(), ReadError> {
+        let tokens = self.inference.tokenizer().tokenize(&text, self.add_bos)?;
+        self.read_tokens(tokens)
+    }
+
+    fn read_tokens(&mut self, tokens: Vec<LlamaToken>) -> Result<(), ReadError> {
         let n_tokens = tokens.len();
         debug!("Reading {n_tokens} tokens.");
 
-        // can't read nothing
-        debug_assert!(tokens.len() > 0);
+        // reading nothing is a no-op, e.g. when the frontend sends an empty prompt
+        if tokens.is_empty() {
+            warn!("Got nothing to read, ignoring it.");
+            return Ok(());
+        }
+        // llama.cpp aborts on tokens outside the vocabulary, so check them first
+        let n_vocab = self.inference.tokenizer().n_vocab();
+        if let Some(token) = tokens.iter().find(|token| !(0..n_vocab).contains(&token.0)) {
+            return Err(ReadError::InvalidToken {
+                token: token.0,
+                n_vocab,
+            });
+        }
         // can't read more than the context size
-        debug_assert!(tokens.len() < self.ctx.n_ctx() as usize);
+        if tokens.len() >= self.inference.n_ctx() as usize {
+            return Err(ReadError::ContextTooSmall {
+                n_tokens: tokens.len(),
+                n_ctx: self.inference.n_ctx(),
+            });
+        }
 
         // apply context shifting
-        if self.n_past as usize + tokens.len() > self.ctx.n_ctx() as usize {
+        if self.n_past as usize + tokens.len() > self.inference.n_ctx() as usize {
             debug!("Applying context shifting");
-            self.n_past -= apply_context_shifting(&mut self.ctx, self.n_past)?;
-        }
-
-        {
-            debug!("Populating batch");
-            // make batch
-            self.big_batch.clear();
-            let seq_ids = &[0];
-            for (i, token) in (0..).zip(tokens.iter()) {
-                // Only compute logits for the last token to save computation
-                let output_logits = i == n_tokens - 1;
-                self.big_batch
-                    .add(*token, self.n_past + i as i32, seq_ids, output_logits)?;
-            }
+            self.n_unreported_discarded += self.shift_context()?;
         }
 
         // llm go brr
         let decode_span = debug_span!("read decode", n_tokens = n_tokens);
         let decode_guard = decode_span.enter();
-        self.ctx.decode(&mut self.big_batch)?;
+        self.inference.decode::<ReadError>(&tokens, self.n_past)?;
         drop(decode_guard);
         // brrr
 
+        if let Some(guidance) = &mut self.guidance {
+            guidance.read_tokens::<ReadError>(&tokens)?;
+        }
+
         debug!("completed read operation");
-        Ok(WorkerState {
-            n_past: self.n_past + tokens.len() as i32,
-            ..self
-        })
+        self.n_past += tokens.len() as i32;
+        Ok(())
     }
 
-    #[tracing::instrument(level = "info", skip(self, respond))]
+    /// Asks the consumer what to do about a full context, if `ask_on_context_full` is set.
+    /// Blocks until it answers. If it goes away without answering, the context is shifted.
+    fn overflow_strategy<F>(&self, respond: &F) -> OverflowStrategy
+    where
+        F: Fn(WriteOutput),
+    {
+        if !self.ask_on_context_full {
+            return OverflowStrategy::Shift;
+        }
+        info!("Context is full, waiting for an overflow strategy");
+        let (resolve_to, strategy) = oneshot::channel();
+        respond(WriteOutput::ContextFull(resolve_to));
+        strategy.blocking_recv().unwrap_or_default()
+    }
+
+    /// Writes `n` responses, going back to the current end of the context after each of them.
+    #[tracing::instrument(level = "info", skip(self))]
+    fn write_responses(&mut self, n: usize) -> Result<Responses, WriteError> {
+        let prompt_end = self.checkpoint();
+        let sampler_config = self.sampler_config.clone();
+        let mut responses = Responses::default();
+
+        for i in 0..n {
+            // a different seed for every response, or they would all be the same
+            self.sampler_config = sampler_config.with_seed_offset(i as u32);
+            let response = std::cell::RefCell::new(None);
+            let result = self.write_until_done(|out| {
+                if let WriteOutput::Done(full_response, _, _) = out {
+                    *response.borrow_mut() = Some(full_response);
+                }
+            });
+            self.sampler_config = sampler_config.clone();
+            result?;
+            responses.responses.extend(response.into_inner());
+
+            if !self.rollback(prompt_end) {
+                warn!(
+                    "Context was shifted while writing response {} of {n}, so the text is lost. Clearing the context.",
+                    i + 1
+                );
+                self.reset_context();
+                responses.context_cleared = true;
+                break;
+            }
+        }
+        Ok(responses)
+    }
+
+    #[tracing::instrument(level = "info", skip(self, respond))]
     fn write_until_done<F>(
-        mut self,
+        &mut self,
         respond: F, // respond_to: Sender<Result<WriteOutput, WriteError>>,
-    ) -> Result<Self, WriteError>
+    ) -> Result<(), WriteError>
     where
         F: Fn(WriteOutput),
     {
         // Token generation loop
         info!("Worker writing until done");
+        self.reset_sampler();
+        let n_discarded = std::mem::take(&mut self.n_unreported_discarded);
+        if n_discarded > 0 {
+            respond(WriteOutput::ContextShifted(n_discarded));
+        }
 
         // pre-allocating 4096 bytes for the response string
         // 4096 is a very randomly chosen number. how does this affect performance?
         let mut full_response: String = String::with_capacity(4096);
+        let mut response_tokens: Vec<i32> = Vec::new();
+        let mut n_parts = 0;
+        let mut started = std::time::Instant::now();
+
+        let finish_reason = loop {
+            // time spent paused doesn't count towards max_response_duration
+            started += self.pause_gate.wait_while_paused();
 
-        loop {
             // Check for context window overflow (it was in the end before)
-            if self.n_past >= self.ctx.n_ctx() as i32 - 1 {
-                self.n_past -= apply_context_shifting(&mut self.ctx, self.n_past)?;
-                // check count
-                // XXX: this check is slow
-                debug_assert!(self.n_past == self.ctx.get_kv_cache_token_count());
+            if self.n_past >= self.inference.n_ctx() as i32 - 1 {
+                match self.overflow_strategy(&respond) {
+                    OverflowStrategy::Shift => {
+                        let n_discarded = self.shift_context()?;
+                        respond(WriteOutput::ContextShifted(n_discarded));
+                    }
+                    OverflowStrategy::Summarize | OverflowStrategy::Stop => {
+                        debug!("Context is full, ending the response");
+                        break FinishReason::ContextFull;
+                    }
+                }
             }
 
-            // Sample next token, no need to use sampler.accept as sample already accepts the token.
-            // using sampler.accept() will cause the sampler to crash when using grammar sampling.
+            // Sample next token. The sampler accepts the token itself, so don't call accept again:
+            // accepting a token twice crashes grammar sampling.
             // https://github.com/utilityai/llama-cpp-rs/issues/604
             trace!("Applying sampler...");
-            let new_token: LlamaToken = self.sampler.sample(&self.ctx, -1);
-
-            // batch of one
-            self.small_batch.clear();
-            self.small_batch.add(new_token, self.n_past, &[0], true)?;
+            let new_token: LlamaToken = self.sample(&respond);
+            // the logits are replaced when the token is decoded, so this has to happen first
+            let probability = self
+                .token_probabilities
+                .then(|| self.next_token_probabilities(&[new_token])[0]);
+            let has_eog = self.inference.tokenizer().is_eog(new_token);
+            response_tokens.push(new_token.0);
+
+            let mut stop_at_eog = has_eog;
+            if has_eog {
+                n_parts += 1;
+                if let EogBehavior::Continue { max_parts, .. } = &self.eog_behavior {
+                    if n_parts < *max_parts {
+                        debug!("Continuing after EOG, finished part {n_parts} of {max_parts}");
+                        stop_at_eog = false;
+                    }
+                }
+            }
+            // the final EOG token is not read, since the chat template renders the end of the turn itself,
+            // and reads it along with the next message.
+            if stop_at_eog {
+                break FinishReason::Eog;
+            }
 
             // llm go brr
             let decode_span = trace_span!("write decode", n_past = self.n_past);
             let decode_guard = decode_span.enter();
-            self.ctx.decode(&mut self.small_batch)?;
+            self.inference
+                .decode::<WriteError>(&[new_token], self.n_past)?;
             drop(decode_guard);
             self.n_past += 1; // keep count
+            if let Some(guidance) = &mut self.guidance {
+                guidance.read_tokens::<WriteError>(&[new_token])?;
+            }
 
-            // Convert token to text
-            let token_string = self
-                .ctx
-                .model
-                .token_to_str_with_size(new_token, MAX_TOKEN_STR_LEN, Special::Tokenize)
-                .unwrap_or("�".to_string());
-            // fall back to "U+FFFD REPLACEMENT CHARACTER"
-            // when encountering bytes that aren't valid UTF-8
-            // wikipedia: "used to replace an unknown, unrecognised, or unrepresentable character"
-
-            trace!(?new_token, ?token_string);
-            let has_eog = self.ctx.model.is_eog_token(new_token);
+            if has_eog {
+                if let EogBehavior::Continue { separator, .. } = &self.eog_behavior {
+                    full_response.push_str(separator);
+                    respond(WriteOutput::Token(separator.clone(), probability));
+                }
+            } else {
+                // Convert token to text
+                let token_string = self.inference.tokenizer().token_text(new_token);
 
-            if !has_eog {
+                trace!(?new_token, ?token_string);
                 full_response.push_str(&token_string);
                 trace!("Sending out token: {token_string}");
-                respond(WriteOutput::Token(token_string));
+                respond(WriteOutput::Token(token_string, probability));
             }
 
-            let has_stop_tokens = self
-                .stop_tokens
-                .iter()
-                .any(|stop_token| full_response.contains(stop_token));
-            if has_eog || has_stop_tokens {
-                break;
+            if let Some(stop_token) = find_stop_token(&self.stop_tokens, &full_response) {
+                break FinishReason::StopToken(stop_token.to_string());
             }
-        }
+            if self
+                .max_response_duration
+                .is_some_and(|max_duration| started.elapsed() >= max_duration)
+            {
+                debug!(
+                    "Response took longer than {:?}, ending it",
+                    self.max_response_duration
+                );
+                break FinishReason::TimeLimit;
+            }
+        };
 
         // we're done!
-        trace!("Sending out response: {full_response}");
-        respond(WriteOutput::Done(full_response));
-        Ok(self)
+        trace!("Sending out response: {full_response} ({finish_reason:?})");
+        respond(WriteOutput::Done(
+            full_response,
+            finish_reason,
+            response_tokens,
+        ));
+        Ok(())
     }
 }
 
+/// Generates a single response to `prompt` on the calling thread, and returns it.
+/// This creates a fresh context, so it is slower per call than an `LLMActorHandle`,
+/// but it needs no async runtime, threads or channels. Useful for scripts and tools.
+pub fn complete(params: &LLMActorParams, prompt: &str) -> Result<String, WorkerError> {
+    let mut state = WorkerState::new(params)?;
+    state.read_string(prompt.to_string())?;
+
+    let response = std::cell::RefCell::new(String::new());
+    state.write_until_done(|out| {
+        if let WriteOutput::Done(full_response, _, _) = out {
+            *response.borrow_mut() = full_response;
+        }
+    })?;
+    Ok(response.into_inner())
+}
+
+/// Tokenizes `text` and converts the tokens back into a string, the same way the worker does.
+/// This shows exactly what the LLM reads, including any lossy tokenization and special tokens.
+pub fn tokenize_roundtrip(
+    model: &(impl Tokenizer + ?Sized),
+    text: &str,
+) -> Result<String, llama_cpp_2::StringToTokenError> {
+    let tokens = model.tokenize(text, AddBos::Never)?;
+    Ok(tokens
+        .into_iter()
+        .map(|token| model.token_text(token))
+        .collect())
+}
+
+/// Turns text into token ids, for `LLMActorHandle::read_tokens`. Special tokens written as text, like `<|im_end|>`, become
+/// the special tokens themselves. No BOS token is added.
+pub fn tokenize(
+    model: &(impl Tokenizer + ?Sized),
+    text: &str,
+) -> Result<Vec<i32>, llama_cpp_2::StringToTokenError> {
+    let tokens = model.tokenize(text, AddBos::Never)?;
+    Ok(tokens.into_iter().map(|token| token.0).collect())
+}
+
+/// Returns the first of `stop_tokens` that occurs anywhere in `text`.
+/// Stop tokens are matched as plain substrings, so they may span several LLM tokens.
+fn find_stop_token<'a>(stop_tokens: &'a [String], text: &str) -> Option<&'a str> {
+    stop_tokens
+        .iter()
+        .map(String::as_str)
+        .find(|stop_token| text.contains(stop_token))
+}
+
 fn dotproduct(a: &[f32], b: &[f32]) -> f32 {
     assert!(a.len() == b.len());
     a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
 }
 
+/// Checks whether embeddings from two models can be compared, i.e. whether they have the same number of dimensions.
+/// Note that different models with the same dimensions still place text differently in the embedding space,
+/// so stored embeddings should be recomputed when switching models, even if this returns true.
+pub fn embeddings_compatible(a: &LlamaModel, b: &LlamaModel) -> bool {
+    a.n_embd() == b.n_embd()
+}
+
+/// Scales an embedding to unit length (L2 normalization), like sentence-transformers does.
+/// The dot product of two normalized embeddings is equal to their cosine similarity.
+/// An all-zero embedding is returned as is.
+pub fn normalize_embedding(embedding: &[f32]) -> Vec<f32> {
+    let norm = dotproduct(embedding, embedding).sqrt();
+    if norm == 0. {
+        return embedding.to_vec();
+    }
+    embedding.iter().map(|x| x / norm).collect()
+}
+
 pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     let norm_a = dotproduct(a, a).sqrt();
     let norm_b = dotproduct(b, b).sqrt();
@@ -687,36 +2299,310 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     dotproduct(a, b) / (norm_a * norm_b)
 }
 
+/// Computes the cosine similarity between every pair of embeddings, as a symmetric matrix with one row per embedding.
+/// Each embedding is normalized once, so this is much cheaper than calling `cosine_similarity` for every pair.
+/// Like `cosine_similarity`, comparisons with an all-zero embedding are NaN.
+pub fn similarity_matrix(embeddings: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let normalized: Vec<Option<Vec<f32>>> = embeddings
+        .iter()
+        .map(|embd| {
+            let norm = dotproduct(embd, embd).sqrt();
+            (norm != 0.).then(|| embd.iter().map(|x| x / norm).collect())
+        })
+        .collect();
+
+    let n = embeddings.len();
+    let mut matrix = vec![vec![f32::NAN; n]; n];
+    for (i, a) in normalized.iter().enumerate() {
+        for (j, b) in normalized.iter().enumerate().skip(i) {
+            if let (Some(a), Some(b)) = (a, b) {
+                let similarity = dotproduct(a, b);
+                matrix[i][j] = similarity;
+                matrix[j][i] = similarity;
+            }
+        }
+    }
+    matrix
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampler_config::{Greedy, SamplerMethod, Temperature};
     use crate::test_utils;
     use tokio_stream::StreamExt;
 
+    #[test]
+    fn test_inference_lock_recovers_from_panic() {
+        let lock = Arc::new(Mutex::new(()));
+        let crashed = std::thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let _lock = inference_lock(&lock);
+                panic!("worker crashed while decoding");
+            }
+        })
+        .join();
+        assert!(crashed.is_err());
+
+        drop(inference_lock(&lock));
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    fn test_is_out_of_memory() {
+        let oom: GenerateResponseError =
+            WriteError::DecodeError(llama_cpp_2::DecodeError::Unknown(-2)).into();
+        assert!(oom.is_out_of_memory());
+        assert!(!oom.is_recoverable());
+
+        let no_slot: GenerateResponseError =
+            ReadError::DecodeError(llama_cpp_2::DecodeError::NoKvCacheSlot).into();
+        assert!(!no_slot.is_out_of_memory());
+
+        let too_small: GenerateResponseError = ReadError::ContextTooSmall {
+            n_tokens: 10,
+            n_ctx: 5,
+        }
+        .into();
+        assert!(!too_small.is_out_of_memory());
+    }
+
     async fn response_from_stream(
         stream: tokio_stream::wrappers::ReceiverStream<Result<WriteOutput, GenerateResponseError>>,
     ) -> Option<String> {
         stream
             .filter_map(|out| match out {
-                Ok(WriteOutput::Done(resp)) => Some(resp),
+                Ok(WriteOutput::Done(resp, _, _)) => Some(resp),
                 _ => None,
             })
             .next()
             .await
     }
 
+    // the tests below need no model file, so they can run anywhere
+
+    #[test]
+    fn test_decode_error_is_recoverable() {
+        assert!(decode_error_is_recoverable(
+            &llama_cpp_2::DecodeError::NoKvCacheSlot
+        ));
+        assert!(!decode_error_is_recoverable(
+            &llama_cpp_2::DecodeError::Unknown(-3)
+        ));
+    }
+
+    #[test]
+    fn test_check_gguf_header() {
+        let dir = std::env::temp_dir();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, bytes).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let valid = write(
+            "nobodywho-valid.gguf",
+            b"GGUF\x03\x00\x00\x00rest of the file",
+        );
+        assert!(check_gguf_header(&valid).is_ok());
+
+        let not_gguf = write("nobodywho-model.bin", b"PK\x03\x04 not a model");
+        assert!(matches!(
+            check_gguf_header(&not_gguf),
+            Err(LoadModelError::NotAGGUF(_))
+        ));
+
+        let truncated = write("nobodywho-truncated.gguf", b"GGU");
+        assert!(matches!(
+            check_gguf_header(&truncated),
+            Err(LoadModelError::NotAGGUF(_))
+        ));
+
+        let old = write("nobodywho-old.gguf", b"GGUF\x01\x00\x00\x00");
+        assert!(matches!(
+            check_gguf_header(&old),
+            Err(LoadModelError::UnsupportedGGUFVersion { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_find_stop_token() {
+        let stop_tokens = vec!["horse-rider".to_string(), "fly".to_string()];
+        assert_eq!(find_stop_token(&stop_tokens, "cat, dog"), None);
+        assert_eq!(find_stop_token(&stop_tokens, "cat, dog, fly"), Some("fly"));
+        // stop tokens can be split across several llm tokens
+        assert_eq!(
+            find_stop_token(&stop_tokens, "dog, horse-rid"),
+            None,
+            "Partial stop tokens should not match"
+        );
+        assert_eq!(
+            find_stop_token(&stop_tokens, "dog, horse-rider"),
+            Some("horse-rider")
+        );
+        assert_eq!(find_stop_token(&[], "anything"), None);
+    }
+
+    #[test]
+    fn test_context_shift_n_discard() {
+        // without any kept tokens, half the context is discarded
+        assert_eq!(context_shift_n_discard(64, 0), 32);
+        // kept tokens are not counted towards the discarded half
+        assert_eq!(context_shift_n_discard(64, 10), 27);
+        // odd numbers round down, so we never discard more than half
+        assert_eq!(context_shift_n_discard(63, 0), 31);
+        assert_eq!(context_shift_n_discard(10, 9), 0);
+        assert_eq!(context_shift_n_discard(10, 10), 0);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]).is_nan());
+    }
+
+    #[test]
+    fn test_similarity_matrix() {
+        let embeddings = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 2.0],
+            vec![3.0, 3.0],
+            vec![0.0, 0.0],
+        ];
+        let matrix = similarity_matrix(&embeddings);
+        assert_eq!(matrix.len(), 4);
+        for (i, row) in matrix.iter().take(3).enumerate() {
+            for (j, similarity) in row.iter().take(3).enumerate() {
+                let expected = cosine_similarity(&embeddings[i], &embeddings[j]);
+                assert!((similarity - expected).abs() < 1e-6);
+            }
+        }
+        assert!(matrix[3].iter().all(|x| x.is_nan()));
+        assert!(matrix[0][3].is_nan());
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let log_probs = log_softmax(&[1.0, 2.0, 3.0]);
+        let total: f32 = log_probs.iter().map(|l| l.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        // differences between logits are kept
+        assert!((log_probs[1] - log_probs[0] - 1.0).abs() < 1e-5);
+        // large logits don't overflow
+        assert!(log_softmax(&[1000.0, 1000.0]).iter().all(|l| l.is_finite()));
+    }
+
+    #[test]
+    fn test_normalize_embedding() {
+        let normalized = normalize_embedding(&[3.0, 4.0]);
+        assert_eq!(normalized, vec![0.6, 0.8]);
+        assert!((dotproduct(&normalized, &normalized) - 1.0).abs() < 1e-6);
+
+        // normalizing does not change the cosine similarity
+        let (a, b) = ([1.0, 2.0, 3.0], [-2.0, 0.5, 4.0]);
+        let normalized_dot = dotproduct(&normalize_embedding(&a), &normalize_embedding(&b));
+        assert!((normalized_dot - cosine_similarity(&a, &b)).abs() < 1e-6);
+
+        assert_eq!(normalize_embedding(&[0.0, 0.0]), vec![0.0, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_context_shifting() {
+        test_utils::init_test_tracing();
+        let actor = mock::MockWorker::new(vec![
+            " 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,".to_string(),
+        ])
+        .n_ctx(16)
+        .stop_tokens(vec!["20".to_string()])
+        .spawn();
+
+        let mut stream = actor
+            .generate_response("I'm going to count to 20: 1, 2, 3, 4, 5, 6, 7,".to_string())
+            .await;
+
+        let mut n_discarded = 0;
+        let response = loop {
+            match stream.next().await.expect("Stream ended early").unwrap() {
+                WriteOutput::ContextShifted(n) => n_discarded += n,
+                WriteOutput::Done(response, _, _) => break response,
+                _ => (),
+            }
+        };
+        assert!(
+            response.contains("15, 16, 17, 18, 19, 20"),
+            "Expected completion to count to 20, got: {response}"
+        );
+        assert!(n_discarded > 0, "Expected the context to be shifted");
+    }
+
+    #[tokio::test]
+    async fn test_context_shift_while_reading() {
+        test_utils::init_test_tracing();
+        let actor = mock::MockWorker::new(vec!["Yes.".to_string()])
+            .n_ctx(24)
+            .stop_tokens(vec![".".to_string()])
+            .spawn();
+        assert_eq!(actor.n_ctx(), 24);
+        // 13 tokens for the mock worker
+        let prompt = "The cat sat on the mat. ".repeat(2);
+
+        // the first prompt fits, the second one only fits after forgetting some of the first
+        for expect_shift in [false, true] {
+            let mut stream = actor.generate_response(prompt.clone()).await;
+            let mut n_discarded = 0;
+            loop {
+                match stream.next().await.expect("Stream ended early").unwrap() {
+                    WriteOutput::ContextShifted(n) => n_discarded += n,
+                    WriteOutput::Done(..) => break,
+                    _ => (),
+                }
+            }
+            assert_eq!(n_discarded > 0, expect_shift);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_tokens() {
+        crate::test_utils::init_test_tracing();
+        let actor = mock::MockWorker::new(vec![" 5, 6, 7, 8, 9, 10,".to_string()])
+            .stop_tokens(vec!["7".to_string()])
+            .spawn();
+        let stream = actor
+            .generate_response("I'm going to count to 10: 1, 2, 3, 4,".to_string())
+            .await;
+
+        let response = response_from_stream(stream).await.unwrap();
+
+        assert!(
+            response.contains("5, 6, "),
+            "Expected output to contain text before stop token. Got: {response}"
+        );
+        // the mock worker's tokens are whole words, so the stop token comes with its comma
+        assert!(
+            response.ends_with("7,"),
+            "Expected output to stop at stop token, but continued. Got: {response}"
+        );
+        assert!(
+            !response.contains("8"),
+            "Expected output to stop at stop token, but continued. Got: {response}"
+        );
+    }
+
+    // the tests below load the models given by TEST_MODEL and TEST_EMBEDDINGS_MODEL
+
     #[tokio::test]
     async fn test_simple_gen() {
         test_utils::init_test_tracing();
         let model = test_utils::load_test_model();
 
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 4096,
-            stop_tokens: vec!["10".to_string()],
-            use_embeddings: false,
-        };
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
 
         let actor = LLMActorHandle::new(params)
             .await
@@ -730,18 +2616,311 @@ mod tests {
         assert!(response.contains("4, 5, 6, 7, 8, 9, 10"));
     }
 
+    #[tokio::test]
+    async fn test_default_sampler_gen() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        // the default penalties must leave the logits alone, with any sampler method
+        for method in [
+            SamplerConfig::default().method,
+            SamplerMethod::Greedy(Greedy::default()),
+        ] {
+            let params = LLMActorParams::builder()
+                .model(model.clone())
+                .sampler_config(SamplerConfig {
+                    method,
+                    ..SamplerConfig::default()
+                })
+                .n_ctx(1024)
+                .stop_tokens(vec!["10".to_string()])
+                .build()
+                .unwrap();
+            let actor = LLMActorHandle::new(params).await.unwrap();
+            let stream = actor
+                .generate_response("I'm gonna count to 10: 1, 2, 3, ".to_string())
+                .await;
+            let response = response_from_stream(stream).await.unwrap();
+            assert!(
+                response.contains("4, 5, 6, 7, 8, 9, 10"),
+                "Expected completion to continue counting, got: {response}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_worker_rejects_invalid_penalty_last_n() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                penalty_last_n: -2,
+                ..SamplerConfig::default()
+            })
+            .build()
+            .unwrap();
+        assert!(matches!(
+            WorkerState::new(&params),
+            Err(InitWorkerError::InvalidPenaltyLastN(-2))
+        ));
+    }
+
+    #[test]
+    fn test_complete() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .n_ctx(1024)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+
+        let response = complete(&params, "I'm gonna count to 10: 1, 2, 3, ").unwrap();
+        assert!(
+            response.contains("4, 5, 6, 7, 8, 9, 10"),
+            "Expected completion to continue counting, got: {response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_greedy_gen() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy::default()),
+                ..SamplerConfig::default()
+            })
+            .n_ctx(1024)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+
+        // greedy sampling has no randomness, so two separate workers must agree exactly
+        let first_actor = LLMActorHandle::new(params.clone()).await.unwrap();
+        let second_actor = LLMActorHandle::new(params).await.unwrap();
+        let prompt = "I'm gonna count to 10: 1, 2, 3, ".to_string();
+
+        let first_response =
+            response_from_stream(first_actor.generate_response(prompt.clone()).await)
+                .await
+                .unwrap();
+        let second_response = response_from_stream(second_actor.generate_response(prompt).await)
+            .await
+            .unwrap();
+
+        assert_eq!(first_response, second_response);
+        assert!(
+            first_response.contains("4, 5, 6, 7, 8, 9, 10"),
+            "Expected completion to continue counting, got: {first_response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_guidance_without_scale() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy::default()),
+                penalty_repeat: 1.0,
+                ..SamplerConfig::default()
+            })
+            .n_ctx(1024)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+        let prompt = "I'm gonna count to 10: 1, 2, 3, ".to_string();
+
+        // a guidance scale of 1 cancels out the negative prompt, so this must match plain greedy sampling
+        let guided_params = LLMActorParams {
+            negative_prompt: Some("Letters are better than numbers.".to_string()),
+            cfg_scale: 1.0,
+            ..params.clone()
+        };
+        let actor = LLMActorHandle::new(params).await.unwrap();
+        let guided_actor = LLMActorHandle::new(guided_params).await.unwrap();
+
+        let response = response_from_stream(actor.generate_response(prompt.clone()).await)
+            .await
+            .unwrap();
+        let guided_response = response_from_stream(guided_actor.generate_response(prompt).await)
+            .await
+            .unwrap();
+        assert_eq!(response, guided_response);
+    }
+
+    #[tokio::test]
+    async fn test_generate_responses() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Temperature(Temperature {
+                    seed: 42,
+                    temperature: 1.5,
+                }),
+                ..SamplerConfig::default()
+            })
+            .n_ctx(1024)
+            .stop_tokens(vec![".".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let responses = actor
+            .generate_responses("My favorite animal is the".to_string(), 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(responses.responses.len(), 3);
+        assert!(!responses.context_cleared);
+        assert!(
+            responses.responses[0] != responses.responses[1]
+                || responses.responses[1] != responses.responses[2],
+            "Expected different seeds to give different responses, got: {:?}",
+            responses.responses
+        );
+
+        // the context ends with the prompt again, so we can keep writing from there
+        let response = actor
+            .write_until_done()
+            .await
+            .filter_map(|out| match out {
+                Ok(WriteOutput::Done(resp, _, _)) => Some(resp),
+                _ => None,
+            })
+            .next()
+            .await;
+        assert!(response.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_seed_is_deterministic() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Temperature(Temperature {
+                    seed: 42,
+                    temperature: 1.5,
+                }),
+                penalty_last_n: 64,
+                penalty_repeat: 1.2,
+                ..SamplerConfig::default()
+            })
+            .n_ctx(1024)
+            .stop_tokens(vec!["\n".to_string()])
+            .build()
+            .unwrap();
+        let prompt = "Once upon a time, in a land far away,".to_string();
+
+        // same prompt on a fresh worker
+        let first_actor = LLMActorHandle::new(params.clone()).await.unwrap();
+        let second_actor = LLMActorHandle::new(params).await.unwrap();
+        let first_response =
+            response_from_stream(first_actor.generate_response(prompt.clone()).await)
+                .await
+                .unwrap();
+        let second_response =
+            response_from_stream(second_actor.generate_response(prompt.clone()).await)
+                .await
+                .unwrap();
+        assert_eq!(first_response.as_bytes(), second_response.as_bytes());
+
+        // same prompt again on a worker that already generated something
+        first_actor.reset_context().await.unwrap();
+        let third_response = response_from_stream(first_actor.generate_response(prompt).await)
+            .await
+            .unwrap();
+        assert_eq!(first_response.as_bytes(), third_response.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["10".to_string()])
+            .max_buffered_tokens(2)
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm gonna count to 10: 1, 2, 3, ".to_string())
+            .await;
+
+        // the worker has to wait for us, and nothing may get lost while it does
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let mut n_tokens = 0;
+        while let Some(out) = stream.next().await {
+            match out.unwrap() {
+                WriteOutput::Token(..) => n_tokens += 1,
+                WriteOutput::ContextFull(_) | WriteOutput::ContextShifted(_) => {
+                    panic!("Context should not fill up")
+                }
+                WriteOutput::AdjustLogits(..) => panic!("Logit processor is disabled"),
+                WriteOutput::Done(response, _, _) => {
+                    assert!(n_tokens > 2, "Expected more tokens than the buffer holds");
+                    assert!(response.contains("4, 5, 6, 7, 8, 9, 10"));
+                    return;
+                }
+            }
+        }
+        panic!("Stream ended without a full response");
+    }
+
+    #[tokio::test]
+    async fn test_embedding_add_bos() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_embeddings_model();
+
+        let mut embeddings = vec![];
+        for add_bos in [true, false] {
+            let params = LLMActorParams::builder()
+                .model(model.clone())
+                .use_embeddings(true)
+                .embedding_add_bos(Some(add_bos))
+                .build()
+                .unwrap();
+            let actor = LLMActorHandle::new(params).await.unwrap();
+            let embedding = actor
+                .generate_embedding("Copenhagen is the capital of Denmark.".to_string())
+                .await
+                .unwrap();
+            embeddings.push(embedding);
+        }
+        assert_ne!(
+            embeddings[0], embeddings[1],
+            "Expected the BOS token to change the embedding"
+        );
+    }
+
     #[tokio::test]
     async fn test_embeddings() {
         test_utils::init_test_tracing();
         let model = test_utils::load_embeddings_model();
 
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 4096,
-            stop_tokens: vec![],
-            use_embeddings: true,
-        };
+        let params = LLMActorParams::builder()
+            .model(model)
+            .use_embeddings(true)
+            .build()
+            .unwrap();
 
         let actor = LLMActorHandle::new(params)
             .await
@@ -795,13 +2974,11 @@ mod tests {
         test_utils::init_test_tracing();
         let model = test_utils::load_test_model();
 
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 4096,
-            stop_tokens: vec!["Copenhagen".to_string(), "Berlin".to_string()],
-            use_embeddings: false,
-        };
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["Copenhagen".to_string(), "Berlin".to_string()])
+            .build()
+            .unwrap();
         let dk_actor = LLMActorHandle::new(params.clone()).await.unwrap();
         let de_actor = LLMActorHandle::new(params).await.unwrap();
 
@@ -832,65 +3009,380 @@ mod tests {
         );
     }
 
-    #[tokio::test]
-    async fn test_context_shifting() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_interleaved_embeddings_and_chat() {
         test_utils::init_test_tracing();
-        let model = test_utils::load_test_model();
-
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 64,
-            stop_tokens: vec!["20".to_string()],
-            use_embeddings: false,
-        };
-        let actor = LLMActorHandle::new(params.clone()).await.unwrap();
+        let chat_params = LLMActorParams::builder()
+            .model(test_utils::load_test_model())
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy::default()),
+                ..SamplerConfig::default()
+            })
+            .build()
+            .unwrap();
+        let embedding_params = LLMActorParams::builder()
+            .model(test_utils::load_embeddings_model())
+            .use_embeddings(true)
+            .build()
+            .unwrap();
+        let chat_actor = LLMActorHandle::new(chat_params).await.unwrap();
+        let embedding_actor = LLMActorHandle::new(embedding_params).await.unwrap();
 
-        let stream = actor
-            .generate_response("I'm going to count to 20: 1, 2, 3, 4, 5, 6, 7".to_string())
+        // start a long response, and wait until it is being generated
+        let mut stream = chat_actor
+            .generate_response("Count from 1 to 100: 1, 2, 3, 4, 5,".to_string())
             .await;
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(WriteOutput::Token(..)))
+        ));
+        let chat_handle = tokio::spawn(response_from_stream(stream));
+
+        // embeddings only wait for single decodes of the chat, not for the whole response
+        for i in 0..10 {
+            let embedding = embedding_actor
+                .generate_embedding(format!("Sentence number {i} about dragons."))
+                .await
+                .unwrap();
+            assert!(!embedding.is_empty());
+        }
+        assert!(
+            !chat_handle.is_finished(),
+            "Expected the embeddings to finish while the chat was still generating"
+        );
 
-        let response = response_from_stream(stream).await.unwrap();
+        let response = chat_handle.await.unwrap().unwrap();
         assert!(
-            response.contains("15, 16, 17, 18, 19, 20"),
-            "Expected completion to count to 20, got: {response}"
+            response.contains("10, 11, 12"),
+            "Expected the chat to keep counting, got: {response}"
         );
     }
 
-    #[tokio::test]
-    async fn test_stop_tokens() {
-        crate::test_utils::init_test_tracing();
+    #[test]
+    fn test_model_inference_lock() {
+        let model = test_utils::load_test_model();
+        let other_model = test_utils::load_embeddings_model();
+        assert!(Arc::ptr_eq(
+            &model_inference_lock(&model),
+            &model_inference_lock(&model.clone())
+        ));
+        assert!(!Arc::ptr_eq(
+            &model_inference_lock(&model),
+            &model_inference_lock(&other_model)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_decodes_on_same_model() {
+        test_utils::init_test_tracing();
+        // two contexts of the same model, which segfaults if they decode at the same time
+        let params = LLMActorParams::builder()
+            .model(test_utils::load_test_model())
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy::default()),
+                ..SamplerConfig::default()
+            })
+            .build()
+            .unwrap();
+        let first_actor = LLMActorHandle::new(params.clone()).await.unwrap();
+        let second_actor = LLMActorHandle::new(params).await.unwrap();
+
+        // the decodes of both responses interleave, one token at a time
+        let prompt = "Count from 1 to 30, separated by commas: 1, 2, 3, 4, 5,";
+        let first = tokio::spawn(response_from_stream(
+            first_actor.generate_response(prompt.to_string()).await,
+        ));
+        let second = tokio::spawn(response_from_stream(
+            second_actor.generate_response(prompt.to_string()).await,
+        ));
+        for response in [first.await.unwrap(), second.await.unwrap()] {
+            let response = response.unwrap();
+            assert!(
+                response.contains("10, 11, 12"),
+                "Expected both responses to keep counting, got: {response}"
+            );
+        }
+    }
 
+    #[test]
+    fn test_special_token_strings() {
         let model = test_utils::load_test_model();
+        let strings = special_token_strings(&model);
+        let eos = model
+            .token_to_str_with_size(model.token_eos(), MAX_TOKEN_STR_LEN, Special::Tokenize)
+            .unwrap();
+        assert!(strings.contains(&eos), "Expected {eos:?} in {strings:?}");
+        assert!(strings.iter().all(|string| !string.is_empty()));
+    }
 
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 1024,
-            stop_tokens: vec!["7".to_string()],
-            use_embeddings: false,
-        };
-        let actor = LLMActorHandle::new(params.clone()).await.unwrap();
+    #[tokio::test]
+    async fn test_pause_gate() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let pause_gate = PauseGate::default();
+        let params = LLMActorParams::builder()
+            .model(model)
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy {}),
+                ..SamplerConfig::default()
+            })
+            .stop_tokens(vec!["10".to_string()])
+            .pause_gate(pause_gate.clone())
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        pause_gate.pause();
         let stream = actor
             .generate_response("I'm going to count to 10: 1, 2, 3, 4,".to_string())
             .await;
 
-        let response = response_from_stream(stream).await.unwrap();
+        // the prompt is read, but nothing is generated while paused
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let mut receiver = stream.into_inner();
+        assert!(
+            receiver.try_recv().is_err(),
+            "Expected no tokens while paused"
+        );
 
+        pause_gate.resume();
+        let response = response_from_stream(receiver.into()).await.unwrap();
         assert!(
-            response.to_lowercase().contains("5, 6, "),
-            "Expected output to contain text before stop token. Got: {response}"
+            response.contains("5, 6, 7, 8, 9, 10"),
+            "Expected the response to continue after resuming, got: {response}"
         );
+    }
+
+    #[tokio::test]
+    async fn test_context_full_stop() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .n_ctx(64)
+            .stop_tokens(vec!["20".to_string()])
+            .ask_on_context_full(true)
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm going to count to 20: 1, 2, 3, 4, 5, 6, 7".to_string())
+            .await;
+
+        let mut n_context_full = 0;
+        let response = loop {
+            match stream.next().await.expect("Stream ended early").unwrap() {
+                WriteOutput::Token(..) => (),
+                WriteOutput::ContextFull(resolve_to) => {
+                    n_context_full += 1;
+                    resolve_to.send(OverflowStrategy::Stop).unwrap();
+                }
+                WriteOutput::ContextShifted(_) => panic!("Context should not be shifted"),
+                WriteOutput::AdjustLogits(..) => panic!("Logit processor is disabled"),
+                WriteOutput::Done(response, finish_reason, _) => {
+                    assert_eq!(finish_reason, FinishReason::ContextFull);
+                    break response;
+                }
+            }
+        };
+        assert_eq!(n_context_full, 1);
         assert!(
-            response.to_lowercase().ends_with("7"),
-            "Expected output to stop at stop token, but continued. Got: {response}"
+            !response.contains("20"),
+            "Expected the response to end when the context is full, got: {response}"
         );
+    }
+
+    #[tokio::test]
+    async fn test_time_limit() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .max_response_duration(Some(std::time::Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm going to count to 100: 1, 2, 3, 4, 5, 6, 7".to_string())
+            .await;
+        let (response, finish_reason) = loop {
+            let out = stream.next().await.expect("Stream ended early").unwrap();
+            if let WriteOutput::Done(response, finish_reason, _) = out {
+                break (response, finish_reason);
+            }
+        };
+        assert_eq!(finish_reason, FinishReason::TimeLimit);
         assert!(
-            !response.to_lowercase().contains("8"),
-            "Expected output to stop at stop token, but continued. Got: {response}"
+            !response.contains("100"),
+            "Expected the response to end early, got: {response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stop_token_finish_reason() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["fly".to_string(), "10".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm gonna count to 10: 1, 2, 3, ".to_string())
+            .await;
+        let finish_reason = loop {
+            let out = stream.next().await.expect("Stream ended early").unwrap();
+            if let WriteOutput::Done(_, finish_reason, _) = out {
+                break finish_reason;
+            }
+        };
+        assert_eq!(finish_reason, FinishReason::StopToken("10".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_response_tokens() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model.clone())
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm gonna count to 10: 1, 2, 3, ".to_string())
+            .await;
+        let (response, tokens) = loop {
+            let out = stream.next().await.expect("Stream ended early").unwrap();
+            if let WriteOutput::Done(response, _, tokens) = out {
+                break (response, tokens);
+            }
+        };
+        let detokenized: String = tokens
+            .iter()
+            .map(|token| {
+                model
+                    .token_to_str(LlamaToken::new(*token), Special::Tokenize)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(detokenized, response);
+    }
+
+    #[tokio::test]
+    async fn test_token_probabilities() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["10".to_string()])
+            .token_probabilities(true)
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm gonna count to 10: 1, 2, 3, ".to_string())
+            .await;
+        let mut probabilities = vec![];
+        while let Some(out) = stream.next().await {
+            match out.unwrap() {
+                WriteOutput::Token(_, probability) => {
+                    probabilities.push(probability.expect("Expected a probability"))
+                }
+                WriteOutput::Done(..) => break,
+                _ => (),
+            }
+        }
+        assert!(!probabilities.is_empty());
+        assert!(probabilities.iter().all(|p| (0.0..=1.0).contains(p)));
+        // counting is easy, so the model should be pretty sure of the first number
+        assert!(probabilities[0] > 0.5, "Got {probabilities:?}");
+    }
+
+    #[tokio::test]
+    async fn test_logit_processor() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec![",".to_string()])
+            .logit_processor_top_k(Some(5))
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let mut stream = actor
+            .generate_response("I'm going to count to 10: 1, 2, 3, 4,".to_string())
+            .await;
+        let response = loop {
+            match stream.next().await.expect("Stream ended early").unwrap() {
+                WriteOutput::AdjustLogits(candidates, resolve_to) => {
+                    assert_eq!(candidates.len(), 5);
+                    assert!(candidates.windows(2).all(|w| w[0].logit >= w[1].logit));
+                    // ban the next number
+                    let banned = candidates
+                        .iter()
+                        .filter(|c| c.text.contains('5'))
+                        .map(|c| (c.token, f32::NEG_INFINITY))
+                        .collect();
+                    resolve_to.send(banned).unwrap();
+                }
+                WriteOutput::Done(response, _, _) => break response,
+                _ => (),
+            }
+        };
+        assert!(
+            !response.contains('5'),
+            "Expected the banned tokens to be avoided, got: {response}"
         );
     }
 
+    #[test]
+    fn test_apply_context_shifting() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+
+        let ctx_params = LlamaContextParams::default().with_n_ctx(std::num::NonZero::new(128));
+        let mut ctx = model.new_context(&LLAMA_BACKEND, ctx_params).unwrap();
+
+        let tokens = model
+            .str_to_token("1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12", AddBos::Never)
+            .unwrap();
+        let n_past = tokens.len() as i32;
+        let mut batch = LlamaBatch::new(128, 1);
+        for (i, token) in (0..).zip(tokens.iter()) {
+            batch.add(*token, i, &[0], i == n_past - 1).unwrap();
+        }
+        ctx.decode(&mut batch).unwrap();
+        assert_eq!(ctx.get_kv_cache_token_count(), n_past);
+
+        let n_keep = 4;
+        let n_discard = apply_context_shifting(&mut ctx, n_past, n_keep).unwrap();
+        assert_eq!(n_discard, (n_past - n_keep) / 2);
+
+        // the discarded tokens are gone, and the rest were shifted left to close the gap
+        assert_eq!(ctx.get_kv_cache_token_count(), n_past - n_discard);
+        assert_eq!(ctx.kv_cache_seq_pos_max(0), n_past - n_discard - 1);
+
+        // the first `n_keep` tokens are still in place, so removing everything after them leaves exactly those
+        ctx.clear_kv_cache_seq(Some(0), Some(n_keep as u32), None)
+            .unwrap();
+        assert_eq!(ctx.get_kv_cache_token_count(), n_keep);
+        assert_eq!(ctx.kv_cache_seq_pos_max(0), n_keep - 1);
+    }
+
     #[tokio::test]
     async fn test_read_string_overrun() {
         // this test looks a bit silly, but we had a bug
@@ -900,13 +3392,11 @@ mod tests {
 
         let model = test_utils::load_test_model();
 
-        let params = LLMActorParams {
-            model,
-            sampler_config: SamplerConfig::default(),
-            n_ctx: 20,
-            stop_tokens: vec![],
-            use_embeddings: false,
-        };
+        let params = LLMActorParams::builder()
+            .model(model)
+            .n_ctx(20)
+            .build()
+            .unwrap();
         let actor = LLMActorHandle::new(params.clone()).await.unwrap();
 
         let () = actor.read("1, 2, 3,".to_string()).await.unwrap().unwrap();
@@ -916,4 +3406,228 @@ mod tests {
         let () = actor.read("1, 2, 3,".to_string()).await.unwrap().unwrap();
         let () = actor.read("1, 2, 3,".to_string()).await.unwrap().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_read_tokens() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let tokens = tokenize(&model, "I'm gonna count to 10: 1, 2, 3, ").unwrap();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["10".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        // tokens outside the vocabulary are rejected, and the worker keeps going
+        let invalid = actor.read_tokens(vec![-1]).await.unwrap();
+        assert!(matches!(
+            invalid,
+            Err(ReadError::InvalidToken { token: -1, .. })
+        ));
+
+        actor.read_tokens(tokens).await.unwrap().unwrap();
+        let stream = actor.generate_response(String::new()).await;
+        let response = response_from_stream(stream).await.unwrap();
+        assert!(
+            response.contains("4, 5, 6"),
+            "Expected the response to continue the tokens, got: {response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_rollback() {
+        crate::test_utils::init_test_tracing();
+
+        let model = test_utils::load_test_model();
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["Berlin".to_string(), "Copenhagen".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        let () = actor
+            .read("Some capitals of Europe:".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let checkpoint = actor.checkpoint().await.unwrap();
+        let () = actor
+            .read(" The capital of Denmark is called".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(actor.rollback(checkpoint).await.unwrap());
+
+        let response = response_from_stream(
+            actor
+                .generate_response(" The capital of Germany is called".to_string())
+                .await,
+        )
+        .await
+        .unwrap();
+        assert!(response.contains("Berlin"), "Got: {response}");
+
+        // a reset makes the checkpoint meaningless
+        actor.reset_context().await.unwrap();
+        assert!(!actor.rollback(checkpoint).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_truncate_to() {
+        crate::test_utils::init_test_tracing();
+
+        let model = test_utils::load_test_model();
+        let params = LLMActorParams::builder()
+            .model(model)
+            .stop_tokens(vec!["Berlin".to_string(), "Copenhagen".to_string()])
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params).await.unwrap();
+
+        // can't keep more than what was read
+        let err = actor.truncate_to(1).await.unwrap().unwrap_err();
+        assert!(matches!(err, TruncateError::OutOfRange { .. }));
+
+        // forgetting everything is as good as a reset
+        let () = actor
+            .read("The capital of Denmark is called".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let () = actor.truncate_to(0).await.unwrap().unwrap();
+
+        let response = response_from_stream(
+            actor
+                .generate_response("The capital of Germany is called".to_string())
+                .await,
+        )
+        .await
+        .unwrap();
+        assert!(
+            response.contains("Berlin"),
+            "Expected the Danish prompt to be forgotten, got: {response}"
+        );
+    }
+
+    #[test]
+    fn test_has_eog_token() {
+        let model = test_utils::load_test_model();
+        assert!(has_eog_token(&model), "The test model is an instruct model");
+    }
+
+    #[test]
+    fn test_defragment() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = LLMActorParams::builder()
+            .model(model)
+            .n_ctx(128)
+            .build()
+            .unwrap();
+        let mut state = WorkerState::new(&params).unwrap();
+
+        // shifting the context leaves a hole where the discarded tokens were
+        for _ in 0..4 {
+            state
+                .read_string(
+                    "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20".into(),
+                )
+                .unwrap();
+        }
+        assert!(
+            state.n_context_shifts > 0,
+            "Expected the context to be shifted"
+        );
+        let n_past = state.n_past;
+
+        let fragmentation = state.defragment();
+        assert!((0.0..=1.0).contains(&fragmentation));
+        assert!(state.inference.kv_cache_fragmentation() <= fragmentation);
+        // defragmenting moves the tokens around, but keeps all of them
+        assert_eq!(state.n_past, n_past);
+        assert_eq!(state.inference.ctx.get_kv_cache_token_count(), n_past);
+    }
+
+    #[test]
+    fn test_tokens_decoded_per_turn() {
+        test_utils::init_test_tracing();
+        let model = test_utils::load_test_model();
+        let params = LLMActorParams::builder()
+            .model(model.clone())
+            .sampler_config(SamplerConfig {
+                method: SamplerMethod::Greedy(Greedy::default()),
+                ..SamplerConfig::default()
+            })
+            .build()
+            .unwrap();
+        let mut state = WorkerState::new(&params).unwrap();
+        let mut chat_state = crate::chat_state::ChatState::from_model(&model).unwrap();
+        let count_tokens =
+            |text: &str| model.str_to_token(text, AddBos::Never).unwrap().len() as i32;
+
+        // the first turn reads the prompt, and then every generated token except the final EOG token
+        chat_state.add_message("user".into(), "What is the capital of Denmark?".into());
+        let diff = chat_state.render_diff().unwrap();
+        let n_prompt = count_tokens(&diff);
+        state.read_string(diff).unwrap();
+        assert_eq!(state.n_past, n_prompt);
+
+        let n_generated = std::cell::Cell::new(0);
+        let response = std::cell::RefCell::new(String::new());
+        state
+            .write_until_done(|out| match out {
+                WriteOutput::Token(..) => n_generated.set(n_generated.get() + 1),
+                WriteOutput::Done(resp, _, _) => *response.borrow_mut() = resp,
+                WriteOutput::ContextFull(_) | WriteOutput::ContextShifted(_) => {
+                    panic!("Context should not fill up")
+                }
+                WriteOutput::AdjustLogits(..) => panic!("Logit processor is disabled"),
+            })
+            .unwrap();
+        assert_eq!(state.n_past, n_prompt + n_generated.get());
+
+        // the next turn only reads what is new since then: the end of the last turn, and the new message
+        let response = response.into_inner();
+        chat_state.add_message("assistant".into(), response.clone());
+        chat_state.mark_response_read().unwrap();
+        chat_state.add_message("user".into(), "And what about Germany?".into());
+        let diff = chat_state.render_diff().unwrap();
+        assert!(
+            !diff.contains(response.trim()),
+            "Expected the response not to be read again, got: {diff}"
+        );
+        let n_diff = count_tokens(&diff);
+        state.read_string(diff).unwrap();
+        assert_eq!(state.n_past, n_prompt + n_generated.get() + n_diff);
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_failed_read() {
+        crate::test_utils::init_test_tracing();
+
+        let model = test_utils::load_test_model();
+
+        let params = LLMActorParams::builder()
+            .model(model)
+            .n_ctx(20)
+            .build()
+            .unwrap();
+        let actor = LLMActorHandle::new(params.clone()).await.unwrap();
+
+        let () = actor.read("1, 2, 3,".to_string()).await.unwrap().unwrap();
+
+        // too long to ever fit in the context
+        let err = actor
+            .read("1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15".to_string())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(err.is_recoverable(), "Expected {err} to be recoverable");
+
+        // the worker is still alive
+        let () = actor.read("4, 5, 6,".to_string()).await.unwrap().unwrap();
+    }
 }
@@ -0,0 +1,407 @@
+//! A stand-in for the model and its context, for testing the worker and the code around it without a model file.
+//! Enable the `mock` feature to use it outside of this crate's tests.
+
+use super::{
+    context_shift_n_discard, run_worker, EogBehavior, Inference, InitWorkerError, LLMActorHandle,
+    PauseGate, StartWorker, Tokenizer, WorkerState, DEFAULT_MAX_BUFFERED_TOKENS,
+};
+use crate::sampler_config::{Sampler, SamplerConfig};
+use llama_cpp_2::model::AddBos;
+use llama_cpp_2::token::LlamaToken;
+use std::sync::{Arc, Mutex};
+
+/// The text of the end-of-generation token, which always has id 0.
+const EOG_TOKEN: &str = "</s>";
+
+/// The logit of the next token of the scripted response. Every other token gets 0.
+const SCRIPTED_LOGIT: f32 = 100.0;
+
+/// The length of the mock embeddings.
+const EMBEDDING_SIZE: usize = 16;
+
+/// Splits text into tokens the way the mock worker does: every token is a word, with the whitespace before it.
+/// "Hello there,  you" becomes `["Hello", " there,", "  you"]`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut after_word = false;
+    for c in text.chars() {
+        match tokens.last_mut() {
+            Some(token) if !(c.is_whitespace() && after_word) => token.push(c),
+            _ => tokens.push(c.to_string()),
+        }
+        after_word = !c.is_whitespace();
+    }
+    tokens
+}
+
+/// A vocabulary of words, split off with `tokenize`. Every new word becomes a token when it is first tokenized,
+/// so ids depend on the order text is seen in. Clones share the vocabulary.
+#[derive(Clone, Debug)]
+pub struct MockModel {
+    vocab: Arc<Mutex<Vec<String>>>,
+}
+
+impl Default for MockModel {
+    fn default() -> Self {
+        Self {
+            vocab: Arc::new(Mutex::new(vec![EOG_TOKEN.to_string()])),
+        }
+    }
+}
+
+impl Tokenizer for MockModel {
+    /// There is no BOS token, so `add_bos` is ignored.
+    fn tokenize(
+        &self,
+        text: &str,
+        _add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, llama_cpp_2::StringToTokenError> {
+        let mut vocab = self.vocab.lock().unwrap();
+        let tokens = tokenize(text)
+            .into_iter()
+            .map(|word| match vocab.iter().position(|known| *known == word) {
+                Some(id) => LlamaToken::new(id as i32),
+                None => {
+                    vocab.push(word);
+                    LlamaToken::new(vocab.len() as i32 - 1)
+                }
+            })
+            .collect();
+        Ok(tokens)
+    }
+
+    fn token_text(&self, token: LlamaToken) -> String {
+        let vocab = self.vocab.lock().unwrap();
+        vocab.get(token.0 as usize).cloned().unwrap_or_default()
+    }
+
+    fn is_eog(&self, token: LlamaToken) -> bool {
+        token.0 == 0
+    }
+
+    fn n_vocab(&self) -> i32 {
+        self.vocab.lock().unwrap().len() as i32
+    }
+}
+
+/// Stands in for a llama.cpp context. Its logits always point at the next token of the scripted response,
+/// and at the end-of-generation token once the response is written.
+/// Decoding that token writes it, and decoding anything else ends the response, so the next one is scripted.
+#[derive(Debug)]
+struct MockInference {
+    model: MockModel,
+    n_ctx: u32,
+    responses: Vec<String>,
+    n_responses: usize,
+    script: Vec<LlamaToken>,
+    /// How much of the script was decoded.
+    n_written: usize,
+    logits: Vec<f32>,
+    /// How often each token id was decoded since the context was cleared, modulo `EMBEDDING_SIZE`.
+    embedding: Vec<f32>,
+}
+
+impl MockInference {
+    fn next_token(&self) -> LlamaToken {
+        self.script
+            .get(self.n_written)
+            .copied()
+            .unwrap_or(LlamaToken::new(0))
+    }
+
+    /// Moves on to the next scripted response, unless the current one hasn't started yet.
+    fn end_response(&mut self) {
+        if self.n_written > 0 || self.script.is_empty() {
+            let response = match self.responses.len() {
+                0 => "",
+                n => self.responses[self.n_responses % n].as_str(),
+            };
+            self.script = self
+                .model
+                .tokenize(response, AddBos::Never)
+                .unwrap_or_default();
+            self.n_responses += 1;
+        }
+        self.n_written = 0;
+        self.update_logits();
+    }
+
+    fn update_logits(&mut self) {
+        self.logits = vec![0.0; self.model.n_vocab() as usize];
+        self.logits[self.next_token().0 as usize] = SCRIPTED_LOGIT;
+    }
+}
+
+impl Inference for MockInference {
+    fn tokenizer(&self) -> &dyn Tokenizer {
+        &self.model
+    }
+
+    fn n_ctx(&self) -> u32 {
+        self.n_ctx
+    }
+
+    fn decode<E>(&mut self, tokens: &[LlamaToken], n_past: i32) -> Result<(), E>
+    where
+        E: From<llama_cpp_2::llama_batch::BatchAddError> + From<llama_cpp_2::DecodeError>,
+    {
+        // llama.cpp finds no room for tokens past the end of the context
+        if n_past as usize + tokens.len() > self.n_ctx as usize {
+            return Err(llama_cpp_2::DecodeError::NoKvCacheSlot.into());
+        }
+        for token in tokens {
+            self.embedding[token.0 as usize % EMBEDDING_SIZE] += 1.0;
+        }
+        if tokens.len() == 1 && self.n_written < self.script.len() && tokens[0] == self.next_token()
+        {
+            self.n_written += 1;
+            self.update_logits();
+        } else {
+            self.end_response();
+        }
+        Ok(())
+    }
+
+    fn logits(&self) -> &[f32] {
+        &self.logits
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.embedding = vec![0.0; EMBEDDING_SIZE];
+        self.end_response();
+    }
+
+    fn truncate_kv_cache(
+        &mut self,
+        _n_tokens: u32,
+    ) -> Result<(), llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        self.end_response();
+        Ok(())
+    }
+
+    fn shift_kv_cache(
+        &mut self,
+        n_past: i32,
+        n_keep: i32,
+    ) -> Result<i32, llama_cpp_2::context::kv_cache::KvCacheConversionError> {
+        Ok(context_shift_n_discard(n_past, n_keep))
+    }
+
+    fn kv_cache_fragmentation(&self) -> f32 {
+        0.0
+    }
+
+    fn defragment_kv_cache(&mut self) {}
+
+    fn embedding(&self) -> Result<Vec<f32>, llama_cpp_2::EmbeddingsError> {
+        Ok(self.embedding.clone())
+    }
+
+    fn make_sampler(&self, _sampler_config: SamplerConfig) -> Sampler {
+        Sampler::greedy()
+    }
+}
+
+/// A worker that writes scripted responses instead of running an LLM, on a `MockModel` that tokenizes with `tokenize`.
+/// Only the model and its context are mocked: reading, writing, context shifting, stop tokens and the rest
+/// are handled by the real worker code, so the code that depends on those can be tested anywhere.
+///
+/// The responses are written in order, starting over after the last one. Sampler settings are ignored,
+/// as the scripted token is always picked. Embeddings count how often each token was read.
+#[derive(Clone, Debug)]
+pub struct MockWorker {
+    model: MockModel,
+    responses: Vec<String>,
+    n_ctx: u32,
+    stop_tokens: Vec<String>,
+    ask_on_context_full: bool,
+}
+
+impl MockWorker {
+    pub fn new(responses: Vec<String>) -> Self {
+        Self {
+            model: MockModel::default(),
+            responses,
+            n_ctx: 4096,
+            stop_tokens: Vec::new(),
+            ask_on_context_full: false,
+        }
+    }
+
+    pub fn n_ctx(mut self, n_ctx: u32) -> Self {
+        self.n_ctx = n_ctx;
+        self
+    }
+
+    pub fn stop_tokens(mut self, stop_tokens: Vec<String>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    pub fn ask_on_context_full(mut self, ask_on_context_full: bool) -> Self {
+        self.ask_on_context_full = ask_on_context_full;
+        self
+    }
+
+    /// The model the worker tokenizes with. Workers spawned from clones of this one share it.
+    pub fn model(&self) -> &MockModel {
+        &self.model
+    }
+
+    /// Starts the worker on its own thread, like `LLMActorHandle::new` does.
+    pub fn spawn(self) -> LLMActorHandle {
+        let (message_tx, message_rx) = std::sync::mpsc::channel();
+        let n_ctx = self.n_ctx;
+        // like the real worker state, the sampler has to stay on the worker thread
+        std::thread::spawn(move || run_worker(self.into_state(), message_rx));
+        LLMActorHandle {
+            message_tx,
+            max_buffered_tokens: DEFAULT_MAX_BUFFERED_TOKENS,
+            n_ctx,
+        }
+    }
+
+    fn into_state(self) -> WorkerState<'static, MockInference> {
+        let mut inference = MockInference {
+            model: self.model,
+            n_ctx: self.n_ctx,
+            responses: self.responses,
+            n_responses: 0,
+            script: Vec::new(),
+            n_written: 0,
+            logits: Vec::new(),
+            embedding: vec![0.0; EMBEDDING_SIZE],
+        };
+        inference.end_response();
+        WorkerState {
+            n_past: 0,
+            n_context_shifts: 0,
+            n_unreported_discarded: 0,
+            n_resets: 0,
+            inference,
+            sampler_config: SamplerConfig::default(),
+            sampler: Sampler::greedy(),
+            stop_tokens: self.stop_tokens,
+            eog_behavior: EogBehavior::default(),
+            ask_on_context_full: self.ask_on_context_full,
+            max_response_duration: None,
+            logit_processor_top_k: None,
+            token_probabilities: false,
+            auto_defrag_threshold: None,
+            add_bos: AddBos::Never,
+            guidance: None,
+            pause_gate: PauseGate::default(),
+        }
+    }
+}
+
+impl StartWorker for MockWorker {
+    fn tokenizer(&self) -> &dyn Tokenizer {
+        &self.model
+    }
+
+    /// Spawns a clone of this worker, with a context of `n_ctx` tokens if given. The sampler settings are ignored.
+    async fn start(
+        &self,
+        n_ctx: Option<u32>,
+        _sampler_config: SamplerConfig,
+    ) -> Result<LLMActorHandle, InitWorkerError> {
+        let worker = self.clone();
+        Ok(match n_ctx {
+            Some(n_ctx) => worker.n_ctx(n_ctx),
+            None => worker,
+        }
+        .spawn())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{FinishReason, WriteOutput};
+    use tokio_stream::StreamExt;
+
+    /// Collects everything the worker sends for one response, with the tokens joined.
+    async fn collect_response(
+        actor: &LLMActorHandle,
+        text: &str,
+    ) -> (Vec<u32>, String, FinishReason) {
+        let mut stream = actor.generate_response(text.to_string()).await;
+        let mut shifts = Vec::new();
+        let mut streamed = String::new();
+        while let Some(out) = stream.next().await {
+            match out.unwrap() {
+                WriteOutput::Token(token, _) => streamed.push_str(&token),
+                WriteOutput::ContextShifted(n_discarded) => shifts.push(n_discarded),
+                WriteOutput::Done(response, finish_reason, _) => {
+                    assert_eq!(response, streamed);
+                    return (shifts, response, finish_reason);
+                }
+                other => panic!("Unexpected output: {other:?}"),
+            }
+        }
+        panic!("The worker stopped before the response was done")
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Hello there,  you"), ["Hello", " there,", "  you"]);
+        assert_eq!(tokenize(" leading"), [" leading"]);
+        assert_eq!(tokenize("trailing\n"), ["trailing", "\n"]);
+        assert!(tokenize("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_stop_tokens() {
+        let actor = MockWorker::new(vec!["One two. Three four.".to_string()])
+            .stop_tokens(vec![".".to_string()])
+            .spawn();
+        let (_, response, finish_reason) = collect_response(&actor, "Count.").await;
+        assert_eq!(response, "One two.");
+        assert_eq!(finish_reason, FinishReason::StopToken(".".to_string()));
+        // "Count." and "One two." were read
+        assert_eq!(actor.checkpoint().await.unwrap().n_past(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_context_shifting() {
+        let actor = MockWorker::new(vec!["a".to_string()]).n_ctx(8).spawn();
+
+        // the prompt and the response fill the context, so it is shifted before the end of the response
+        let (shifts, response, finish_reason) = collect_response(&actor, "1 2 3 4 5 6").await;
+        assert_eq!(shifts, [3]);
+        assert_eq!(response, "a");
+        assert_eq!(finish_reason, FinishReason::Eog);
+        assert_eq!(actor.checkpoint().await.unwrap().n_past(), 7 - 3);
+
+        // shifts while reading the prompt are reported when the response starts
+        actor.read("1 2 3 4".to_string()).await.unwrap().unwrap();
+        let (shifts, _, _) = collect_response(&actor, "5").await;
+        assert_eq!(shifts, [4]);
+        assert_eq!(actor.checkpoint().await.unwrap().n_past(), 8 - 4 + 1 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_rollback_after_shift() {
+        let actor = MockWorker::new(vec!["a b c".to_string()]).n_ctx(8).spawn();
+        actor.read("1 2 3".to_string()).await.unwrap().unwrap();
+        let responses = actor
+            .generate_responses("4 5".to_string(), 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(responses.responses, ["a b c"]);
+        assert!(responses.context_cleared);
+    }
+
+    #[tokio::test]
+    async fn test_mock_embedding() {
+        let actor = MockWorker::new(vec![]).spawn();
+        let embedding = actor.generate_embedding("a b a".to_string()).await.unwrap();
+        assert_eq!(embedding.len(), EMBEDDING_SIZE);
+        assert_eq!(embedding.iter().sum::<f32>(), 3.0);
+        // the context is cleared after every embedding, so the same text gets the same embedding
+        let again = actor.generate_embedding("a b a".to_string()).await.unwrap();
+        assert_eq!(embedding, again);
+    }
+}
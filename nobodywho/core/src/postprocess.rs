@@ -0,0 +1,206 @@
+//! Steps for cleaning up responses before they are shown, e.g. removing markdown that the game can't display.
+//! They only change the emitted response. The chat history keeps the response as the LLM wrote it,
+//! since the LLM has already read it that way.
+//! The same steps can normalize texts before they are embedded, see `EmbeddingParams::preprocess`.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+#[derive(Clone, Debug)]
+pub enum PostProcessStep {
+    /// Removes whitespace from the start and end.
+    Trim,
+    /// Removes markdown formatting (headings, emphasis, code, links and list bullets), keeping the text itself.
+    StripMarkdown,
+    /// Makes the first letter uppercase.
+    Capitalize,
+    /// Makes every letter lowercase.
+    Lowercase,
+    /// Removes punctuation, like periods, commas, quotes and exclamation marks.
+    StripPunctuation,
+    /// Replaces every run of whitespace with a single space, and removes it from the start and end.
+    CollapseWhitespace,
+    /// Replaces every match of the pattern. The replacement can refer to capture groups, like `$1`.
+    RegexReplace { pattern: Regex, replacement: String },
+    /// Removes every occurrence of the strings, e.g. special tokens like `<|im_end|>` that leaked into the text.
+    RemoveStrings(Vec<String>),
+}
+
+static PUNCTUATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\p{P}+").unwrap());
+
+/// Markdown syntax and what to replace it with, in the order they are applied.
+static MARKDOWN: LazyLock<Vec<(Regex, &str)>> = LazyLock::new(|| {
+    [
+        (r"(?m)^```[^\n]*\n?", ""),
+        (r"`([^`\n]*)`", "$1"),
+        (r"(?m)^[ \t]*#{1,6}[ \t]+", ""),
+        (r"(?m)^[ \t]*[-*+][ \t]+", ""),
+        (r"\*\*([^*\n]+)\*\*", "$1"),
+        (r"__([^_\n]+)__", "$1"),
+        (r"\*([^*\n]+)\*", "$1"),
+        (r"!?\[([^\]\n]*)\]\([^)\n]*\)", "$1"),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), replacement))
+    .collect()
+});
+
+impl PostProcessStep {
+    pub fn regex_replace(pattern: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(PostProcessStep::RegexReplace {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            PostProcessStep::Trim => text.trim().to_string(),
+            PostProcessStep::StripMarkdown => {
+                MARKDOWN
+                    .iter()
+                    .fold(text.to_string(), |text, (pattern, replacement)| {
+                        pattern.replace_all(&text, *replacement).into_owned()
+                    })
+            }
+            PostProcessStep::Capitalize => {
+                let mut chars = text.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            }
+            PostProcessStep::Lowercase => text.to_lowercase(),
+            PostProcessStep::StripPunctuation => PUNCTUATION.replace_all(text, "").into_owned(),
+            PostProcessStep::CollapseWhitespace => {
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            }
+            PostProcessStep::RegexReplace {
+                pattern,
+                replacement,
+            } => pattern.replace_all(text, replacement.as_str()).into_owned(),
+            PostProcessStep::RemoveStrings(strings) => strings
+                .iter()
+                .filter(|string| !string.is_empty())
+                .fold(text.to_string(), |text, string| {
+                    text.replace(string.as_str(), "")
+                }),
+        }
+    }
+}
+
+/// Removes strings from text that arrives a piece at a time, like a streamed response.
+/// The strings are often split over several tokens, so text that could be the start of one
+/// is held back until it is clear whether it is.
+#[derive(Clone, Debug, Default)]
+pub struct StringStripper {
+    strings: Vec<String>,
+    pending: String,
+}
+
+impl StringStripper {
+    pub fn new(strings: Vec<String>) -> Self {
+        Self {
+            strings: strings.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Reads the next piece of text, and returns the text before it that is known to be clean.
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        for string in &self.strings {
+            if self.pending.contains(string.as_str()) {
+                self.pending = self.pending.replace(string.as_str(), "");
+            }
+        }
+        // hold back the end of the text, if one of the strings starts with it
+        let held_back = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let end = &self.pending[i..];
+                self.strings.iter().any(|string| string.starts_with(end))
+            })
+            .unwrap_or(self.pending.len());
+        let rest = self.pending.split_off(held_back);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Ends the text, and returns whatever was still held back.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Applies the steps to the response, in order.
+pub fn apply_all(steps: &[PostProcessStep], response: &str) -> String {
+    steps
+        .iter()
+        .fold(response.to_string(), |text, step| step.apply(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_markdown() {
+        let markdown = "# Potions\n\n- **Red**: heals you\n- *Blue*: see the [wiki](https://example.com)\n\nUse `drink` to drink.";
+        assert_eq!(
+            PostProcessStep::StripMarkdown.apply(markdown),
+            "Potions\n\nRed: heals you\nBlue: see the wiki\n\nUse drink to drink."
+        );
+    }
+
+    #[test]
+    fn test_string_stripper() {
+        let strings = vec!["<|im_end|>".to_string(), "<|endoftext|>".to_string()];
+        let response = "Welcome, traveler!<|im_end|> Need a <|room?<|endoftext|>";
+        for size in [1, 2, 3, 7, 100] {
+            let mut stripper = StringStripper::new(strings.clone());
+            let chars: Vec<char> = response.chars().collect();
+            let mut stripped: String = chars
+                .chunks(size)
+                .map(|chunk| stripper.push(&chunk.iter().collect::<String>()))
+                .collect();
+            stripped.push_str(&stripper.finish());
+            assert_eq!(
+                stripped, "Welcome, traveler! Need a <|room?",
+                "pieces of {size}"
+            );
+        }
+        assert_eq!(
+            PostProcessStep::RemoveStrings(strings).apply(response),
+            "Welcome, traveler! Need a <|room?"
+        );
+    }
+
+    #[test]
+    fn test_normalize_input() {
+        let steps = [
+            PostProcessStep::Lowercase,
+            PostProcessStep::StripPunctuation,
+            PostProcessStep::CollapseWhitespace,
+        ];
+        assert_eq!(
+            apply_all(&steps, "  BUY the   Red Potion!!\n¿Now? "),
+            "buy the red potion now"
+        );
+    }
+
+    #[test]
+    fn test_apply_all() {
+        let steps = [
+            PostProcessStep::regex_replace(r"^\s*Guard:\s*", "").unwrap(),
+            PostProcessStep::Trim,
+            PostProcessStep::Capitalize,
+        ];
+        assert_eq!(
+            apply_all(&steps, "  Guard: halt! who goes there?\n"),
+            "Halt! who goes there?"
+        );
+        assert_eq!(apply_all(&[], " unchanged "), " unchanged ");
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// One response of a recorded chat.
+///
+/// # Fields
+/// * `message` - The user message that was answered
+/// * `tokens` - The response, split into tokens in the order they were generated
+/// * `response` - The full response, as sent when the response was finished
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub message: String,
+    pub tokens: Vec<String>,
+    pub response: String,
+}
+
+/// The responses of a chat, token by token, so they can be replayed without running the LLM.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatRecording {
+    pub responses: Vec<RecordedResponse>,
+}
+
+/// What the chat loop does with the responses it generates.
+#[derive(Clone, Debug)]
+pub enum RecordingMode {
+    /// Write every response to a recording file at this path.
+    Record(PathBuf),
+    /// Check every response against this recording, token by token.
+    /// This only makes sense with a sampler that has a fixed seed.
+    Verify(ChatRecording),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("Could not read or write recording file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse recording file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl ChatRecording {
+    pub fn load(path: &Path) -> Result<Self, RecordingError> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RecordingError> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Compares a response with the recorded response at the same position in the chat.
+    /// Returns a description of the first difference, if any.
+    pub fn verify(&self, index: usize, actual: &RecordedResponse) -> Result<(), String> {
+        let Some(expected) = self.responses.get(index) else {
+            return Err(format!(
+                "Recording has {} responses, but got response number {}",
+                self.responses.len(),
+                index + 1
+            ));
+        };
+        if expected.message != actual.message {
+            return Err(format!(
+                "Response {} answers a different message. Recorded: {:?}, got: {:?}",
+                index + 1,
+                expected.message,
+                actual.message
+            ));
+        }
+        if let Some(i) = (0..expected.tokens.len().max(actual.tokens.len()))
+            .find(|&i| expected.tokens.get(i) != actual.tokens.get(i))
+        {
+            return Err(format!(
+                "Response {} differs at token {i}. Recorded: {:?}, got: {:?}",
+                index + 1,
+                expected.tokens.get(i),
+                actual.tokens.get(i)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(message: &str, tokens: &[&str]) -> RecordedResponse {
+        RecordedResponse {
+            message: message.into(),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            response: tokens.concat(),
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let recording = ChatRecording {
+            responses: vec![recorded("Hi!", &["Hel", "lo", " there", "\n🦜"])],
+        };
+        let path = std::env::temp_dir().join("nobodywho_test_recording.json");
+        recording.save(&path).unwrap();
+        assert_eq!(ChatRecording::load(&path).unwrap(), recording);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let recording = ChatRecording {
+            responses: vec![recorded("Hi!", &["Hel", "lo"])],
+        };
+        assert!(recording
+            .verify(0, &recorded("Hi!", &["Hel", "lo"]))
+            .is_ok());
+        assert!(recording
+            .verify(0, &recorded("Hi!", &["Hel", "lo", "!"]))
+            .is_err());
+        assert!(recording.verify(0, &recorded("Hi!", &["Hello"])).is_err());
+        assert!(recording
+            .verify(0, &recorded("Bye!", &["Hel", "lo"]))
+            .is_err());
+        assert!(recording
+            .verify(1, &recorded("Hi!", &["Hel", "lo"]))
+            .is_err());
+    }
+}
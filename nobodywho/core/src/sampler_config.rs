@@ -1,18 +1,29 @@
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data::LlamaTokenData;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::LlamaToken;
+use tracing::{debug, warn};
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct SamplerConfig {
     pub method: SamplerMethod,
+    /// How many of the latest tokens the repetition penalties look at. -1 means the whole context, and 0 disables them.
+    /// Windows larger than the context are clamped to it. Other negative values are rejected when the worker starts.
     pub penalty_last_n: i32,
+    /// Divides the odds of tokens that were seen recently. 1.0 disables it.
     pub penalty_repeat: f32,
     pub penalty_freq: f32,
     pub penalty_present: f32,
     pub use_grammar: bool,
     pub gbnf_grammar: String,
+    /// The least probability the most likely token keeps, between 0.0 and 1.0, see `apply_top_probability_floor`.
+    /// It is applied last, right before the token is picked. This keeps high temperatures from going completely off the rails.
+    /// 0.0 disables it.
+    pub top_probability_floor: f32,
 }
 
-const JSON_GRAMMAR: &str = r#"# this default gbnf grammar forces valid json output
+pub const JSON_GRAMMAR: &str = r#"# this default gbnf grammar forces valid json output
 root   ::= object
 value  ::= object | array | string | number | ("true" | "false" | "null") ws
 
@@ -43,11 +54,12 @@ impl Default for SamplerConfig {
     fn default() -> Self {
         Self {
             penalty_last_n: -1,
-            penalty_repeat: 0.0,
+            penalty_repeat: 1.0,
             penalty_freq: 0.0,
             penalty_present: 0.0,
             use_grammar: false,
             gbnf_grammar: JSON_GRAMMAR.into(),
+            top_probability_floor: 0.0,
             method: SamplerMethod::MirostatV2(MirostatV2 {
                 seed: 1234,
                 temperature: 0.8,
@@ -58,6 +70,121 @@ impl Default for SamplerConfig {
     }
 }
 
+impl SamplerConfig {
+    /// Returns the same configuration with the seed moved by `offset`, e.g. to get several different responses to one prompt.
+    /// Greedy sampling has no seed, so it is returned unchanged.
+    pub fn with_seed_offset(&self, offset: u32) -> Self {
+        let mut config = self.clone();
+        if let Some(seed) = config.seed_mut() {
+            *seed = seed.wrapping_add(offset);
+        }
+        config
+    }
+
+    /// Returns the same configuration with a different seed, whichever sampler method it uses.
+    /// Greedy sampling has no seed, so it is returned unchanged.
+    pub fn with_seed(&self, new_seed: u32) -> Self {
+        let mut config = self.clone();
+        if let Some(seed) = config.seed_mut() {
+            *seed = new_seed;
+        }
+        config
+    }
+
+    fn seed_mut(&mut self) -> Option<&mut u32> {
+        match &mut self.method {
+            SamplerMethod::Greedy(_) => None,
+            SamplerMethod::DRY(conf) => Some(&mut conf.seed),
+            SamplerMethod::TopK(conf) => Some(&mut conf.seed),
+            SamplerMethod::TopP(conf) => Some(&mut conf.seed),
+            SamplerMethod::MinP(conf) => Some(&mut conf.seed),
+            SamplerMethod::XTC(conf) => Some(&mut conf.seed),
+            SamplerMethod::TypicalP(conf) => Some(&mut conf.seed),
+            SamplerMethod::Temperature(conf) => Some(&mut conf.seed),
+            SamplerMethod::MirostatV1(conf) => Some(&mut conf.seed),
+            SamplerMethod::MirostatV2(conf) => Some(&mut conf.seed),
+            SamplerMethod::Balanced(conf) => Some(&mut conf.seed),
+        }
+    }
+
+    /// Reads the recommended sampler settings that some GGUF files include in their metadata, under `general.sampling.*`.
+    /// Returns `None` if the model doesn't recommend any.
+    ///
+    /// Each sampler method here applies a single filter, so only the first one the metadata sets is used,
+    /// in the order mirostat, min_p, top_p, top_k. The temperature is used along with it, or on its own.
+    pub fn from_model_metadata(model: &LlamaModel) -> Option<Self> {
+        Self::from_metadata(|key| model.meta_val_str(&format!("general.sampling.{key}")).ok())
+    }
+
+    fn from_metadata(get: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let get_f32 = |key: &str| get(key).and_then(|val| val.trim().parse::<f32>().ok());
+        let get_i32 = |key: &str| get(key).and_then(|val| val.trim().parse::<i32>().ok());
+        let seed = 1234;
+
+        let temperature = get_f32("temp");
+        let filter_temperature = temperature.unwrap_or(1.0);
+        let method = match get_i32("mirostat") {
+            Some(1) => Some(SamplerMethod::MirostatV1(MirostatV1 {
+                seed,
+                temperature: temperature.unwrap_or(MirostatV1::default().temperature),
+                tau: get_f32("mirostat_tau").unwrap_or(MirostatV1::default().tau),
+                eta: get_f32("mirostat_eta").unwrap_or(MirostatV1::default().eta),
+            })),
+            Some(2) => Some(SamplerMethod::MirostatV2(MirostatV2 {
+                seed,
+                temperature: temperature.unwrap_or(MirostatV2::default().temperature),
+                tau: get_f32("mirostat_tau").unwrap_or(MirostatV2::default().tau),
+                eta: get_f32("mirostat_eta").unwrap_or(MirostatV2::default().eta),
+            })),
+            _ => None,
+        }
+        .or_else(|| {
+            get_f32("min_p").map(|min_p| {
+                SamplerMethod::MinP(MinP {
+                    min_p,
+                    temperature: filter_temperature,
+                    ..MinP::default()
+                })
+            })
+        })
+        .or_else(|| {
+            get_f32("top_p").map(|top_p| {
+                SamplerMethod::TopP(TopP {
+                    top_p,
+                    temperature: filter_temperature,
+                    ..TopP::default()
+                })
+            })
+        })
+        .or_else(|| {
+            get_i32("top_k").map(|top_k| {
+                SamplerMethod::TopK(TopK {
+                    top_k,
+                    seed,
+                    temperature: filter_temperature,
+                })
+            })
+        })
+        .or_else(|| {
+            temperature
+                .map(|temperature| SamplerMethod::Temperature(Temperature { seed, temperature }))
+        });
+
+        let penalty_repeat = get_f32("penalty_repeat");
+        let penalty_last_n = get_i32("penalty_last_n");
+        if method.is_none() && penalty_repeat.is_none() {
+            return None;
+        }
+        let default = Self::default();
+        Some(Self {
+            method: method.unwrap_or(default.method),
+            penalty_repeat: penalty_repeat.unwrap_or(default.penalty_repeat),
+            penalty_last_n: penalty_last_n.unwrap_or(default.penalty_last_n),
+            ..default
+        })
+    }
+}
+
 /// ----- Sampler Methods -----
 
 #[derive(Clone, Debug)]
@@ -72,6 +199,7 @@ pub enum SamplerMethod {
     Temperature(Temperature),
     MirostatV1(MirostatV1),
     MirostatV2(MirostatV2),
+    Balanced(Balanced),
 }
 
 #[derive(Clone, Debug)]
@@ -108,6 +236,8 @@ impl Default for DRY {
 pub struct TopK {
     pub top_k: i32,
     pub seed: u32,
+    /// Applied after the filter. 1.0 leaves the probabilities as they are.
+    pub temperature: f32,
 }
 
 impl Default for TopK {
@@ -115,6 +245,7 @@ impl Default for TopK {
         Self {
             top_k: 40,
             seed: 1234,
+            temperature: 1.0,
         }
     }
 }
@@ -124,6 +255,8 @@ pub struct TopP {
     pub seed: u32,
     pub min_keep: u32,
     pub top_p: f32,
+    /// Applied after the filter. 1.0 leaves the probabilities as they are.
+    pub temperature: f32,
 }
 
 impl Default for TopP {
@@ -132,6 +265,7 @@ impl Default for TopP {
             seed: 1234,
             top_p: 0.95,
             min_keep: 0,
+            temperature: 1.0,
         }
     }
 }
@@ -141,6 +275,8 @@ pub struct MinP {
     pub seed: u32,
     pub min_keep: u32,
     pub min_p: f32,
+    /// Applied after the filter. 1.0 leaves the probabilities as they are.
+    pub temperature: f32,
 }
 
 impl Default for MinP {
@@ -149,6 +285,7 @@ impl Default for MinP {
             seed: 1234,
             min_p: 0.05,
             min_keep: 0,
+            temperature: 1.0,
         }
     }
 }
@@ -242,7 +379,129 @@ impl Default for MirostatV2 {
     }
 }
 
-pub fn make_sampler(model: &LlamaModel, sampler_config: SamplerConfig) -> LlamaSampler {
+/// A preset that works well with most models: min-p filtering, a mild repetition penalty,
+/// and a temperature set by a single `creativity` value between 0 and 1.
+/// The penalty settings of `SamplerConfig` are not used with this preset.
+#[derive(Clone, Debug)]
+pub struct Balanced {
+    pub seed: u32,
+    pub creativity: f32,
+}
+
+impl Default for Balanced {
+    fn default() -> Self {
+        Self {
+            seed: 1234,
+            creativity: 0.5,
+        }
+    }
+}
+
+impl Balanced {
+    /// Maps `creativity` from 0..1 to a temperature from 0.2 (focused) to 1.2 (creative).
+    pub fn temperature(&self) -> f32 {
+        0.2 + self.creativity.clamp(0.0, 1.0)
+    }
+}
+
+/// Raises the logit of the most likely token just enough that, after `temperature` is applied,
+/// it keeps at least `floor` of the probability. The other tokens keep their odds relative to each other,
+/// so sampling still varies among them, but total nonsense gets rare. Does nothing if the token is likely enough already.
+pub fn apply_top_probability_floor(logits: &mut [f32], floor: f32, temperature: f32) {
+    // a floor of 1.0 would need an infinite logit, which breaks the softmax
+    let floor = floor.min(0.999);
+    if floor <= 0.0 || temperature <= 0.0 {
+        return;
+    }
+    let Some((top, &top_logit)) = logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    else {
+        return;
+    };
+    // the weight of every other token, relative to the top one
+    let rest: f32 = logits
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| *id != top)
+        .map(|(_, logit)| ((logit - top_logit) / temperature).exp())
+        .sum();
+    if 1.0 / (1.0 + rest) >= floor {
+        return;
+    }
+    logits[top] = top_logit + temperature * (floor * rest / (1.0 - floor)).ln();
+}
+
+/// Turns `penalty_last_n` into the number of tokens the penalties look at, in a context of `n_ctx` tokens.
+/// llama.cpp treats negative windows as 0, which would silently disable the penalties for -1.
+pub fn resolve_penalty_last_n(penalty_last_n: i32, n_ctx: u32) -> i32 {
+    let n_ctx = n_ctx.min(i32::MAX as u32) as i32;
+    match penalty_last_n {
+        -1 => n_ctx,
+        n if n > n_ctx => {
+            debug!("penalty_last_n is {n}, but the context only has {n_ctx} tokens. Using {n_ctx} instead.");
+            n_ctx
+        }
+        n if n < -1 => {
+            warn!(
+                "penalty_last_n is {n}, which is not a number of tokens. Disabling the penalties."
+            );
+            0
+        }
+        n => n,
+    }
+}
+
+/// A sampler chain, split before the stage that picks the token.
+/// The top probability floor goes in between, so it holds for the probabilities that are actually picked from,
+/// after grammar, penalties, filters and temperature have had their say.
+#[derive(Debug)]
+pub struct Sampler {
+    filters: LlamaSampler,
+    select: LlamaSampler,
+    top_probability_floor: f32,
+}
+
+impl Sampler {
+    /// Picks the next token, given the logits of every token in the vocabulary, and updates the sampler state with it.
+    /// The logits may be computed or changed outside of llama.cpp, e.g. by classifier-free guidance or the logit processor.
+    pub fn sample(&mut self, logits: &[f32]) -> LlamaToken {
+        let candidates = logits
+            .iter()
+            .enumerate()
+            .map(|(id, logit)| LlamaTokenData::new(LlamaToken::new(id as i32), *logit, 0.0));
+        let mut candidates = LlamaTokenDataArray::from_iter(candidates, false);
+        candidates.apply_sampler(&mut self.filters);
+        if self.top_probability_floor > 0.0 {
+            // the filters already applied the temperature
+            let mut filtered: Vec<f32> = candidates.data.iter().map(|c| c.logit()).collect();
+            apply_top_probability_floor(&mut filtered, self.top_probability_floor, 1.0);
+            for (candidate, logit) in candidates.data.iter_mut().zip(filtered) {
+                candidate.set_logit(logit);
+            }
+        }
+        candidates.apply_sampler(&mut self.select);
+        let token = candidates
+            .selected_token()
+            .expect("Sampler chain did not select a token");
+        self.filters.accept(token);
+        self.select.accept(token);
+        token
+    }
+
+    /// Always picks the token with the highest logit, with no penalties or grammar. Needs no model, for the mock worker.
+    #[cfg(any(test, feature = "mock"))]
+    pub(crate) fn greedy() -> Self {
+        Self {
+            filters: LlamaSampler::chain(Vec::new(), true),
+            select: LlamaSampler::greedy(),
+            top_probability_floor: 0.0,
+        }
+    }
+}
+
+pub fn make_sampler(model: &LlamaModel, sampler_config: SamplerConfig, n_ctx: u32) -> Sampler {
     let mut chainvec = Vec::new();
 
     // Add grammar sampler first if configured
@@ -254,19 +513,19 @@ pub fn make_sampler(model: &LlamaModel, sampler_config: SamplerConfig) -> LlamaS
         ));
     }
 
-    // Add penalties
-    chainvec.push(LlamaSampler::penalties(
-        sampler_config.penalty_last_n,
-        sampler_config.penalty_repeat,
-        sampler_config.penalty_freq,
-        sampler_config.penalty_present,
-    ));
-
-    // Add method-specific samplers
-    match sampler_config.method {
-        SamplerMethod::Greedy(_) => {
-            chainvec.push(LlamaSampler::greedy());
-        }
+    // Add penalties, unless the method brings its own
+    if !matches!(sampler_config.method, SamplerMethod::Balanced(_)) {
+        chainvec.push(LlamaSampler::penalties(
+            resolve_penalty_last_n(sampler_config.penalty_last_n, n_ctx),
+            sampler_config.penalty_repeat,
+            sampler_config.penalty_freq,
+            sampler_config.penalty_present,
+        ));
+    }
+
+    // Add method-specific samplers, and return the one that picks the token
+    let select = match sampler_config.method {
+        SamplerMethod::Greedy(_) => LlamaSampler::greedy(),
         SamplerMethod::DRY(conf) => {
             chainvec.push(LlamaSampler::dry(
                 model,
@@ -276,19 +535,22 @@ pub fn make_sampler(model: &LlamaModel, sampler_config: SamplerConfig) -> LlamaS
                 conf.dry_penalty_last_n,
                 vec!["\n", ":", "\"", "*"],
             ));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::TopK(conf) => {
             chainvec.push(LlamaSampler::top_k(conf.top_k));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            chainvec.push(LlamaSampler::temp(conf.temperature));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::TopP(conf) => {
             chainvec.push(LlamaSampler::top_p(conf.top_p, conf.min_keep as usize));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            chainvec.push(LlamaSampler::temp(conf.temperature));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::MinP(conf) => {
             chainvec.push(LlamaSampler::min_p(conf.min_p, conf.min_keep as usize));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            chainvec.push(LlamaSampler::temp(conf.temperature));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::XTC(conf) => {
             chainvec.push(LlamaSampler::xtc(
@@ -297,31 +559,136 @@ pub fn make_sampler(model: &LlamaModel, sampler_config: SamplerConfig) -> LlamaS
                 conf.min_keep as usize,
                 conf.seed,
             ));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::TypicalP(conf) => {
             chainvec.push(LlamaSampler::typical(conf.typ_p, conf.min_keep as usize));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::Temperature(conf) => {
             chainvec.push(LlamaSampler::temp(conf.temperature));
-            chainvec.push(LlamaSampler::dist(conf.seed));
+            LlamaSampler::dist(conf.seed)
         }
         SamplerMethod::MirostatV1(conf) => {
             chainvec.push(LlamaSampler::temp(conf.temperature));
-            chainvec.push(LlamaSampler::mirostat(
-                model.n_vocab(),
-                conf.seed,
-                conf.tau,
-                conf.eta,
-                100,
-            ));
+            LlamaSampler::mirostat(model.n_vocab(), conf.seed, conf.tau, conf.eta, 100)
         }
         SamplerMethod::MirostatV2(conf) => {
             chainvec.push(LlamaSampler::temp(conf.temperature));
-            chainvec.push(LlamaSampler::mirostat_v2(conf.seed, conf.tau, conf.eta));
+            LlamaSampler::mirostat_v2(conf.seed, conf.tau, conf.eta)
+        }
+        SamplerMethod::Balanced(conf) => {
+            chainvec.push(LlamaSampler::penalties(64, 1.1, 0.0, 0.0));
+            chainvec.push(LlamaSampler::min_p(0.05, 1));
+            chainvec.push(LlamaSampler::temp(conf.temperature()));
+            LlamaSampler::dist(conf.seed)
+        }
+    };
+
+    Sampler {
+        filters: LlamaSampler::chain(chainvec, true),
+        select,
+        top_probability_floor: sampler_config.top_probability_floor,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(
+        entries: &'static [(&'static str, &'static str)],
+    ) -> impl Fn(&str) -> Option<String> {
+        move |key| {
+            entries
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
         }
     }
 
-    LlamaSampler::chain(chainvec, true)
+    #[test]
+    fn test_with_seed() {
+        let config = SamplerConfig::default().with_seed(42);
+        assert!(matches!(
+            config.method,
+            SamplerMethod::MirostatV2(MirostatV2 { seed: 42, .. })
+        ));
+
+        let greedy = SamplerConfig {
+            method: SamplerMethod::Greedy(Greedy::default()),
+            ..SamplerConfig::default()
+        };
+        assert!(matches!(
+            greedy.with_seed(42).method,
+            SamplerMethod::Greedy(_)
+        ));
+    }
+
+    #[test]
+    fn test_resolve_penalty_last_n() {
+        assert_eq!(resolve_penalty_last_n(-1, 4096), 4096);
+        assert_eq!(resolve_penalty_last_n(0, 4096), 0);
+        assert_eq!(resolve_penalty_last_n(64, 4096), 64);
+        assert_eq!(resolve_penalty_last_n(8192, 4096), 4096);
+        assert_eq!(resolve_penalty_last_n(-2, 4096), 0);
+    }
+
+    #[test]
+    fn test_apply_top_probability_floor() {
+        let probability = |logits: &[f32], temperature: f32, id: usize| {
+            let total: f32 = logits.iter().map(|l| (l / temperature).exp()).sum();
+            (logits[id] / temperature).exp() / total
+        };
+
+        let mut logits = vec![2.0, 1.5, 1.0, 0.5];
+        apply_top_probability_floor(&mut logits, 0.6, 1.5);
+        assert!((probability(&logits, 1.5, 0) - 0.6).abs() < 1e-4);
+        // the others keep their odds relative to each other
+        assert_eq!(&logits[1..], &[1.5, 1.0, 0.5]);
+
+        // likely enough already
+        let mut logits = vec![10.0, 1.0, 0.0];
+        apply_top_probability_floor(&mut logits, 0.6, 1.0);
+        assert_eq!(logits, vec![10.0, 1.0, 0.0]);
+
+        // disabled
+        let mut logits = vec![1.0, 1.0, 1.0];
+        apply_top_probability_floor(&mut logits, 0.0, 1.0);
+        assert_eq!(logits, vec![1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_from_metadata() {
+        assert!(SamplerConfig::from_metadata(metadata(&[])).is_none());
+
+        let config =
+            SamplerConfig::from_metadata(metadata(&[("top_p", "0.9"), ("temp", "0.6")])).unwrap();
+        assert!(matches!(
+            config.method,
+            SamplerMethod::TopP(TopP { top_p, temperature, .. }) if top_p == 0.9 && temperature == 0.6
+        ));
+
+        // without a recommended temperature, the filter leaves the probabilities alone
+        let config = SamplerConfig::from_metadata(metadata(&[("top_k", "20")])).unwrap();
+        assert!(matches!(
+            config.method,
+            SamplerMethod::TopK(TopK { top_k: 20, temperature, .. }) if temperature == 1.0
+        ));
+
+        let config = SamplerConfig::from_metadata(metadata(&[
+            ("mirostat", "2"),
+            ("temp", "0.6"),
+            ("penalty_repeat", "1.1"),
+        ]))
+        .unwrap();
+        assert!(matches!(
+            config.method,
+            SamplerMethod::MirostatV2(MirostatV2 { temperature, .. }) if temperature == 0.6
+        ));
+        assert_eq!(config.penalty_repeat, 1.1);
+
+        // unparseable values are ignored
+        assert!(SamplerConfig::from_metadata(metadata(&[("temp", "hot")])).is_none());
+    }
 }
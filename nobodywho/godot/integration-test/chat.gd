@@ -6,11 +6,46 @@ func run_test():
 	system_prompt = "You are a helpful assistant, capable of answering questions about the world."
 
 	
+	assert(test_inspect_model())
 	assert(await test_say())
+	assert(await test_say_and_wait())
+	assert(await test_timing_breakdown())
+	assert(await test_tokens_remaining())
+	assert(await test_pause())
+	assert(await test_word_completed())
+	assert(await test_partial_response())
+	assert(await test_say_n())
+	assert(await test_draft())
+	assert(await test_say_tokens())
+	assert(await test_ask_yes_no())
+	assert(await test_pending_messages())
+	assert(await test_say_matching())
+	assert(await test_say_json())
+	assert(await test_stream_field())
+	assert(await test_code_blocks())
+	assert(await test_message_embeddings())
+	assert(await test_typing_speed())
+	assert(await test_context_too_small())
+	assert(await test_resize_context())
+	assert(await test_new_conversation())
+	assert(await test_logit_processor())
+	assert(await test_token_probability())
+	assert(await test_persona())
 	assert(await test_antiprompts())
 	assert(await test_antiprompts_multitokens())
 	return true
 
+func test_inspect_model():
+	var info = NobodyWhoModel.inspect_model(model_node.model_path)
+
+	print("✨ Got model info: " + str(info))
+	assert(info.architecture == "qwen2")
+	assert(info.quantization == "Q4_0")
+	assert(info.parameter_count > 1000000000)
+	assert(info.estimated_vram_bytes > info.file_size_bytes)
+	assert(NobodyWhoModel.inspect_model("res://missing.gguf").is_empty())
+	return true
+
 func test_say():
 	say("Please tell me what the capital city of Denmark is.")
 
@@ -18,14 +53,382 @@ func test_say():
 
 	print("✨ Got response: " + response)
 	assert("Copenhagen" in response)
+	assert(get_finish_reason() == "eog")
+	return true
+
+func test_say_and_wait():
+	var response = await say_and_wait("And what is the capital city of Germany?")
+
+	print("✨ Got awaited response: " + response)
+	assert("Berlin" in response)
+	assert(get_last_response() == response)
+	assert(get_last_response_tokens().size() > 0)
+	return true
+
+func test_timing_breakdown():
+	await say_and_wait("And what is the capital city of Poland?")
+	var timings = get_timing_breakdown()
+
+	print("✨ Got timings: " + str(timings))
+	assert(timings.worker_init_ms > 0)
+	assert(timings.first_prompt_ms > 0)
+	assert(timings.cold_start_ms >= timings.worker_init_ms + timings.first_prompt_ms)
+	assert(timings.last_generation_ms > 0)
+	assert(timings.turns >= 3)
+	return true
+
+func test_tokens_remaining():
+	var before = tokens_remaining()
+	var response = await say_and_wait("And what is the capital city of Austria?")
+	var after = tokens_remaining()
+
+	print("✨ Tokens remaining: " + str(before) + " before, " + str(after) + " after: " + response)
+	assert(after > 0)
+	assert(after < before)
+	assert(after < context_length)
+	return true
+
+func test_pause():
+	var n_tokens = [0]
+	var count = func(_token): n_tokens[0] += 1
+	response_updated.connect(count)
+
+	pause()
+	assert(is_paused())
+	say("Please count from 1 to 10.")
+	await get_tree().create_timer(0.5).timeout
+	var n_paused = n_tokens[0]
+
+	resume()
+	var response = await response_finished
+	response_updated.disconnect(count)
+
+	print("✨ Got response after resuming: " + response)
+	assert(n_paused == 0)
+	assert(n_tokens[0] > 0)
+	assert("10" in response)
+	return true
+
+func test_word_completed():
+	var words = []
+	var collect_word = func(word): words.append(word)
+	word_completed.connect(collect_word)
+
+	var response = await say_and_wait("And what is the capital city of France?")
+	word_completed.disconnect(collect_word)
+
+	print("✨ Got words: " + str(words))
+	assert(words.size() > 0)
+	for word in words:
+		assert(not " " in word, "Words should not contain whitespace")
+		assert(word in response)
+	return true
+
+func test_partial_response():
+	var streamed = [""]
+	var check_partial = func(token):
+		streamed[0] += token
+		assert(get_partial_response() == streamed[0])
+	response_updated.connect(check_partial)
+
+	var response = await say_and_wait("And what is the capital city of Italy?")
+	response_updated.disconnect(check_partial)
+
+	print("✨ Got partial responses up to: " + streamed[0])
+	assert(streamed[0] == response)
+	assert(get_partial_response() == "")
+	return true
+
+func test_say_n():
+	say_n("And what is the capital city of Sweden?", 3)
+
+	var responses = await responses_finished
+
+	print("✨ Got responses: " + str(responses))
+	assert(responses.size() == 3)
+	for response in responses:
+		assert("Stockholm" in response)
+	assert(get_last_response() == responses[0])
+	return true
+
+func test_draft():
+	draft("And what is the capital city of Finland?", 2)
+	var drafts = await drafts_finished
+
+	print("✨ Got drafts: " + str(drafts))
+	assert(drafts.size() == 2)
+	for d in drafts:
+		assert("Helsinki" in d)
+
+	commit_draft(0)
+	var response = await say_and_wait("Which country did I just ask about?")
+	assert("Finland" in response)
+	return true
+
+func test_say_tokens():
+	var tokens = model_node.tokenize("I'm gonna count to 10: 1, 2, 3, ")
+	assert(tokens.size() > 0)
+	say_tokens(tokens)
+
+	var response = await response_finished
+
+	print("✨ Got response to tokens: " + response)
+	assert("4, 5" in response)
+	return true
+
+func test_ask_yes_no():
+	var answer = await ask_yes_no("Is Copenhagen the capital city of Denmark? Answer yes or no.")
+	print("✨ Got yes/no answer: " + str(answer) + ", confidence: " + str(get_yes_no_confidence()))
+	assert(answer == true)
+	assert(get_yes_no_confidence() >= 0.5)
+
+	answer = await ask_yes_no("Is Oslo the capital city of Denmark? Answer yes or no.")
+	assert(answer == false)
+	return true
+
+func test_pending_messages():
+	say("What is the capital city of Spain?")
+	say("And of Portugal?")
+	assert(pending_messages() >= 1)
+
+	await response_finished
+	await response_finished
+	assert(pending_messages() == 0)
+	return true
+
+func test_say_matching():
+	say_matching("What year did the first moon landing happen?", "\\d{4}")
+
+	var response = await response_finished
+
+	print("✨ Got matching response: " + response)
+	assert(response.length() == 4)
+	assert(response.is_valid_int())
+	return true
+
+func test_say_json():
+	var failures = [0]
+	var count_failure = func(_response): failures[0] += 1
+	structured_response_failed.connect(count_failure)
+
+	say_json("Describe Denmark as a JSON object with the keys \"capital\" and \"language\".", 3)
+	var response = await response_finished
+	structured_response_failed.disconnect(count_failure)
+
+	print("✨ Got JSON response: " + response)
+	assert(failures[0] == 0)
+	assert(JSON.parse_string(response) is Dictionary)
+	return true
+
+func test_stream_field():
+	stream_field = "text"
+	start_worker() # restart the worker to constrain responses to JSON
+
+	var streamed = [""]
+	var collect = func(token): streamed[0] += token
+	response_updated.connect(collect)
+
+	say("Respond with a JSON object with the keys \"emotion\" and \"text\", where text greets the player.")
+	var parsed = await structured_response_finished
+	response_updated.disconnect(collect)
+
+	print("✨ Got structured response: " + str(parsed) + ", streamed: " + streamed[0])
+	assert(parsed.has("text"))
+	assert(streamed[0] == parsed["text"])
+	assert(JSON.parse_string(get_last_response()) is Dictionary)
+
+	stream_field = ""
+	start_worker()
+	return true
+
+func test_code_blocks():
+	detect_code_blocks = true
+	start_worker() # restart the worker to detect code blocks
+
+	var events = []
+	var code = [""]
+	var on_started = func(language): events.append("started " + language)
+	var on_code = func(text): code[0] += text
+	var on_ended = func(): events.append("ended")
+	code_block_started.connect(on_started)
+	code_updated.connect(on_code)
+	code_block_ended.connect(on_ended)
+
+	var response = await say_and_wait("Write a python function that adds two numbers, in a markdown code block.")
+	code_block_started.disconnect(on_started)
+	code_updated.disconnect(on_code)
+	code_block_ended.disconnect(on_ended)
+
+	print("✨ Got code block events: " + str(events) + ", code: " + code[0])
+	assert(events.size() >= 2)
+	assert(events[0].begins_with("started"))
+	assert(events[-1] == "ended")
+	assert("def " in code[0])
+	assert(not "```" in code[0])
+	assert("```" in response)
+
+	detect_code_blocks = false
+	start_worker()
+	return true
+
+func test_message_embeddings():
+	embedding_model_node = get_node("../EmbeddingModel")
+	start_worker() # restart the worker to start embedding messages
+
+	var embedded = []
+	var collect = func(role, message, embedding): embedded.append([role, message, embedding])
+	message_embedded.connect(collect)
+
+	var response = await say_and_wait("Please tell me what the capital city of Denmark is.")
+	while embedded.size() < 2:
+		await message_embedded
+	message_embedded.disconnect(collect)
+
+	print("✨ Got message embeddings for: " + str(embedded.map(func(e): return e[0])))
+	assert(embedded[0][0] == "user")
+	assert(embedded[1][0] == "assistant")
+	assert(embedded[1][1] == response)
+	assert(embedded[0][2].size() > 0)
+	assert(NobodyWhoEmbedding.cosine_similarity(embedded[0][2], embedded[1][2]) > 0.0)
+
+	embedding_model_node = null
+	start_worker()
+	return true
+
+func test_typing_speed():
+	typing_speed = 100.0
+	start_worker() # restart the worker to type out the responses
+
+	var start_time = Time.get_ticks_msec()
+	var response = await say_and_wait("Please tell me what the capital city of Norway is.")
+	var elapsed = (Time.get_ticks_msec() - start_time) / 1000.0
+
+	print("✨ Typed out response in " + str(elapsed) + " seconds: " + response)
+	assert("Oslo" in response)
+	assert(elapsed >= (response.length() - 1) / typing_speed)
+
+	typing_speed = 0.0
+	start_worker()
+	return true
+
+func test_context_too_small():
+	var errors = []
+	var collect = func(message): errors.append(message)
+	configuration_error.connect(collect)
+
+	var original_prompt = system_prompt
+	var original_length = context_length
+	system_prompt = "You are a very elaborate pirate captain. ".repeat(50)
+	context_length = 64
+	start_worker()
+	configuration_error.disconnect(collect)
+
+	print("✨ Got configuration errors: " + str(errors))
+	assert(errors.size() == 1)
+	assert("system prompt" in errors[0])
+
+	system_prompt = original_prompt
+	context_length = original_length
+	start_worker()
+	return true
+
+func test_resize_context():
+	await say_and_wait("Please tell me what the capital city of Iceland is.")
+
+	var original_length = context_length
+	resize_context(original_length * 2)
+	assert(context_length == original_length * 2)
+
+	var response = await say_and_wait("Which country did I just ask about?")
+
+	print("✨ Got response after resizing: " + response)
+	assert("Iceland" in response)
+
+	resize_context(original_length)
+	return true
+
+func test_new_conversation():
+	await say_and_wait("My name is Gertrud. Please remember it.")
+	new_conversation()
+
+	var response = await say_and_wait("Do you know my name?")
+
+	print("✨ Got response in new conversation: " + response)
+	assert(not "Gertrud" in response)
+	return true
+
+func test_logit_processor():
+	var n_calls = [0]
+	logit_processor = func(candidates):
+		n_calls[0] += 1
+		var adjustments = {}
+		for candidate in candidates:
+			if "Hel" in candidate.text:
+				adjustments[candidate.token] = -INF
+		return adjustments
+	use_logit_processor = true
+	start_worker() # restart the worker to use the logit processor
+
+	var response = await say_and_wait("Please tell me what the capital city of Finland is.")
+
+	print("✨ Got processed response: " + response)
+	assert(n_calls[0] > 0)
+	assert(not "Helsinki" in response)
+
+	use_logit_processor = false
+	logit_processor = Callable()
+	start_worker()
+	return true
+
+func test_token_probability():
+	emit_token_probabilities = true
+	start_worker() # restart the worker to read the probabilities
+
+	var probabilities = []
+	var collect = func(_token, probability): probabilities.append(probability)
+	token_probability.connect(collect)
+
+	await say_and_wait("Please tell me what the capital city of Denmark is.")
+	token_probability.disconnect(collect)
+
+	print("✨ Got token probabilities: " + str(probabilities))
+	assert(probabilities.size() > 0)
+	for probability in probabilities:
+		assert(probability >= 0.0 and probability <= 1.0)
+
+	emit_token_probabilities = false
+	start_worker()
+	return true
+
+func test_persona():
+	var original_prompt = system_prompt
+	var pirate = NobodyWhoPersona.new()
+	pirate.system_prompt = "You are a pirate. Always talk like a pirate."
+	pirate.stop_tokens = PackedStringArray(["Arr"])
+	persona = pirate
+	start_worker() # the persona is applied when the worker starts
+
+	assert(system_prompt == pirate.system_prompt)
+	assert(stop_tokens == pirate.stop_tokens)
+	assert(sampler == null)
+
+	persona = null
+	system_prompt = original_prompt
+	stop_tokens = PackedStringArray()
+	start_worker()
 	return true
 
 func test_antiprompts():
 	stop_tokens = PackedStringArray(["fly"])
 	start_worker() # restart the worker to include the antiprompts
 	
+	var stopped_on_tokens = []
+	var collect = func(token): stopped_on_tokens.append(token)
+	stopped_on.connect(collect)
+
 	say("List these animals in alphabetical order: cat, dog, fly, lion, mouse")
 	var response = await response_finished
+	stopped_on.disconnect(collect)
 
 	print("✨ Got antiprompt response: " + response)
 
@@ -33,6 +436,9 @@ func test_antiprompts():
 	assert("fly" in response, "Should reach the antiprompt")
 	assert(not "lion" in response, "Should stop at antiprompt")
 	assert(not "mouse" in response, "Should not continue past antiprompt")
+	assert(get_finish_reason() == "stop_token")
+	assert(get_stop_token() == "fly")
+	assert(stopped_on_tokens == ["fly"])
 	
 	return true
 
@@ -0,0 +1,53 @@
+extends NobodyWhoCompletion
+
+func run_test():
+	# configure node
+	model_node = get_node("../ChatModel")
+	stop_tokens = PackedStringArray(["10"])
+
+	assert(await test_complete())
+	assert(await test_continues_story())
+	assert(await test_reset_context())
+	assert(await test_complete_batch())
+	return true
+
+func test_complete():
+	var response = await complete("I'm gonna count to 10: 1, 2, 3, ")
+
+	print("✨ Got continuation: " + response)
+	assert("4, 5, 6" in response)
+	assert(not "I'm gonna count" in response)
+	return true
+
+func test_continues_story():
+	var response = await complete(" Now backwards from 10: ")
+
+	print("✨ Got continued story: " + response)
+	assert("9" in response)
+	return true
+
+func test_reset_context():
+	reset_context()
+	var response = await complete("The days of the week are Monday, Tuesday, ")
+
+	print("✨ Got continuation after reset: " + response)
+	assert("Wednesday" in response)
+	return true
+
+func test_complete_batch():
+	var indices = []
+	var collect = func(index, _response): indices.append(index)
+	batch_response_finished.connect(collect)
+
+	var responses = await complete_batch(PackedStringArray([
+		"I'm gonna count to 10: 1, 2, 3, ",
+		"The days of the week are Monday, Tuesday, ",
+	]))
+	batch_response_finished.disconnect(collect)
+
+	print("✨ Got batch: " + str(responses))
+	assert(responses.size() == 2)
+	assert("4, 5" in responses[0])
+	assert("Wednesday" in responses[1])
+	assert(indices == [0, 1])
+	return true
@@ -19,5 +19,31 @@ func run_test():
 	var high_similarity = cosine_similarity(dragon_hill_embd, dragon_hungry_embd) 
 	var result = low_similarity < high_similarity
 	assert(result)
+
+	# the matrix agrees with the pairwise similarities
+	var matrix = similarity_matrix([dragon_hill_embd, dragon_hungry_embd, irrelevant_embd])
+	assert(matrix.size() == 3)
+	assert(is_equal_approx(matrix[0][1], high_similarity))
+	assert(is_equal_approx(matrix[2][0], low_similarity))
+	assert(is_equal_approx(matrix[1][1], 1.0))
+
+	# normalized embeddings have unit length, so their dot product is the cosine similarity
+	var a = normalize_embedding(dragon_hill_embd)
+	var b = normalize_embedding(dragon_hungry_embd)
+	var dot = 0.0
+	for i in a.size():
+		dot += a[i] * b[i]
+	assert(is_equal_approx(dot, high_similarity))
+
+	# with input normalization, casing and punctuation don't change the embedding
+	input_normalization = PackedStringArray(["lowercase", "strip_punctuation", "collapse_whitespace"])
+	start_worker()
+	embed("Buy the RED potion!!")
+	var shouted_embd = await self.embedding_finished
+	embed("buy the  red potion")
+	var plain_embd = await self.embedding_finished
+	assert(is_equal_approx(cosine_similarity(shouted_embd, plain_embd), 1.0))
+	input_normalization = PackedStringArray()
+	start_worker()
 	print("✨ embeddings completed")
 	return result
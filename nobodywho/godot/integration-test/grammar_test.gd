@@ -15,6 +15,7 @@ func run_test() -> bool:
 	chat.start_worker()
 
 	var result = await test_json_output()
+	result = await test_grammar_file()
 	return true
 
 func test_json_output():
@@ -39,3 +40,18 @@ func test_json_output():
 	assert(json.data.has("name"))
 	assert(json.data.has("class"))
 	assert(json.data.has("level"))
+
+func test_grammar_file():
+	var file = FileAccess.open("user://yes_no.gbnf", FileAccess.WRITE)
+	file.store_string('root ::= "yes" | "no"')
+	file.close()
+
+	chat.sampler.gbnf_grammar = ""
+	chat.sampler.grammar_file = "user://yes_no.gbnf"
+	chat.start_worker()
+
+	chat.say("Is Copenhagen the capital of Denmark?")
+	var response = await chat.response_finished
+	print("✨ Got response: " + response)
+	assert(response in ["yes", "no"])
+	return true
@@ -5,6 +5,7 @@ func _ready() -> void:
 	print("👷 running tests...")
 	assert(await $NobodyWhoEmbedding.run_test())
 	assert(await $NobodyWhoChat.run_test())
+	assert(await $NobodyWhoCompletion.run_test())
 	assert(await $Grammar.run_test())
 	print("✨ all tests complete")
 	get_tree().quit()
@@ -0,0 +1,261 @@
+use godot::prelude::*;
+use nobodywho::{chat, chat_state, grammar, llm, replay};
+
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorCode {
+    ModelNotSet = 1,
+    ModelNotFound = 2,
+    ModelInvalid = 3,
+    ChatTemplateMissing = 4,
+    ChatTemplateInvalid = 5,
+    ContextCreationFailed = 6,
+    WorkerInitFailed = 7,
+    GenerationFailed = 8,
+    WorkerDied = 9,
+    RecordingFailed = 10,
+    SystemPromptFileFailed = 11,
+    InvalidPattern = 12,
+    GrammarFileFailed = 13,
+    EmbeddingTimedOut = 14,
+    OutOfMemory = 15,
+    ChatTemplateFileFailed = 16,
+    ContextTooSmall = 17,
+    InvalidSampler = 18,
+    QuestionUnanswered = 19,
+}
+
+#[derive(GodotClass)]
+#[class(no_init, base=Object)]
+/// The error codes found in the `code` field of the `error_occurred` signal, on NobodyWhoChat and NobodyWhoEmbedding.
+/// Use these to react to specific errors without matching on the error message.
+///
+/// Example:
+///
+/// ```
+/// func _on_error_occurred(error: Dictionary):
+///     if error.code == NobodyWhoErrorCode.CHAT_TEMPLATE_MISSING:
+///         fallback_chat_template = "chatml"
+///         start_worker()
+/// ```
+pub struct NobodyWhoErrorCode {
+    base: Base<Object>,
+}
+
+#[godot_api]
+impl NobodyWhoErrorCode {
+    /// No NobodyWhoModel node was assigned to `model_node`.
+    #[constant]
+    const MODEL_NOT_SET: i64 = ErrorCode::ModelNotSet as i64;
+
+    /// The model file does not exist.
+    #[constant]
+    const MODEL_NOT_FOUND: i64 = ErrorCode::ModelNotFound as i64;
+
+    /// The model file could not be loaded, e.g. because it is not a valid GGUF file.
+    #[constant]
+    const MODEL_INVALID: i64 = ErrorCode::ModelInvalid as i64;
+
+    /// The model file does not include a chat template, and no fallback template was set.
+    #[constant]
+    const CHAT_TEMPLATE_MISSING: i64 = ErrorCode::ChatTemplateMissing as i64;
+
+    /// The chat template could not be rendered.
+    #[constant]
+    const CHAT_TEMPLATE_INVALID: i64 = ErrorCode::ChatTemplateInvalid as i64;
+
+    /// The LLM context could not be created. This is usually because there is not enough memory for the context length.
+    #[constant]
+    const CONTEXT_CREATION_FAILED: i64 = ErrorCode::ContextCreationFailed as i64;
+
+    /// The worker could not be started for some other reason.
+    #[constant]
+    const WORKER_INIT_FAILED: i64 = ErrorCode::WorkerInitFailed as i64;
+
+    /// The worker failed while generating a response or an embedding.
+    #[constant]
+    const GENERATION_FAILED: i64 = ErrorCode::GenerationFailed as i64;
+
+    /// The worker stopped unexpectedly, and can't receive messages.
+    #[constant]
+    const WORKER_DIED: i64 = ErrorCode::WorkerDied as i64;
+
+    /// The recording file for `replay_mode` could not be read or written, or it ran out of responses.
+    #[constant]
+    const RECORDING_FAILED: i64 = ErrorCode::RecordingFailed as i64;
+
+    /// The file set in `system_prompt_file` could not be read.
+    #[constant]
+    const SYSTEM_PROMPT_FILE_FAILED: i64 = ErrorCode::SystemPromptFileFailed as i64;
+
+    /// The regex passed to `say_matching` is invalid, or uses syntax that can't be turned into a grammar.
+    #[constant]
+    const INVALID_PATTERN: i64 = ErrorCode::InvalidPattern as i64;
+
+    /// The file set in `grammar_file` on the sampler could not be read.
+    #[constant]
+    const GRAMMAR_FILE_FAILED: i64 = ErrorCode::GrammarFileFailed as i64;
+
+    /// Embedding a text took longer than `embed_timeout_ms`, so it was skipped.
+    #[constant]
+    const EMBEDDING_TIMED_OUT: i64 = ErrorCode::EmbeddingTimedOut as i64;
+
+    /// The GPU or RAM ran out of memory while generating. The `out_of_memory` signal is triggered too.
+    #[constant]
+    const OUT_OF_MEMORY: i64 = ErrorCode::OutOfMemory as i64;
+
+    /// The file set in `chat_template_file` could not be read.
+    #[constant]
+    const CHAT_TEMPLATE_FILE_FAILED: i64 = ErrorCode::ChatTemplateFileFailed as i64;
+
+    /// The system prompt doesn't fit in `context_length`, so the worker was not started. The `configuration_error` signal is triggered too.
+    #[constant]
+    const CONTEXT_TOO_SMALL: i64 = ErrorCode::ContextTooSmall as i64;
+
+    /// The sampler has a setting that can't work, e.g. a negative `penalty_last_n` other than -1.
+    #[constant]
+    const INVALID_SAMPLER: i64 = ErrorCode::InvalidSampler as i64;
+
+    /// `ask_yes_no` got no answer, because the question was empty or couldn't be read, or the LLM found yes and no
+    /// equally likely. `yes_no_answered` is triggered with false.
+    #[constant]
+    const QUESTION_UNANSWERED: i64 = ErrorCode::QuestionUnanswered as i64;
+}
+
+/// An error with a code that game code can branch on, and a message for humans.
+#[derive(Debug)]
+pub struct NobodyWhoError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl NobodyWhoError {
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self.code, ErrorCode::OutOfMemory)
+    }
+
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The `{code, message}` dictionary passed to the `error_occurred` signal.
+    pub fn to_dictionary(&self) -> Dictionary {
+        dict! {
+            "code": self.code as i64,
+            "message": self.message.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for NobodyWhoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<llm::LoadModelError> for NobodyWhoError {
+    fn from(err: llm::LoadModelError) -> Self {
+        let code = match err {
+            llm::LoadModelError::ModelNotFound(_) => ErrorCode::ModelNotFound,
+            llm::LoadModelError::InvalidModel(_)
+            | llm::LoadModelError::NotAGGUF(_)
+            | llm::LoadModelError::UnsupportedGGUFVersion { .. } => ErrorCode::ModelInvalid,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+impl From<chat::SystemPromptTooLongError> for NobodyWhoError {
+    fn from(err: chat::SystemPromptTooLongError) -> Self {
+        Self::new(ErrorCode::ContextTooSmall, err.to_string())
+    }
+}
+
+impl From<llm::BuildParamsError> for NobodyWhoError {
+    fn from(err: llm::BuildParamsError) -> Self {
+        let code = match err {
+            llm::BuildParamsError::MissingModel => ErrorCode::ModelNotSet,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+/// Suggestions for running out of memory, appended to the error message.
+const OUT_OF_MEMORY_HINT: &str =
+    "Ran out of memory. Try reducing context_length or the length of the text, \
+    or disable use_gpu_if_available on the model node to run on the CPU";
+
+impl From<chat::ChatLoopError> for NobodyWhoError {
+    fn from(err: chat::ChatLoopError) -> Self {
+        if let chat::ChatLoopError::GenerateResponseError(e) = &err {
+            if e.is_out_of_memory() {
+                return Self::new(
+                    ErrorCode::OutOfMemory,
+                    format!("{OUT_OF_MEMORY_HINT}: {err}"),
+                );
+            }
+        }
+        let code = match &err {
+            chat::ChatLoopError::InitChatTemplateError(
+                chat_state::FromModelError::ChatTemplateError(_),
+            ) => ErrorCode::ChatTemplateMissing,
+            chat::ChatLoopError::InitChatTemplateError(_)
+            | chat::ChatLoopError::RenderChatTemplateError(_) => ErrorCode::ChatTemplateInvalid,
+            chat::ChatLoopError::InitWorkerError(llm::InitWorkerError::CreateContextError(_)) => {
+                ErrorCode::ContextCreationFailed
+            }
+            chat::ChatLoopError::InitWorkerError(llm::InitWorkerError::InvalidPenaltyLastN(_)) => {
+                ErrorCode::InvalidSampler
+            }
+            chat::ChatLoopError::InitWorkerError(_) => ErrorCode::WorkerInitFailed,
+            chat::ChatLoopError::GenerateResponseError(_)
+            | chat::ChatLoopError::NoResponseError => ErrorCode::GenerationFailed,
+            chat::ChatLoopError::WorkerDiedError(_) => ErrorCode::WorkerDied,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+impl From<chat::EmbeddingLoopError> for NobodyWhoError {
+    fn from(err: chat::EmbeddingLoopError) -> Self {
+        if let chat::EmbeddingLoopError::GenerateEmbeddingError(e) = &err {
+            if e.is_out_of_memory() {
+                return Self::new(
+                    ErrorCode::OutOfMemory,
+                    format!("{OUT_OF_MEMORY_HINT}: {err}"),
+                );
+            }
+        }
+        let code = match &err {
+            chat::EmbeddingLoopError::InitWorkerError(
+                llm::InitWorkerError::CreateContextError(_),
+            ) => ErrorCode::ContextCreationFailed,
+            chat::EmbeddingLoopError::InitWorkerError(
+                llm::InitWorkerError::InvalidPenaltyLastN(_),
+            ) => ErrorCode::InvalidSampler,
+            chat::EmbeddingLoopError::InitWorkerError(_) => ErrorCode::WorkerInitFailed,
+            chat::EmbeddingLoopError::GenerateEmbeddingError(_) => ErrorCode::GenerationFailed,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+impl From<grammar::RegexGrammarError> for NobodyWhoError {
+    fn from(err: grammar::RegexGrammarError) -> Self {
+        Self::new(ErrorCode::InvalidPattern, err.to_string())
+    }
+}
+
+impl From<replay::RecordingError> for NobodyWhoError {
+    fn from(err: replay::RecordingError) -> Self {
+        Self::new(ErrorCode::RecordingFailed, err.to_string())
+    }
+}
+
+impl From<chat::ReplayLoopError> for NobodyWhoError {
+    fn from(err: chat::ReplayLoopError) -> Self {
+        Self::new(ErrorCode::RecordingFailed, err.to_string())
+    }
+}
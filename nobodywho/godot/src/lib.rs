@@ -1,10 +1,16 @@
+mod errors;
+mod persona_resource;
 mod sampler_resource;
 
-use godot::classes::{INode, ProjectSettings};
+use godot::classes::notify::NodeNotification;
+use godot::classes::{INode, Json, ProjectSettings};
 use godot::prelude::*;
-use nobodywho::{chat, llm, sampler_config};
+use nobodywho::{chat, chat_state, gguf, grammar, llm, postprocess, replay, sampler_config};
+use std::collections::VecDeque;
 use tokio;
 
+use crate::errors::{ErrorCode, NobodyWhoError};
+use crate::persona_resource::NobodyWhoPersona;
 use crate::sampler_resource::NobodyWhoSampler;
 
 struct NobodyWhoExtension;
@@ -12,6 +18,45 @@ struct NobodyWhoExtension;
 #[gdextension]
 unsafe impl ExtensionLibrary for NobodyWhoExtension {}
 
+/// Turns the name of a bundled chat template into the template itself. Anything else is used as a template as is,
+/// and an empty string means no template.
+fn resolve_chat_template(template: &GString) -> Option<String> {
+    let template = template.to_string();
+    if template.trim().is_empty() {
+        return None;
+    }
+    Some(
+        chat_state::builtin_template(template.trim())
+            .map(str::to_string)
+            .unwrap_or(template),
+    )
+}
+
+#[derive(GodotConvert, Var, Export, Debug, Clone, Copy, PartialEq)]
+#[godot(via=GString)]
+enum ReplayMode {
+    Off,
+    Record,
+    Replay,
+    Verify,
+}
+
+#[derive(GodotConvert, Var, Export, Debug, Clone, Copy, PartialEq)]
+#[godot(via=GString)]
+enum AddBosMode {
+    Auto,
+    Always,
+    Never,
+}
+
+fn worker_priority(low_priority: bool) -> llm::WorkerPriority {
+    if low_priority {
+        llm::WorkerPriority::Low
+    } else {
+        llm::WorkerPriority::Normal
+    }
+}
+
 #[derive(GodotClass)]
 #[class(base=Node)]
 /// The model node is used to load the model, currently only GGUF models are supported.
@@ -21,250 +66,2246 @@ struct NobodyWhoModel {
     #[export(file = "*.gguf")]
     model_path: GString,
 
-    #[export]
-    use_gpu_if_available: bool,
+    #[export]
+    use_gpu_if_available: bool,
+
+    // shared with the thread that preloads the model, which holds the lock while loading
+    model: std::sync::Arc<std::sync::Mutex<Option<llm::Model>>>,
+
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl INode for NobodyWhoModel {
+    fn init(base: Base<Node>) -> Self {
+        // default values to show in godot editor
+        let model_path: String = "model.gguf".into();
+
+        Self {
+            model_path: model_path.into(),
+            use_gpu_if_available: true,
+            model: Default::default(),
+            base,
+        }
+    }
+}
+
+/// Loads the model into `cache`, unless it is already there.
+fn load_model_cached(
+    cache: &std::sync::Mutex<Option<llm::Model>>,
+    model_path: &str,
+    use_gpu_if_available: bool,
+) -> Result<llm::Model, llm::LoadModelError> {
+    let mut cache = cache.lock().expect("Model mutex poisoned");
+    if let Some(model) = cache.as_ref() {
+        return Ok(model.clone());
+    }
+    let model = llm::get_model(model_path, use_gpu_if_available)?;
+    *cache = Some(model.clone());
+    Ok(model)
+}
+
+#[godot_api]
+impl NobodyWhoModel {
+    fn get_model_path(&self) -> String {
+        ProjectSettings::singleton()
+            .globalize_path(&self.model_path.clone())
+            .into()
+    }
+
+    // memoized model loader
+    // if the model is being preloaded, this waits for it
+    fn get_model(&mut self) -> Result<llm::Model, llm::LoadModelError> {
+        match load_model_cached(
+            &self.model,
+            &self.get_model_path(),
+            self.use_gpu_if_available,
+        ) {
+            Ok(model) => Ok(model),
+            Err(err) => {
+                godot_error!("Could not load model: {:?}", err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    /// Starts loading the model on a background thread. The receiver gets whether it succeeded.
+    fn start_preload(&mut self) -> tokio::sync::oneshot::Receiver<bool> {
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let cache = self.model.clone();
+        let model_path = self.get_model_path();
+        let use_gpu_if_available = self.use_gpu_if_available;
+        std::thread::spawn(move || {
+            let _ = result_tx.send(load_model_cached(&cache, &model_path, use_gpu_if_available));
+        });
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let emit_node = self.to_gd();
+        godot::task::spawn(async move {
+            let success = match result_rx.await {
+                Ok(Ok(_)) => {
+                    emit_node.signals().model_loaded().emit();
+                    true
+                }
+                Ok(Err(err)) => {
+                    godot_error!("Could not load model: {err}");
+                    emit_node
+                        .signals()
+                        .error_occurred()
+                        .emit(NobodyWhoError::from(err).to_dictionary());
+                    false
+                }
+                Err(_) => false,
+            };
+            let _ = done_tx.send(success);
+        });
+        done_rx
+    }
+
+    #[func]
+    /// Returns the context length the model was trained with, which is the most it supports.
+    /// A `context_length` above this is lowered to it when the worker starts. Returns 0 if the model could not be loaded.
+    fn get_training_context_length(&mut self) -> i64 {
+        self.get_model()
+            .map(|model| model.n_ctx_train() as i64)
+            .unwrap_or(0)
+    }
+
+    #[func]
+    /// Turns text into the model's token ids, e.g. for `NobodyWhoChat.say_tokens`. Special tokens written as text,
+    /// like `<|im_end|>`, become the special tokens themselves. Returns an empty array if the model could not be loaded.
+    fn tokenize(&mut self, text: String) -> PackedInt32Array {
+        let Ok(model) = self.get_model() else {
+            return PackedInt32Array::new();
+        };
+        match llm::tokenize(&model, &text) {
+            Ok(tokens) => PackedInt32Array::from(tokens.as_slice()),
+            Err(err) => {
+                godot_error!("Could not tokenize text: {err}");
+                PackedInt32Array::new()
+            }
+        }
+    }
+
+    #[func]
+    /// Reads the metadata of a model file without loading it, e.g. for a settings menu to warn about models that won't fit.
+    /// This only reads the headers, so it is fast even for large models. Returns a dictionary with:
+    /// - "file_size_bytes": the size of the file.
+    /// - "architecture": the model architecture, like "llama" or "qwen2".
+    /// - "parameter_count": the number of parameters, e.g. 1500000000 for a 1.5B model.
+    /// - "quantization": the quantization of the weights, like "Q4_K_M".
+    /// - "layer_count": the number of layers.
+    /// - "training_context_length": the context length the model was trained with.
+    /// - "estimated_vram_bytes": a rough estimate of the memory needed to run it with the default `context_length` of 4096.
+    /// The estimate leaves out backend overhead, so treat it as a lower bound.
+    /// Returns an empty dictionary if the file is missing or not a valid GGUF file.
+    fn inspect_model(path: String) -> Dictionary {
+        let path: String = ProjectSettings::singleton().globalize_path(&path).into();
+        match gguf::inspect_model(&path) {
+            Ok(info) => dict! {
+                "file_size_bytes": info.file_size as i64,
+                "architecture": info.architecture.clone(),
+                "parameter_count": info.n_params as i64,
+                "quantization": info.file_type.clone(),
+                "layer_count": info.n_layers as i64,
+                "training_context_length": info.n_ctx_train as i64,
+                "estimated_vram_bytes": info.estimate_memory(4096) as i64,
+            },
+            Err(err) => {
+                godot_error!("Could not inspect model {path}: {err}");
+                Dictionary::new()
+            }
+        }
+    }
+
+    #[func]
+    /// Loads the model on a background thread, so the game doesn't freeze when a chat or embedding node first uses it.
+    /// Triggers `model_loaded` when done. Nodes that need the model before then wait for it to finish loading.
+    fn preload(&mut self) {
+        let _ = self.start_preload();
+    }
+
+    #[func]
+    /// Initializes the llama.cpp backend on a background thread, e.g. at game start, so the first model load doesn't also
+    /// have to wait for it. This is shared by all models, so it only needs to be called once, on any model node.
+    /// Triggers `backend_ready` when done.
+    fn init_backend(&mut self) {
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            llm::init_backend();
+            let _ = ready_tx.send(());
+        });
+        let emit_node = self.to_gd();
+        godot::task::spawn(async move {
+            if ready_rx.await.is_ok() {
+                emit_node.signals().backend_ready().emit();
+            }
+        });
+    }
+
+    #[signal]
+    /// Triggered when the llama.cpp backend has been initialized after calling `init_backend`.
+    fn backend_ready();
+
+    #[signal]
+    /// Triggered when the model has finished loading after calling `preload`.
+    fn model_loaded();
+
+    #[signal]
+    /// Triggered when the model could not be loaded after calling `preload`, with a dictionary like
+    /// `{"code": NobodyWhoErrorCode.MODEL_NOT_FOUND, "message": "..."}`.
+    fn error_occurred(error: Dictionary);
+}
+
+#[derive(GodotClass)]
+#[class(base=Node)]
+/// Loads several models at once, e.g. behind a loading screen, so no NPC stutters when it first uses its model.
+/// The models are loaded in parallel, on background threads.
+///
+/// Example:
+///
+/// ```
+/// func _ready():
+///     $ModelLoader.models = [$ShopkeeperModel, $GuardModel]
+///     $ModelLoader.load_all()
+///     await $ModelLoader.all_models_loaded
+///     get_tree().change_scene_to_file("res://game.tscn")
+/// ```
+struct NobodyWhoModelLoader {
+    #[export]
+    /// The models to load.
+    models: Array<Gd<NobodyWhoModel>>,
+
+    base: Base<Node>,
+}
+
+#[godot_api]
+impl INode for NobodyWhoModelLoader {
+    fn init(base: Base<Node>) -> Self {
+        Self {
+            models: Array::new(),
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl NobodyWhoModelLoader {
+    #[func]
+    /// Starts loading all the models. Triggers `all_models_loaded` when every one of them is done.
+    fn load_all(&mut self) {
+        let loading: Vec<_> = self
+            .models
+            .iter_shared()
+            .map(|mut model| model.bind_mut().start_preload())
+            .collect();
+        let emit_node = self.to_gd();
+        godot::task::spawn(async move {
+            let mut success = true;
+            for done in loading {
+                success &= done.await.unwrap_or(false);
+            }
+            emit_node.signals().all_models_loaded().emit(success);
+        });
+    }
+
+    #[signal]
+    /// Triggered when all the models have finished loading. `success` is false if any of them failed to load,
+    /// in which case that model also triggers its `error_occurred` signal.
+    fn all_models_loaded(success: bool);
+}
+
+#[derive(GodotClass)]
+#[class(base=Node)]
+/// NobodyWhoChat is the main node for interacting with the LLM. It functions as a chat, and can be used to send and receive messages.
+///
+/// The chat node is used to start a new context to send and receive messages (multiple contexts can be used at the same time with the same model).
+/// It requires a call to `start_worker()` before it can be used. This happens when the node is ready if `auto_start` is enabled and the model node is set in the inspector.
+/// Otherwise, if you do not call it, the chat will start the worker when you send the first message.
+///
+/// Example:
+///
+/// ```
+/// extends NobodyWhoChat
+///
+/// func _ready():
+///     # configure node
+///     self.model_node = get_node("../ChatModel")
+///     self.system_prompt = "You are an evil wizard. Always try to curse anyone who talks to you."
+///
+///     # say something
+///     say("Hi there! Who are you?")
+///
+///     # wait for the response
+///     var response = await response_finished
+///     print("Got response: " + response)
+///
+///     # in this example we just use the `response_finished` signal to get the complete response
+///     # in real-world-use you definitely want to connect `response_updated`, which gives one token at a time,
+///     # or `word_completed`, which gives one word at a time.
+///     # the whole interaction feels *much* smoother if you stream the response out word-by-word.
+/// ```
+///
+struct NobodyWhoChat {
+    #[export]
+    /// The model node for the chat.
+    model_node: Option<Gd<NobodyWhoModel>>,
+
+    #[export]
+    /// An embedding model node, e.g. a small sentence-transformer, for embedding each message and response of the chat.
+    /// When set, `start_worker()` also starts an embedding worker, and every message from `say`, `say_n` and `say_json`,
+    /// and every response that triggers `response_finished`, also triggers `message_embedded`. Useful for storing the conversation and retrieving it later.
+    embedding_model_node: Option<Gd<NobodyWhoModel>>,
+
+    #[export]
+    /// The sampler configuration for the chat. It is read when the worker starts, so later changes take effect on the next `start_worker()`.
+    /// Like other resources, a sampler can be shared between several chats, and changing it changes it for all of them.
+    /// Use `clone_sampler()` on the sampler to give a chat its own copy.
+    sampler: Option<Gd<NobodyWhoSampler>>,
+
+    #[export]
+    /// Starts the worker as soon as the node is ready, so the first message doesn't have to wait for it.
+    /// Only works when `model_node` is set in the inspector, since it happens before the `_ready` of a script on this node.
+    auto_start: bool,
+
+    #[export]
+    /// When no `sampler` is set, use the sampler settings recommended in the model file's metadata, instead of the generic default.
+    /// Many recent GGUF files include these (e.g. temperature and top_p), and they usually give better responses for that model.
+    /// Falls back to the default sampler when the model doesn't include any.
+    use_model_sampler_defaults: bool,
+
+    #[export]
+    /// A persona to take the system prompt, sampler and stop tokens from. When set, `start_worker()` overwrites
+    /// `system_prompt`, `sampler` and `stop_tokens` with the ones from the persona.
+    persona: Option<Gd<NobodyWhoPersona>>,
+
+    #[export]
+    #[var(hint = MULTILINE_TEXT)]
+    /// The system prompt for the chat, this is the basic instructions for the LLM's behavior.
+    system_prompt: GString,
+
+    #[export]
+    #[var(hint = MULTILINE_TEXT)]
+    /// Text that the LLM should steer away from, e.g. "The assistant is rude and insults the user."
+    /// This uses classifier-free guidance, which gives finer control than instructions in the system prompt,
+    /// but makes generation about twice as slow. Leave empty to disable it.
+    negative_prompt: GString,
+
+    #[export(range = (1.0, 5.0))]
+    /// How strongly to steer away from `negative_prompt`. 1.0 has no effect, and values around 1.5 to 3.0 usually work well.
+    cfg_scale: f32,
+
+    #[export(file = "*.txt,*.md")]
+    /// A text file to load the system prompt from, for long prompts that are easier to write in a text editor.
+    /// The file is read when the worker starts. A non-empty `system_prompt` takes precedence over this file.
+    system_prompt_file: GString,
+
+    #[export]
+    /// Stop tokens to stop generation at these specified tokens.
+    stop_tokens: PackedStringArray,
+
+    #[export]
+    /// This is the maximum number of tokens that can be stored in the chat history. It will delete information from the chat history if it exceeds this limit.
+    /// Higher values use more VRAM, but allow for longer "short term memory" for the LLM.
+    /// It can't be larger than what the model was trained with, see `get_training_context_length` on the model node.
+    context_length: u32,
+
+    #[export]
+    /// The maximum number of user and assistant messages to keep in the chat history. The system prompt is always kept.
+    /// When the history grows past this limit, the oldest messages are dropped and the context is rebuilt from the remaining ones.
+    /// A value of 0 means no limit, in which case only the context length limits the history.
+    max_history_messages: u32,
+
+    #[export]
+    /// Runs the LLM worker with a lower priority than the rest of the game.
+    /// Generation gets a bit slower, but it is less likely to cause stutter when the CPU is busy.
+    low_priority: bool,
+
+    #[export]
+    /// The number of parts a single response may consist of. By default, a response ends when the LLM ends its turn.
+    /// With a value above 1, the end of a turn only separates the parts of the response, and generation continues until
+    /// this many parts have been generated or a stop token is reached. Useful for e.g. a monologue with several parts.
+    max_response_parts: u32,
+
+    #[export]
+    /// The longest time in milliseconds to spend generating a single response. When it runs out, the response ends
+    /// with what was generated so far, and `get_finish_reason` returns "time_limit". A value of 0 means no limit.
+    max_response_duration_ms: u32,
+
+    #[export(range = (0.0, 1.0))]
+    /// Defragments the KV cache after a context shift, when more than this fraction of its free space is scattered in holes.
+    /// Long conversations that shift the context often stay faster this way. Leave at 1.0 to only defragment with `defragment()`.
+    auto_defragment_threshold: f32,
+
+    #[export]
+    /// DANGER: decodes without waiting for other nodes that use the same model. Only enable this if the model node
+    /// is used by this chat node and nothing else, not even an embedding node. If two nodes decode with the same model
+    /// at the same time, the game crashes with a segfault. When it is safe, it saves a little overhead on every token.
+    unsafe_skip_inference_lock_i_know_what_i_am_doing: bool,
+
+    #[export]
+    /// The text inserted between the parts of a response, when `max_response_parts` is above 1.
+    response_part_separator: GString,
+
+    #[export]
+    /// When enabled, the `prompt_echoed` signal is triggered before each response with the exact text the LLM reads.
+    /// The prompt is tokenized and converted back to text, so this shows special tokens and any lossy tokenization.
+    /// This is useful for debugging chat templates and stop tokens.
+    echo_prompt: bool,
+
+    #[export]
+    /// Renames the roles of the chat messages, for chat templates that don't use the standard "system", "user" and "assistant" roles.
+    /// For example `{"assistant": "model"}` for templates that call the assistant "model".
+    role_names: Dictionary,
+
+    #[export]
+    /// When enabled, `response_updated` is triggered at most once per physics frame, with all the tokens generated since the last frame.
+    /// On fast GPUs, many tokens can be generated per frame, and this gives smoother text animations with less signal overhead.
+    batch_tokens_per_frame: bool,
+
+    #[export(range = (0.0, 200.0, or_greater))]
+    /// Releases the response at this many characters per second, like someone typing, no matter how fast it is generated.
+    /// `response_updated`, `word_completed` and `response_finished` all follow this pace, while generation runs at full speed in the background.
+    /// A value of 0 disables this. Turning it on or off takes effect on the next `start_worker()`.
+    typing_speed: f32,
+
+    #[export]
+    /// The number of generated tokens that may wait to be handled by the game. When the game falls this far behind,
+    /// e.g. during a long frame, generation pauses until it catches up, so memory use stays bounded.
+    max_buffered_tokens: u32,
+
+    #[export]
+    /// Calls `logit_processor` before every generated token, so the game can make tokens more or less likely as the response is written.
+    /// This is slow: generation waits for the main thread at every token, which can limit it to about one token per frame,
+    /// on top of the time the callable itself takes. Leave it off unless you need it. Takes effect on the next `start_worker()`.
+    use_logit_processor: bool,
+
+    #[export(range = (1.0, 100.0, or_greater))]
+    /// The number of most likely tokens passed to `logit_processor` at each step.
+    logit_processor_top_k: u32,
+
+    #[export]
+    /// Triggers `token_probability` with how likely the LLM found each token it generated, e.g. to animate how certain an NPC is.
+    /// This costs a little extra work per token, so it is off by default. Takes effect on the next `start_worker()`.
+    emit_token_probabilities: bool,
+
+    #[var]
+    /// Called with the most likely next tokens when `use_logit_processor` is enabled, as an array of dictionaries with the keys
+    /// "token" (the token id), "text" and "logit", most likely first. It should return a dictionary from token ids to amounts
+    /// to add to their logits, e.g. `{candidates[0].token: -INF}` to forbid the most likely token. An empty dictionary changes nothing.
+    /// ```
+    /// chat.logit_processor = func(candidates):
+    ///     var adjustments = {}
+    ///     for candidate in candidates:
+    ///         if "sword" in candidate.text:
+    ///             adjustments[candidate.token] = 2.0
+    ///     return adjustments
+    /// ```
+    logit_processor: Callable,
+
+    #[export]
+    #[var(hint = MULTILINE_TEXT)]
+    /// The chat template to use when the model file doesn't include one, which is the case for many older GGUF files (e.g. LLaMA2-based ones).
+    /// Either the name of a bundled template ("chatml" or "llama2"), or a full jinja chat template. Leave empty to fail instead.
+    fallback_chat_template: GString,
+
+    #[export]
+    #[var(hint = MULTILINE_TEXT)]
+    /// Overrides the chat template included in the model file, e.g. to fix a broken template or to force ChatML.
+    /// Either the name of a bundled template ("chatml" or "llama2"), or a full jinja chat template. Leave empty to use the model's own template.
+    chat_template: GString,
+
+    #[export(file = "*.jinja")]
+    /// A jinja file to load the chat template from, for iterating on a template in a text editor.
+    /// The file is read when the worker starts, so restart the worker to try changes. It takes precedence over `chat_template`.
+    chat_template_file: GString,
+
+    #[export]
+    /// Puts the model's BOS token at the start of the conversation, when the chat template doesn't.
+    /// Templates included in model files usually handle this themselves, so this is mostly useful with a custom `chat_template`.
+    prepend_bos_token: bool,
+
+    #[export]
+    /// Starts the assistant's turn after each user message, for chat templates that render nothing for the generation prompt.
+    /// It should match how the template starts assistant messages, e.g. "<|assistant|>\n". A warning is printed
+    /// when the worker starts with such a template and this is empty. Templates that do start the turn themselves are left alone.
+    fallback_generation_prompt: GString,
+
+    #[export]
+    /// Lets reasoning models like Qwen3 think before they respond. Turning it off makes responses faster, but often worse.
+    /// This only works with chat templates that read the `enable_thinking` variable, and takes effect on the next `start_worker()`.
+    enable_thinking: bool,
+
+    #[export]
+    /// Regular expressions to replace in each full response, with what to replace them with, e.g. `{"^\\s*Guard:\\s*": ""}`
+    /// to remove a role prefix. The replacement can refer to capture groups, like `$1`. They are applied in order, before `post_process_steps`.
+    post_process_replacements: Dictionary,
+
+    #[export]
+    /// Clean-up steps applied to each full response, in order:
+    /// - "trim": removes whitespace from the start and end.
+    /// - "strip_markdown": removes markdown formatting like headings, `**bold**`, `*italics*`, code and links, keeping the text.
+    /// - "capitalize": makes the first letter uppercase.
+    /// Only `response_finished` gets the cleaned up response. `response_updated` still streams the tokens as they are generated,
+    /// and the LLM remembers its responses as it wrote them.
+    post_process_steps: PackedStringArray,
+
+    #[export]
+    /// For NPCs that respond with JSON objects like `{"emotion": "angry", "text": "Get out!"}`, set this to the name of
+    /// the field to show while it is generated, e.g. "text". Then `response_updated` and `word_completed` only get that field,
+    /// while `response_finished` still gets the whole JSON, and `structured_response_finished` gets it parsed into a Dictionary.
+    /// If the sampler has no grammar, responses are constrained to JSON. Use a grammar that puts the other fields first,
+    /// to have them ready as soon as the text starts streaming. Leave empty to stream the whole response.
+    stream_field: GString,
+
+    #[export]
+    /// Picks markdown code blocks out of responses as they stream, e.g. for a coding assistant.
+    /// Code inside ``` fences goes to `code_updated` instead of `response_updated`, between `code_block_started` and `code_block_ended`.
+    /// `response_finished` still gets the whole response, fences included. Takes effect on the next `start_worker()`.
+    detect_code_blocks: bool,
+
+    #[export]
+    /// Removes special tokens like `<|im_end|>` that some models write into their responses, before they reach any signal.
+    /// Takes effect on the next `start_worker()`.
+    strip_special_tokens: bool,
+
+    #[export]
+    /// The strings removed by `strip_special_tokens`. Leave empty to use the special tokens of the model.
+    special_token_strings: PackedStringArray,
+
+    #[export]
+    /// Records the responses to `recording_file`, token by token, so they can be replayed later without running the model.
+    /// - Record: saves every response to the recording file.
+    /// - Replay: answers with the recorded responses, in order. No model is loaded, so this works in automated tests.
+    /// - Verify: runs the model and reports an error when a response differs from the recording. Use a sampler with a fixed seed.
+    replay_mode: ReplayMode,
+
+    #[export(file = "*.json")]
+    /// The recording file used by `replay_mode`.
+    recording_file: GString,
+
+    #[export]
+    /// Sent to the LLM instead of messages that are empty or only whitespace, which are usually sent by accident.
+    /// When left empty, such messages are ignored with a warning.
+    empty_message_placeholder: GString,
+
+    msg_tx: Option<tokio::sync::mpsc::Sender<chat::ChatMsg>>,
+    embed_tx: Option<tokio::sync::mpsc::Sender<String>>,
+    /// The role and text of each message sent to the embedding worker, in order, until its embedding is done.
+    embedding_queue: VecDeque<(String, String)>,
+    reported_missing_model: bool,
+    prompt_variables: Dictionary,
+    token_buffer: String,
+    word_buffer: String,
+    typing_queue: VecDeque<TypedOutput>,
+    typing_progress: f64,
+    overflow_resolver: Option<tokio::sync::oneshot::Sender<llm::OverflowStrategy>>,
+    last_response: String,
+    last_response_tokens: PackedInt32Array,
+    partial_response: String,
+    finish_reason: String,
+    stop_token: String,
+    yes_no_confidence: f64,
+    timings: Timings,
+    context_used: u32,
+    context_size: u32,
+    pause_gate: llm::PauseGate,
+
+    base: Base<Node>,
+}
+
+struct ChatAdapter {
+    emit_node: Gd<NobodyWhoChat>,
+    batch_tokens_per_frame: bool,
+    type_out: bool,
+}
+
+/// Where the time went since the worker started, for `get_timing_breakdown`.
+#[derive(Default)]
+struct Timings {
+    model_load: Option<std::time::Duration>,
+    worker_init: Option<std::time::Duration>,
+    first_turn: Option<chat::TurnTimings>,
+    last_turn: Option<chat::TurnTimings>,
+    n_turns: u64,
+}
+
+/// Output waiting to be released in `physics_process`, when `typing_speed` is set.
+enum TypedOutput {
+    Text(String),
+    FinishReason(llm::FinishReason),
+    Response(String),
+}
+
+/// Moves all words that are followed by whitespace out of the buffer, leaving only the unfinished word.
+fn take_completed_words(buffer: &mut String) -> Vec<String> {
+    let Some(last_whitespace) = buffer.rfind(char::is_whitespace) else {
+        return vec![];
+    };
+    let unfinished = buffer.split_off(last_whitespace).trim_start().to_string();
+    let words = buffer.split_whitespace().map(String::from).collect();
+    *buffer = unfinished;
+    words
+}
+
+impl ChatAdapter {
+    fn emit_words(&self, words: Vec<String>) {
+        for word in words {
+            self.emit_node.signals().word_completed().emit(word);
+        }
+    }
+}
+
+/// Reads the `{token: bias}` dictionary returned by `logit_processor`, skipping entries of the wrong type.
+fn parse_logit_adjustments(adjustments: &Dictionary) -> Vec<(i32, f32)> {
+    adjustments
+        .iter_shared()
+        .filter_map(|(token, bias)| {
+            let token = token.try_to::<i32>().ok()?;
+            let bias = bias
+                .try_to::<f64>()
+                .or_else(|_| bias.try_to::<i64>().map(|bias| bias as f64))
+                .ok()?;
+            Some((token, bias as f32))
+        })
+        .collect()
+}
+
+impl chat::ChatOutput for ChatAdapter {
+    fn emit_token(&self, tok: String) {
+        if self.type_out {
+            // words are completed as the text is typed out
+            self.emit_node
+                .clone()
+                .bind_mut()
+                .typing_queue
+                .push_back(TypedOutput::Text(tok));
+            return;
+        }
+
+        let words = {
+            let mut emit_node = self.emit_node.clone();
+            let mut node = emit_node.bind_mut();
+            node.word_buffer.push_str(&tok);
+            take_completed_words(&mut node.word_buffer)
+        };
+        self.emit_words(words);
+
+        if self.batch_tokens_per_frame {
+            // emitted in `physics_process`
+            self.emit_node
+                .clone()
+                .bind_mut()
+                .token_buffer
+                .push_str(&tok);
+            return;
+        }
+        self.emit_node
+            .clone()
+            .bind_mut()
+            .partial_response
+            .push_str(&tok);
+        self.emit_node.signals().response_updated().emit(tok)
+    }
+    fn emit_response(&self, resp: String) {
+        self.emit_node
+            .clone()
+            .bind_mut()
+            .embed_message("assistant", &resp);
+        if self.type_out {
+            // finished once everything before it is typed out
+            self.emit_node
+                .clone()
+                .bind_mut()
+                .typing_queue
+                .push_back(TypedOutput::Response(resp));
+            return;
+        }
+        // flush any tokens that are still waiting for the next frame, so they arrive before the full response
+        let buffered = {
+            let mut emit_node = self.emit_node.clone();
+            let mut node = emit_node.bind_mut();
+            node.last_response = resp.clone();
+            node.partial_response.clear();
+            std::mem::take(&mut node.token_buffer)
+        };
+        if !buffered.is_empty() {
+            self.emit_node.signals().response_updated().emit(buffered);
+        }
+        // the last word of a response isn't followed by whitespace
+        let last_words = std::mem::take(&mut self.emit_node.clone().bind_mut().word_buffer);
+        self.emit_words(last_words.split_whitespace().map(String::from).collect());
+        let parsed = self.emit_node.bind().parse_structured_response(&resp);
+        self.emit_node.signals().response_finished().emit(resp);
+        if let Some(parsed) = parsed {
+            self.emit_node
+                .signals()
+                .structured_response_finished()
+                .emit(parsed);
+        }
+    }
+    fn emit_error(&self, err: String) {
+        godot_error!("LLM Worker failed: {err}");
+    }
+    fn emit_prompt(&self, prompt: String) {
+        self.emit_node.signals().prompt_echoed().emit(prompt)
+    }
+    fn emit_partial_response(&self, partial: String) {
+        // the partial response already contains whatever was still buffered
+        {
+            let mut emit_node = self.emit_node.clone();
+            let mut node = emit_node.bind_mut();
+            node.token_buffer.clear();
+            node.word_buffer.clear();
+            node.typing_queue.clear();
+            node.partial_response.clear();
+        }
+        self.emit_node
+            .signals()
+            .response_interrupted()
+            .emit(partial)
+    }
+    fn emit_responses(&self, responses: Vec<String>) {
+        if let Some(first) = responses.first() {
+            self.emit_node.clone().bind_mut().last_response = first.clone();
+        }
+        let responses: PackedStringArray = responses.iter().map(GString::from).collect();
+        self.emit_node
+            .signals()
+            .responses_finished()
+            .emit(responses)
+    }
+    fn emit_code_block_started(&self, language: String) {
+        self.emit_node.signals().code_block_started().emit(language)
+    }
+    fn emit_code(&self, code: String) {
+        self.emit_node.signals().code_updated().emit(code)
+    }
+    fn emit_code_block_ended(&self) {
+        self.emit_node.signals().code_block_ended().emit()
+    }
+    fn emit_response_tokens(&self, tokens: Vec<i32>) {
+        self.emit_node.clone().bind_mut().last_response_tokens =
+            PackedInt32Array::from(tokens.as_slice());
+    }
+    fn emit_token_probability(&self, token: &str, probability: f32) {
+        self.emit_node
+            .signals()
+            .token_probability()
+            .emit(token.to_string(), probability as f64)
+    }
+    fn emit_yes_no(&self, answer: Option<bool>, confidence: f32) {
+        self.emit_node.clone().bind_mut().yes_no_confidence = confidence as f64;
+        if answer.is_none() {
+            self.emit_node.signals().error_occurred().emit(
+                NobodyWhoError::new(
+                    ErrorCode::QuestionUnanswered,
+                    "Got no answer to the yes/no question, answering false.",
+                )
+                .to_dictionary(),
+            );
+        }
+        self.emit_node
+            .signals()
+            .yes_no_answered()
+            .emit(answer.unwrap_or(false))
+    }
+    fn emit_drafts(&self, drafts: Vec<String>) {
+        let drafts: PackedStringArray = drafts.iter().map(GString::from).collect();
+        self.emit_node.signals().drafts_finished().emit(drafts)
+    }
+    fn emit_invalid_response(&self, response: String) {
+        self.emit_node
+            .signals()
+            .structured_response_failed()
+            .emit(response)
+    }
+    fn emit_worker_ready(&self, init_duration: std::time::Duration) {
+        self.emit_node.clone().bind_mut().timings.worker_init = Some(init_duration);
+    }
+    fn emit_turn_timings(&self, turn: chat::TurnTimings) {
+        let mut emit_node = self.emit_node.clone();
+        let mut node = emit_node.bind_mut();
+        let timings = &mut node.timings;
+        timings.first_turn.get_or_insert(turn);
+        timings.last_turn = Some(turn);
+        timings.n_turns += 1;
+    }
+    fn emit_context_usage(&self, n_past: u32, n_ctx: u32) {
+        let mut emit_node = self.emit_node.clone();
+        let mut node = emit_node.bind_mut();
+        node.context_used = n_past;
+        node.context_size = n_ctx;
+    }
+    fn emit_context_shifted(&self, n_discarded: u32) {
+        {
+            let mut emit_node = self.emit_node.clone();
+            let mut node = emit_node.bind_mut();
+            node.context_used = node.context_used.saturating_sub(n_discarded);
+        }
+        self.emit_node
+            .signals()
+            .context_shifted()
+            .emit(n_discarded as i64);
+    }
+    fn emit_finish_reason(&self, finish_reason: llm::FinishReason) {
+        if self.type_out {
+            self.emit_node
+                .clone()
+                .bind_mut()
+                .typing_queue
+                .push_back(TypedOutput::FinishReason(finish_reason));
+            return;
+        }
+        self.emit_node
+            .clone()
+            .bind_mut()
+            .set_finish_reason(&finish_reason);
+        if let llm::FinishReason::StopToken(stop_token) = finish_reason {
+            self.emit_node.signals().stopped_on().emit(stop_token);
+        }
+    }
+    fn emit_context_full(&self, resolve_to: tokio::sync::oneshot::Sender<llm::OverflowStrategy>) {
+        // without a handler, nobody would ever resolve it, so just shift like we always did
+        if self
+            .emit_node
+            .get_signal_connection_list("context_full")
+            .is_empty()
+        {
+            let _ = resolve_to.send(llm::OverflowStrategy::Shift);
+            return;
+        }
+        self.emit_node.clone().bind_mut().overflow_resolver = Some(resolve_to);
+        self.emit_node.signals().context_full().emit();
+    }
+    fn emit_adjust_logits(
+        &self,
+        candidates: Vec<llm::TokenCandidate>,
+        resolve_to: tokio::sync::oneshot::Sender<Vec<(i32, f32)>>,
+    ) {
+        let processor = self.emit_node.bind().logit_processor.clone();
+        if !processor.is_valid() {
+            let _ = resolve_to.send(vec![]);
+            return;
+        }
+        let candidates: Array<Dictionary> = candidates
+            .into_iter()
+            .map(|candidate| {
+                dict! {
+                    "token": candidate.token,
+                    "text": candidate.text,
+                    "logit": candidate.logit,
+                }
+            })
+            .collect();
+        let result = processor.call(&[candidates.to_variant()]);
+        let adjustments = match result.try_to::<Dictionary>() {
+            Ok(adjustments) => parse_logit_adjustments(&adjustments),
+            Err(_) => {
+                godot_warn!(
+                    "logit_processor should return a dictionary, but returned {result}. Ignoring it."
+                );
+                vec![]
+            }
+        };
+        let _ = resolve_to.send(adjustments);
+    }
+}
+
+#[godot_api]
+impl INode for NobodyWhoChat {
+    fn init(base: Base<Node>) -> Self {
+        Self {
+            // config
+            model_node: None,
+            sampler: None,
+            auto_start: true,
+            use_model_sampler_defaults: false,
+            persona: None,
+            system_prompt: "".into(),
+            system_prompt_file: "".into(),
+            negative_prompt: "".into(),
+            cfg_scale: 1.5,
+            stop_tokens: PackedStringArray::new(),
+            context_length: 4096,
+            max_history_messages: 0,
+            low_priority: false,
+            max_response_parts: 1,
+            max_response_duration_ms: 0,
+            auto_defragment_threshold: 1.0,
+            unsafe_skip_inference_lock_i_know_what_i_am_doing: false,
+            response_part_separator: "\n\n".into(),
+            echo_prompt: false,
+            role_names: Dictionary::new(),
+            batch_tokens_per_frame: false,
+            typing_speed: 0.0,
+            max_buffered_tokens: 4096,
+            use_logit_processor: false,
+            emit_token_probabilities: false,
+            logit_processor_top_k: 10,
+            logit_processor: Callable::invalid(),
+            fallback_chat_template: "".into(),
+            chat_template: "".into(),
+            chat_template_file: "".into(),
+            prepend_bos_token: false,
+            fallback_generation_prompt: GString::new(),
+            enable_thinking: true,
+            post_process_replacements: Dictionary::new(),
+            post_process_steps: PackedStringArray::new(),
+            stream_field: GString::new(),
+            detect_code_blocks: false,
+            strip_special_tokens: false,
+            special_token_strings: PackedStringArray::new(),
+            replay_mode: ReplayMode::Off,
+            recording_file: "user://recording.json".into(),
+            empty_message_placeholder: "".into(),
+            msg_tx: None,
+            embed_tx: None,
+            embedding_queue: VecDeque::new(),
+            reported_missing_model: false,
+            prompt_variables: Dictionary::new(),
+            token_buffer: String::new(),
+            word_buffer: String::new(),
+            typing_queue: VecDeque::new(),
+            typing_progress: 0.0,
+            overflow_resolver: None,
+            last_response: String::new(),
+            last_response_tokens: PackedInt32Array::new(),
+            partial_response: String::new(),
+            timings: Timings::default(),
+            finish_reason: String::new(),
+            stop_token: String::new(),
+            yes_no_confidence: 0.0,
+            context_used: 0,
+            context_size: 0,
+            pause_gate: llm::PauseGate::default(),
+
+            base,
+        }
+    }
+
+    // a notification rather than `ready`, since a script's `_ready` would replace `ready`, but not this
+    fn on_notification(&mut self, what: NodeNotification) {
+        if what == NodeNotification::READY && self.auto_start && self.model_node.is_some() {
+            self.start_worker();
+        }
+    }
+
+    fn physics_process(&mut self, delta: f64) {
+        if !self.token_buffer.is_empty() {
+            let tokens = std::mem::take(&mut self.token_buffer);
+            self.partial_response.push_str(&tokens);
+            self.signals().response_updated().emit(tokens);
+        }
+        self.type_out(delta);
+    }
+}
+
+#[godot_api]
+impl NobodyWhoChat {
+    /// Releases as much of the typing queue as `typing_speed` allows in `delta` seconds.
+    fn type_out(&mut self, delta: f64) {
+        if self.typing_queue.is_empty() {
+            self.typing_progress = 0.0;
+            return;
+        }
+        let mut budget = if self.typing_speed > 0.0 {
+            // keep the fraction of a character for the next frame, so slow speeds still make progress
+            self.typing_progress += delta * self.typing_speed as f64;
+            let n_chars = self.typing_progress.floor();
+            self.typing_progress -= n_chars;
+            n_chars as usize
+        } else {
+            usize::MAX
+        };
+
+        let mut typed = String::new();
+        while let Some(output) = self.typing_queue.pop_front() {
+            match output {
+                TypedOutput::Text(mut text) => {
+                    let split = text
+                        .char_indices()
+                        .nth(budget)
+                        .map_or(text.len(), |(i, _)| i);
+                    let rest = text.split_off(split);
+                    budget -= text.chars().count();
+                    typed.push_str(&text);
+                    if !rest.is_empty() {
+                        self.typing_queue.push_front(TypedOutput::Text(rest));
+                        break;
+                    }
+                }
+                TypedOutput::FinishReason(finish_reason) => {
+                    self.set_finish_reason(&finish_reason);
+                    if let llm::FinishReason::StopToken(stop_token) = finish_reason {
+                        self.emit_typed(std::mem::take(&mut typed));
+                        self.signals().stopped_on().emit(stop_token);
+                    }
+                }
+                TypedOutput::Response(response) => {
+                    self.emit_typed(std::mem::take(&mut typed));
+                    // the last word of a response isn't followed by whitespace
+                    let last_words = std::mem::take(&mut self.word_buffer);
+                    for word in last_words.split_whitespace() {
+                        self.signals().word_completed().emit(word.to_string());
+                    }
+                    self.last_response = response.clone();
+                    self.partial_response.clear();
+                    let parsed = self.parse_structured_response(&response);
+                    self.signals().response_finished().emit(response);
+                    if let Some(parsed) = parsed {
+                        self.signals().structured_response_finished().emit(parsed);
+                    }
+                }
+            }
+        }
+        self.emit_typed(typed);
+    }
+
+    fn emit_typed(&mut self, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        self.word_buffer.push_str(&text);
+        let words = take_completed_words(&mut self.word_buffer);
+        self.partial_response.push_str(&text);
+        self.signals().response_updated().emit(text);
+        for word in words {
+            self.signals().word_completed().emit(word);
+        }
+    }
+
+    fn get_model(&mut self) -> Result<llm::Model, NobodyWhoError> {
+        let gd_model_node = self
+            .model_node
+            .as_mut()
+            .ok_or_else(|| NobodyWhoError::new(ErrorCode::ModelNotSet, "Model node was not set"))?;
+        let mut nobody_model = gd_model_node.bind_mut();
+        let model: llm::Model = nobody_model.get_model()?;
+
+        Ok(model)
+    }
+
+    fn get_sampler_config(
+        &mut self,
+        model: &llm::Model,
+    ) -> Result<sampler_config::SamplerConfig, NobodyWhoError> {
+        if let Some(gd_sampler) = self.sampler.as_mut() {
+            let nobody_sampler: GdRef<NobodyWhoSampler> = gd_sampler.bind();
+            // copied, so the running worker isn't affected by changes to a shared sampler
+            nobody_sampler.get_sampler_config()
+        } else if self.use_model_sampler_defaults {
+            let config = sampler_config::SamplerConfig::from_model_metadata(model);
+            if config.is_none() {
+                godot_warn!(
+                    "The model doesn't recommend any sampler settings. Using the default sampler."
+                );
+            }
+            Ok(config.unwrap_or_default())
+        } else {
+            Ok(sampler_config::SamplerConfig::default())
+        }
+    }
+
+    fn apply_persona(&mut self) {
+        let Some(persona) = self.persona.as_ref() else {
+            return;
+        };
+        let persona = persona.bind();
+        self.system_prompt = persona.system_prompt.clone();
+        self.sampler = persona.sampler.clone();
+        self.stop_tokens = persona.stop_tokens.clone();
+    }
+
+    fn get_role_names(&self) -> chat_state::RoleNames {
+        let mut role_names = chat_state::RoleNames::default();
+        for (role, name) in self.role_names.iter_shared() {
+            let name = name.to_string();
+            match role.to_string().as_str() {
+                "system" => role_names.system = name,
+                "user" => role_names.user = name,
+                "assistant" => role_names.assistant = name,
+                other => godot_warn!(
+                    "Unknown role in role_names: {other}. Expected system, user or assistant."
+                ),
+            }
+        }
+        role_names
+    }
+
+    fn get_system_prompt(&self) -> Result<String, NobodyWhoError> {
+        let mut system_prompt =
+            if !self.system_prompt.is_empty() || self.system_prompt_file.is_empty() {
+                self.system_prompt.to_string()
+            } else {
+                let path: String = ProjectSettings::singleton()
+                    .globalize_path(&self.system_prompt_file)
+                    .into();
+                std::fs::read_to_string(&path).map_err(|e| {
+                    NobodyWhoError::new(
+                        ErrorCode::SystemPromptFileFailed,
+                        format!("Could not read system prompt file {path}: {e}"),
+                    )
+                })?
+            };
+        for (key, value) in self.prompt_variables.iter_shared() {
+            let placeholder = format!("{{{key}}}");
+            system_prompt = system_prompt.replace(&placeholder, &value.to_string());
+        }
+        Ok(system_prompt)
+    }
+
+    /// The chat template override, from `chat_template_file` or else `chat_template`, or `None` to use the model's own.
+    fn get_chat_template(&self) -> Result<Option<String>, NobodyWhoError> {
+        if self.chat_template_file.is_empty() {
+            return Ok(resolve_chat_template(&self.chat_template));
+        }
+        let path: String = ProjectSettings::singleton()
+            .globalize_path(&self.chat_template_file)
+            .into();
+        std::fs::read_to_string(&path).map(Some).map_err(|e| {
+            NobodyWhoError::new(
+                ErrorCode::ChatTemplateFileFailed,
+                format!("Could not read chat template file {path}: {e}"),
+            )
+        })
+    }
+
+    fn get_post_process(&self) -> Result<Vec<postprocess::PostProcessStep>, NobodyWhoError> {
+        let mut steps = Vec::new();
+        for (pattern, replacement) in self.post_process_replacements.iter_shared() {
+            let step = postprocess::PostProcessStep::regex_replace(
+                &pattern.to_string(),
+                &replacement.to_string(),
+            )
+            .map_err(|e| {
+                NobodyWhoError::new(
+                    ErrorCode::InvalidPattern,
+                    format!("Invalid pattern in post_process_replacements: {e}"),
+                )
+            })?;
+            steps.push(step);
+        }
+        for name in self.post_process_steps.as_slice() {
+            match name.to_string().as_str() {
+                "trim" => steps.push(postprocess::PostProcessStep::Trim),
+                "strip_markdown" => steps.push(postprocess::PostProcessStep::StripMarkdown),
+                "capitalize" => steps.push(postprocess::PostProcessStep::Capitalize),
+                other => godot_warn!(
+                    "Unknown step in post_process_steps: {other}. Expected trim, strip_markdown or capitalize."
+                ),
+            }
+        }
+        Ok(steps)
+    }
+
+    fn get_recording_path(&self) -> std::path::PathBuf {
+        let path: String = ProjectSettings::singleton()
+            .globalize_path(&self.recording_file)
+            .into();
+        path.into()
+    }
+
+    fn get_strip_strings(&self, model: &llm::Model) -> Vec<String> {
+        if !self.strip_special_tokens {
+            return vec![];
+        }
+        if self.special_token_strings.is_empty() {
+            return llm::special_token_strings(model);
+        }
+        self.special_token_strings
+            .to_vec()
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    fn get_stream_field(&self) -> Option<String> {
+        (!self.stream_field.is_empty()).then(|| self.stream_field.to_string())
+    }
+
+    fn set_finish_reason(&mut self, finish_reason: &llm::FinishReason) {
+        self.finish_reason = finish_reason.as_str().to_string();
+        self.stop_token = match finish_reason {
+            llm::FinishReason::StopToken(stop_token) => stop_token.clone(),
+            _ => String::new(),
+        };
+    }
+
+    /// Parses a response for `structured_response_finished`, when `stream_field` is set.
+    fn parse_structured_response(&self, response: &str) -> Option<Dictionary> {
+        if self.stream_field.is_empty() {
+            return None;
+        }
+        let parsed = Json::parse_string(response).try_to::<Dictionary>().ok();
+        if parsed.is_none() {
+            godot_warn!("Response is not a JSON object: {response}");
+        }
+        parsed
+    }
+
+    fn get_recording_mode(&self) -> Result<Option<replay::RecordingMode>, NobodyWhoError> {
+        Ok(match self.replay_mode {
+            ReplayMode::Off | ReplayMode::Replay => None,
+            ReplayMode::Record => Some(replay::RecordingMode::Record(self.get_recording_path())),
+            ReplayMode::Verify => Some(replay::RecordingMode::Verify(replay::ChatRecording::load(
+                &self.get_recording_path(),
+            )?)),
+        })
+    }
+
+    fn start_replay_worker(&mut self) {
+        let recording = replay::ChatRecording::load(&self.get_recording_path())
+            .map_err(NobodyWhoError::from)
+            .and_then(|recording| Ok((recording, self.get_post_process()?)));
+        let (recording, post_process) = match recording {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                godot_error!("Could not start replay: {err}");
+                self.signals().error_occurred().emit(err.to_dictionary());
+                return;
+            }
+        };
+
+        let stream_field = self.get_stream_field();
+        let detect_code_blocks = self.detect_code_blocks;
+        let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(4096);
+        self.msg_tx = Some(msg_tx);
+        let adapter = ChatAdapter {
+            emit_node: self.to_gd(),
+            batch_tokens_per_frame: self.batch_tokens_per_frame,
+            type_out: self.typing_speed > 0.0,
+        };
+        let emit_node = self.to_gd();
+        godot::task::spawn(async move {
+            if let Err(e) = chat::replay_chat_loop(
+                recording,
+                post_process,
+                stream_field,
+                detect_code_blocks,
+                msg_rx,
+                Box::new(adapter),
+            )
+            .await
+            {
+                godot_error!("{e}");
+                emit_node
+                    .signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::from(e).to_dictionary());
+            }
+        });
+    }
+
+    #[func]
+    /// Starts the LLM worker thread. This is required before you can send messages to the LLM.
+    /// This fuction is blocking and can be a bit slow, so you may want to be strategic about when you call it.
+    fn start_worker(&mut self) {
+        self.apply_persona();
+
+        // replaying doesn't need a model
+        if self.replay_mode == ReplayMode::Replay {
+            self.start_replay_worker();
+            return;
+        }
+
+        if self.model_node.is_none() {
+            if !self.reported_missing_model {
+                self.reported_missing_model = true;
+                let message = "Model node was not set. Assign a NobodyWhoModel node to `model_node` before starting the worker.";
+                godot_error!("{message}");
+                self.signals()
+                    .configuration_error()
+                    .emit(message.to_string());
+                self.signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::new(ErrorCode::ModelNotSet, message).to_dictionary());
+            }
+            return;
+        }
+        self.reported_missing_model = false;
+
+        let chat_template = match self.get_chat_template() {
+            Ok(template) => template,
+            Err(err) => {
+                godot_error!("{err}");
+                self.signals()
+                    .configuration_error()
+                    .emit(err.message.clone());
+                self.signals().error_occurred().emit(err.to_dictionary());
+                return;
+            }
+        };
+        if let Some(template) = &chat_template {
+            if let Err(e) = chat_state::validate_template(template) {
+                let message = format!("The chat template override does not render: {e}");
+                godot_error!("{message}");
+                self.signals().configuration_error().emit(message.clone());
+                self.signals().error_occurred().emit(
+                    NobodyWhoError::new(ErrorCode::ChatTemplateInvalid, message).to_dictionary(),
+                );
+                return;
+            }
+        }
+
+        self.timings = Timings::default();
+        self.context_used = 0;
+        self.context_size = 0;
+        // a paused worker would never finish, so a new one starts unpaused
+        self.pause_gate.resume();
+        let mut result = || -> Result<(), NobodyWhoError> {
+            let load_started = std::time::Instant::now();
+            let model = self.get_model()?;
+            self.timings.model_load = Some(load_started.elapsed());
+            if !llm::has_eog_token(&model)
+                && self.stop_tokens.is_empty()
+                && self.max_response_duration_ms == 0
+            {
+                godot_warn!(
+                    "The model has no end-of-generation token, which is common for base models. Responses will only end when the context fills up. \
+                    Set `stop_tokens` or `max_response_duration_ms`, or use an instruct model."
+                );
+            }
+            let recording = self.get_recording_mode()?;
+            let post_process = self.get_post_process()?;
+            let system_prompt = self.get_system_prompt()?;
+            let mut sampler_config = self.get_sampler_config(&model)?;
+            if !self.stream_field.is_empty() && !sampler_config.use_grammar {
+                sampler_config.use_grammar = true;
+                sampler_config.gbnf_grammar = sampler_config::JSON_GRAMMAR.to_string();
+            }
+            let stop_tokens: Vec<String> = self
+                .stop_tokens
+                .to_vec()
+                .into_iter()
+                .map(|g| g.to_string())
+                .collect();
+
+            let eog_behavior = if self.max_response_parts > 1 {
+                llm::EogBehavior::Continue {
+                    separator: self.response_part_separator.to_string(),
+                    max_parts: self.max_response_parts,
+                }
+            } else {
+                llm::EogBehavior::Stop
+            };
+
+            let params = llm::LLMActorParams::builder()
+                .model(model)
+                .sampler_config(sampler_config)
+                .stop_tokens(stop_tokens)
+                .n_ctx(self.context_length)
+                .priority(worker_priority(self.low_priority))
+                .eog_behavior(eog_behavior)
+                .max_buffered_tokens(self.max_buffered_tokens as usize)
+                .ask_on_context_full(true)
+                .negative_prompt(
+                    (!self.negative_prompt.is_empty()).then(|| self.negative_prompt.to_string()),
+                )
+                .cfg_scale(self.cfg_scale)
+                .max_response_duration((self.max_response_duration_ms > 0).then(|| {
+                    std::time::Duration::from_millis(self.max_response_duration_ms as u64)
+                }))
+                .auto_defrag_threshold(
+                    (self.auto_defragment_threshold < 1.0)
+                        .then_some(self.auto_defragment_threshold),
+                )
+                .logit_processor_top_k(
+                    self.use_logit_processor
+                        .then_some(self.logit_processor_top_k as usize),
+                )
+                .token_probabilities(self.emit_token_probabilities)
+                .unsafe_skip_inference_lock(self.unsafe_skip_inference_lock_i_know_what_i_am_doing)
+                .pause_gate(self.pause_gate.clone())
+                .build()?;
+
+            let chat_params = chat::ChatParams {
+                system_prompt,
+                max_history_messages: (self.max_history_messages > 0)
+                    .then_some(self.max_history_messages as usize),
+                echo_prompt: self.echo_prompt,
+                role_names: self.get_role_names(),
+                fallback_chat_template: resolve_chat_template(&self.fallback_chat_template),
+                chat_template: chat_template.clone(),
+                recording,
+                empty_message_placeholder: (!self.empty_message_placeholder.is_empty())
+                    .then(|| self.empty_message_placeholder.to_string()),
+                prepend_bos: self.prepend_bos_token,
+                enable_thinking: Some(self.enable_thinking),
+                fallback_generation_prompt: (!self.fallback_generation_prompt.is_empty())
+                    .then(|| self.fallback_generation_prompt.to_string()),
+                post_process,
+                stream_field: self.get_stream_field(),
+                detect_code_blocks: self.detect_code_blocks,
+                strip_strings: self.get_strip_strings(&params.model),
+            };
+            if let Err(err) =
+                chat::check_system_prompt_fits(&params.model, &chat_params, params.n_ctx)
+            {
+                let err = NobodyWhoError::from(err);
+                self.signals()
+                    .configuration_error()
+                    .emit(err.message.clone());
+                return Err(err);
+            }
+            if chat_params.fallback_generation_prompt.is_none()
+                && chat::lacks_generation_prompt(&params.model, &chat_params)
+            {
+                godot_warn!(
+                    "The chat template doesn't start the assistant's turn after user messages, so the model may not know it should respond. \
+                    Set `fallback_generation_prompt` to how the template starts assistant messages, or use another chat template."
+                );
+            }
+
+            // start the llm worker
+            let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(4096); // TODO: 4096 is super random
+            self.msg_tx = Some(msg_tx);
+            let adapter = ChatAdapter {
+                emit_node: self.to_gd(),
+                batch_tokens_per_frame: self.batch_tokens_per_frame,
+                type_out: self.typing_speed > 0.0,
+            };
+            let emit_node = self.to_gd();
+            godot::task::spawn(async move {
+                if let Err(e) =
+                    chat::simple_chat_loop(params, chat_params, msg_rx, Box::new(adapter)).await
+                {
+                    godot_error!("{e:?}");
+                    let err = NobodyWhoError::from(e);
+                    if err.is_out_of_memory() {
+                        emit_node
+                            .signals()
+                            .out_of_memory()
+                            .emit(err.message.clone());
+                    }
+                    emit_node
+                        .signals()
+                        .error_occurred()
+                        .emit(err.to_dictionary());
+                }
+            });
+
+            self.start_embedding_worker()
+        };
+
+        // run it and show error in godot if it fails
+        if let Err(err) = result() {
+            godot_error!("Error running model: {}", err);
+            self.signals().error_occurred().emit(err.to_dictionary());
+        }
+    }
+
+    /// Starts a worker for embedding the messages of the chat, if `embedding_model_node` is set.
+    fn start_embedding_worker(&mut self) -> Result<(), NobodyWhoError> {
+        self.embed_tx = None;
+        self.embedding_queue.clear();
+        let Some(embedding_model_node) = self.embedding_model_node.as_mut() else {
+            return Ok(());
+        };
+        let model = embedding_model_node.bind_mut().get_model()?;
+        let params = llm::LLMActorParams::builder()
+            .model(model)
+            .use_embeddings(true)
+            .priority(worker_priority(self.low_priority))
+            .build()?;
+
+        let (embed_tx, embed_rx) = tokio::sync::mpsc::channel(4096);
+        self.embed_tx = Some(embed_tx);
+        let adapter = MessageEmbeddingAdapter {
+            emit_node: self.to_gd(),
+        };
+        let emit_node = self.to_gd();
+        godot::task::spawn(async move {
+            if let Err(e) = chat::simple_embedding_loop(
+                params,
+                chat::EmbeddingParams::default(),
+                embed_rx,
+                Box::new(adapter),
+            )
+            .await
+            {
+                godot_error!("{e:?}");
+                emit_node
+                    .signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::from(e).to_dictionary());
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends a message to the embedding worker, if there is one. The embedding arrives through `message_embedded`.
+    fn embed_message(&mut self, role: &str, text: &str) {
+        let Some(embed_tx) = self.embed_tx.as_ref() else {
+            return;
+        };
+        if text.trim().is_empty() {
+            return;
+        }
+        if embed_tx.blocking_send(text.to_string()).is_err() {
+            godot_error!("Couldn't send message to the embedding worker, it has stopped.");
+            self.embed_tx = None;
+            self.embedding_queue.clear();
+            return;
+        }
+        self.embedding_queue
+            .push_back((role.to_string(), text.to_string()));
+    }
+
+    #[func]
+    /// Sends a message to the LLM.
+    /// This will start the inference process. meaning you can also listen on the `response_updated` and `response_finished` signals to get the response.
+    fn say(&mut self, message: String) {
+        if message.trim().is_empty() && self.empty_message_placeholder.is_empty() {
+            godot_warn!("Ignoring empty message. Set `empty_message_placeholder` to send something else instead.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::Say(message));
+    }
+
+    #[func]
+    /// Sends a message to the LLM, and generates `n` independent responses to it, each with a different seed.
+    /// When they are all done, they are returned together through the `responses_finished` signal.
+    /// Only the first response is kept in the chat history, so the conversation continues from that one.
+    /// `response_updated` and `response_finished` are not triggered for these responses.
+    fn say_n(&mut self, message: String, n: i64) {
+        if n < 1 {
+            godot_warn!("say_n needs at least one response, got {n}.");
+            return;
+        }
+        if message.trim().is_empty() && self.empty_message_placeholder.is_empty() {
+            godot_warn!("Ignoring empty message. Set `empty_message_placeholder` to send something else instead.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::SayN(message, n as usize));
+    }
+
+    #[func]
+    /// Reads token ids straight into the context and generates a response to them, like `say`, but without the chat template.
+    /// This gives full control over the prompt, including special tokens, e.g. with ids from `NobodyWhoModel.tokenize`
+    /// or from an external tokenizer. The tokens and the response are not added to the chat history.
+    fn say_tokens(&mut self, tokens: PackedInt32Array) {
+        if tokens.is_empty() {
+            godot_warn!("Ignoring empty list of tokens.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::SayTokens(tokens.to_vec()));
+    }
+
+    #[func]
+    /// Generates `n` drafts of a response to a message, without adding either to the chat history.
+    /// The drafts are returned together through the `drafts_finished` signal, and the conversation stays where it was,
+    /// so this can be called several times to explore different branches. Everything said before is only read once.
+    /// Call `commit_draft` to continue the conversation from one of the drafts.
+    fn draft(&mut self, message: String, n: i64) {
+        if n < 1 {
+            godot_warn!("draft needs at least one response, got {n}.");
+            return;
+        }
+        if message.trim().is_empty() && self.empty_message_placeholder.is_empty() {
+            godot_warn!("Ignoring empty message. Set `empty_message_placeholder` to send something else instead.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::Draft(message, n as usize));
+    }
+
+    #[func]
+    /// Adds the message of the last `draft` call, and the draft at `index`, to the chat history.
+    /// Must be called before sending any other message, which forgets the drafts.
+    fn commit_draft(&mut self, index: i64) {
+        if index < 0 {
+            godot_warn!("commit_draft needs a non-negative index, got {index}.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::CommitDraft(index as usize));
+    }
+
+    #[func]
+    /// Sends a message to the LLM, and makes it respond with a JSON object, like `say` with the JSON grammar.
+    /// If the response still doesn't parse, e.g. because it was cut short by `max_response_duration_ms` or a full context,
+    /// it is generated again with a fresh seed, up to `max_attempts` times in total. The first valid response triggers `response_finished`.
+    /// If none of the attempts are valid, `structured_response_failed` is triggered with the last one instead.
+    /// Example: `say_json("Describe the weather as {\"temperature\": number, \"sky\": string}", 3)`
+    fn say_json(&mut self, message: String, max_attempts: i64) {
+        if max_attempts < 1 {
+            godot_warn!("say_json needs at least one attempt, got {max_attempts}.");
+            return;
+        }
+        if message.trim().is_empty() && self.empty_message_placeholder.is_empty() {
+            godot_warn!("Ignoring empty message. Set `empty_message_placeholder` to send something else instead.");
+            return;
+        }
+        self.send_message(chat::ChatMsg::SayJson(message, max_attempts as usize));
+    }
+
+    #[func]
+    /// Sends a message to the LLM, like `say`, but only lets it respond with text that matches the regular expression `pattern`.
+    /// This is an easier way to get simple structured answers, like a number or a date, than writing a GBNF grammar.
+    /// Supports literals, `.`, character classes like `[a-z]`, `\d`, `\w`, `\s`, groups, `|`, and the quantifiers `*`, `+`, `?` and `{m,n}`.
+    /// Example: `say_matching("What year did the first moon landing happen?", "\\d{4}")`
+    fn say_matching(&mut self, message: String, pattern: String) {
+        let grammar = match grammar::regex_to_gbnf(&pattern) {
+            Ok(grammar) => grammar,
+            Err(err) => {
+                godot_error!("Invalid pattern {pattern:?}: {err}");
+                self.signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::from(err).to_dictionary());
+                return;
+            }
+        };
+        self.send_message(chat::ChatMsg::SetGrammar(Some(grammar)));
+        self.say(message);
+        self.send_message(chat::ChatMsg::SetGrammar(None));
+    }
+
+    fn send_message(&mut self, msg: chat::ChatMsg) {
+        if self.msg_tx.is_none() {
+            godot_warn!("Worker was not started yet, starting now... You may want to call `start_worker()` ahead of time to avoid waiting.");
+            self.start_worker();
+        }
+        if self.msg_tx.is_none() {
+            return;
+        }
+        if let chat::ChatMsg::Say(message)
+        | chat::ChatMsg::SayN(message, _)
+        | chat::ChatMsg::SayJson(message, _) = &msg
+        {
+            self.embed_message("user", message);
+        }
+        let Some(msg_tx) = self.msg_tx.as_mut() else {
+            return;
+        };
+        if let Err(msg) = msg_tx.blocking_send(msg) {
+            // check error
+            godot_error!("Couldn't say to worker: {:?}", msg);
+            self.msg_tx = None;
+            self.signals().error_occurred().emit(
+                NobodyWhoError::new(
+                    ErrorCode::WorkerDied,
+                    "Couldn't say to worker, it has stopped.",
+                )
+                .to_dictionary(),
+            );
+        }
+    }
+
+    #[func]
+    /// Sends a message to the LLM, like `say`, but also returns the `response_finished` signal.
+    /// This lets you wait for the full response in a single line: `var response = await say_and_wait("Hi there!")`
+    fn say_and_wait(&mut self, message: String) -> Signal {
+        self.say(message);
+        godot::builtin::Signal::from_object_signal(&self.base_mut(), "response_finished")
+    }
+
+    #[func]
+    /// Asks the LLM a yes/no question about the conversation so far, and returns the `yes_no_answered` signal,
+    /// which is triggered with true for yes and false for no: `if await ask_yes_no("Was the player rude?"): ...`
+    /// Instead of generating a response, this compares how likely the LLM is to start its answer with "yes" or "no",
+    /// so the answer is always one of the two, and it is fast. The question is not kept in the chat history.
+    /// Use `get_yes_no_confidence` to find out how sure the LLM was.
+    /// If there is no answer, e.g. for an empty question, the signal is triggered with false, after `error_occurred`
+    /// with the code `NobodyWhoErrorCode.QUESTION_UNANSWERED`.
+    fn ask_yes_no(&mut self, question: String) -> Signal {
+        if question.trim().is_empty() && self.empty_message_placeholder.is_empty() {
+            godot_warn!("Got an empty question, which can't be answered. Set `empty_message_placeholder` to send something else instead.");
+        }
+        // the worker answers ignored questions too, so the signal is always triggered
+        self.send_message(chat::ChatMsg::AskYesNo(question));
+        godot::builtin::Signal::from_object_signal(&self.base_mut(), "yes_no_answered")
+    }
+
+    #[func]
+    /// Returns how confident the LLM was in the last answer from `ask_yes_no`, from 0.5 (a coin toss) to 1 (certain),
+    /// or 0 if no question has been answered yet.
+    fn get_yes_no_confidence(&self) -> f64 {
+        self.yes_no_confidence
+    }
+
+    #[func]
+    /// Returns the last full response from the LLM, or an empty string if there hasn't been one yet.
+    /// This is the same text as the latest `response_finished` signal, for when polling is more convenient than connecting to it.
+    fn get_last_response(&self) -> String {
+        self.last_response.clone()
+    }
+
+    #[func]
+    /// Returns the ids of the tokens the LLM generated for the last response, including special tokens that the text hides,
+    /// like the end-of-generation token that ended it. Useful for finding out why a response looks off.
+    /// Use `NobodyWhoModel.tokenize` to compare with the ids of a text. This is already updated when `response_finished` is triggered.
+    fn get_last_response_tokens(&self) -> PackedInt32Array {
+        self.last_response_tokens.clone()
+    }
+
+    #[func]
+    /// Returns the response that is being generated, as far as `response_updated` has sent it, or an empty string
+    /// if no response is in progress. Useful for e.g. a log window that opens partway through a response,
+    /// and needs to catch up before connecting to `response_updated`.
+    fn get_partial_response(&self) -> String {
+        self.partial_response.clone()
+    }
+
+    #[func]
+    /// Returns where the time went since the worker started, in milliseconds, for finding out what is worth preloading:
+    /// - "model_load_ms": how long the worker waited for the model to load. Close to 0 if it was preloaded.
+    /// - "worker_init_ms": how long it took to create the context.
+    /// - "first_prompt_ms": how long the LLM took to read the first message, including the system prompt.
+    /// - "cold_start_ms": the three above together, i.e. the extra wait before the first response starts.
+    /// - "last_prompt_ms": how long the LLM took to read the last message, before generating its first token.
+    /// - "last_generation_ms": how long it took to generate the rest of the last response.
+    /// - "last_tokens_per_second": how fast the last response was generated.
+    /// - "turns": how many responses were timed.
+    /// Only responses from `say` and `say_and_wait` are timed, and values that weren't measured yet are 0.
+    fn get_timing_breakdown(&self) -> Dictionary {
+        let ms = |duration: Option<std::time::Duration>| {
+            duration.map_or(0.0, |duration| duration.as_secs_f64() * 1000.0)
+        };
+        let timings = &self.timings;
+        let first_prompt = timings.first_turn.map(|turn| turn.prompt);
+        let last_prompt = timings.last_turn.map(|turn| turn.prompt);
+        let last_generation = timings.last_turn.map(|turn| turn.generation);
+        let tokens_per_second = timings
+            .last_turn
+            .filter(|turn| !turn.generation.is_zero())
+            .map_or(0.0, |turn| {
+                turn.n_tokens.saturating_sub(1) as f64 / turn.generation.as_secs_f64()
+            });
+        dict! {
+            "model_load_ms": ms(timings.model_load),
+            "worker_init_ms": ms(timings.worker_init),
+            "first_prompt_ms": ms(first_prompt),
+            "cold_start_ms": ms(timings.model_load) + ms(timings.worker_init) + ms(first_prompt),
+            "last_prompt_ms": ms(last_prompt),
+            "last_generation_ms": ms(last_generation),
+            "last_tokens_per_second": tokens_per_second,
+            "turns": timings.n_turns as i64,
+        }
+    }
+
+    #[func]
+    /// Returns how many messages are waiting for the worker, not counting the one it is working on.
+    /// Some functions queue more than one message, e.g. `say_matching` also queues setting and clearing its grammar.
+    /// Useful for e.g. showing that an NPC is busy, or not piling up more messages. Returns 0 if the worker hasn't started.
+    fn pending_messages(&self) -> i64 {
+        self.msg_tx
+            .as_ref()
+            .map_or(0, |tx| (tx.max_capacity() - tx.capacity()) as i64)
+    }
+
+    #[func]
+    /// Returns how many more tokens fit in the context, i.e. how much more the LLM can read and say before
+    /// the context is full and `context_full` is triggered. The next message and its response share these.
+    /// Useful for e.g. showing that an NPC is running out of things to say, or summarizing the conversation in time.
+    /// This is updated after every message the worker handles, so it is already up to date when `response_finished` is triggered.
+    /// Returns 0 until the worker has started.
+    fn tokens_remaining(&self) -> i64 {
+        self.context_size.saturating_sub(self.context_used) as i64
+    }
+
+    #[func]
+    /// Pauses generating, e.g. while the game is paused, so the LLM doesn't keep the GPU or CPU busy.
+    /// The response stops before its next token, and continues where it left off when `resume()` is called.
+    /// Messages sent while paused wait until then. Restarting the worker with `start_worker()` resumes it.
+    fn pause(&mut self) {
+        self.pause_gate.pause();
+    }
+
+    #[func]
+    /// Continues generating after `pause()`.
+    fn resume(&mut self) {
+        self.pause_gate.resume();
+    }
+
+    #[func]
+    /// Returns whether generating is paused with `pause()`.
+    fn is_paused(&self) -> bool {
+        self.pause_gate.is_paused()
+    }
+
+    #[func]
+    /// Returns why the last response ended, or an empty string if there hasn't been a response yet:
+    /// - "eog": the LLM ended its turn.
+    /// - "stop_token": one of the `stop_tokens` was generated.
+    /// - "context_full": the context filled up, and `resolve_overflow` ended the response.
+    /// - "time_limit": generating took longer than `max_response_duration_ms`.
+    /// There is no "max_tokens" or "cancelled", as responses can't be limited to a number of tokens or cancelled yet.
+    /// This is already updated when `response_finished` is triggered, so it can be checked there, e.g. to offer a "continue" button.
+    fn get_finish_reason(&self) -> String {
+        self.finish_reason.clone()
+    }
+
+    #[func]
+    /// Returns the stop token that ended the last response, or an empty string if it didn't end on a stop token.
+    /// This is useful with several `stop_tokens` that mean different things, e.g. different branches in a dialogue.
+    fn get_stop_token(&self) -> String {
+        self.stop_token.clone()
+    }
+
+    #[func]
+    /// Tells the paused generation what to do about the full context, after the `context_full` signal.
+    /// - "shift": forget the oldest part of the conversation, and keep generating. This is what happens when nothing is connected to `context_full`.
+    /// - "summarize": end the response here, and replace the chat history with a summary written by the LLM.
+    /// - "stop": end the response here.
+    fn resolve_overflow(&mut self, strategy: String) {
+        let strategy = match strategy.to_lowercase().as_str() {
+            "shift" => llm::OverflowStrategy::Shift,
+            "summarize" => llm::OverflowStrategy::Summarize,
+            "stop" => llm::OverflowStrategy::Stop,
+            other => {
+                godot_error!(
+                    "Unknown overflow strategy: {other}. Expected shift, summarize or stop."
+                );
+                return;
+            }
+        };
+        match self.overflow_resolver.take() {
+            Some(resolve_to) => {
+                let _ = resolve_to.send(strategy);
+            }
+            None => {
+                godot_warn!("Called resolve_overflow, but the context is not full. Doing nothing.")
+            }
+        }
+    }
+
+    #[func]
+    /// Sets values for placeholders in the system prompt. Every `{key}` in the system prompt is replaced with the value for that key.
+    /// The variables are used the next time the worker starts or the context is reset.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// chat.system_prompt = "You are a shopkeeper in {town}. The customer's name is {player_name}."
+    /// chat.set_prompt_variables({"town": "Riverwood", "player_name": player.name})
+    /// chat.start_worker()
+    /// ```
+    fn set_prompt_variables(&mut self, vars: Dictionary) {
+        self.prompt_variables = vars;
+    }
+
+    #[func]
+    /// Picks a new random seed for the sampler, so the next responses differ from what the same messages gave before.
+    /// Works with any sampler method except greedy, which has no randomness. Returns the new seed, which can be set on
+    /// a sampler resource to reproduce the responses. Restarting the worker goes back to the sampler's own seed.
+    fn randomize_seed(&mut self) -> i64 {
+        let seed = godot::global::randi() as u32;
+        self.send_message(chat::ChatMsg::SetSeed(seed));
+        seed as i64
+    }
+
+    #[func]
+    /// Defragments the KV cache, the memory holding the conversation. After many context shifts it is full of holes,
+    /// which make generation slower. This takes a moment, so it is best done during a pause, e.g. a loading screen.
+    /// Does nothing if the worker hasn't started. See also `auto_defragment_threshold`.
+    fn defragment(&mut self) {
+        if self.msg_tx.is_none() {
+            return;
+        }
+        self.send_message(chat::ChatMsg::Defragment);
+    }
+
+    #[func]
+    /// Changes `context_length` while the worker is running, e.g. to start with a small, fast context,
+    /// and grow it for an important conversation. The worker restarts with the new context,
+    /// and reads the chat history again along with the next message, so nothing is forgotten.
+    /// If the history doesn't fit in a smaller context, the next message fails, so consider resetting the context too.
+    /// Before the worker starts, this is the same as setting `context_length`.
+    fn resize_context(&mut self, context_length: i64) {
+        if context_length < 1 {
+            godot_warn!("context_length must be at least 1, got {context_length}.");
+            return;
+        }
+        self.context_length = context_length as u32;
+        if self.msg_tx.is_none() {
+            return;
+        }
+        self.send_message(chat::ChatMsg::SetContextLength(self.context_length));
+    }
+
+    #[func]
+    /// Starts a new conversation with the same system prompt, forgetting every message since.
+    /// Unlike `reset_context`, the LLM doesn't have to read the system prompt again, which makes the next response
+    /// start faster with a long system prompt. Changes to `system_prompt` are not picked up, use `reset_context` for that.
+    /// Does nothing if the worker hasn't started.
+    fn new_conversation(&mut self) {
+        if self.msg_tx.is_none() {
+            return;
+        }
+        self.send_message(chat::ChatMsg::NewConversation);
+    }
+
+    #[func]
+    fn reset_context(&mut self) {
+        let sysem_prompt = match self.get_system_prompt() {
+            Ok(prompt) => prompt,
+            Err(err) => {
+                godot_error!("Couldn't reset context: {err}");
+                self.signals().error_occurred().emit(err.to_dictionary());
+                return;
+            }
+        };
+        if let Some(msg_tx) = self.msg_tx.as_mut() {
+            let resp = msg_tx.blocking_send(chat::ChatMsg::ResetContext(sysem_prompt));
+            if let Err(msg) = resp {
+                // check error
+                godot_error!("Couldn't reset context: {:?}", msg);
+                self.msg_tx = None;
+                self.signals().error_occurred().emit(
+                    NobodyWhoError::new(
+                        ErrorCode::WorkerDied,
+                        "Couldn't reset context, the worker has stopped.",
+                    )
+                    .to_dictionary(),
+                );
+            }
+        } else {
+            godot_error!("Attempted to reset context, but no worker is running. Doing nothing.");
+        }
+    }
+
+    #[signal]
+    /// Triggered when a new token is received from the LLM. Returns the new token as a string.
+    /// It is strongly recommended to connect to this signal, and display the text output as it is
+    /// being generated. This makes for a much nicer user experience.
+    fn response_updated(new_token: String);
+
+    #[signal]
+    /// Triggered when a whole word of the response has been generated. Tokens are often only parts of words,
+    /// so this is more convenient than `response_updated` for e.g. speech bubbles that show one word at a time.
+    /// The word does not include the whitespace around it.
+    fn word_completed(word: String);
+
+    #[signal]
+    /// Triggered with each generated token and how likely the LLM found it, from 0.0 to 1.0, when `emit_token_probabilities` is on.
+    /// Low probabilities mean the LLM was unsure, which can drive e.g. a confidence meter or a hesitant animation.
+    /// This is triggered as soon as the token is generated, so it runs ahead of `response_updated` when `typing_speed` is set.
+    fn token_probability(token: String, probability: f64);
+
+    #[signal]
+    /// Triggered when the LLM has finished generating the response. Returns the full response as a string.
+    /// Use `get_finish_reason` to find out why it ended.
+    fn response_finished(response: String);
+
+    #[signal]
+    /// Triggered right before `response_finished` when the response ended because one of the `stop_tokens` was generated.
+    /// Returns the stop token that matched, so different stop tokens can lead to different outcomes.
+    fn stopped_on(stop_token: String);
 
-    model: Option<llm::Model>,
-}
+    #[signal]
+    /// Triggered when generating a response fails partway through, with the text that was generated before the failure.
+    /// `response_finished` is not triggered for this response. The error itself is reported through `error_occurred`.
+    fn response_interrupted(partial_response: String);
 
-#[godot_api]
-impl INode for NobodyWhoModel {
-    fn init(_base: Base<Node>) -> Self {
-        // default values to show in godot editor
-        let model_path: String = "model.gguf".into();
+    #[signal]
+    /// Triggered when all the responses requested with `say_n` are done. Returns them as an array of strings, in order.
+    /// The first one is the response that was kept in the chat history.
+    fn responses_finished(responses: PackedStringArray);
 
-        Self {
-            model_path: model_path.into(),
-            use_gpu_if_available: true,
-            model: None,
-        }
-    }
-}
+    #[signal]
+    /// Triggered with the answer to a question from `ask_yes_no`: true for yes, and false for no.
+    fn yes_no_answered(answer: bool);
 
-impl NobodyWhoModel {
-    // memoized model loader
-    fn get_model(&mut self) -> Result<llm::Model, llm::LoadModelError> {
-        if let Some(model) = &self.model {
-            return Ok(model.clone());
-        }
+    #[signal]
+    /// Triggered when all the drafts requested with `draft` are done. Returns them as an array of strings, in order.
+    fn drafts_finished(drafts: PackedStringArray);
 
-        let project_settings = ProjectSettings::singleton();
-        let model_path_string: String = project_settings
-            .globalize_path(&self.model_path.clone())
-            .into();
+    #[signal]
+    /// Triggered when a markdown code block starts in the response, when `detect_code_blocks` is on.
+    /// `language` is what follows the opening fence, e.g. "gdscript", or an empty string.
+    fn code_block_started(language: String);
 
-        match llm::get_model(model_path_string.as_str(), self.use_gpu_if_available) {
-            Ok(model) => {
-                self.model = Some(model.clone());
-                Ok(model.clone())
-            }
-            Err(err) => {
-                godot_error!("Could not load model: {:?}", err.to_string());
-                Err(err)
-            }
-        }
-    }
+    #[signal]
+    /// Triggered with the code of a code block as it is generated, when `detect_code_blocks` is on.
+    /// Unlike `response_updated`, this is not paced by `typing_speed` or `batch_tokens_per_frame`.
+    fn code_updated(code: String);
+
+    #[signal]
+    /// Triggered when a code block ends, or the response ends in the middle of one, when `detect_code_blocks` is on.
+    fn code_block_ended();
+
+    #[signal]
+    /// Triggered after `response_finished` when `stream_field` is set, with the response parsed into a Dictionary.
+    fn structured_response_finished(response: Dictionary);
+
+    #[signal]
+    /// Triggered when none of the attempts of a `say_json` call produced valid JSON. Returns the last response,
+    /// or an empty string if generating it failed. `response_finished` is not triggered for this message.
+    fn structured_response_failed(last_response: String);
+
+    #[signal]
+    /// Triggered when a message or response has been embedded, when `embedding_model_node` is set.
+    /// `role` is "user" or "assistant", and `embedding` is normalized, so it can be compared with `NobodyWhoEmbedding.cosine_similarity`.
+    fn message_embedded(role: String, message: String, embedding: PackedFloat32Array);
+
+    #[signal]
+    /// Triggered when the worker can't start because the node is misconfigured, e.g. when no model node is set,
+    /// or when the system prompt doesn't fit in `context_length`.
+    /// A missing model node is only reported once, until it is set. The other errors are reported every time the worker starts.
+    fn configuration_error(message: String);
+
+    #[signal]
+    /// Triggered whenever something goes wrong, with a dictionary like `{"code": NobodyWhoErrorCode.MODEL_NOT_FOUND, "message": "..."}`.
+    /// Compare `code` with the constants on NobodyWhoErrorCode to handle specific errors.
+    fn error_occurred(error: Dictionary);
+
+    #[signal]
+    /// Triggered when the GPU or RAM runs out of memory while generating, with a message suggesting what to change.
+    /// The worker stops, and `error_occurred` is triggered too. Lower `context_length` and call `start_worker()` to try again.
+    fn out_of_memory(message: String);
+
+    #[signal]
+    /// Triggered when the context is full while generating a response. Generation pauses until `resolve_overflow` is called.
+    /// When nothing is connected to this signal, the oldest part of the conversation is forgotten automatically.
+    fn context_full();
+
+    #[signal]
+    /// Triggered when the oldest part of the conversation was forgotten to make room for a message or a response,
+    /// with how many tokens were forgotten. Useful for e.g. reminding the LLM of important facts from early on.
+    fn context_shifted(n_forgotten: i64);
+
+    #[signal]
+    /// Triggered before each response when `echo_prompt` is enabled. Returns the new prompt text exactly as the LLM reads it.
+    fn prompt_echoed(prompt: String);
 }
 
 #[derive(GodotClass)]
 #[class(base=Node)]
-/// NobodyWhoChat is the main node for interacting with the LLM. It functions as a chat, and can be used to send and receive messages.
-///
-/// The chat node is used to start a new context to send and receive messages (multiple contexts can be used at the same time with the same model).
-/// It requires a call to `start_worker()` before it can be used. If you do not call it, the chat will start the worker when you send the first message.
+/// The Completion node continues text as it is, without a chat template or roles. This is useful for interactive fiction,
+/// or with base models, which are trained to continue text rather than to chat.
 ///
+/// Everything is kept in the context, so each call to `complete` continues the story so far.
+/// It requires a "NobodyWhoModel" node to be set.
 /// Example:
 ///
 /// ```
-/// extends NobodyWhoChat
+/// extends NobodyWhoCompletion
 ///
 /// func _ready():
-///     # configure node
-///     self.model_node = get_node("../ChatModel")
-///     self.system_prompt = "You are an evil wizard. Always try to curse anyone who talks to you."
-///
-///     # say something
-///     say("Hi there! Who are you?")
-///
-///     # wait for the response
-///     var response = await response_finished
-///     print("Got response: " + response)
+///     model_node = get_node("../StoryModel")
+///     stop_tokens = PackedStringArray(["\n\n"])
 ///
-///     # in this example we just use the `response_finished` signal to get the complete response
-///     # in real-world-use you definitely want to connect `response_updated`, which gives one word at a time
-///     # the whole interaction feels *much* smoother if you stream the response out word-by-word.
+///     var continuation = await complete("The knight drew her sword and")
+///     print(continuation)
 /// ```
-///
-struct NobodyWhoChat {
+struct NobodyWhoCompletion {
     #[export]
-    /// The model node for the chat.
+    /// The model node for the completion.
     model_node: Option<Gd<NobodyWhoModel>>,
 
     #[export]
-    /// The sampler configuration for the chat.
+    /// The sampler configuration, or null to use the default sampler.
     sampler: Option<Gd<NobodyWhoSampler>>,
 
     #[export]
-    #[var(hint = MULTILINE_TEXT)]
-    /// The system prompt for the chat, this is the basic instructions for the LLM's behavior.
-    system_prompt: GString,
-
-    #[export]
-    /// Stop tokens to stop generation at these specified tokens.
+    /// Generation ends when one of these strings is generated. It is included at the end of the continuation.
+    /// Base models often have no end-of-generation token, so set this or the continuation only ends when the context is full.
     stop_tokens: PackedStringArray,
 
     #[export]
-    /// This is the maximum number of tokens that can be stored in the chat history. It will delete information from the chat history if it exceeds this limit.
-    /// Higher values use more VRAM, but allow for longer "short term memory" for the LLM.
+    /// The number of tokens the model can keep in mind at once. When the story gets longer than this,
+    /// the oldest part is forgotten. Takes effect on the next `start_worker()`.
     context_length: u32,
 
-    msg_tx: Option<tokio::sync::mpsc::Sender<chat::ChatMsg>>,
+    #[export]
+    /// Runs the worker with a lower priority than the rest of the game.
+    low_priority: bool,
 
+    msg_tx: Option<tokio::sync::mpsc::Sender<chat::CompletionMsg>>,
+    reported_missing_model: bool,
     base: Base<Node>,
 }
 
-struct ChatAdapter {
-    emit_node: Gd<NobodyWhoChat>,
-}
-
-impl chat::ChatOutput for ChatAdapter {
-    fn emit_token(&self, tok: String) {
-        self.emit_node.signals().response_updated().emit(tok)
-    }
-    fn emit_response(&self, resp: String) {
-        self.emit_node.signals().response_finished().emit(resp)
-    }
-    fn emit_error(&self, err: String) {
-        godot_error!("LLM Worker failed: {err}");
-    }
-}
-
 #[godot_api]
-impl INode for NobodyWhoChat {
+impl INode for NobodyWhoCompletion {
     fn init(base: Base<Node>) -> Self {
         Self {
-            // config
             model_node: None,
             sampler: None,
-            system_prompt: "".into(),
             stop_tokens: PackedStringArray::new(),
             context_length: 4096,
+            low_priority: false,
             msg_tx: None,
-
+            reported_missing_model: false,
             base,
         }
     }
 }
 
+struct CompletionAdapter {
+    emit_node: Gd<NobodyWhoCompletion>,
+}
+
+impl chat::ChatOutput for CompletionAdapter {
+    fn emit_token(&self, token: String) {
+        self.emit_node.signals().response_updated().emit(token);
+    }
+    fn emit_response(&self, resp: String) {
+        self.emit_node.signals().response_finished().emit(resp);
+    }
+    fn emit_batch_response(&self, index: usize, response: String) {
+        self.emit_node
+            .signals()
+            .batch_response_finished()
+            .emit(index as i64, response);
+    }
+    fn emit_responses(&self, responses: Vec<String>) {
+        let responses: PackedStringArray = responses.iter().map(GString::from).collect();
+        self.emit_node.signals().batch_finished().emit(responses);
+    }
+    fn emit_error(&self, err: String) {
+        godot_error!("LLM Worker failed: {err}");
+    }
+}
+
 #[godot_api]
-impl NobodyWhoChat {
-    fn get_model(&mut self) -> Result<llm::Model, String> {
-        let gd_model_node = self.model_node.as_mut().ok_or("Model node was not set")?;
+impl NobodyWhoCompletion {
+    #[signal]
+    /// Triggered when a new token of the continuation is generated.
+    fn response_updated(new_token: String);
+
+    #[signal]
+    /// Triggered when the continuation is done, with only the generated text, not the text that was continued.
+    fn response_finished(response: String);
+
+    #[signal]
+    /// Triggered for each text of a `complete_batch` as soon as its continuation is done, with the index of the text.
+    fn batch_response_finished(index: i64, response: String);
+
+    #[signal]
+    /// Triggered when all texts of a `complete_batch` are continued, with the continuations in the same order as the texts.
+    fn batch_finished(responses: PackedStringArray);
+
+    #[signal]
+    /// Triggered when the worker can't start because the node is misconfigured, e.g. when no model node is set.
+    /// It is only triggered once, until the configuration is fixed.
+    fn configuration_error(message: String);
+
+    #[signal]
+    /// Triggered whenever something goes wrong, with a dictionary like `{"code": NobodyWhoErrorCode.MODEL_NOT_FOUND, "message": "..."}`.
+    fn error_occurred(error: Dictionary);
+
+    fn get_model(&mut self) -> Result<llm::Model, NobodyWhoError> {
+        let gd_model_node = self
+            .model_node
+            .as_mut()
+            .ok_or_else(|| NobodyWhoError::new(ErrorCode::ModelNotSet, "Model node was not set"))?;
         let mut nobody_model = gd_model_node.bind_mut();
-        let model: llm::Model = nobody_model.get_model().map_err(|e| e.to_string())?;
+        let model: llm::Model = nobody_model.get_model()?;
 
         Ok(model)
     }
 
-    fn get_sampler_config(&mut self) -> sampler_config::SamplerConfig {
-        if let Some(gd_sampler) = self.sampler.as_mut() {
-            let nobody_sampler: GdRef<NobodyWhoSampler> = gd_sampler.bind();
-            nobody_sampler.sampler_config.clone()
-        } else {
-            sampler_config::SamplerConfig::default()
-        }
-    }
-
     #[func]
-    /// Starts the LLM worker thread. This is required before you can send messages to the LLM.
-    /// This fuction is blocking and can be a bit slow, so you may want to be strategic about when you call it.
+    /// Starts the worker thread. This is called automatically when you call `complete`, if it wasn't already called.
+    /// Starting it again forgets the story so far.
     fn start_worker(&mut self) {
-        let mut result = || -> Result<(), String> {
+        if self.model_node.is_none() {
+            if !self.reported_missing_model {
+                self.reported_missing_model = true;
+                let message = "Model node was not set. Assign a NobodyWhoModel node to `model_node` before starting the worker.";
+                godot_error!("{message}");
+                self.signals()
+                    .configuration_error()
+                    .emit(message.to_string());
+                self.signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::new(ErrorCode::ModelNotSet, message).to_dictionary());
+            }
+            return;
+        }
+        self.reported_missing_model = false;
+
+        let mut result = || -> Result<(), NobodyWhoError> {
             let model = self.get_model()?;
-            let sampler_config = self.get_sampler_config();
+            let sampler_config = match self.sampler.as_ref() {
+                Some(sampler) => sampler.bind().get_sampler_config()?,
+                None => sampler_config::SamplerConfig::default(),
+            };
             let stop_tokens: Vec<String> = self
                 .stop_tokens
                 .to_vec()
                 .into_iter()
                 .map(|g| g.to_string())
                 .collect();
+            let params = llm::LLMActorParams::builder()
+                .model(model)
+                .sampler_config(sampler_config)
+                .stop_tokens(stop_tokens)
+                .n_ctx(self.context_length)
+                .priority(worker_priority(self.low_priority))
+                .build()?;
 
-            let params = llm::LLMActorParams {
-                model,
-                sampler_config,
-                stop_tokens,
-                n_ctx: self.context_length,
-                use_embeddings: false,
-            };
-
-            // start the llm worker
-            let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(4096); // TODO: 4096 is super random
+            let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(4096);
             self.msg_tx = Some(msg_tx);
-            let adapter = ChatAdapter {
+            let adapter = CompletionAdapter {
                 emit_node: self.to_gd(),
             };
-            let system_prompt = self.system_prompt.to_string();
-            godot::task::spawn(async {
-                chat::simple_chat_loop(params, system_prompt, msg_rx, Box::new(adapter))
-                    .await
-                    .unwrap_or_else(|e| {
-                        godot_error!("{e:?}");
-                        ()
-                    })
+            let emit_node = self.to_gd();
+            godot::task::spawn(async move {
+                if let Err(e) =
+                    chat::simple_completion_loop(params, msg_rx, Box::new(adapter)).await
+                {
+                    godot_error!("{e:?}");
+                    emit_node
+                        .signals()
+                        .error_occurred()
+                        .emit(NobodyWhoError::from(e).to_dictionary());
+                }
             });
-
             Ok(())
         };
 
-        // run it and show error in godot if it fails
-        if let Err(msg) = result() {
-            godot_error!("Error running model: {}", msg);
+        if let Err(err) = result() {
+            godot_error!("Error running model: {}", err);
+            self.signals().error_occurred().emit(err.to_dictionary());
         }
     }
 
-    #[func]
-    /// Sends a message to the LLM.
-    /// This will start the inference process. meaning you can also listen on the `response_updated` and `response_finished` signals to get the response.
-    fn say(&mut self, message: String) {
-        if let Some(msg_tx) = self.msg_tx.as_mut() {
-            let resp = msg_tx.blocking_send(chat::ChatMsg::Say(message));
-
-            if let Err(msg) = resp {
-                // check error
-                godot_error!("Couldn't say to worker: {:?}", msg);
-                self.msg_tx = None;
-            }
-        } else {
+    fn send_message(&mut self, msg: chat::CompletionMsg) {
+        if self.msg_tx.is_none() {
             godot_warn!("Worker was not started yet, starting now... You may want to call `start_worker()` ahead of time to avoid waiting.");
             self.start_worker();
-            self.say(message);
+        }
+        let Some(msg_tx) = self.msg_tx.as_ref() else {
+            return;
+        };
+        if msg_tx.blocking_send(msg).is_err() {
+            godot_error!("Completion worker died.");
+            self.msg_tx = None;
+            self.signals().error_occurred().emit(
+                NobodyWhoError::new(ErrorCode::WorkerDied, "Completion worker died.")
+                    .to_dictionary(),
+            );
         }
     }
 
     #[func]
-    fn reset_context(&mut self) {
-        if let Some(msg_tx) = self.msg_tx.as_mut() {
-            let sysem_prompt = self.system_prompt.to_string();
-            let resp = msg_tx.blocking_send(chat::ChatMsg::ResetContext(sysem_prompt));
-            if let Err(msg) = resp {
-                // check error
-                godot_error!("Couldn't reset context: {:?}", msg);
-                self.msg_tx = None;
-            }
-        } else {
-            godot_error!("Attempted to reset context, but no worker is running. Doing nothing.");
-        }
+    /// Feeds the text to the model exactly as it is, and generates a continuation of it.
+    /// Returns the `response_finished` signal, so the continuation can be awaited: `var text = await complete("Once upon a time")`
+    fn complete(&mut self, text: String) -> Signal {
+        self.send_message(chat::CompletionMsg::Complete(text));
+        godot::builtin::Signal::from_object_signal(&self.base_mut(), "response_finished")
     }
 
-    #[signal]
-    /// Triggered when a new token is received from the LLM. Returns the new token as a string.
-    /// It is strongly recommended to connect to this signal, and display the text output as it is
-    /// being generated. This makes for a much nicer user experience.
-    fn response_updated(new_token: String);
+    #[func]
+    /// Continues each of the texts independently, e.g. to generate a bunch of NPC barks during a loading screen.
+    /// This reuses the worker's context for all of them, which is much cheaper than a node per text.
+    /// `batch_response_finished` is triggered as each continuation is done, and the returned `batch_finished` signal when all are.
+    /// The story so far is forgotten: `var barks = await complete_batch(PackedStringArray(["The guard said:", "The merchant said:"]))`
+    fn complete_batch(&mut self, texts: PackedStringArray) -> Signal {
+        let texts: Vec<String> = texts.to_vec().into_iter().map(|g| g.to_string()).collect();
+        self.send_message(chat::CompletionMsg::CompleteBatch(texts));
+        godot::builtin::Signal::from_object_signal(&self.base_mut(), "batch_finished")
+    }
 
-    #[signal]
-    /// Triggered when the LLM has finished generating the response. Returns the full response as a string.
-    fn response_finished(response: String);
+    #[func]
+    /// Forgets the story so far, so the next `complete` starts from scratch.
+    fn reset_context(&mut self) {
+        if self.msg_tx.is_none() {
+            return;
+        }
+        self.send_message(chat::CompletionMsg::ResetContext);
+    }
 }
 
 #[derive(GodotClass)]
@@ -313,7 +2354,52 @@ struct NobodyWhoEmbedding {
     #[export]
     /// The model node for the embedding.
     model_node: Option<Gd<NobodyWhoModel>>,
+
+    #[export]
+    /// Scales the embeddings to unit length (L2 normalization), which is what sentence-transformers does by default.
+    /// This makes similarity scores comparable to what you'd get from the Python ecosystem.
+    /// For unit length vectors, the dot product equals the cosine similarity, so they can be used directly
+    /// with vector search libraries that rank by dot product.
+    normalize: bool,
+
+    #[export]
+    /// Runs the embedding worker with a lower priority than the rest of the game.
+    /// Embedding gets a bit slower, but it is less likely to cause stutter when the CPU is busy.
+    low_priority: bool,
+
+    #[export]
+    /// Whether to start each text with the model's BOS token before embedding it. Some embedding models are trained with it
+    /// and some without, and the wrong choice gives worse similarity scores.
+    /// - Auto: follows the model file's `tokenizer.ggml.add_bos_token` setting, and leaves it out when there is none.
+    /// - Always: always adds it.
+    /// - Never: never adds it.
+    /// Takes effect on the next `start_worker()`.
+    add_bos: AddBosMode,
+
+    #[export]
+    /// The longest time in milliseconds to wait for a single embedding. When it runs out, the text is skipped:
+    /// `error_occurred` is triggered with `NobodyWhoErrorCode.EMBEDDING_TIMED_OUT`, and `embedding_finished` with an empty array,
+    /// so code awaiting it doesn't wait forever. The next text waits until the model is done with the skipped one.
+    /// A value of 0 means no limit. Takes effect on the next `start_worker()`.
+    embed_timeout_ms: u32,
+
+    #[export]
+    /// Normalizes each text before it is embedded, so e.g. "Buy the RED potion!" and "buy the red potion" get the same embedding.
+    /// Contains the names of the steps to apply, in order:
+    /// - "lowercase": makes every letter lowercase.
+    /// - "strip_punctuation": removes punctuation, like periods, commas, quotes and exclamation marks.
+    /// - "collapse_whitespace": replaces every run of whitespace with a single space, and trims the ends.
+    /// Empty by default, which embeds the text as it is. Texts compared with each other should be embedded with the same steps.
+    /// Takes effect on the next `start_worker()`.
+    input_normalization: PackedStringArray,
+
+    #[export]
+    /// Starts the worker as soon as the node is ready, so the first embedding doesn't have to wait for it.
+    /// Only works when `model_node` is set in the inspector, since it happens before the `_ready` of a script on this node.
+    auto_start: bool,
+
     embed_tx: Option<tokio::sync::mpsc::Sender<String>>,
+    reported_missing_model: bool,
     base: Base<Node>,
 }
 
@@ -322,16 +2408,53 @@ impl INode for NobodyWhoEmbedding {
     fn init(base: Base<Node>) -> Self {
         Self {
             model_node: None,
+            normalize: true,
+            low_priority: false,
+            add_bos: AddBosMode::Auto,
+            embed_timeout_ms: 0,
+            input_normalization: PackedStringArray::new(),
+            auto_start: true,
             embed_tx: None,
+            reported_missing_model: false,
             base,
         }
     }
+
+    fn on_notification(&mut self, what: NodeNotification) {
+        if what == NodeNotification::READY && self.auto_start && self.model_node.is_some() {
+            self.start_worker();
+        }
+    }
 }
 
 struct EmbeddingAdapter {
     emit_node: Gd<NobodyWhoEmbedding>,
 }
 
+/// Embeds the messages of a chat with its `embedding_model_node`.
+struct MessageEmbeddingAdapter {
+    emit_node: Gd<NobodyWhoChat>,
+}
+
+impl chat::EmbeddingOutput for MessageEmbeddingAdapter {
+    fn emit_embedding(&self, embd: Vec<f32>) {
+        // the worker embeds the messages in the order they were sent
+        let Some((role, message)) = self
+            .emit_node
+            .clone()
+            .bind_mut()
+            .embedding_queue
+            .pop_front()
+        else {
+            return;
+        };
+        self.emit_node
+            .signals()
+            .message_embedded()
+            .emit(role, message, embd.into());
+    }
+}
+
 impl chat::EmbeddingOutput for EmbeddingAdapter {
     fn emit_embedding(&self, embd: Vec<f32>) {
         self.emit_node
@@ -339,6 +2462,21 @@ impl chat::EmbeddingOutput for EmbeddingAdapter {
             .embedding_finished()
             .emit(embd.into());
     }
+    fn emit_timed_out(&self, text: String) {
+        let err = NobodyWhoError::new(
+            ErrorCode::EmbeddingTimedOut,
+            format!("Embedding took too long, skipping it: {text}"),
+        );
+        godot_warn!("{err}");
+        self.emit_node
+            .signals()
+            .error_occurred()
+            .emit(err.to_dictionary());
+        self.emit_node
+            .signals()
+            .embedding_finished()
+            .emit(PackedFloat32Array::new());
+    }
 }
 
 #[godot_api]
@@ -347,31 +2485,83 @@ impl NobodyWhoEmbedding {
     /// Triggered when the embedding has finished. Returns the embedding as a PackedFloat32Array.
     fn embedding_finished(embedding: PackedFloat32Array);
 
-    fn get_model(&mut self) -> Result<llm::Model, String> {
-        let gd_model_node = self.model_node.as_mut().ok_or("Model node was not set")?;
+    #[signal]
+    /// Triggered when the worker can't start because the node is misconfigured, e.g. when no model node is set.
+    /// It is only triggered once, until the configuration is fixed.
+    fn configuration_error(message: String);
+
+    #[signal]
+    /// Triggered whenever something goes wrong, with a dictionary like `{"code": NobodyWhoErrorCode.MODEL_NOT_FOUND, "message": "..."}`.
+    /// Compare `code` with the constants on NobodyWhoErrorCode to handle specific errors.
+    fn error_occurred(error: Dictionary);
+
+    #[signal]
+    /// Triggered when the GPU or RAM runs out of memory while generating, with a message suggesting what to change.
+    /// The worker stops, and `error_occurred` is triggered too. Embed shorter texts, or disable `use_gpu_if_available`
+    /// on the model node, and call `start_worker()` to try again.
+    fn out_of_memory(message: String);
+
+    fn get_model(&mut self) -> Result<llm::Model, NobodyWhoError> {
+        let gd_model_node = self
+            .model_node
+            .as_mut()
+            .ok_or_else(|| NobodyWhoError::new(ErrorCode::ModelNotSet, "Model node was not set"))?;
         let mut nobody_model = gd_model_node.bind_mut();
-        let model: llm::Model = nobody_model.get_model().map_err(|e| e.to_string())?;
+        let model: llm::Model = nobody_model.get_model()?;
 
         Ok(model)
     }
 
+    fn get_input_normalization(&self) -> Vec<postprocess::PostProcessStep> {
+        let mut steps = Vec::new();
+        for name in self.input_normalization.as_slice() {
+            match name.to_string().as_str() {
+                "lowercase" => steps.push(postprocess::PostProcessStep::Lowercase),
+                "strip_punctuation" => steps.push(postprocess::PostProcessStep::StripPunctuation),
+                "collapse_whitespace" => {
+                    steps.push(postprocess::PostProcessStep::CollapseWhitespace)
+                }
+                other => godot_warn!(
+                    "Unknown step in input_normalization: {other}. Expected lowercase, strip_punctuation or collapse_whitespace."
+                ),
+            }
+        }
+        steps
+    }
+
     #[func]
     /// Starts the embedding worker thread. This is called automatically when you call `embed`, if it wasn't already called.
     fn start_worker(&mut self) {
-        let mut result = || -> Result<(), String> {
-            let model = self.get_model()?;
+        if self.model_node.is_none() {
+            if !self.reported_missing_model {
+                self.reported_missing_model = true;
+                let message = "Model node was not set. Assign a NobodyWhoModel node to `model_node` before starting the worker.";
+                godot_error!("{message}");
+                self.signals()
+                    .configuration_error()
+                    .emit(message.to_string());
+                self.signals()
+                    .error_occurred()
+                    .emit(NobodyWhoError::new(ErrorCode::ModelNotSet, message).to_dictionary());
+            }
+            return;
+        }
+        self.reported_missing_model = false;
 
-            let sampler_config = nobodywho::sampler_config::SamplerConfig::default();
+        let mut result = || -> Result<(), NobodyWhoError> {
+            let model = self.get_model()?;
 
-            // TODO: sampler_config and stop_tokens actually aren't needed here
             // TODO: n_ctx should be configurable
-            let params = llm::LLMActorParams {
-                model,
-                sampler_config,
-                stop_tokens: vec![],
-                n_ctx: 4096,
-                use_embeddings: true,
-            };
+            let params = llm::LLMActorParams::builder()
+                .model(model)
+                .use_embeddings(true)
+                .embedding_add_bos(match self.add_bos {
+                    AddBosMode::Auto => None,
+                    AddBosMode::Always => Some(true),
+                    AddBosMode::Never => Some(false),
+                })
+                .priority(worker_priority(self.low_priority))
+                .build()?;
 
             let (embed_tx, embed_rx) = tokio::sync::mpsc::channel(4096); // TODO: this number is super random
             self.embed_tx = Some(embed_tx.clone());
@@ -379,24 +2569,56 @@ impl NobodyWhoEmbedding {
             let adapter = EmbeddingAdapter {
                 emit_node: self.to_gd(),
             };
-            godot::task::spawn(async {
-                chat::simple_embedding_loop(params, embed_rx, Box::new(adapter))
-                    .await
-                    .unwrap_or_else(|e| {
-                        godot_error!("{e:?}");
-                        ()
-                    });
+            let embedding_params = chat::EmbeddingParams {
+                normalize: self.normalize,
+                timeout: (self.embed_timeout_ms > 0)
+                    .then(|| std::time::Duration::from_millis(self.embed_timeout_ms as u64)),
+                preprocess: self.get_input_normalization(),
+            };
+            let emit_node = self.to_gd();
+            godot::task::spawn(async move {
+                if let Err(e) = chat::simple_embedding_loop(
+                    params,
+                    embedding_params,
+                    embed_rx,
+                    Box::new(adapter),
+                )
+                .await
+                {
+                    godot_error!("{e:?}");
+                    let err = NobodyWhoError::from(e);
+                    if err.is_out_of_memory() {
+                        emit_node
+                            .signals()
+                            .out_of_memory()
+                            .emit(err.message.clone());
+                    }
+                    emit_node
+                        .signals()
+                        .error_occurred()
+                        .emit(err.to_dictionary());
+                }
             });
 
             Ok(())
         };
 
         // run it and show error in godot if it fails
-        if let Err(msg) = result() {
-            godot_error!("Error running model: {}", msg);
+        if let Err(err) = result() {
+            godot_error!("Error running model: {}", err);
+            self.signals().error_occurred().emit(err.to_dictionary());
         }
     }
 
+    #[func]
+    /// Returns how many texts are waiting to be embedded, not counting the one the worker is working on.
+    /// Returns 0 if the worker hasn't started.
+    fn pending_messages(&self) -> i64 {
+        self.embed_tx
+            .as_ref()
+            .map_or(0, |tx| (tx.max_capacity() - tx.capacity()) as i64)
+    }
+
     #[func]
     /// Generates the embedding of a text string. This will return a signal that you can use to wait for the embedding.
     /// The signal will return a PackedFloat32Array.
@@ -410,20 +2632,72 @@ impl NobodyWhoEmbedding {
             let result = embed_tx.blocking_send(text);
             if result.is_err() {
                 godot_error!("Embedding worker died.");
+                self.signals().error_occurred().emit(
+                    NobodyWhoError::new(ErrorCode::WorkerDied, "Embedding worker died.")
+                        .to_dictionary(),
+                );
             }
         } else {
             godot_warn!("Worker was not started yet, starting now... You may want to call `start_worker()` ahead of time to avoid waiting.");
             self.start_worker();
-            return self.embed(text);
+            if self.embed_tx.is_some() {
+                return self.embed(text);
+            }
         };
 
         return godot::builtin::Signal::from_object_signal(&self.base_mut(), "embedding_finished");
     }
 
+    #[func]
+    /// Checks whether the embeddings from this node can be compared with the embeddings from another embedding node,
+    /// i.e. whether both models produce embeddings with the same number of dimensions.
+    /// Use this to detect when a model change invalidates embeddings you have stored.
+    /// Note that two different models can still disagree about similarity, even when this returns true.
+    fn is_compatible_with(&mut self, mut other: Gd<NobodyWhoEmbedding>) -> bool {
+        if other == self.to_gd() {
+            return true;
+        }
+        let model = match self.get_model() {
+            Ok(model) => model,
+            Err(msg) => {
+                godot_error!("Could not check compatibility: {msg}");
+                return false;
+            }
+        };
+        let other_model = match other.bind_mut().get_model() {
+            Ok(model) => model,
+            Err(msg) => {
+                godot_error!("Could not check compatibility with other node: {msg}");
+                return false;
+            }
+        };
+        llm::embeddings_compatible(&model, &other_model)
+    }
+
     #[func]
     /// Calculates the similarity between two embedding vectors.
     /// Returns a value between 0 and 1, where 1 is the highest similarity.
     fn cosine_similarity(a: PackedFloat32Array, b: PackedFloat32Array) -> f32 {
         llm::cosine_similarity(a.as_slice(), b.as_slice())
     }
+
+    #[func]
+    /// Scales a vector to unit length (L2 normalization), like the `normalize` option does for new embeddings.
+    /// Useful for embeddings that were stored before `normalize` was enabled. A vector of all zeros is returned unchanged.
+    fn normalize_embedding(embedding: PackedFloat32Array) -> PackedFloat32Array {
+        PackedFloat32Array::from(llm::normalize_embedding(embedding.as_slice()).as_slice())
+    }
+
+    #[func]
+    /// Calculates the cosine similarity between every pair of the given embeddings.
+    /// Returns one row per embedding, where `result[i][j]` is the similarity between `embeddings[i]` and `embeddings[j]`.
+    /// This is much faster than calling `cosine_similarity` for every pair in GDScript, e.g. when looking for near-duplicate lines.
+    fn similarity_matrix(embeddings: Array<PackedFloat32Array>) -> Array<PackedFloat32Array> {
+        let embeddings: Vec<Vec<f32>> =
+            embeddings.iter_shared().map(|embd| embd.to_vec()).collect();
+        llm::similarity_matrix(&embeddings)
+            .iter()
+            .map(|row| PackedFloat32Array::from(row.as_slice()))
+            .collect()
+    }
 }
@@ -0,0 +1,45 @@
+use godot::prelude::*;
+
+use crate::sampler_resource::NobodyWhoSampler;
+
+#[derive(GodotClass)]
+#[class(tool, base=Resource)]
+/// The system prompt, sampler and stop tokens of a character, bundled so they can be saved as a `.tres` file
+/// and shared between chat nodes. Assign it to the `persona` of a NobodyWhoChat, which copies all three
+/// into its own settings when the worker starts.
+///
+/// Example:
+///
+/// ```
+/// var guard = load("res://personas/guard.tres")
+/// $GuardChat.persona = guard
+/// $GuardChat.start_worker()
+/// ```
+pub struct NobodyWhoPersona {
+    base: Base<Resource>,
+
+    #[export]
+    #[var(hint = MULTILINE_TEXT)]
+    /// The system prompt for the character.
+    pub system_prompt: GString,
+
+    #[export]
+    /// The sampler configuration for the character. Leave empty to use the default sampler.
+    pub sampler: Option<Gd<NobodyWhoSampler>>,
+
+    #[export]
+    /// Stop tokens for the character.
+    pub stop_tokens: PackedStringArray,
+}
+
+#[godot_api]
+impl IResource for NobodyWhoPersona {
+    fn init(base: Base<Resource>) -> Self {
+        Self {
+            base,
+            system_prompt: "".into(),
+            sampler: None,
+            stop_tokens: PackedStringArray::new(),
+        }
+    }
+}
@@ -1,23 +1,65 @@
+use godot::classes::ProjectSettings;
 use godot::global::PropertyHint;
 use godot::meta::PropertyHintInfo;
 use godot::prelude::*;
 use nobodywho::sampler_config;
 
-#[derive(GodotConvert, Var, Export, Debug, Clone, Copy)]
-#[godot(via=GString)]
-enum SamplerMethodName {
-    Greedy,
-    DRY,
-    TopK,
-    TopP,
-    MinP,
-    XTC,
-    TypicalP,
-    Temperature,
-    MirostatV1,
-    MirostatV2,
+use crate::errors::{ErrorCode, NobodyWhoError};
+
+/// The properties shared by all sampler methods, and the properties of each method.
+/// This is the only list of them: the macros below generate the method names, the inspector properties,
+/// their getters and setters, and `list_sampler_methods` from it.
+macro_rules! with_sampler_properties {
+    ($callback:ident!($($args:tt)*)) => {
+        $callback!($($args)*
+            base: {
+                penalty_last_n: i32 : NONE,
+                penalty_repeat: f32 : NONE,
+                penalty_freq: f32 : NONE,
+                penalty_present: f32 : NONE,
+                top_probability_floor: f32 : NONE,
+                use_grammar: bool : NONE,
+                gbnf_grammar: GString : MULTILINE_TEXT
+            },
+            methods: {
+                Greedy { },
+                DRY { seed: u32, dry_multiplier: f32, dry_base: f32, dry_allowed_length: i32, dry_penalty_last_n: i32 },
+                TopK { seed: u32, top_k: i32, temperature: f32 },
+                TopP { seed: u32, top_p: f32, temperature: f32 },
+                MinP { seed: u32, min_keep: u32, min_p: f32, temperature: f32 },
+                XTC { seed: u32, xtc_probability: f32, xtc_threshold: f32, min_keep: u32 },
+                TypicalP { seed: u32, typ_p: f32, min_keep: u32 },
+                Temperature { temperature: f32, seed: u32 },
+                MirostatV1 { temperature: f32, seed: u32, tau: f32, eta: f32 },
+                MirostatV2 { temperature: f32, seed: u32, tau: f32, eta: f32 },
+                Balanced { seed: u32, creativity: f32 }
+            }
+        )
+    };
 }
 
+macro_rules! method_names {
+    (base: {$($base:tt)*},
+     methods: {$($variant:ident { $($field:ident : $type:ty),*}),*}
+     ) => {
+        #[derive(GodotConvert, Var, Export, Debug, Clone, Copy)]
+        #[godot(via=GString)]
+        enum SamplerMethodName {
+            $($variant,)*
+        }
+
+        impl SamplerMethodName {
+            fn of(method: &sampler_config::SamplerMethod) -> Self {
+                match method {
+                    $(sampler_config::SamplerMethod::$variant(_) => SamplerMethodName::$variant,)*
+                }
+            }
+        }
+    };
+}
+
+with_sampler_properties!(method_names!());
+
 #[derive(GodotClass)]
 #[class(tool, base=Resource)]
 pub struct NobodyWhoSampler {
@@ -26,6 +68,11 @@ pub struct NobodyWhoSampler {
     #[export]
     method: SamplerMethodName,
 
+    #[export(file = "*.gbnf")]
+    /// A GBNF grammar file to use when `use_grammar` is enabled, for grammars that are too large to edit in the inspector.
+    /// The file is read when the worker starts. An inline `gbnf_grammar` takes precedence, unless it is empty or the default JSON grammar.
+    grammar_file: GString,
+
     pub sampler_config: sampler_config::SamplerConfig,
 }
 
@@ -64,7 +111,7 @@ macro_rules! property_list {
 macro_rules! get_property {
     ($self:expr,
      $property:expr,
-     base: {$($base_field:ident : $base_type:ty),*},
+     base: {$($base_field:ident : $base_type:ty : $property_hint:ident),*},
      methods: {$($variant:ident { $($variant_field:ident : $variant_type:ty),*}),*}
      ) => {{
         match (&$self.sampler_config.method, $property.to_string().as_str()) {
@@ -88,7 +135,7 @@ macro_rules! set_property {
     ($self:expr,
      $property:expr,
      $value:expr,
-     base: {$($base_field:ident : $base_type:ty),*},
+     base: {$($base_field:ident : $base_type:ty : $property_hint:ident),*},
      methods: {$($variant:ident { $($variant_field:ident : $variant_type:ty),*}),*}
      ) => {{
         match (&mut $self.sampler_config.method, $property.to_string().as_str()) {
@@ -111,9 +158,10 @@ macro_rules! set_property {
                 // generates arms like this:
                 //     (_, "penalty_last_n") => {
                 //         self.sampler_config.penalty_last_n =
-                //             i32::try_from_variant(&value).expect("Unexpected type for penalty_last_n");
+                //             FromGodot::try_from_variant(&value).expect("Unexpected type for penalty_last_n");
+                // the type comes from the field, as the inspector type can differ, e.g. `GString` for `String`
                 (_, stringify!($base_field)) => {
-                    $self.sampler_config.$base_field = <$base_type>::try_from_variant(&$value)
+                    $self.sampler_config.$base_field = FromGodot::try_from_variant(&$value)
                         .expect(format!("Unexpected type for {}", stringify!($base_field)).as_str());
                 }
             )*
@@ -135,103 +183,106 @@ macro_rules! set_property {
     }};
 }
 
+macro_rules! method_list {
+    (base: {$($base:tt)*},
+     methods: {$($variant:ident { $($field:ident : $type:ty),*}),*}) => {{
+        let mut methods = Dictionary::new();
+        $(
+            // makes entries like this:
+            // "TopK": {"seed": 1234, "top_k": 40, "temperature": 1.0}
+            #[allow(unused_mut)]
+            let mut parameters = Dictionary::new();
+            $(
+                parameters.set(
+                    stringify!($field),
+                    Variant::from(sampler_config::$variant::default().$field),
+                );
+            )*
+            methods.set(stringify!($variant), parameters);
+        )*
+        methods
+    }};
+}
+
+#[godot_api]
+impl NobodyWhoSampler {
+    #[func]
+    /// Returns an independent copy of this sampler. Sampler resources are shared between all chats that use them,
+    /// so use this to tweak the sampler of a single chat without affecting the others:
+    /// `chat.sampler = chat.sampler.clone_sampler()`
+    fn clone_sampler(&self) -> Gd<NobodyWhoSampler> {
+        Gd::from_init_fn(|base| Self {
+            base,
+            method: self.method,
+            grammar_file: self.grammar_file.clone(),
+            sampler_config: self.sampler_config.clone(),
+        })
+    }
+
+    /// Returns a copy of the sampler configuration, with the grammar read from `grammar_file` if it is used.
+    pub fn get_sampler_config(&self) -> Result<sampler_config::SamplerConfig, NobodyWhoError> {
+        let mut config = self.sampler_config.clone();
+        let inline_grammar_unset = config.gbnf_grammar.trim().is_empty()
+            || config.gbnf_grammar == sampler_config::SamplerConfig::default().gbnf_grammar;
+        if config.use_grammar && !self.grammar_file.is_empty() && inline_grammar_unset {
+            let path: String = ProjectSettings::singleton()
+                .globalize_path(&self.grammar_file)
+                .into();
+            config.gbnf_grammar = std::fs::read_to_string(&path).map_err(|e| {
+                NobodyWhoError::new(
+                    ErrorCode::GrammarFileFailed,
+                    format!("Could not read grammar file {path}: {e}"),
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    #[func]
+    /// Returns every sampler method, mapped to its parameters and their default values.
+    /// Useful for building sampler settings in-game without hardcoding them, e.g.
+    /// `NobodyWhoSampler.list_sampler_methods()["TopK"]` gives `{"seed": 1234, "top_k": 40, "temperature": 1.0}`.
+    fn list_sampler_methods() -> Dictionary {
+        with_sampler_properties!(method_list!())
+    }
+}
+
 #[godot_api]
 impl IResource for NobodyWhoSampler {
     fn init(base: Base<Resource>) -> Self {
-        let methodname = match sampler_config::SamplerConfig::default().method {
-            sampler_config::SamplerMethod::Greedy(_) => SamplerMethodName::Greedy,
-            sampler_config::SamplerMethod::DRY(_) => SamplerMethodName::DRY,
-            sampler_config::SamplerMethod::TopK(_) => SamplerMethodName::TopK,
-            sampler_config::SamplerMethod::TopP(_) => SamplerMethodName::TopP,
-            sampler_config::SamplerMethod::MinP(_) => SamplerMethodName::MinP,
-            sampler_config::SamplerMethod::XTC(_) => SamplerMethodName::XTC,
-            sampler_config::SamplerMethod::TypicalP(_) => SamplerMethodName::TypicalP,
-            sampler_config::SamplerMethod::Temperature(_) => SamplerMethodName::Temperature,
-            sampler_config::SamplerMethod::MirostatV1(_) => SamplerMethodName::MirostatV1,
-            sampler_config::SamplerMethod::MirostatV2(_) => SamplerMethodName::MirostatV2,
-        };
         Self {
-            method: methodname,
+            method: SamplerMethodName::of(&sampler_config::SamplerConfig::default().method),
+            grammar_file: GString::new(),
             sampler_config: sampler_config::SamplerConfig::default(),
             base,
         }
     }
 
     fn get_property_list(&mut self) -> Vec<godot::meta::PropertyInfo> {
-        property_list!(
-            self,
-            base: {
-                penalty_last_n: i32 : NONE,
-                penalty_repeat: f32 : NONE,
-                penalty_freq: f32 : NONE,
-                penalty_present: f32 : NONE,
-                use_grammar: bool : NONE,
-                gbnf_grammar: GString : MULTILINE_TEXT
-            },
-            methods: {
-                Greedy { },
-                DRY { seed: u32, dry_multiplier: f32, dry_base: f32, dry_allowed_length: i32, dry_penalty_last_n: i32 },
-                TopK { seed: u32, top_k: i32 },
-                TopP { seed: u32, top_p: f32 },
-                MinP { seed: u32, min_keep: u32, min_p: f32 },
-                XTC { seed: u32, xtc_probability: f32, xtc_threshold: f32, min_keep: u32 },
-                TypicalP { seed: u32, typ_p: f32, min_keep: u32 },
-                Temperature { temperature: f32, seed: u32 },
-                MirostatV1 { temperature: f32, seed: u32, tau: f32, eta: f32 },
-                MirostatV2 { temperature: f32, seed: u32, tau: f32, eta: f32 }
+        let mut properties = with_sampler_properties!(property_list!(self,));
+        // the balanced preset is meant to be tuned with a single slider, and the floor is a probability
+        for property in properties.iter_mut() {
+            if property.property_name == StringName::from("creativity")
+                || property.property_name == StringName::from("top_probability_floor")
+            {
+                property.hint_info = PropertyHintInfo {
+                    hint: PropertyHint::RANGE,
+                    hint_string: "0,1,0.01".into(),
+                };
             }
-        )
+        }
+        properties
     }
 
     fn get_property(&self, property: StringName) -> Option<Variant> {
-        get_property!(
-            self, property,
-            base: {
-                penalty_last_n: i32,
-                penalty_repeat: f32,
-                penalty_freq: f32,
-                penalty_present: f32,
-                use_grammar: bool,
-                gbnf_grammar: String
-            },
-            methods: {
-                Greedy { },
-                DRY { seed: u32, dry_multiplier: f32, dry_base: f32, dry_allowed_length: i32, dry_penalty_last_n: i32 },
-                TopK { seed: u32, top_k: i32 },
-                TopP { seed: u32, top_p: f32 },
-                MinP { seed: u32, min_keep: u32, min_p: f32 },
-                XTC { seed: u32, xtc_probability: f32, xtc_threshold: f32, min_keep: u32 },
-                TypicalP { seed: u32, typ_p: f32, min_keep: u32 },
-                Temperature { temperature: f32, seed: u32 },
-                MirostatV1 { temperature: f32, seed: u32, tau: f32, eta: f32 },
-                MirostatV2 { temperature: f32, seed: u32, tau: f32, eta: f32 }
-            }
-        )
+        with_sampler_properties!(get_property!(self, property,))
     }
 
     fn set_property(&mut self, property: StringName, value: Variant) -> bool {
-        set_property!(
-            self, property, value,
-            base: {
-                penalty_last_n: i32,
-                penalty_repeat: f32,
-                penalty_freq: f32,
-                penalty_present: f32,
-                use_grammar: bool,
-                gbnf_grammar: String
-            },
-            methods: {
-                Greedy { },
-                DRY { seed: u32, dry_multiplier: f32, dry_base: f32, dry_allowed_length: i32, dry_penalty_last_n: i32 },
-                TopK { seed: u32, top_k: i32 },
-                TopP { seed: u32, top_p: f32 },
-                MinP { seed: u32, min_keep: u32, min_p: f32 },
-                XTC { seed: u32, xtc_probability: f32, xtc_threshold: f32, min_keep: u32 },
-                TypicalP { seed: u32, typ_p: f32, min_keep: u32 },
-                Temperature { temperature: f32, seed: u32 },
-                MirostatV1 { temperature: f32, seed: u32, tau: f32, eta: f32 },
-                MirostatV2 { temperature: f32, seed: u32, tau: f32, eta: f32 }
-            }
-        )
+        // a regular exported field, so let godot set it
+        if property == StringName::from("grammar_file") {
+            return false;
+        }
+        with_sampler_properties!(set_property!(self, property, value,))
     }
 }